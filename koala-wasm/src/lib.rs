@@ -1,6 +1,9 @@
-use koala::compiler::{CodeGen, CompilerContext};
+use koala::backend::wasm;
+use koala::compiler::CompilerContext;
 use koala::grammar::Program;
+use koala::instructions;
 use koala::kvm::VirtualMachine;
+use koala::observer::{Step, VmObserver};
 use koala::parser::parse_code;
 use wasm_bindgen::prelude::*;
 
@@ -15,24 +18,135 @@ extern "C" {
     fn alert(s: &str);
 }
 
+/// Run bytecode, piping printed output to `output_callback`.
+/// When a `debug_callback` is passed, it is called with an object for every event the VM
+/// reports, such as `{ kind: "step", pc, opcode, stack }` or `{ kind: "call", pc, fn_addr, args }`.
+/// `input_callback` is called whenever the program has used up the input it has, and returns
+/// the next line of it as a string, or `null` once there is no more input. Programs don't see
+/// lines themselves: `read_int()` and `read_line()` both parse an integer from them, and
+/// `read_line()` then discards the rest of the line it was on.
+/// `rand()` starts from `seed`, or from a random one when it is not given.
+/// Returns the code the program passed to `exit()`, or 0 when it ran to the end
 #[wasm_bindgen]
 pub fn run(
     machine_code: &[u32],
     output_callback: &js_sys::Function,
-    debug_callback: &js_sys::Function,
-) {
+    debug_callback: Option<js_sys::Function>,
+    input_callback: Option<js_sys::Function>,
+    seed: Option<u32>,
+) -> i32 {
     let rust_output_callback = &|msg: &str| {
         output_callback
             .call1(&JsValue::NULL, &JsValue::from_str(msg))
             .unwrap();
     };
-    let rust_debug_callback = &|msg: &str| {
-        debug_callback
-            .call1(&JsValue::NULL, &JsValue::from_str(msg))
-            .unwrap();
+    let mut observer = debug_callback.map(|callback| JsObserver { callback });
+    let mut rust_input_callback = || {
+        let callback = input_callback.as_ref()?;
+        callback.call0(&JsValue::NULL).unwrap().as_string()
     };
-    let mut vm = VirtualMachine::new(rust_output_callback, rust_debug_callback);
+    let mut vm = VirtualMachine::new(rust_output_callback);
+    vm.set_input(&mut rust_input_callback);
+    vm.set_seed(match seed {
+        Some(seed) => seed as u64,
+        None => (js_sys::Math::random() * u32::MAX as f64) as u64,
+    });
+    // Instant is not available in the browser
+    let start = js_sys::Date::now();
+    vm.set_clock(Box::new(move || (js_sys::Date::now() - start) as i32));
+    // events are skipped entirely unless a debug callback is passed
+    if let Some(observer) = &mut observer {
+        vm.add_observer(observer);
+    }
     vm.run(machine_code);
+    vm.exit_code()
+}
+
+/// Forwards the events of the VM to JavaScript as objects
+struct JsObserver {
+    callback: js_sys::Function,
+}
+
+impl JsObserver {
+    fn send(&self, kind: &str, pc: usize, fields: &[(&str, JsValue)]) {
+        let event = js_sys::Object::new();
+        let set = |key: &str, value: &JsValue| {
+            js_sys::Reflect::set(&event, &JsValue::from_str(key), value).unwrap();
+        };
+        set("kind", &JsValue::from_str(kind));
+        set("pc", &JsValue::from(pc as u32));
+        for (key, value) in fields {
+            set(key, value);
+        }
+        self.callback.call1(&JsValue::NULL, &event).unwrap();
+    }
+}
+
+fn values(values: &[i32]) -> JsValue {
+    values
+        .iter()
+        .map(|val| JsValue::from(*val))
+        .collect::<js_sys::Array>()
+        .into()
+}
+
+impl VmObserver for JsObserver {
+    fn on_step(&mut self, step: &Step) {
+        self.send(
+            "step",
+            step.pc,
+            &[
+                ("opcode", JsValue::from_str(instructions::mnemonic(step.opcode()))),
+                ("stack", values(step.stack)),
+            ],
+        );
+    }
+
+    fn on_call(&mut self, pc: usize, fn_addr: usize, args: &[i32]) {
+        self.send(
+            "call",
+            pc,
+            &[
+                ("fn_addr", JsValue::from(fn_addr as u32)),
+                ("args", values(args)),
+            ],
+        );
+    }
+
+    fn on_return(&mut self, pc: usize, fn_addr: usize, locals: &[i32]) {
+        self.send(
+            "return",
+            pc,
+            &[
+                ("fn_addr", JsValue::from(fn_addr as u32)),
+                ("locals", values(locals)),
+            ],
+        );
+    }
+
+    fn on_print(&mut self, pc: usize, message: &str) {
+        self.send("print", pc, &[("message", JsValue::from_str(message))]);
+    }
+
+    fn on_store_local(&mut self, pc: usize, offset: usize, old: i32, new: i32) {
+        self.send("store_local", pc, &store_fields(offset, old, new));
+    }
+
+    fn on_store_global(&mut self, pc: usize, offset: usize, old: i32, new: i32) {
+        self.send("store_global", pc, &store_fields(offset, old, new));
+    }
+
+    fn on_error(&mut self, pc: usize, message: &str) {
+        self.send("error", pc, &[("message", JsValue::from_str(message))]);
+    }
+}
+
+fn store_fields(offset: usize, old: i32, new: i32) -> [(&'static str, JsValue); 3] {
+    [
+        ("offset", JsValue::from(offset as u32)),
+        ("old", JsValue::from(old)),
+        ("new", JsValue::from(new)),
+    ]
 }
 
 #[wasm_bindgen]
@@ -57,7 +171,7 @@ pub fn astCodeGen(ast_string: &str) -> Vec<u32> {
         Err(e) => panic!("{}", e),
     };
 
-    program.code_gen(&mut CompilerContext::new(), 0)
+    program.code_gen(&mut CompilerContext::new())
 }
 
 #[wasm_bindgen]
@@ -66,3 +180,25 @@ pub fn sourceCodeGen(source_code: &str) -> Vec<u32> {
     let ast_string = parseAst(source_code);
     astCodeGen(&ast_string)
 }
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+pub fn sourceWasmGen(source_code: &str) -> Vec<u8> {
+    let program = match parse_code(source_code) {
+        Ok(program) => program,
+        Err(e) => panic!("{}", e),
+    };
+
+    wasm::compile(&program).to_wasm()
+}
+
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+pub fn sourceWatGen(source_code: &str) -> String {
+    let program = match parse_code(source_code) {
+        Ok(program) => program,
+        Err(e) => panic!("{}", e),
+    };
+
+    wasm::compile(&program).to_wat()
+}
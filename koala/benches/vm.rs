@@ -0,0 +1,265 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use koala::compiler::CompilerContext;
+use koala::instructions;
+use koala::kvm::{self, VirtualMachine};
+use koala::observer::DebugPrinter;
+use koala::parser::parse_code;
+use koala::rvm::{self, RegisterMachine};
+use std::cell::RefCell;
+
+const FIB: &str = "
+fn main() {
+  print(fib(20))
+}
+
+fn fib(n) {
+  if n < 2 {
+    return 1
+  }
+  return fib(n - 1) + fib(n - 2)
+}
+";
+
+const LOOPS: &str = "
+fn main() {
+  let mut sum = 0
+  let mut i = 0
+  while i < 200 {
+    let mut j = 0
+    while j < 200 {
+      sum = sum + i * j
+      j = j + 1
+    }
+    i = i + 1
+  }
+  print(sum)
+}
+";
+
+const ARRAYS: &str = "
+fn main() {
+  global totals[50]
+  let a[50]
+  let mut round = 0
+  while round < 100 {
+    let mut i = 0
+    while i < 50 {
+      a[i] = a[i] + i
+      totals[i] = totals[i] + a[i]
+      i = i + 1
+    }
+    round = round + 1
+  }
+  print(totals[49])
+}
+";
+
+const PROGRAMS: [(&str, &str); 3] = [("fib", FIB), ("loops", LOOPS), ("arrays", ARRAYS)];
+
+/// Compare the stack VM with tracing off and on, the stack VM's old dispatch loop,
+/// and the register VM, on the same programs
+fn vm_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vm");
+    for (name, source) in PROGRAMS {
+        let module = parse_code(source)
+            .unwrap()
+            .to_ir(&mut CompilerContext::new());
+        let bytecode = module.link();
+        let stack_code = kvm::load(&bytecode).unwrap();
+        let register_code = rvm::translate(&module);
+        // the baseline has to compute the same results for the comparison to mean anything
+        let output = RefCell::new(String::new());
+        VirtualMachine::new(&|msg: &str| output.borrow_mut().push_str(msg))
+            .run_program(&stack_code);
+        let baseline: String = decode_per_step::run(&bytecode)
+            .iter()
+            .map(i32::to_string)
+            .collect();
+        assert_eq!(output.take(), baseline);
+
+        group.bench_with_input(BenchmarkId::new("kvm", name), &stack_code, |b, code| {
+            b.iter(|| VirtualMachine::new(&|_: &str| {}).run_program(code))
+        });
+        // the baseline the pre-decoded dispatch above is measured against
+        group.bench_with_input(
+            BenchmarkId::new("kvm-decode-per-step", name),
+            &bytecode,
+            |b, code| b.iter(|| decode_per_step::run(code)),
+        );
+        // the cost of formatting a trace for every instruction, which used to be paid on every run
+        group.bench_with_input(
+            BenchmarkId::new("kvm-traced", name),
+            &stack_code,
+            |b, code| {
+                let trace = |_: &str| {};
+                b.iter(|| {
+                    let mut debugger = DebugPrinter::new(&trace);
+                    let mut vm = VirtualMachine::new(&|_: &str| {});
+                    vm.add_observer(&mut debugger);
+                    vm.run_program(code)
+                })
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("rvm", name), &register_code, |b, code| {
+            b.iter(|| RegisterMachine::new(&|_: &str| {}, None).run(code))
+        });
+    }
+    group.finish();
+}
+
+/// The stack VM's dispatch loop from before instructions were pre-decoded, which reads the
+/// opcode and operands of every instruction from the bytecode as it runs. The debug trace it
+/// used to format on every step is left out, as `kvm-traced` measures that separately, and
+/// locals are stored the way the current VM stores them, so that it runs today's bytecode.
+mod decode_per_step {
+    use super::instructions;
+    use std::collections::HashMap;
+
+    struct Frame {
+        locals: Vec<i32>,
+        return_addr: usize,
+    }
+
+    pub fn run(code: &[u32]) -> Vec<i32> {
+        let mut pc = 0;
+        let mut call_stack: Vec<Frame> = Vec::new();
+        let mut stack: Vec<i32> = Vec::new();
+        let mut globals: HashMap<usize, i32> = HashMap::new();
+        let mut printed = Vec::new();
+        let fetch = |pc: &mut usize| {
+            *pc += 1;
+            code[*pc - 1]
+        };
+
+        loop {
+            let opcode = fetch(&mut pc);
+            match opcode {
+                instructions::END => break,
+                instructions::PUSH => stack.push(fetch(&mut pc) as i32),
+                instructions::POP => {
+                    stack.pop();
+                }
+                instructions::GT
+                | instructions::GTE
+                | instructions::LT
+                | instructions::LTE
+                | instructions::EQ
+                | instructions::NEQ => {
+                    let first = stack.pop().unwrap();
+                    let second = stack.pop().unwrap();
+                    let result = match opcode {
+                        instructions::GT => first > second,
+                        instructions::GTE => first >= second,
+                        instructions::LT => first < second,
+                        instructions::LTE => first <= second,
+                        instructions::EQ => first == second,
+                        _ => first != second,
+                    };
+                    stack.push(result as i32);
+                }
+                instructions::OR | instructions::AND => {
+                    let first = stack.pop().unwrap() != 0;
+                    let second = stack.pop().unwrap() != 0;
+                    let result = match opcode {
+                        instructions::OR => first || second,
+                        _ => first && second,
+                    };
+                    stack.push(result as i32);
+                }
+                instructions::IADD
+                | instructions::IMUL
+                | instructions::ISUB
+                | instructions::IDIV => {
+                    let first = stack.pop().unwrap();
+                    let second = stack.pop().unwrap();
+                    stack.push(match opcode {
+                        instructions::IADD => first.wrapping_add(second),
+                        instructions::IMUL => first.wrapping_mul(second),
+                        instructions::ISUB => first.wrapping_sub(second),
+                        _ => first / second,
+                    });
+                }
+                instructions::JUMP => pc = fetch(&mut pc) as usize,
+                instructions::BEQZ | instructions::BNEZ => {
+                    let branch_addr = fetch(&mut pc) as usize;
+                    let val = stack.pop().unwrap();
+                    if (opcode == instructions::BEQZ) == (val == 0) {
+                        pc = branch_addr;
+                    }
+                }
+                instructions::CALL => {
+                    let arg_count = fetch(&mut pc);
+                    let fn_addr = fetch(&mut pc) as usize;
+                    let locals = (0..arg_count).map(|_| stack.pop().unwrap()).collect();
+                    call_stack.push(Frame {
+                        locals,
+                        return_addr: pc,
+                    });
+                    pc = fn_addr;
+                }
+                instructions::RET => pc = call_stack.pop().unwrap().return_addr,
+                instructions::PRINT => {
+                    fetch(&mut pc);
+                    printed.push(stack.pop().unwrap());
+                }
+                instructions::LOCAL_LOAD => {
+                    let offset = fetch(&mut pc) as usize;
+                    let locals = &call_stack.last().unwrap().locals;
+                    stack.push(locals.get(offset).copied().unwrap_or(0));
+                }
+                instructions::LOCAL_STORE => {
+                    let offset = fetch(&mut pc) as usize;
+                    let val = stack.pop().unwrap();
+                    store(&mut call_stack.last_mut().unwrap().locals, offset, val);
+                }
+                instructions::LOCAL_ARR_LOAD => {
+                    let index = stack.pop().unwrap() as usize;
+                    let offset = stack.pop().unwrap() as usize;
+                    let locals = &call_stack.last().unwrap().locals;
+                    stack.push(locals.get(offset + index).copied().unwrap_or(0));
+                }
+                instructions::LOCAL_ARR_STORE => {
+                    let offset = stack.pop().unwrap() as usize;
+                    let index = stack.pop().unwrap() as usize;
+                    let val = stack.pop().unwrap();
+                    store(
+                        &mut call_stack.last_mut().unwrap().locals,
+                        offset + index,
+                        val,
+                    );
+                }
+                instructions::GLOBAL_LOAD => {
+                    let offset = fetch(&mut pc) as usize;
+                    stack.push(globals.get(&offset).copied().unwrap_or(0));
+                }
+                instructions::GLOBAL_STORE => {
+                    let offset = fetch(&mut pc) as usize;
+                    globals.insert(offset, stack.pop().unwrap());
+                }
+                instructions::GLOBAL_ARR_LOAD => {
+                    let index = stack.pop().unwrap() as usize;
+                    let offset = stack.pop().unwrap() as usize;
+                    stack.push(globals.get(&(offset + index)).copied().unwrap_or(0));
+                }
+                instructions::GLOBAL_ARR_STORE => {
+                    let offset = stack.pop().unwrap() as usize;
+                    let index = stack.pop().unwrap() as usize;
+                    globals.insert(offset + index, stack.pop().unwrap());
+                }
+                opcode => panic!("the baseline does not run opcode {:#x}", opcode),
+            }
+        }
+
+        printed
+    }
+
+    fn store(locals: &mut Vec<i32>, offset: usize, val: i32) {
+        if offset >= locals.len() {
+            locals.resize(offset + 1, 0);
+        }
+        locals[offset] = val;
+    }
+}
+
+criterion_group!(benches, vm_benchmark);
+criterion_main!(benches);
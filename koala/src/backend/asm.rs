@@ -0,0 +1,423 @@
+use crate::grammar::BinOp;
+use crate::ir::{self, Inst, Label, PrintType, ReadType, Terminator, ENTRY_POINT};
+use crate::natives;
+use crate::rng::{Rng, DEFAULT_SEED};
+use std::fmt::Write;
+
+/// Lower a module to x86-64 assembly for the GNU assembler, targeting Linux.
+///
+/// Each instruction is translated on its own, using the machine stack as the operand stack
+/// with one 8 byte slot per value, the same way the KVM executes it. Locals live in a frame
+/// of 4 byte slots below `%rbp`, arguments are passed on the stack with the first on top,
+/// and results are returned in `%eax`. Printing calls `printf` and reading calls `scanf`,
+/// so the output should be linked against the C runtime, e.g. with `cc program.s`.
+/// Natives are expanded inline, with `exit()` and `clock()` calling the C runtime.
+/// Reading anything other than an integer, calling `rand()` with an empty range,
+/// or a native failing, such as `assert()`, exits with status 1.
+pub fn emit(module: &ir::Module) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "    .text").unwrap();
+    for function in &module.functions {
+        writeln!(out).unwrap();
+        FunctionEmitter {
+            function,
+            out: &mut out,
+        }
+        .emit();
+    }
+
+    // the C runtime's entry point bootstraps the Koala one, the same way the KVM does.
+    // %rbx is used to realign the stack around printf, so it is saved for the caller
+    writeln!(out).unwrap();
+    writeln!(out, "    .globl main").unwrap();
+    writeln!(out, "main:").unwrap();
+    writeln!(out, "    pushq %rbx").unwrap();
+    writeln!(out, "    call {}", symbol(ENTRY_POINT)).unwrap();
+    writeln!(out, "    popq %rbx").unwrap();
+    writeln!(out, "    xorl %eax, %eax").unwrap();
+    writeln!(out, "    ret").unwrap();
+
+    let fails = ["pow", "sqrt", "assert"]
+        .iter()
+        .any(|name| module.calls_native(name));
+    if module.reads_input() || module.uses_rand() || fails {
+        // reads, rand() and natives jump here when they fail, from any stack depth
+        writeln!(out).unwrap();
+        writeln!(out, "runtime.error:").unwrap();
+        writeln!(out, "    andq $-16, %rsp").unwrap();
+        writeln!(out, "    movl $1, %edi").unwrap();
+        writeln!(out, "    call exit@PLT").unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "    .section .rodata").unwrap();
+    writeln!(out, "fmt.int:").unwrap();
+    writeln!(out, "    .string \"%d\"").unwrap();
+    writeln!(out, "fmt.char:").unwrap();
+    writeln!(out, "    .string \"%c\"").unwrap();
+    if module.reads_input() {
+        writeln!(out, "fmt.read_line:").unwrap();
+        writeln!(out, "    .string \"%d%*[^\\n]\"").unwrap();
+    }
+
+    if module.uses_rand() {
+        writeln!(out).unwrap();
+        writeln!(out, "    .data").unwrap();
+        writeln!(out, "    .align 4").unwrap();
+        writeln!(out, "rand.state:").unwrap();
+        writeln!(out, "    .long {}", Rng::new(DEFAULT_SEED).state()).unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "    .bss").unwrap();
+    writeln!(out, "    .align 4").unwrap();
+    writeln!(out, "globals:").unwrap();
+    writeln!(out, "    .zero {}", (module.globals_size * 4).max(4)).unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(out, "    .section .note.GNU-stack,\"\",@progbits").unwrap();
+
+    out
+}
+
+/// Koala functions are namespaced so they cannot collide with C symbols such as `main`
+fn symbol(id: &str) -> String {
+    format!("koala.{}", id.replace("::", "."))
+}
+
+struct FunctionEmitter<'a> {
+    function: &'a ir::Function,
+    out: &'a mut String,
+}
+
+impl<'a> FunctionEmitter<'a> {
+    fn emit(mut self) {
+        let function = self.function;
+        writeln!(self.out, "{}:", symbol(&function.id)).unwrap();
+
+        // reserve a zeroed frame, keeping %rsp 16 byte aligned, then copy the arguments in
+        self.line("pushq %rbp");
+        self.line("movq %rsp, %rbp");
+        if function.frame_size > 0 {
+            let frame_bytes = (function.frame_size * 4).div_ceil(16) * 16;
+            self.line(&format!("subq ${}, %rsp", frame_bytes));
+            self.line(&format!("leaq {}(%rbp), %rdi", self.frame_base()));
+            self.line(&format!("movl ${}, %ecx", function.frame_size));
+            self.line("xorl %eax, %eax");
+            self.line("rep stosl");
+        }
+        for i in 0..function.arity {
+            self.line(&format!("movl {}(%rbp), %eax", 16 + 8 * i));
+            self.line(&format!("movl %eax, {}", self.local(i)));
+        }
+
+        let reachable = function.reachable();
+        let blocks: Vec<&ir::Block> = function
+            .blocks
+            .iter()
+            .filter(|block| reachable.contains(&block.label))
+            .collect();
+        for (i, block) in blocks.iter().enumerate() {
+            writeln!(self.out, "{}:", self.label(block.label)).unwrap();
+            for inst in &block.insts {
+                self.inst(inst);
+            }
+            let next = blocks.get(i + 1).map(|next| next.label);
+            self.terminator(&block.terminator, next);
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        writeln!(self.out, "    {}", line).unwrap();
+    }
+
+    fn label(&self, label: Label) -> String {
+        format!(".L{}.{}", symbol(&self.function.id), label.0)
+    }
+
+    /// Offset from %rbp of the first frame slot
+    fn frame_base(&self) -> i64 {
+        -4 * self.function.frame_size as i64
+    }
+
+    fn local(&self, offset: usize) -> String {
+        format!("{}(%rbp)", self.frame_base() + 4 * offset as i64)
+    }
+
+    fn global(&self, offset: usize) -> String {
+        format!("globals+{}(%rip)", 4 * offset)
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Push(val) => self.line(&format!("pushq ${}", val)),
+            Inst::GlobalAddr(offset) => self.line(&format!("pushq ${}", offset)),
+            Inst::Pop => self.line("addq $8, %rsp"),
+            Inst::BinOp(binop) => {
+                self.line("popq %rax");
+                self.line("popq %rcx");
+                self.bin_op(*binop);
+                self.line("pushq %rax");
+            }
+            Inst::LocalLoad(offset) => {
+                self.line(&format!("movl {}, %eax", self.local(*offset)));
+                self.line("pushq %rax");
+            }
+            Inst::LocalStore(offset) => {
+                self.line("popq %rax");
+                self.line(&format!("movl %eax, {}", self.local(*offset)));
+            }
+            Inst::GlobalLoad(offset) => {
+                self.line(&format!("movl {}, %eax", self.global(*offset)));
+                self.line("pushq %rax");
+            }
+            Inst::GlobalStore(offset) => {
+                self.line("popq %rax");
+                self.line(&format!("movl %eax, {}", self.global(*offset)));
+            }
+            Inst::LocalArrLoad | Inst::GlobalArrLoad => {
+                // the index is on top of the base offset
+                self.line("popq %rax");
+                self.line("popq %rcx");
+                self.line("addl %ecx, %eax");
+                self.line("movslq %eax, %rax");
+                let slot = self.array_slot(matches!(inst, Inst::LocalArrLoad));
+                self.line(&format!("movl {}, %eax", slot));
+                self.line("pushq %rax");
+            }
+            Inst::LocalArrStore | Inst::GlobalArrStore => {
+                // the base offset is on top of the index, which is on top of the value
+                self.line("popq %rax");
+                self.line("popq %rcx");
+                self.line("popq %rdx");
+                self.line("addl %ecx, %eax");
+                self.line("movslq %eax, %rax");
+                let slot = self.array_slot(matches!(inst, Inst::LocalArrStore));
+                self.line(&format!("movl %edx, {}", slot));
+            }
+            Inst::Print(print_type) => {
+                let fmt = match print_type {
+                    PrintType::Int => "fmt.int",
+                    PrintType::Char => "fmt.char",
+                };
+                // the depth of the operand stack is not known here, so align it for the call
+                self.line("popq %rsi");
+                self.line(&format!("leaq {}(%rip), %rdi", fmt));
+                self.line("xorl %eax, %eax");
+                self.line("movq %rsp, %rbx");
+                self.line("andq $-16, %rsp");
+                self.line("call printf@PLT");
+                self.line("movq %rbx, %rsp");
+            }
+            Inst::Read(read_type) => {
+                let fmt = match read_type {
+                    ReadType::Int => "fmt.int",
+                    ReadType::Line => "fmt.read_line",
+                };
+                // scanf writes the value into a slot below the realigned stack
+                self.line("movq %rsp, %rbx");
+                self.line("andq $-16, %rsp");
+                self.line("subq $16, %rsp");
+                self.line("movq %rsp, %rsi");
+                self.line(&format!("leaq {}(%rip), %rdi", fmt));
+                self.line("xorl %eax, %eax");
+                self.line("call scanf@PLT");
+                self.line("cmpl $1, %eax");
+                self.line("jne runtime.error");
+                self.line("movl (%rsp), %eax");
+                self.line("movq %rbx, %rsp");
+                self.line("pushq %rax");
+            }
+            Inst::Rand => {
+                // lo is on top of hi
+                self.line("popq %rcx");
+                self.line("popq %rdx");
+                self.line("cmpl %ecx, %edx");
+                self.line("jl runtime.error");
+                // step the xorshift generator
+                self.line("movl rand.state(%rip), %eax");
+                for (shift, amount) in [("shll", 13), ("shrl", 17), ("shll", 5)] {
+                    self.line("movl %eax, %esi");
+                    self.line(&format!("{} ${}, %esi", shift, amount));
+                    self.line("xorl %esi, %eax");
+                }
+                self.line("movl %eax, rand.state(%rip)");
+                // the span wraps to 0 when it covers every i32, leaving the number as it is
+                self.line("subl %ecx, %edx");
+                self.line("addl $1, %edx");
+                self.line("jz 1f");
+                self.line("movl %edx, %esi");
+                self.line("xorl %edx, %edx");
+                self.line("divl %esi");
+                self.line("movl %edx, %eax");
+                writeln!(self.out, "1:").unwrap();
+                self.line("addl %ecx, %eax");
+                self.line("pushq %rax");
+            }
+            Inst::Call { id, argc } => {
+                self.line(&format!("call {}", symbol(id)));
+                if *argc > 0 {
+                    self.line(&format!("addq ${}, %rsp", 8 * argc));
+                }
+                self.line("pushq %rax");
+            }
+            Inst::CallNative { index, .. } => self.native(*index),
+        }
+    }
+
+    /// Expand a call to a standard native, whose first argument is on top of the stack
+    fn native(&mut self, index: usize) {
+        let name = match natives::STANDARD.get(index) {
+            Some((name, _)) => *name,
+            None => panic!(
+                "only the standard natives can be compiled, not native {}",
+                index
+            ),
+        };
+        match name {
+            "abs" => {
+                // keep the original value when negating it gives a negative number
+                self.line("popq %rax");
+                self.line("movl %eax, %ecx");
+                self.line("negl %eax");
+                self.line("cmovll %ecx, %eax");
+            }
+            "min" | "max" => {
+                self.line("popq %rax");
+                self.line("popq %rcx");
+                self.line("cmpl %ecx, %eax");
+                self.line(match name {
+                    "min" => "cmovgl %ecx, %eax",
+                    _ => "cmovll %ecx, %eax",
+                });
+            }
+            "pow" => {
+                // square the base for every bit of the exponent
+                self.line("popq %rcx");
+                self.line("popq %rdx");
+                self.line("testl %edx, %edx");
+                self.line("js runtime.error");
+                self.line("movl $1, %eax");
+                writeln!(self.out, "1:").unwrap();
+                self.line("testl %edx, %edx");
+                self.line("jz 3f");
+                self.line("testl $1, %edx");
+                self.line("jz 2f");
+                self.line("imull %ecx, %eax");
+                writeln!(self.out, "2:").unwrap();
+                self.line("imull %ecx, %ecx");
+                self.line("shrl $1, %edx");
+                self.line("jmp 1b");
+                writeln!(self.out, "3:").unwrap();
+            }
+            "sqrt" => {
+                self.line("popq %rax");
+                self.line("testl %eax, %eax");
+                self.line("js runtime.error");
+                self.line("cvtsi2sdl %eax, %xmm0");
+                self.line("sqrtsd %xmm0, %xmm0");
+                self.line("cvttsd2sil %xmm0, %eax");
+            }
+            "assert" => {
+                self.line("popq %rax");
+                self.line("testl %eax, %eax");
+                self.line("jz runtime.error");
+                self.line("xorl %eax, %eax");
+            }
+            "exit" => {
+                self.line("popq %rdi");
+                self.line("andq $-16, %rsp");
+                self.line("call exit@PLT");
+            }
+            "clock" => {
+                // clock() counts microseconds of processor time
+                self.line("movq %rsp, %rbx");
+                self.line("andq $-16, %rsp");
+                self.line("call clock@PLT");
+                self.line("movq %rbx, %rsp");
+                self.line("movl $1000, %ecx");
+                self.line("cqto");
+                self.line("idivq %rcx");
+            }
+            _ => unreachable!("standard native {}() is not implemented", name),
+        }
+        self.line("pushq %rax");
+    }
+
+    /// Address of the array slot indexed by %rax
+    fn array_slot(&mut self, local: bool) -> String {
+        match local {
+            true => format!("{}(%rbp,%rax,4)", self.frame_base()),
+            false => {
+                self.line("leaq globals(%rip), %rcx");
+                "(%rcx,%rax,4)".to_string()
+            }
+        }
+    }
+
+    /// Apply the operator to %eax and %ecx, leaving the result in %eax
+    fn bin_op(&mut self, binop: BinOp) {
+        match binop {
+            BinOp::Plus => self.line("addl %ecx, %eax"),
+            BinOp::Minus => self.line("subl %ecx, %eax"),
+            BinOp::Mul => self.line("imull %ecx, %eax"),
+            BinOp::Div => {
+                self.line("cltd");
+                self.line("idivl %ecx");
+            }
+            BinOp::Or | BinOp::And => {
+                // both operands are evaluated, then compared against zero like the VM does
+                self.line("testl %eax, %eax");
+                self.line("setne %al");
+                self.line("testl %ecx, %ecx");
+                self.line("setne %cl");
+                self.line(match binop {
+                    BinOp::Or => "orb %cl, %al",
+                    _ => "andb %cl, %al",
+                });
+                self.line("movzbl %al, %eax");
+            }
+            _ => {
+                let set = match binop {
+                    BinOp::Less => "setl",
+                    BinOp::LessOrEqual => "setle",
+                    BinOp::Greater => "setg",
+                    BinOp::GreaterOrEqual => "setge",
+                    BinOp::Equal => "sete",
+                    _ => "setne",
+                };
+                self.line("cmpl %ecx, %eax");
+                self.line(&format!("{} %al", set));
+                self.line("movzbl %al, %eax");
+            }
+        }
+    }
+
+    fn terminator(&mut self, terminator: &Terminator, next: Option<Label>) {
+        match terminator {
+            Terminator::Jump(label) => {
+                if next != Some(*label) {
+                    self.line(&format!("jmp {}", self.label(*label)));
+                }
+            }
+            Terminator::Branch { nonzero, zero } => {
+                self.line("popq %rax");
+                self.line("testl %eax, %eax");
+                if next == Some(*nonzero) {
+                    self.line(&format!("je {}", self.label(*zero)));
+                } else {
+                    self.line(&format!("jne {}", self.label(*nonzero)));
+                    if next != Some(*zero) {
+                        self.line(&format!("jmp {}", self.label(*zero)));
+                    }
+                }
+            }
+            Terminator::Ret => {
+                self.line("popq %rax");
+                self.line("leave");
+                self.line("ret");
+            }
+        }
+    }
+}
@@ -0,0 +1,752 @@
+use super::{calls_builtin, global_vars, has_call, reads_input};
+use crate::compiler::{const_size, find_loop, CompilerContext};
+use crate::grammar::{
+    BinExpr, BinOp, Expr, FunctionCall, FunctionDefinition, If, IfElse, Program, Statement,
+};
+use crate::ir::{ReadType, ENTRY_POINT};
+use crate::natives;
+use crate::rng::{Rng, DEFAULT_SEED};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Translate a program into a standalone C source file.
+///
+/// Constants are inlined as literals. Locals become C variables declared at the top of each function, while globals and arrays
+/// become static storage. The KVM evaluates the right operand of a binary expression and the
+/// last argument of a call first, so those are hoisted into temporaries whenever more than
+/// one of them could print something. Input is read with `scanf`, exiting with status 1
+/// when it does not find an integer, and `rand()` steps the same generator as the VMs.
+/// Natives are helper functions, which exit with status 1 when they fail, and so is division,
+/// which fails on a zero divisor or an overflowing quotient like the VM does. Addition,
+/// subtraction and multiplication wrap through `unsigned int`, as they do in the other backends.
+///
+/// The program is checked first, so that it is rejected with the same errors as `koalac`
+/// would report, and the translation can assume it is valid.
+pub fn emit(program: &Program) -> String {
+    if let Err(e) = program.check(&CompilerContext::new()) {
+        panic!("{}", e);
+    }
+    let consts = program.const_values();
+    let globals = global_vars(program);
+
+    let mut out = String::new();
+    writeln!(out, "#include <stdio.h>").unwrap();
+    let uses_rand = calls_builtin(program, &["rand"]);
+    let natives: Vec<&str> = natives::STANDARD
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| calls_builtin(program, &[name]))
+        .collect();
+    let arities: HashMap<String, usize> = program
+        .0
+        .iter()
+        .map(|def| (def.id.clone(), def.args.len()))
+        .collect();
+    // the definitions come first, since they tell whether the division helper is needed
+    let mut definitions = String::new();
+    let mut divides = false;
+    for def in &program.0 {
+        writeln!(definitions).unwrap();
+        divides |=
+            FunctionEmitter::new(&globals, &consts, &arities).function(def, &mut definitions);
+    }
+
+    if reads_input(program) || uses_rand || divides || !natives.is_empty() {
+        writeln!(out, "#include <stdlib.h>").unwrap();
+    }
+    if divides {
+        writeln!(out, "#include <limits.h>").unwrap();
+    }
+    if natives.contains(&"clock") {
+        writeln!(out, "#include <time.h>").unwrap();
+    }
+    if reads_input(program) {
+        writeln!(out).unwrap();
+        out.push_str(READ_HELPERS);
+    }
+    if uses_rand {
+        writeln!(out).unwrap();
+        writeln!(
+            out,
+            "static unsigned int _rand_state = {}u;",
+            Rng::new(DEFAULT_SEED).state()
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+        out.push_str(RAND_HELPER);
+    }
+    if divides {
+        writeln!(out).unwrap();
+        out.push_str(DIV_HELPER);
+    }
+    for name in natives {
+        writeln!(out).unwrap();
+        out.push_str(native_helper(name));
+    }
+
+    if !globals.is_empty() {
+        writeln!(out).unwrap();
+        let mut names: Vec<&String> = globals.keys().collect();
+        names.sort();
+        for name in names {
+            match globals[name] {
+                Some(size) => writeln!(out, "static int {}[{}];", var_name(name), size),
+                None => writeln!(out, "static int {};", var_name(name)),
+            }
+            .unwrap();
+        }
+    }
+
+    // prototypes allow functions to be called before they are defined
+    writeln!(out).unwrap();
+    for def in &program.0 {
+        writeln!(out, "{};", signature(def)).unwrap();
+    }
+
+    out.push_str(&definitions);
+
+    writeln!(out).unwrap();
+    writeln!(out, "int main(void) {{").unwrap();
+    writeln!(out, "    {}();", fn_name(ENTRY_POINT)).unwrap();
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    out
+}
+
+/// Implementations of `read_int()` and `read_line()`.
+/// Koala identifiers start with a letter, so the leading underscore avoids any collision
+const READ_HELPERS: &str = "\
+static int _read_int(void) {
+    int val;
+    if (scanf(\"%d\", &val) != 1) {
+        exit(1);
+    }
+    return val;
+}
+
+static int _read_line(void) {
+    int val = _read_int();
+    scanf(\"%*[^\\n]\");
+    return val;
+}
+";
+
+/// Implementation of `rand()`, which exits with status 1 when the range is empty
+const RAND_HELPER: &str = "\
+static int _rand(int lo, int hi) {
+    unsigned int x = _rand_state;
+    unsigned int span;
+    if (hi < lo) {
+        exit(1);
+    }
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    _rand_state = x;
+    /* the span wraps to 0 when it covers every int */
+    span = (unsigned int)hi - (unsigned int)lo + 1u;
+    return (int)((unsigned int)lo + (span == 0u ? x : x % span));
+}
+";
+
+/// Signed division, which exits with status 1 where `/` would be undefined
+const DIV_HELPER: &str = "\
+static int _div(int lhs, int rhs) {
+    if (rhs == 0 || (lhs == INT_MIN && rhs == -1)) {
+        exit(1);
+    }
+    return lhs / rhs;
+}
+";
+
+/// Implementation of a standard native, named `_native_<name>`
+fn native_helper(name: &str) -> &'static str {
+    match name {
+        "abs" => {
+            "\
+static int _native_abs(int x) {
+    return x < 0 ? (int)(0u - (unsigned int)x) : x;
+}
+"
+        }
+        "min" => {
+            "\
+static int _native_min(int a, int b) {
+    return a < b ? a : b;
+}
+"
+        }
+        "max" => {
+            "\
+static int _native_max(int a, int b) {
+    return a > b ? a : b;
+}
+"
+        }
+        "pow" => {
+            "\
+static int _native_pow(int base, int exp) {
+    unsigned int result = 1u;
+    unsigned int b = (unsigned int)base;
+    if (exp < 0) {
+        exit(1);
+    }
+    for (; exp > 0; exp >>= 1) {
+        if (exp & 1) {
+            result *= b;
+        }
+        b *= b;
+    }
+    return (int)result;
+}
+"
+        }
+        "sqrt" => {
+            "\
+static int _native_sqrt(int x) {
+    long long r = x;
+    if (x < 0) {
+        exit(1);
+    }
+    while (r * r > x) {
+        r = (r + x / r) / 2;
+    }
+    return (int)r;
+}
+"
+        }
+        "assert" => {
+            "\
+static int _native_assert(int cond) {
+    if (!cond) {
+        exit(1);
+    }
+    return 0;
+}
+"
+        }
+        "exit" => {
+            "\
+static int _native_exit(int code) {
+    exit(code);
+}
+"
+        }
+        "clock" => {
+            "\
+static int _native_clock(void) {
+    return (int)(clock() / (CLOCKS_PER_SEC / 1000));
+}
+"
+        }
+        _ => unreachable!("standard native {}() is not implemented", name),
+    }
+}
+
+const C_KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "inline", "int", "long", "main", "printf",
+    "register", "restrict", "return", "short", "signed", "sizeof", "static", "struct", "switch",
+    "typedef", "union", "unsigned", "void", "volatile", "while",
+];
+
+/// Spell an identifier with the characters of a C identifier, doubling `_` so that the
+/// `::` of a module's namespace and the `.` of a for loop's hidden variables can be
+/// written `_s` and `_d` without two names ending up the same
+fn escape(id: &str) -> String {
+    id.replace('_', "__").replace("::", "_s").replace('.', "_d")
+}
+
+/// Koala identifiers are valid C identifiers once escaped. Keywords get a trailing `_`,
+/// which is not the escape of anything, so they can't clash with another name either
+fn var_name(id: &str) -> String {
+    match C_KEYWORDS.contains(&id) {
+        true => format!("{}_", id),
+        false => escape(id),
+    }
+}
+
+/// Functions are prefixed so that they cannot collide with the C library
+fn fn_name(id: &str) -> String {
+    format!("koala_{}", escape(id))
+}
+
+fn signature(def: &FunctionDefinition) -> String {
+    let params: Vec<String> = def
+        .args
+        .iter()
+        .map(|arg| format!("int {}", var_name(arg)))
+        .collect();
+    format!(
+        "int {}({})",
+        fn_name(&def.id),
+        match params.is_empty() {
+            true => "void".to_string(),
+            false => params.join(", "),
+        }
+    )
+}
+
+struct FunctionEmitter<'a> {
+    globals: &'a HashMap<String, Option<u32>>,
+    consts: &'a HashMap<String, i32>,
+    /// Parameter counts of every function in the program
+    arities: &'a HashMap<String, usize>,
+    /// The blocks enclosing the current statement, innermost last,
+    /// with the C name of each variable they declare
+    scopes: Vec<HashMap<String, String>>,
+    /// C names which are taken, so that shadowing variables can be given names of their own
+    names: HashSet<String>,
+    /// Lengths of the local arrays by C name, for `len()`
+    array_lens: HashMap<String, u32>,
+    /// The loops enclosing the current statement, innermost last
+    loops: Vec<CLoop>,
+    /// Declarations for the top of the function
+    decls: Vec<String>,
+    body: String,
+    indent: usize,
+    next_temp: usize,
+    next_loop: usize,
+    /// Whether the function divides, which needs the division helper
+    divides: bool,
+}
+
+/// A loop being emitted. `break` and `continue` use C's own statements where they can,
+/// and otherwise jump to labels numbered after the loop, which are only emitted when used.
+struct CLoop {
+    label: Option<String>,
+    id: usize,
+    /// Whether a step follows the body, which C's `continue` would skip
+    has_step: bool,
+    break_used: bool,
+    continue_used: bool,
+}
+
+impl<'a> FunctionEmitter<'a> {
+    fn new(
+        globals: &'a HashMap<String, Option<u32>>,
+        consts: &'a HashMap<String, i32>,
+        arities: &'a HashMap<String, usize>,
+    ) -> Self {
+        FunctionEmitter {
+            globals,
+            consts,
+            arities,
+            scopes: Vec::new(),
+            names: globals.keys().map(|id| var_name(id)).collect(),
+            array_lens: HashMap::new(),
+            loops: Vec::new(),
+            decls: Vec::new(),
+            body: String::new(),
+            indent: 1,
+            next_temp: 0,
+            next_loop: 0,
+            divides: false,
+        }
+    }
+
+    /// Write the definition of the function, returning whether it divides
+    fn function(mut self, def: &FunctionDefinition, out: &mut String) -> bool {
+        let args = def.args.iter().map(|id| (id.clone(), var_name(id)));
+        self.scopes.push(args.collect());
+        self.names.extend(self.scopes[0].values().cloned());
+        self.block(&def.body);
+        // falling off the end of a function returns 0
+        if !matches!(
+            def.body.last(),
+            Some(Statement::Return | Statement::ReturnExpr(_))
+        ) {
+            self.line("return 0;");
+        }
+
+        writeln!(out, "{} {{", signature(def)).unwrap();
+        for decl in &self.decls {
+            writeln!(out, "    {}", decl).unwrap();
+        }
+        if !self.decls.is_empty() {
+            writeln!(out).unwrap();
+        }
+        out.push_str(&self.body);
+        writeln!(out, "}}").unwrap();
+        self.divides
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.body.push_str("    ");
+        }
+        self.body.push_str(line);
+        self.body.push('\n');
+    }
+
+    fn block(&mut self, stmts: &[Statement]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn indented_block(&mut self, stmts: &[Statement]) {
+        self.indent += 1;
+        self.scopes.push(HashMap::new());
+        self.block(stmts);
+        self.scopes.pop();
+        self.indent -= 1;
+    }
+
+    /// C name of the innermost local with an ID
+    fn local(&self, id: &str) -> Option<&String> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(id))
+    }
+
+    /// Resolve a variable the way the compiler does, preferring locals over globals
+    fn var(&self, id: &str) -> String {
+        if let Some(val) = self.consts.get(id) {
+            return val.to_string();
+        }
+        match self.local(id) {
+            Some(name) => name.clone(),
+            None => var_name(id),
+        }
+    }
+
+    /// Declare a local in the current block, returning its C name.
+    /// Every declaration gets a name of its own, which also keeps shadowed variables apart
+    fn declare_local(&mut self, id: &str, size: Option<u32>) -> String {
+        let base = var_name(id);
+        let mut name = base.clone();
+        let mut suffix = 1;
+        while !self.names.insert(name.clone()) {
+            name = format!("{}_{}", base, suffix);
+            suffix += 1;
+        }
+        self.decls.push(match size {
+            Some(size) => format!("int {}[{}] = {{0}};", name, size),
+            None => format!("int {} = 0;", name),
+        });
+        let scope = self.scopes.last_mut().unwrap();
+        scope.insert(id.to_string(), name.clone());
+        name
+    }
+
+    fn temp(&mut self, value: String) -> String {
+        let name = format!("_t{}", self.next_temp);
+        self.next_temp += 1;
+        self.line(&format!("int {} = {};", name, value));
+        name
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Print { expr, newline } => {
+                if let Some(e) = expr {
+                    let val = self.top_expr(e);
+                    self.line(&format!("printf(\"%d\", {});", val));
+                }
+                if *newline {
+                    self.line("printf(\"\\n\");");
+                }
+            }
+            Statement::Let { id, expr, .. } => {
+                let val = self.top_expr(expr);
+                let var = self.declare_local(id, None);
+                self.line(&format!("{} = {};", var, val));
+            }
+            Statement::VarAssignment { id, expr, global } => {
+                let val = self.top_expr(expr);
+                let var = match self.local(id) {
+                    Some(name) if !*global => name.clone(),
+                    _ => var_name(id),
+                };
+                self.line(&format!("{} = {};", var, val));
+            }
+            Statement::ArrayIndexAssignment { id, index, expr } => {
+                let val = self.top_expr(expr);
+                let val = match has_call(index) {
+                    true => self.temp(val),
+                    false => val,
+                };
+                let index = self.top_expr(index);
+                let var = self.var(id);
+                self.line(&format!("{}[{}] = {};", var, index, val));
+            }
+            Statement::ArrayInstantiation {
+                id,
+                size,
+                elements,
+                global,
+            } => {
+                if let Some(size) = const_size(size, self.consts) {
+                    let var = match global {
+                        true => var_name(id),
+                        false => self.declare_local(id, Some(size)),
+                    };
+                    if !*global {
+                        self.array_lens.insert(var.clone(), size);
+                    }
+                    for index in 0..size as usize {
+                        let val = match elements {
+                            Some(elements) => self.top_expr(&elements[index]),
+                            None => "0".to_string(),
+                        };
+                        self.line(&format!("{}[{}] = {};", var, index, val));
+                    }
+                }
+            }
+            Statement::FunctionCall(func_call) => {
+                let call = self.call(func_call);
+                self.line(&format!("{};", call));
+            }
+            Statement::If(if_data) => self.if_stmt(if_data),
+            Statement::IfElse(if_else) => self.if_else(if_else),
+            Statement::While { cond, stmts, label } => {
+                self.while_loop(label, cond, None, stmts, None)
+            }
+            Statement::For(for_data) => {
+                let for_loop = for_data.desugar(self.consts, |array| self.array_len(array));
+                // the counter and bounds are only visible to the loop
+                self.scopes.push(HashMap::new());
+                for stmt in &for_loop.init {
+                    self.statement(stmt);
+                }
+                self.while_loop(
+                    &for_data.label,
+                    &for_loop.cond,
+                    Some(&for_loop.bind),
+                    &for_data.stmts,
+                    Some(&for_loop.step),
+                );
+                self.scopes.pop();
+            }
+            Statement::Return => self.line("return 0;"),
+            Statement::ReturnExpr(expr) => {
+                let val = self.top_expr(expr);
+                self.line(&format!("return {};", val));
+            }
+            Statement::Break(label) => {
+                let index = self.find_loop(label, "break");
+                let innermost = index + 1 == self.loops.len();
+                let target = &mut self.loops[index];
+                if innermost {
+                    self.line("break;");
+                } else {
+                    target.break_used = true;
+                    let line = format!("goto break_{};", target.id);
+                    self.line(&line);
+                }
+            }
+            Statement::Continue(label) => {
+                let index = self.find_loop(label, "continue");
+                let innermost = index + 1 == self.loops.len();
+                let target = &mut self.loops[index];
+                if innermost && !target.has_step {
+                    self.line("continue;");
+                } else {
+                    target.continue_used = true;
+                    let line = format!("goto continue_{};", target.id);
+                    self.line(&line);
+                }
+            }
+            Statement::When(_) => { /* no-op */ }
+        }
+    }
+
+    fn find_loop(&self, label: &Option<String>, keyword: &str) -> usize {
+        let labels = self.loops.iter().map(|c_loop| c_loop.label.as_ref());
+        match find_loop(labels, label, keyword) {
+            Ok(index) => index,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// A while loop, whose body starts by declaring `bind` and is followed by `step`
+    fn while_loop(
+        &mut self,
+        label: &Option<String>,
+        cond: &Expr,
+        bind: Option<&Statement>,
+        stmts: &[Statement],
+        step: Option<&Statement>,
+    ) {
+        let mark = self.body.len();
+        let val = self.top_expr(cond);
+        if self.body.len() == mark {
+            self.line(&format!("while ({}) {{", val));
+        } else {
+            // the condition needed temporaries, so evaluate it inside the loop
+            let hoisted = self.body.split_off(mark);
+            self.line("while (1) {");
+            self.indent += 1;
+            for line in hoisted.lines() {
+                self.line(line.trim_start());
+            }
+            self.line(&format!("if (!({})) break;", val));
+            self.indent -= 1;
+        }
+        self.indent += 1;
+        self.loops.push(CLoop {
+            label: label.clone(),
+            id: self.next_loop,
+            has_step: step.is_some(),
+            break_used: false,
+            continue_used: false,
+        });
+        self.next_loop += 1;
+        self.scopes.push(HashMap::new());
+        for stmt in bind.into_iter().chain(stmts) {
+            self.statement(stmt);
+        }
+        self.scopes.pop();
+        let c_loop = self.loops.pop().unwrap();
+        if c_loop.continue_used {
+            self.line(&format!("continue_{}:;", c_loop.id));
+        }
+        if let Some(step) = step {
+            self.statement(step);
+        }
+        self.indent -= 1;
+        self.line("}");
+        if c_loop.break_used {
+            self.line(&format!("break_{}:;", c_loop.id));
+        }
+    }
+
+    fn if_stmt(&mut self, if_data: &If) {
+        let cond = self.top_expr(&if_data.expr);
+        self.line(&format!("if ({}) {{", cond));
+        self.indented_block(&if_data.stmts);
+        self.line("}");
+    }
+
+    fn if_else(&mut self, if_else: &IfElse) {
+        let cond = self.top_expr(&if_else.expr);
+        self.line(&format!("if ({}) {{", cond));
+        self.indented_block(&if_else.stmts);
+        self.line("} else {");
+        self.indented_block(&if_else.else_stmts);
+        self.line("}");
+    }
+
+    fn call(&mut self, func_call: &FunctionCall) -> String {
+        // the program's own functions shadow builtins and natives of the same name
+        let defined = self.arities.contains_key(&func_call.id);
+        let builtin = !defined && func_call.is_builtin();
+        if builtin && func_call.id == "len" {
+            return self.array_len(&func_call.args[0]).to_string();
+        }
+        let native = natives::standard_index(&func_call.id).filter(|_| !defined);
+        if let Some(read_type) = ReadType::builtin(&func_call.id).filter(|_| builtin) {
+            return match read_type {
+                ReadType::Int => "_read_int()".to_string(),
+                ReadType::Line => "_read_line()".to_string(),
+            };
+        }
+        // arguments are evaluated last to first
+        let arity = match builtin || native.is_some() {
+            true => func_call.args.len(),
+            false => self.arities.get(&func_call.id).copied().unwrap_or(0),
+        };
+        let ordered = func_call.args.iter().filter(|arg| has_call(arg)).count() > 1
+            || func_call.args.iter().skip(arity).any(has_call);
+        let mut args: Vec<String> = Vec::new();
+        for arg in func_call.args.iter().rev() {
+            let val = self.top_expr(arg);
+            args.push(match ordered && has_call(arg) {
+                true => self.temp(val),
+                false => val,
+            });
+        }
+        args.reverse();
+        if builtin {
+            return format!("_{}({})", func_call.id, args.join(", "));
+        }
+        if native.is_some() {
+            return format!("_native_{}({})", func_call.id, args.join(", "));
+        }
+        // the VM ignores surplus arguments, but C would reject the call
+        args.truncate(self.arities[&func_call.id]);
+        format!("{}({})", fn_name(&func_call.id), args.join(", "))
+    }
+
+    /// Length of the array passed to `len()`, preferring a local array to a global one
+    fn array_len(&self, arg: &Expr) -> u32 {
+        let id = match arg {
+            Expr::Variable { id } => id,
+            _ => unreachable!("len() of an expression was rejected by the compiler"),
+        };
+        let len = match self.local(id) {
+            Some(name) => self.array_lens.get(name).copied(),
+            None => self.globals.get(id).copied().flatten(),
+        };
+        len.unwrap_or_else(|| unreachable!("len() of '{}' was rejected by the compiler", id))
+    }
+
+    /// Translate an expression which is not nested in another,
+    /// leaving off the parentheses binary expressions are wrapped in
+    fn top_expr(&mut self, expr: &Expr) -> String {
+        let val = self.expr(expr);
+        match expr {
+            Expr::BinExpr(_) => val[1..val.len() - 1].to_string(),
+            _ => val,
+        }
+    }
+
+    /// Translate an expression, emitting any temporaries it needs into the current block
+    fn expr(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::IntLit(int) => (*int as i32).to_string(),
+            Expr::BoolLit(truthy) => (*truthy as i32).to_string(),
+            Expr::StringLit(_) => panic!("string literals are not supported yet."),
+            Expr::ArrayIndex { id, expr } => {
+                let index = self.top_expr(expr);
+                format!("{}[{}]", self.var(id), index)
+            }
+            Expr::Variable { id } => self.var(id),
+            Expr::FunctionCall(func_call) => self.call(func_call),
+            Expr::BinExpr(bin_expr) => self.bin_expr(bin_expr),
+        }
+    }
+
+    fn bin_expr(&mut self, bin_expr: &BinExpr) -> String {
+        let ordered = has_call(&bin_expr.op1) && has_call(&bin_expr.op2);
+        let mut rhs = self.expr(&bin_expr.op2);
+        if ordered {
+            rhs = self.temp(rhs);
+        }
+        let lhs = self.expr(&bin_expr.op1);
+
+        match bin_expr.binop {
+            // both sides are always evaluated, like the VM does
+            BinOp::Or => format!("({} != 0 | {} != 0)", lhs, rhs),
+            BinOp::And => format!("({} != 0 & {} != 0)", lhs, rhs),
+            // signed overflow is undefined in C, while unsigned arithmetic wraps
+            BinOp::Plus | BinOp::Minus | BinOp::Mul => format!(
+                "((int)((unsigned int){} {} (unsigned int){}))",
+                lhs,
+                match bin_expr.binop {
+                    BinOp::Plus => "+",
+                    BinOp::Minus => "-",
+                    _ => "*",
+                },
+                rhs
+            ),
+            BinOp::Div => {
+                self.divides = true;
+                format!("(_div({}, {}))", lhs, rhs)
+            }
+            binop => format!(
+                "({} {} {})",
+                lhs,
+                match binop {
+                    BinOp::Less => "<",
+                    BinOp::LessOrEqual => "<=",
+                    BinOp::Greater => ">",
+                    BinOp::GreaterOrEqual => ">=",
+                    BinOp::Equal => "==",
+                    _ => "!=",
+                },
+                rhs
+            ),
+        }
+    }
+}
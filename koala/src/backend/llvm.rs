@@ -0,0 +1,613 @@
+use crate::grammar::BinOp;
+use crate::ir::{self, Inst, PrintType, ReadType, Terminator, ENTRY_POINT};
+use crate::natives;
+use crate::rng::{Rng, DEFAULT_SEED};
+use std::fmt::Write;
+
+/// Lower a module to textual LLVM IR (`.ll`).
+///
+/// Every KVM frame becomes a stack allocated array of i32 slots and the globals a single
+/// global array, so dynamically indexed arrays keep working. The operand stack is resolved
+/// into SSA values while emitting each block, which is possible because blocks always start
+/// with an empty stack. Pointers are opaque `ptr`s, as required from LLVM 17 on
+/// (LLVM 14 reads them with `-opaque-pointers`). Division goes through a helper which exits
+/// with status 1 when the divisor is zero or the quotient overflows, where the VM would fail.
+/// Reads go through a helper around `scanf`, which exits with status 1 unless it finds an integer.
+/// `rand()` goes through a helper stepping the same xorshift generator as the VMs,
+/// and each native through a helper of its own, which exits with status 1 if the native fails.
+pub fn emit(module: &ir::Module) -> String {
+    let mut out = String::new();
+
+    writeln!(out, "; ModuleID = 'koala'").unwrap();
+    writeln!(
+        out,
+        "@globals = internal global [{} x i32] zeroinitializer",
+        module.globals_size
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "@fmt.int = private unnamed_addr constant [3 x i8] c\"%d\\00\""
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "@fmt.char = private unnamed_addr constant [3 x i8] c\"%c\\00\""
+    )
+    .unwrap();
+    if module.reads_input() {
+        writeln!(
+            out,
+            "@fmt.read_line = private unnamed_addr constant [9 x i8] c\"%d%*[^\\0A]\\00\""
+        )
+        .unwrap();
+    }
+    if module.uses_rand() {
+        writeln!(
+            out,
+            "@rand.state = internal global i32 {}",
+            Rng::new(DEFAULT_SEED).state()
+        )
+        .unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "declare i32 @printf(ptr, ...)").unwrap();
+    let natives: Vec<&str> = natives::STANDARD
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| module.calls_native(name))
+        .collect();
+    if module.reads_input() {
+        writeln!(out, "declare i32 @scanf(ptr, ...)").unwrap();
+    }
+    let exits = ["pow", "sqrt", "assert", "exit"]
+        .iter()
+        .any(|name| natives.contains(name));
+    if module.reads_input() || module.uses_rand() || module.divides() || exits {
+        writeln!(out, "declare void @exit(i32)").unwrap();
+    }
+    if natives.contains(&"sqrt") {
+        writeln!(out, "declare double @llvm.sqrt.f64(double)").unwrap();
+    }
+    if natives.contains(&"clock") {
+        writeln!(out, "declare i64 @clock()").unwrap();
+    }
+    if module.reads_input() {
+        writeln!(out).unwrap();
+        out.push_str(READ_HELPER);
+    }
+    if module.uses_rand() {
+        writeln!(out).unwrap();
+        out.push_str(RAND_HELPER);
+    }
+    if module.divides() {
+        writeln!(out).unwrap();
+        out.push_str(DIV_HELPER);
+    }
+    for name in natives {
+        writeln!(out).unwrap();
+        out.push_str(native_helper(name));
+    }
+
+    for function in &module.functions {
+        writeln!(out).unwrap();
+        FunctionEmitter::new(function, module.globals_size).emit(&mut out);
+    }
+
+    // the C runtime's entry point bootstraps the Koala one, the same way the KVM does
+    writeln!(out).unwrap();
+    writeln!(out, "define i32 @main() {{").unwrap();
+    writeln!(out, "entry:").unwrap();
+    writeln!(out, "  %0 = call i32 @{}()", symbol(ENTRY_POINT)).unwrap();
+    writeln!(out, "  ret i32 0").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    out
+}
+
+/// Reads an integer with the `scanf` format it is passed.
+/// Koala identifiers cannot contain a `.`, so the name cannot collide with a function
+const READ_HELPER: &str = "\
+define internal i32 @input.read(ptr %fmt) {
+entry:
+  %val = alloca i32
+  %count = call i32 (ptr, ...) @scanf(ptr %fmt, ptr %val)
+  %found = icmp eq i32 %count, 1
+  br i1 %found, label %done, label %error
+error:
+  call void @exit(i32 1)
+  unreachable
+done:
+  %result = load i32, ptr %val
+  ret i32 %result
+}
+";
+
+/// Draws a number from `lo` to `hi` inclusive, exiting with status 1 when the range is empty
+const RAND_HELPER: &str = "\
+define internal i32 @rand.range(i32 %lo, i32 %hi) {
+entry:
+  %empty = icmp slt i32 %hi, %lo
+  br i1 %empty, label %error, label %draw
+error:
+  call void @exit(i32 1)
+  unreachable
+draw:
+  %x0 = load i32, ptr @rand.state
+  %s1 = shl i32 %x0, 13
+  %x1 = xor i32 %x0, %s1
+  %s2 = lshr i32 %x1, 17
+  %x2 = xor i32 %x1, %s2
+  %s3 = shl i32 %x2, 5
+  %x3 = xor i32 %x2, %s3
+  store i32 %x3, ptr @rand.state
+  %diff = sub i32 %hi, %lo
+  %span = add i32 %diff, 1
+  %full = icmp eq i32 %span, 0
+  br i1 %full, label %done, label %reduce
+reduce:
+  %rem = urem i32 %x3, %span
+  br label %done
+done:
+  %offset = phi i32 [ %x3, %draw ], [ %rem, %reduce ]
+  %result = add i32 %lo, %offset
+  ret i32 %result
+}
+";
+
+/// Signed division, exiting with status 1 on a zero divisor or an overflowing quotient,
+/// either of which `sdiv` leaves undefined
+const DIV_HELPER: &str = "\
+define internal i32 @int.div(i32 %lhs, i32 %rhs) {
+entry:
+  %zero = icmp eq i32 %rhs, 0
+  %min = icmp eq i32 %lhs, -2147483648
+  %minus.one = icmp eq i32 %rhs, -1
+  %overflow = and i1 %min, %minus.one
+  %fails = or i1 %zero, %overflow
+  br i1 %fails, label %error, label %divide
+error:
+  call void @exit(i32 1)
+  unreachable
+divide:
+  %result = sdiv i32 %lhs, %rhs
+  ret i32 %result
+}
+";
+
+/// Implementation of a standard native, named `@native.<name>`
+fn native_helper(name: &str) -> &'static str {
+    match name {
+        "abs" => {
+            "\
+define internal i32 @native.abs(i32 %x) {
+entry:
+  %negative = icmp slt i32 %x, 0
+  %neg = sub i32 0, %x
+  %result = select i1 %negative, i32 %neg, i32 %x
+  ret i32 %result
+}
+"
+        }
+        "min" => {
+            "\
+define internal i32 @native.min(i32 %a, i32 %b) {
+entry:
+  %less = icmp slt i32 %a, %b
+  %result = select i1 %less, i32 %a, i32 %b
+  ret i32 %result
+}
+"
+        }
+        "max" => {
+            "\
+define internal i32 @native.max(i32 %a, i32 %b) {
+entry:
+  %greater = icmp sgt i32 %a, %b
+  %result = select i1 %greater, i32 %a, i32 %b
+  ret i32 %result
+}
+"
+        }
+        "pow" => {
+            "\
+define internal i32 @native.pow(i32 %base, i32 %exp) {
+entry:
+  %negative = icmp slt i32 %exp, 0
+  br i1 %negative, label %error, label %loop
+error:
+  call void @exit(i32 1)
+  unreachable
+loop:
+  %result = phi i32 [ 1, %entry ], [ %next.result, %step ]
+  %b = phi i32 [ %base, %entry ], [ %next.b, %step ]
+  %e = phi i32 [ %exp, %entry ], [ %next.e, %step ]
+  %finished = icmp eq i32 %e, 0
+  br i1 %finished, label %done, label %step
+step:
+  %bit = and i32 %e, 1
+  %odd = icmp ne i32 %bit, 0
+  %product = mul i32 %result, %b
+  %next.result = select i1 %odd, i32 %product, i32 %result
+  %next.b = mul i32 %b, %b
+  %next.e = lshr i32 %e, 1
+  br label %loop
+done:
+  ret i32 %result
+}
+"
+        }
+        "sqrt" => {
+            "\
+define internal i32 @native.sqrt(i32 %x) {
+entry:
+  %negative = icmp slt i32 %x, 0
+  br i1 %negative, label %error, label %root
+error:
+  call void @exit(i32 1)
+  unreachable
+root:
+  %double = sitofp i32 %x to double
+  %sqrt = call double @llvm.sqrt.f64(double %double)
+  %result = fptosi double %sqrt to i32
+  ret i32 %result
+}
+"
+        }
+        "assert" => {
+            "\
+define internal i32 @native.assert(i32 %cond) {
+entry:
+  %failed = icmp eq i32 %cond, 0
+  br i1 %failed, label %error, label %done
+error:
+  call void @exit(i32 1)
+  unreachable
+done:
+  ret i32 0
+}
+"
+        }
+        "exit" => {
+            "\
+define internal i32 @native.exit(i32 %code) {
+entry:
+  call void @exit(i32 %code)
+  unreachable
+}
+"
+        }
+        "clock" => {
+            "\
+define internal i32 @native.clock() {
+entry:
+  %ticks = call i64 @clock()
+  %millis = sdiv i64 %ticks, 1000
+  %result = trunc i64 %millis to i32
+  ret i32 %result
+}
+"
+        }
+        _ => unreachable!("standard native {}() is not implemented", name),
+    }
+}
+
+/// Koala functions are namespaced so they cannot collide with C symbols such as `main`
+fn symbol(id: &str) -> String {
+    format!("koala.{}", id.replace("::", "."))
+}
+
+struct FunctionEmitter<'a> {
+    function: &'a ir::Function,
+    globals_size: usize,
+    frame_type: String,
+    /// Counter for SSA temporaries
+    next_temp: usize,
+    /// Values currently on the operand stack, as LLVM operands
+    stack: Vec<String>,
+    body: String,
+}
+
+impl<'a> FunctionEmitter<'a> {
+    fn new(function: &'a ir::Function, globals_size: usize) -> Self {
+        FunctionEmitter {
+            function,
+            globals_size,
+            frame_type: format!("[{} x i32]", function.frame_size),
+            next_temp: 0,
+            stack: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn emit(mut self, out: &mut String) {
+        let function = self.function;
+        let params: Vec<String> = (0..function.arity)
+            .map(|i| format!("i32 %arg{}", i))
+            .collect();
+        writeln!(
+            out,
+            "define internal i32 @{}({}) {{",
+            symbol(&function.id),
+            params.join(", ")
+        )
+        .unwrap();
+
+        // allocate the frame and copy the arguments into their local slots
+        writeln!(out, "entry:").unwrap();
+        writeln!(out, "  %frame = alloca {}", self.frame_type).unwrap();
+        writeln!(
+            out,
+            "  store {} zeroinitializer, ptr %frame",
+            self.frame_type
+        )
+        .unwrap();
+        for i in 0..function.arity {
+            let slot = self.local_ptr(&i.to_string());
+            self.line(format!("store i32 %arg{}, ptr {}", i, slot));
+        }
+        out.push_str(&std::mem::take(&mut self.body));
+        writeln!(out, "  br label %L{}", function.blocks[0].label.0).unwrap();
+
+        let reachable = function.reachable();
+        for block in &function.blocks {
+            if !reachable.contains(&block.label) {
+                continue;
+            }
+            writeln!(out, "L{}:", block.label.0).unwrap();
+            for inst in &block.insts {
+                self.inst(inst);
+            }
+            self.terminator(&block.terminator);
+            out.push_str(&std::mem::take(&mut self.body));
+        }
+
+        writeln!(out, "}}").unwrap();
+    }
+
+    fn temp(&mut self) -> String {
+        self.next_temp += 1;
+        format!("%t{}", self.next_temp - 1)
+    }
+
+    fn line(&mut self, line: String) {
+        self.body.push_str("  ");
+        self.body.push_str(&line);
+        self.body.push('\n');
+    }
+
+    fn pop(&mut self) -> String {
+        match self.stack.pop() {
+            Some(val) => val,
+            None => panic!("operand stack underflow in {}()", self.function.id),
+        }
+    }
+
+    fn local_ptr(&mut self, index: &str) -> String {
+        let ptr = self.temp();
+        let frame_type = self.frame_type.clone();
+        self.line(format!(
+            "{} = getelementptr inbounds {}, ptr %frame, i32 0, i32 {}",
+            ptr, frame_type, index
+        ));
+        ptr
+    }
+
+    fn global_ptr(&mut self, index: &str) -> String {
+        let ptr = self.temp();
+        let globals_type = format!("[{} x i32]", self.globals_size);
+        self.line(format!(
+            "{} = getelementptr inbounds {}, ptr @globals, i32 0, i32 {}",
+            ptr, globals_type, index
+        ));
+        ptr
+    }
+
+    fn load(&mut self, ptr: String) {
+        let val = self.temp();
+        self.line(format!("{} = load i32, ptr {}", val, ptr));
+        self.stack.push(val);
+    }
+
+    fn store(&mut self, ptr: String, val: String) {
+        self.line(format!("store i32 {}, ptr {}", val, ptr));
+    }
+
+    /// Add a popped index and base offset, for the array instructions
+    fn array_index(&mut self, first: String, second: String) -> String {
+        let index = self.temp();
+        self.line(format!("{} = add i32 {}, {}", index, first, second));
+        index
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Push(val) => self.stack.push(val.to_string()),
+            Inst::GlobalAddr(offset) => self.stack.push(offset.to_string()),
+            Inst::Pop => {
+                self.pop();
+            }
+            Inst::BinOp(binop) => {
+                let lhs = self.pop();
+                let rhs = self.pop();
+                let result = self.bin_op(*binop, lhs, rhs);
+                self.stack.push(result);
+            }
+            Inst::LocalLoad(offset) => {
+                let ptr = self.local_ptr(&offset.to_string());
+                self.load(ptr);
+            }
+            Inst::LocalStore(offset) => {
+                let val = self.pop();
+                let ptr = self.local_ptr(&offset.to_string());
+                self.store(ptr, val);
+            }
+            Inst::GlobalLoad(offset) => {
+                let ptr = self.global_ptr(&offset.to_string());
+                self.load(ptr);
+            }
+            Inst::GlobalStore(offset) => {
+                let val = self.pop();
+                let ptr = self.global_ptr(&offset.to_string());
+                self.store(ptr, val);
+            }
+            Inst::LocalArrLoad | Inst::GlobalArrLoad => {
+                let index = self.pop();
+                let offset = self.pop();
+                let slot = self.array_index(offset, index);
+                let ptr = match inst {
+                    Inst::LocalArrLoad => self.local_ptr(&slot),
+                    _ => self.global_ptr(&slot),
+                };
+                self.load(ptr);
+            }
+            Inst::LocalArrStore | Inst::GlobalArrStore => {
+                let offset = self.pop();
+                let index = self.pop();
+                let val = self.pop();
+                let slot = self.array_index(offset, index);
+                let ptr = match inst {
+                    Inst::LocalArrStore => self.local_ptr(&slot),
+                    _ => self.global_ptr(&slot),
+                };
+                self.store(ptr, val);
+            }
+            Inst::Print(print_type) => {
+                let val = self.pop();
+                let fmt = match print_type {
+                    PrintType::Int => "@fmt.int",
+                    PrintType::Char => "@fmt.char",
+                };
+                let result = self.temp();
+                self.line(format!(
+                    "{} = call i32 (ptr, ...) @printf(ptr {}, i32 {})",
+                    result, fmt, val
+                ));
+            }
+            Inst::Read(read_type) => {
+                let fmt = match read_type {
+                    ReadType::Int => "@fmt.int",
+                    ReadType::Line => "@fmt.read_line",
+                };
+                let result = self.temp();
+                self.line(format!("{} = call i32 @input.read(ptr {})", result, fmt));
+                self.stack.push(result);
+            }
+            Inst::Rand => {
+                let lo = self.pop();
+                let hi = self.pop();
+                let result = self.temp();
+                self.line(format!(
+                    "{} = call i32 @rand.range(i32 {}, i32 {})",
+                    result, lo, hi
+                ));
+                self.stack.push(result);
+            }
+            Inst::CallNative { index, argc } => {
+                let name = match natives::STANDARD.get(*index) {
+                    Some((name, _)) => *name,
+                    None => panic!(
+                        "only the standard natives can be compiled, not native {}",
+                        index
+                    ),
+                };
+                let args: Vec<String> = (0..*argc).map(|_| format!("i32 {}", self.pop())).collect();
+                let result = self.temp();
+                self.line(format!(
+                    "{} = call i32 @native.{}({})",
+                    result,
+                    name,
+                    args.join(", ")
+                ));
+                self.stack.push(result);
+            }
+            Inst::Call { id, argc } => {
+                // the first argument is on top of the stack
+                let args: Vec<String> = (0..*argc).map(|_| format!("i32 {}", self.pop())).collect();
+                let result = self.temp();
+                self.line(format!(
+                    "{} = call i32 @{}({})",
+                    result,
+                    symbol(id),
+                    args.join(", ")
+                ));
+                self.stack.push(result);
+            }
+        }
+    }
+
+    fn bin_op(&mut self, binop: BinOp, lhs: String, rhs: String) -> String {
+        let arithmetic = match binop {
+            BinOp::Plus => Some("add"),
+            BinOp::Minus => Some("sub"),
+            BinOp::Mul => Some("mul"),
+            _ => None,
+        };
+        if binop == BinOp::Div {
+            let result = self.temp();
+            self.line(format!(
+                "{} = call i32 @int.div(i32 {}, i32 {})",
+                result, lhs, rhs
+            ));
+            return result;
+        }
+        if let Some(op) = arithmetic {
+            let result = self.temp();
+            self.line(format!("{} = {} i32 {}, {}", result, op, lhs, rhs));
+            return result;
+        }
+
+        let cond = self.temp();
+        match binop {
+            BinOp::Or | BinOp::And => {
+                // both operands are evaluated, then compared against zero like the VM does
+                let lhs_bool = self.temp();
+                let rhs_bool = self.temp();
+                self.line(format!("{} = icmp ne i32 {}, 0", lhs_bool, lhs));
+                self.line(format!("{} = icmp ne i32 {}, 0", rhs_bool, rhs));
+                let op = match binop {
+                    BinOp::Or => "or",
+                    _ => "and",
+                };
+                self.line(format!("{} = {} i1 {}, {}", cond, op, lhs_bool, rhs_bool));
+            }
+            _ => {
+                let predicate = match binop {
+                    BinOp::Less => "slt",
+                    BinOp::LessOrEqual => "sle",
+                    BinOp::Greater => "sgt",
+                    BinOp::GreaterOrEqual => "sge",
+                    BinOp::Equal => "eq",
+                    _ => "ne",
+                };
+                self.line(format!(
+                    "{} = icmp {} i32 {}, {}",
+                    cond, predicate, lhs, rhs
+                ));
+            }
+        }
+        let result = self.temp();
+        self.line(format!("{} = zext i1 {} to i32", result, cond));
+        result
+    }
+
+    fn terminator(&mut self, terminator: &Terminator) {
+        match terminator {
+            Terminator::Jump(label) => self.line(format!("br label %L{}", label.0)),
+            Terminator::Branch { nonzero, zero } => {
+                let val = self.pop();
+                let cond = self.temp();
+                self.line(format!("{} = icmp ne i32 {}, 0", cond, val));
+                self.line(format!(
+                    "br i1 {}, label %L{}, label %L{}",
+                    cond, nonzero.0, zero.0
+                ));
+            }
+            Terminator::Ret => {
+                let val = self.pop();
+                self.line(format!("ret i32 {}", val));
+            }
+        }
+        self.stack.clear();
+    }
+}
@@ -0,0 +1,124 @@
+//! Backends which translate Koala programs into source for other platforms,
+//! as an alternative to KVM bytecode
+
+use crate::compiler::const_size;
+use crate::grammar::{Expr, ForIter, FunctionCall, Program, Statement};
+use std::collections::HashMap;
+use std::iter;
+
+pub mod asm;
+pub mod c;
+pub mod llvm;
+pub mod wasm;
+
+/// Global variables by name, with their length when they are arrays
+pub(crate) fn global_vars(program: &Program) -> HashMap<String, Option<u32>> {
+    let consts = program.const_values();
+    let mut globals = HashMap::new();
+    for def in &program.0 {
+        for stmt in &def.body {
+            match stmt {
+                Statement::ArrayInstantiation {
+                    id,
+                    size,
+                    global: true,
+                    ..
+                } => {
+                    if let Some(size) = const_size(size, &consts) {
+                        globals.insert(id.clone(), Some(size));
+                    }
+                }
+                Statement::VarAssignment {
+                    id, global: true, ..
+                } => {
+                    globals.insert(id.clone(), None);
+                }
+                _ => { /* no-op */ }
+            }
+        }
+    }
+
+    globals
+}
+
+/// Whether the program calls any of the given builtins, leaving out those it shadows
+/// with functions of its own
+pub(crate) fn calls_builtin(program: &Program, ids: &[&str]) -> bool {
+    let ids: Vec<&str> = ids
+        .iter()
+        .copied()
+        .filter(|id| program.0.iter().all(|def| def.id != *id))
+        .collect();
+    program
+        .0
+        .iter()
+        .any(|def| def.body.iter().any(|stmt| statement_calls(stmt, &ids)))
+}
+
+/// Whether the program calls any of the builtins which read input
+pub(crate) fn reads_input(program: &Program) -> bool {
+    calls_builtin(program, &["read_int", "read_line"])
+}
+
+fn statement_calls(stmt: &Statement, ids: &[&str]) -> bool {
+    let stmts_call = |stmts: &[Statement]| stmts.iter().any(|stmt| statement_calls(stmt, ids));
+    match stmt {
+        Statement::If(if_data) => expr_calls(&if_data.expr, ids) || stmts_call(&if_data.stmts),
+        Statement::IfElse(if_else) => {
+            expr_calls(&if_else.expr, ids)
+                || stmts_call(&if_else.stmts)
+                || stmts_call(&if_else.else_stmts)
+        }
+        Statement::While { cond, stmts, .. } => expr_calls(cond, ids) || stmts_call(stmts),
+        Statement::For(for_data) => {
+            let iter_calls = match &for_data.iter {
+                ForIter::Range {
+                    start, end, step, ..
+                } => iter::once(start)
+                    .chain(iter::once(end))
+                    .chain(step)
+                    .any(|expr| expr_calls(expr, ids)),
+                ForIter::Array(_) => false,
+            };
+            iter_calls || stmts_call(&for_data.stmts)
+        }
+        Statement::Print { expr, .. } => expr.iter().any(|expr| expr_calls(expr, ids)),
+        Statement::ReturnExpr(expr)
+        | Statement::Let { expr, .. }
+        | Statement::VarAssignment { expr, .. } => expr_calls(expr, ids),
+        Statement::ArrayInstantiation { size, elements, .. } => size
+            .iter()
+            .chain(elements.iter().flatten())
+            .any(|expr| expr_calls(expr, ids)),
+        Statement::ArrayIndexAssignment { index, expr, .. } => {
+            expr_calls(index, ids) || expr_calls(expr, ids)
+        }
+        Statement::FunctionCall(func_call) => call_calls(func_call, ids),
+        Statement::Return | Statement::Break(_) | Statement::Continue(_) | Statement::When(_) => {
+            false
+        }
+    }
+}
+
+fn expr_calls(expr: &Expr, ids: &[&str]) -> bool {
+    match expr {
+        Expr::FunctionCall(func_call) => call_calls(func_call, ids),
+        Expr::ArrayIndex { expr, .. } => expr_calls(expr, ids),
+        Expr::BinExpr(bin_expr) => expr_calls(&bin_expr.op1, ids) || expr_calls(&bin_expr.op2, ids),
+        _ => false,
+    }
+}
+
+fn call_calls(func_call: &FunctionCall, ids: &[&str]) -> bool {
+    ids.contains(&func_call.id.as_str()) || func_call.args.iter().any(|arg| expr_calls(arg, ids))
+}
+
+/// Whether evaluating the expression could call a function
+pub(crate) fn has_call(expr: &Expr) -> bool {
+    match expr {
+        Expr::FunctionCall(_) => true,
+        Expr::ArrayIndex { expr, .. } => has_call(expr),
+        Expr::BinExpr(bin_expr) => has_call(&bin_expr.op1) || has_call(&bin_expr.op2),
+        _ => false,
+    }
+}
@@ -0,0 +1,1280 @@
+use super::{calls_builtin, global_vars, has_call, reads_input};
+use crate::compiler::{const_size, find_loop};
+use crate::grammar::{BinExpr, BinOp, Expr, FunctionCall, FunctionDefinition, Program, Statement};
+use crate::ir::ENTRY_POINT;
+use crate::natives;
+use crate::rng::{Rng, DEFAULT_SEED};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Module the host functions are imported from
+pub const IMPORT_MODULE: &str = "env";
+/// Host function printing an i32 as a decimal number
+pub const PRINT_INT: &str = "print_int";
+/// Host function printing an i32 as a character code
+pub const PRINT_CHAR: &str = "print_char";
+/// Host function returning the next integer of the input, for `read_int()`
+pub const READ_INT: &str = "read_int";
+/// Host function returning the next integer of the input and discarding the rest of its line,
+/// for `read_line()`, which returns that integer rather than the line
+pub const READ_LINE: &str = "read_line";
+/// Host function stopping the program with an exit code, for `exit(code)`
+pub const EXIT: &str = "exit";
+/// Host function returning the milliseconds since the program started, for `clock()`
+pub const CLOCK: &str = "clock";
+
+/// Pages of linear memory, which hold global arrays followed by a stack of local array frames
+const MEMORY_PAGES: u32 = 16;
+
+/// A WebAssembly module compiled from a Koala program,
+/// which can be written out in the text format or the binary encoding.
+///
+/// Scalar variables become wasm locals and globals. Arrays need to be indexed dynamically,
+/// so they live in linear memory: global arrays at fixed addresses, and local arrays in a
+/// frame reserved from a stack pointer on entry to the function.
+/// The module imports `env.print_int` and `env.print_char`, along with `env.read_int` and
+/// `env.read_line` when the program reads input, and `env.exit` and `env.clock` when it calls
+/// those natives. It exports `main` and `memory`. `rand()` and the other natives are generated
+/// functions, which trap when they fail, with `rand()` stepping the same generator as the VMs.
+pub struct WasmModule {
+    /// Names of the imported functions, which come before the module's own
+    imports: Vec<&'static str>,
+    /// Number of i32 globals after the stack pointer
+    globals: Vec<String>,
+    /// Initial state of the `rand()` generator, kept in a global after the program's own
+    rand_state: Option<u32>,
+    /// Bytes of memory used by global arrays, where the stack starts
+    stack_base: u32,
+    functions: Vec<WasmFunction>,
+}
+
+struct WasmFunction {
+    name: String,
+    arity: usize,
+    /// Names of every local, parameters first
+    locals: Vec<String>,
+    body: Vec<Inst>,
+}
+
+/// The subset of wasm instructions the backend uses
+#[derive(Clone, Debug, PartialEq)]
+enum Inst {
+    I32Const(i32),
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    I32Load,
+    I32Store,
+    Numeric(Numeric),
+    Call(u32),
+    Drop,
+    Block,
+    Loop,
+    If,
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Unreachable,
+    Select,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Numeric {
+    Eqz,
+    Eq,
+    Ne,
+    LtS,
+    GtS,
+    LeS,
+    GeS,
+    Add,
+    Sub,
+    Mul,
+    DivS,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrU,
+    RemU,
+    F64ConvertI32S,
+    F64Sqrt,
+    I32TruncF64S,
+}
+
+impl Numeric {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Eqz => "i32.eqz",
+            Self::Eq => "i32.eq",
+            Self::Ne => "i32.ne",
+            Self::LtS => "i32.lt_s",
+            Self::GtS => "i32.gt_s",
+            Self::LeS => "i32.le_s",
+            Self::GeS => "i32.ge_s",
+            Self::Add => "i32.add",
+            Self::Sub => "i32.sub",
+            Self::Mul => "i32.mul",
+            Self::DivS => "i32.div_s",
+            Self::And => "i32.and",
+            Self::Or => "i32.or",
+            Self::Xor => "i32.xor",
+            Self::Shl => "i32.shl",
+            Self::ShrU => "i32.shr_u",
+            Self::RemU => "i32.rem_u",
+            Self::F64ConvertI32S => "f64.convert_i32_s",
+            Self::F64Sqrt => "f64.sqrt",
+            Self::I32TruncF64S => "i32.trunc_f64_s",
+        }
+    }
+
+    fn opcode(self) -> u8 {
+        match self {
+            Self::Eqz => 0x45,
+            Self::Eq => 0x46,
+            Self::Ne => 0x47,
+            Self::LtS => 0x48,
+            Self::GtS => 0x4A,
+            Self::LeS => 0x4C,
+            Self::GeS => 0x4E,
+            Self::Add => 0x6A,
+            Self::Sub => 0x6B,
+            Self::Mul => 0x6C,
+            Self::DivS => 0x6D,
+            Self::And => 0x71,
+            Self::Or => 0x72,
+            Self::Xor => 0x73,
+            Self::Shl => 0x74,
+            Self::ShrU => 0x76,
+            Self::RemU => 0x70,
+            Self::F64ConvertI32S => 0xB7,
+            Self::F64Sqrt => 0x9F,
+            Self::I32TruncF64S => 0xAA,
+        }
+    }
+}
+
+/// Index of the stack pointer global
+const STACK_POINTER: u32 = 0;
+/// Function indexes of the print functions, which are always imported first.
+/// The other imports are called through the function table like the program's own functions
+const PRINT_INT_FN: u32 = 0;
+const PRINT_CHAR_FN: u32 = 1;
+
+/// Number of parameters of an imported function, which all return an i32 apart from printing
+fn import_arity(name: &str) -> usize {
+    match name {
+        PRINT_INT | PRINT_CHAR | EXIT => 1,
+        _ => 0,
+    }
+}
+
+/// Compile a program into a WebAssembly module
+pub fn compile(program: &Program) -> WasmModule {
+    let consts = program.const_values();
+    let vars = global_vars(program);
+    let mut imports = vec![PRINT_INT, PRINT_CHAR];
+    if reads_input(program) {
+        imports.extend([READ_INT, READ_LINE]);
+    }
+    // exit() and clock() need the host, while the other natives are generated
+    for name in [EXIT, CLOCK] {
+        if calls_builtin(program, &[name]) {
+            imports.push(name);
+        }
+    }
+
+    // global scalars become wasm globals, and global arrays are laid out from address 0
+    let mut names: Vec<&String> = vars.keys().collect();
+    names.sort();
+    let mut globals = Vec::new();
+    let mut global_arrays = HashMap::new();
+    let mut stack_base = 0;
+    for name in names {
+        match vars[name] {
+            Some(size) => {
+                global_arrays.insert(name.clone(), (stack_base, size));
+                stack_base += size * 4;
+            }
+            None => globals.push(name.clone()),
+        }
+    }
+
+    let defined: HashSet<&str> = program.0.iter().map(|def| def.id.as_str()).collect();
+    let mut fn_indexes: HashMap<String, (u32, usize)> = program
+        .0
+        .iter()
+        .enumerate()
+        .map(|(i, def)| {
+            (
+                def.id.clone(),
+                (imports.len() as u32 + i as u32, def.args.len()),
+            )
+        })
+        .collect();
+    for (index, name) in imports.iter().enumerate().skip(2) {
+        fn_indexes.insert(name.to_string(), (index as u32, import_arity(name)));
+    }
+    // rand() and the natives are generated after the program's functions,
+    // and called like one of them
+    let uses_rand = calls_builtin(program, &["rand"]);
+    let mut generated = Vec::new();
+    if uses_rand {
+        generated.push(("rand", 2));
+    }
+    for (name, arity) in natives::STANDARD {
+        if !imports.contains(&name) && calls_builtin(program, &[name]) {
+            generated.push((name, arity));
+        }
+    }
+    for (i, (name, arity)) in generated.iter().enumerate() {
+        let index = imports.len() + program.0.len() + i;
+        fn_indexes.insert(name.to_string(), (index as u32, *arity));
+    }
+
+    let mut functions: Vec<WasmFunction> = program
+        .0
+        .iter()
+        .map(|def| {
+            FunctionCompiler {
+                globals: &globals,
+                global_arrays: &global_arrays,
+                fn_indexes: &fn_indexes,
+                defined: &defined,
+                consts: &consts,
+                locals: def.args.clone(),
+                scopes: vec![(
+                    (0..def.args.len())
+                        .map(|index| (def.args[index].clone(), Local::Scalar(index as u32)))
+                        .collect(),
+                    0,
+                )],
+                frame_size: 0,
+                immutable: HashSet::new(),
+                body: Vec::new(),
+                depth: 0,
+                loops: Vec::new(),
+            }
+            .compile(def)
+        })
+        .collect();
+    for (name, _) in generated {
+        functions.push(match name {
+            "rand" => rand_function(1 + globals.len() as u32),
+            name => native_function(name),
+        });
+    }
+
+    WasmModule {
+        imports,
+        rand_state: uses_rand.then(|| Rng::new(DEFAULT_SEED).state()),
+        globals,
+        stack_base,
+        functions,
+    }
+}
+
+/// Implementation of `rand(lo, hi)`, keeping the generator's state in the given global
+fn rand_function(state: u32) -> WasmFunction {
+    const LO: u32 = 0;
+    const HI: u32 = 1;
+    const X: u32 = 2;
+    const SPAN: u32 = 3;
+    let mut body = vec![
+        Inst::LocalGet(HI),
+        Inst::LocalGet(LO),
+        Inst::Numeric(Numeric::LtS),
+        Inst::If,
+        Inst::Unreachable,
+        Inst::End,
+        Inst::GlobalGet(state),
+        Inst::LocalSet(X),
+    ];
+    for (shift, amount) in [(Numeric::Shl, 13), (Numeric::ShrU, 17), (Numeric::Shl, 5)] {
+        body.extend([
+            Inst::LocalGet(X),
+            Inst::LocalGet(X),
+            Inst::I32Const(amount),
+            Inst::Numeric(shift),
+            Inst::Numeric(Numeric::Xor),
+            Inst::LocalSet(X),
+        ]);
+    }
+    body.extend([
+        Inst::LocalGet(X),
+        Inst::GlobalSet(state),
+        // the span wraps to 0 when it covers every i32, leaving the number as it is
+        Inst::LocalGet(HI),
+        Inst::LocalGet(LO),
+        Inst::Numeric(Numeric::Sub),
+        Inst::I32Const(1),
+        Inst::Numeric(Numeric::Add),
+        Inst::LocalSet(SPAN),
+        Inst::LocalGet(SPAN),
+        Inst::If,
+        Inst::LocalGet(X),
+        Inst::LocalGet(SPAN),
+        Inst::Numeric(Numeric::RemU),
+        Inst::LocalSet(X),
+        Inst::End,
+        Inst::LocalGet(LO),
+        Inst::LocalGet(X),
+        Inst::Numeric(Numeric::Add),
+        Inst::Return,
+    ]);
+
+    WasmFunction {
+        name: "rand.range".to_string(),
+        arity: 2,
+        locals: ["lo", "hi", "x", "span"].map(String::from).to_vec(),
+        body,
+    }
+}
+
+/// Implementation of a standard native which does not need the host
+fn native_function(name: &str) -> WasmFunction {
+    // the failure of a native is a trap
+    let trap_if = |cond: Vec<Inst>| {
+        let mut insts = cond;
+        insts.extend([Inst::If, Inst::Unreachable, Inst::End]);
+        insts
+    };
+    let (locals, body): (&[&str], Vec<Inst>) = match name {
+        // select takes the first value when the condition is nonzero
+        "abs" => (
+            &["x"],
+            vec![
+                Inst::I32Const(0),
+                Inst::LocalGet(0),
+                Inst::Numeric(Numeric::Sub),
+                Inst::LocalGet(0),
+                Inst::LocalGet(0),
+                Inst::I32Const(0),
+                Inst::Numeric(Numeric::LtS),
+                Inst::Select,
+            ],
+        ),
+        "min" | "max" => (
+            &["a", "b"],
+            vec![
+                Inst::LocalGet(0),
+                Inst::LocalGet(1),
+                Inst::LocalGet(0),
+                Inst::LocalGet(1),
+                Inst::Numeric(match name {
+                    "min" => Numeric::LtS,
+                    _ => Numeric::GtS,
+                }),
+                Inst::Select,
+            ],
+        ),
+        "pow" => {
+            // square the base for every bit of the exponent
+            let mut body = trap_if(vec![
+                Inst::LocalGet(1),
+                Inst::I32Const(0),
+                Inst::Numeric(Numeric::LtS),
+            ]);
+            body.extend([
+                Inst::I32Const(1),
+                Inst::LocalSet(2),
+                Inst::Block,
+                Inst::Loop,
+                Inst::LocalGet(1),
+                Inst::Numeric(Numeric::Eqz),
+                Inst::BrIf(1),
+                Inst::LocalGet(1),
+                Inst::I32Const(1),
+                Inst::Numeric(Numeric::And),
+                Inst::If,
+                Inst::LocalGet(2),
+                Inst::LocalGet(0),
+                Inst::Numeric(Numeric::Mul),
+                Inst::LocalSet(2),
+                Inst::End,
+                Inst::LocalGet(0),
+                Inst::LocalGet(0),
+                Inst::Numeric(Numeric::Mul),
+                Inst::LocalSet(0),
+                Inst::LocalGet(1),
+                Inst::I32Const(1),
+                Inst::Numeric(Numeric::ShrU),
+                Inst::LocalSet(1),
+                Inst::Br(0),
+                Inst::End,
+                Inst::End,
+                Inst::LocalGet(2),
+            ]);
+            (&["base", "exp", "result"], body)
+        }
+        "sqrt" => {
+            let mut body = trap_if(vec![
+                Inst::LocalGet(0),
+                Inst::I32Const(0),
+                Inst::Numeric(Numeric::LtS),
+            ]);
+            body.extend([
+                Inst::LocalGet(0),
+                Inst::Numeric(Numeric::F64ConvertI32S),
+                Inst::Numeric(Numeric::F64Sqrt),
+                Inst::Numeric(Numeric::I32TruncF64S),
+            ]);
+            (&["x"], body)
+        }
+        "assert" => {
+            let mut body = trap_if(vec![Inst::LocalGet(0), Inst::Numeric(Numeric::Eqz)]);
+            body.push(Inst::I32Const(0));
+            (&["cond"], body)
+        }
+        _ => unreachable!("standard native {}() is not implemented", name),
+    };
+
+    let mut body = body;
+    body.push(Inst::Return);
+    WasmFunction {
+        name: format!("native.{}", name),
+        arity: natives::STANDARD[natives::standard_index(name).unwrap()].1,
+        locals: locals.iter().map(|local| local.to_string()).collect(),
+        body,
+    }
+}
+
+struct FunctionCompiler<'a> {
+    globals: &'a [String],
+    /// Addresses and lengths of the global arrays
+    global_arrays: &'a HashMap<String, (u32, u32)>,
+    fn_indexes: &'a HashMap<String, (u32, usize)>,
+    /// Names of the program's own functions, which shadow builtins and natives
+    defined: &'a HashSet<&'a str>,
+    /// Values of the constants, which are inlined
+    consts: &'a HashMap<String, i32>,
+    locals: Vec<String>,
+    /// The blocks enclosing the current statement, innermost last, with the variables
+    /// they declare and the next free word of the memory frame
+    scopes: Vec<(HashMap<String, Local>, u32)>,
+    frame_size: u32,
+    /// Locals which cannot be assigned again
+    immutable: HashSet<u32>,
+    body: Vec<Inst>,
+    /// Number of enclosing blocks, used to compute branch depths
+    depth: u32,
+    /// The loops enclosing the current statement, innermost last, with their labels
+    /// and the depths `break` and `continue` branch to
+    loops: Vec<(Option<String>, u32, u32)>,
+}
+
+/// Where a variable a block declares is kept
+#[derive(Clone, Copy)]
+enum Local {
+    /// Index of a wasm local
+    Scalar(u32),
+    /// Word offset and length of an array within the function's memory frame
+    Array(u32, u32),
+}
+
+/// Name of the local holding the base address of the memory frame.
+/// Generated names contain a `.`, so they cannot clash with Koala identifiers.
+const FRAME_POINTER: &str = "frame.ptr";
+
+impl<'a> FunctionCompiler<'a> {
+    fn compile(mut self, def: &FunctionDefinition) -> WasmFunction {
+        if let Some(arg) = def.args.iter().find(|arg| self.consts.contains_key(*arg)) {
+            panic!(
+                "cannot declare a variable named '{}', which is a constant",
+                arg
+            );
+        }
+        for stmt in &def.body {
+            self.statement(stmt);
+        }
+        // falling off the end of a function returns 0
+        if !matches!(
+            def.body.last(),
+            Some(Statement::Return | Statement::ReturnExpr(_))
+        ) {
+            self.ret_const(0);
+        }
+
+        let mut body = Vec::new();
+        if self.frame_size > 0 {
+            // reserve the frame for local arrays, and release it again before every return
+            let frame_pointer = self.local(FRAME_POINTER);
+            body.extend([
+                Inst::GlobalGet(STACK_POINTER),
+                Inst::LocalSet(frame_pointer),
+                Inst::LocalGet(frame_pointer),
+                Inst::I32Const(self.frame_size as i32 * 4),
+                Inst::Numeric(Numeric::Add),
+                Inst::GlobalSet(STACK_POINTER),
+            ]);
+            for inst in self.body {
+                if inst == Inst::Return {
+                    body.push(Inst::LocalGet(frame_pointer));
+                    body.push(Inst::GlobalSet(STACK_POINTER));
+                }
+                body.push(inst);
+            }
+        } else {
+            body = self.body;
+        }
+
+        WasmFunction {
+            name: def.id.clone(),
+            arity: def.args.len(),
+            locals: self.locals,
+            body,
+        }
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        match inst {
+            Inst::Block | Inst::Loop | Inst::If => self.depth += 1,
+            Inst::End => self.depth -= 1,
+            _ => {}
+        }
+        self.body.push(inst);
+    }
+
+    /// Index of a local by name, declaring it if needed
+    fn local(&mut self, id: &str) -> u32 {
+        match self.locals.iter().position(|local| local == id) {
+            Some(index) => index as u32,
+            None => {
+                self.locals.push(id.to_string());
+                self.locals.len() as u32 - 1
+            }
+        }
+    }
+
+    /// The innermost variable with an ID
+    fn find_local(&self, id: &str) -> Option<Local> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|(scope, _)| scope.get(id).copied())
+    }
+
+    /// Declare a variable in the current block. Shadowed variables keep a wasm local of their own,
+    /// named with a `#` which Koala identifiers cannot contain, while arrays reuse the memory frame
+    /// of blocks which have ended
+    fn declare_local(&mut self, id: &str, size: Option<u32>) -> Local {
+        if self.consts.contains_key(id) {
+            panic!(
+                "cannot declare a variable named '{}', which is a constant",
+                id
+            );
+        }
+        let (_, next) = self.scopes.last().unwrap();
+        let local = match size {
+            Some(size) => {
+                let offset = *next;
+                self.frame_size = self.frame_size.max(offset + size);
+                self.scopes.last_mut().unwrap().1 += size;
+                Local::Array(offset, size)
+            }
+            None => {
+                let mut name = id.to_string();
+                let mut suffix = 1;
+                while self.locals.contains(&name) {
+                    name = format!("{}#{}", id, suffix);
+                    suffix += 1;
+                }
+                Local::Scalar(self.local(&name))
+            }
+        };
+        let scope = &mut self.scopes.last_mut().unwrap().0;
+        scope.insert(id.to_string(), local);
+        local
+    }
+
+    /// Compile the statements of a nested block
+    fn block<'s>(&mut self, stmts: impl IntoIterator<Item = &'s Statement>) {
+        let (_, next) = self.scopes.last().unwrap();
+        self.scopes.push((HashMap::new(), *next));
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    /// A while loop, whose body starts by declaring `bind` and is followed by `step`
+    fn while_loop(
+        &mut self,
+        label: &Option<String>,
+        cond: &Expr,
+        bind: Option<&Statement>,
+        stmts: &[Statement],
+        step: Option<&Statement>,
+    ) {
+        // block $exit (loop $continue (br_if $exit (!cond)) ... (br $continue))
+        self.emit(Inst::Block);
+        let exit_depth = self.depth;
+        self.emit(Inst::Loop);
+        let loop_depth = self.depth;
+        self.expr(cond);
+        self.emit(Inst::Numeric(Numeric::Eqz));
+        self.emit(Inst::BrIf(self.depth - exit_depth));
+        match step {
+            Some(step) => {
+                // `continue` leaves a block around the body, so that the step still runs
+                self.emit(Inst::Block);
+                let body_depth = self.depth;
+                self.loops.push((label.clone(), exit_depth, body_depth));
+                self.block(bind.into_iter().chain(stmts));
+                self.emit(Inst::End);
+                self.statement(step);
+            }
+            None => {
+                self.loops.push((label.clone(), exit_depth, loop_depth));
+                self.block(bind.into_iter().chain(stmts));
+            }
+        }
+        self.loops.pop();
+        self.emit(Inst::Br(self.depth - loop_depth));
+        self.emit(Inst::End);
+        self.emit(Inst::End);
+    }
+
+    fn find_loop(&self, label: &Option<String>, keyword: &str) -> usize {
+        let labels = self.loops.iter().map(|(name, _, _)| name.as_ref());
+        match find_loop(labels, label, keyword) {
+            Ok(index) => index,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// A fresh scratch local
+    fn temp(&mut self) -> u32 {
+        let name = format!("tmp.{}", self.locals.len());
+        self.local(&name)
+    }
+
+    fn global(&self, id: &str) -> Option<u32> {
+        self.globals
+            .iter()
+            .position(|global| global == id)
+            .map(|index| index as u32 + 1)
+    }
+
+    /// Length of the array passed to `len()`, preferring a local array to a global one
+    fn array_len(&self, arg: &Expr) -> u32 {
+        let id = match arg {
+            Expr::Variable { id } => id,
+            _ => panic!("len() takes the name of an array"),
+        };
+        let len = match self.find_local(id) {
+            Some(Local::Array(_, len)) => Some(len),
+            Some(Local::Scalar(_)) => None,
+            None => self.global_arrays.get(id).map(|(_, len)| *len),
+        };
+        match len {
+            Some(len) => len,
+            None => panic!("len() takes an array, but '{}' is not one", id),
+        }
+    }
+
+    /// Push the address of the first element of an array
+    fn array_base(&mut self, id: &str) {
+        if let Some(Local::Array(offset, _)) = self.find_local(id) {
+            let frame_pointer = self.local(FRAME_POINTER);
+            self.emit(Inst::LocalGet(frame_pointer));
+            self.emit(Inst::I32Const(offset as i32 * 4));
+            self.emit(Inst::Numeric(Numeric::Add));
+        } else if let Some((addr, _)) = self.global_arrays.get(id).copied() {
+            self.emit(Inst::I32Const(addr as i32));
+        } else {
+            panic!("cound not find array index to load for id: {}.", id);
+        }
+    }
+
+    /// Push the address of an array element
+    fn element_addr(&mut self, id: &str, index: &Expr) {
+        self.array_base(id);
+        self.expr(index);
+        self.emit(Inst::I32Const(4));
+        self.emit(Inst::Numeric(Numeric::Mul));
+        self.emit(Inst::Numeric(Numeric::Add));
+    }
+
+    fn ret_const(&mut self, val: i32) {
+        self.emit(Inst::I32Const(val));
+        self.emit(Inst::Return);
+    }
+
+    fn statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::Print { expr, newline } => {
+                if let Some(e) = expr {
+                    self.expr(e);
+                    self.emit(Inst::Call(PRINT_INT_FN));
+                }
+                if *newline {
+                    self.emit(Inst::I32Const('\n' as i32));
+                    self.emit(Inst::Call(PRINT_CHAR_FN));
+                }
+            }
+            Statement::Let { id, expr, mutable } => {
+                // the value can read a variable the new one shadows
+                self.expr(expr);
+                if let Local::Scalar(local) = self.declare_local(id, None) {
+                    if !*mutable {
+                        self.immutable.insert(local);
+                    }
+                    self.emit(Inst::LocalSet(local));
+                }
+            }
+            Statement::VarAssignment { id, expr, global } => {
+                if self.consts.contains_key(id) {
+                    panic!("cannot assign to the constant '{}'", id);
+                }
+                self.expr(expr);
+                // prefer the innermost local, then a global
+                match self.find_local(id) {
+                    Some(Local::Scalar(local)) if !*global => {
+                        if self.immutable.contains(&local) {
+                            panic!(
+                                "cannot assign twice to immutable variable '{}', declare it with `let mut {} = ...`",
+                                id, id
+                            );
+                        }
+                        self.emit(Inst::LocalSet(local))
+                    }
+                    _ => match self.global(id) {
+                        Some(global) => self.emit(Inst::GlobalSet(global)),
+                        None => panic!(
+                            "cannot assign to undeclared variable '{}', declare it with `let mut {} = ...`",
+                            id, id
+                        ),
+                    },
+                }
+            }
+            Statement::ArrayIndexAssignment { id, index, expr } => {
+                // the VM evaluates the value before the index
+                if has_call(expr) || has_call(index) {
+                    let val = self.temp();
+                    self.expr(expr);
+                    self.emit(Inst::LocalSet(val));
+                    self.element_addr(id, index);
+                    self.emit(Inst::LocalGet(val));
+                } else {
+                    self.element_addr(id, index);
+                    self.expr(expr);
+                }
+                self.emit(Inst::I32Store);
+            }
+            Statement::ArrayInstantiation {
+                id,
+                size,
+                elements,
+                global,
+            } => {
+                if let Some(size) = const_size(size, self.consts) {
+                    if !*global {
+                        self.declare_local(id, Some(size));
+                    }
+                    for index in 0..size {
+                        self.element_addr(id, &Expr::IntLit(index));
+                        match elements {
+                            Some(elements) => self.expr(&elements[index as usize]),
+                            None => self.emit(Inst::I32Const(0)),
+                        }
+                        self.emit(Inst::I32Store);
+                    }
+                }
+            }
+            Statement::FunctionCall(func_call) => {
+                self.call(func_call);
+                self.emit(Inst::Drop);
+            }
+            Statement::If(if_data) => {
+                self.expr(&if_data.expr);
+                self.emit(Inst::If);
+                self.block(&if_data.stmts);
+                self.emit(Inst::End);
+            }
+            Statement::IfElse(if_else) => {
+                self.expr(&if_else.expr);
+                self.emit(Inst::If);
+                self.block(&if_else.stmts);
+                self.emit(Inst::Else);
+                self.block(&if_else.else_stmts);
+                self.emit(Inst::End);
+            }
+            Statement::While { cond, stmts, label } => {
+                self.while_loop(label, cond, None, stmts, None)
+            }
+            Statement::For(for_data) => {
+                let for_loop = for_data.desugar(self.consts, |array| self.array_len(array));
+                // the counter and bounds are only visible to the loop
+                let (_, next) = self.scopes.last().unwrap();
+                self.scopes.push((HashMap::new(), *next));
+                for stmt in &for_loop.init {
+                    self.statement(stmt);
+                }
+                self.while_loop(
+                    &for_data.label,
+                    &for_loop.cond,
+                    Some(&for_loop.bind),
+                    &for_data.stmts,
+                    Some(&for_loop.step),
+                );
+                self.scopes.pop();
+            }
+            Statement::Return => self.ret_const(0),
+            Statement::ReturnExpr(expr) => {
+                self.expr(expr);
+                self.emit(Inst::Return);
+            }
+            Statement::Break(label) => {
+                let (_, exit_depth, _) = self.loops[self.find_loop(label, "break")];
+                self.emit(Inst::Br(self.depth - exit_depth));
+            }
+            Statement::Continue(label) => {
+                let (_, _, continue_depth) = self.loops[self.find_loop(label, "continue")];
+                self.emit(Inst::Br(self.depth - continue_depth));
+            }
+            Statement::When(_) => { /* no-op */ }
+        }
+    }
+
+    fn call(&mut self, func_call: &FunctionCall) {
+        // builtins and natives are imported or generated, and called like any other function,
+        // once the number of arguments they are passed is checked
+        let defined = self.defined.contains(func_call.id.as_str());
+        if !defined && func_call.is_builtin() && func_call.id == "len" {
+            let len = self.array_len(&func_call.args[0]);
+            self.emit(Inst::I32Const(len as i32));
+            return;
+        }
+        if let Some(index) = natives::standard_index(&func_call.id).filter(|_| !defined) {
+            let (name, arity) = natives::STANDARD[index];
+            if func_call.args.len() != arity {
+                panic!("{}() takes {} arguments.", name, arity);
+            }
+        }
+        let (index, arity) = match self.fn_indexes.get(&func_call.id) {
+            Some(entry) => *entry,
+            None => panic!("No function found to jump to"),
+        };
+        // arguments are evaluated last to first, and surplus arguments are dropped
+        let ordered = func_call.args.len() > 1 && func_call.args.iter().any(has_call);
+        let mut temps = Vec::new();
+        for (i, arg) in func_call.args.iter().enumerate().rev() {
+            if i >= arity {
+                if has_call(arg) {
+                    self.expr(arg);
+                    self.emit(Inst::Drop);
+                }
+            } else if ordered {
+                let temp = self.temp();
+                self.expr(arg);
+                self.emit(Inst::LocalSet(temp));
+                temps.push((i, temp));
+            }
+        }
+        for (i, arg) in func_call.args.iter().enumerate().take(arity) {
+            match temps.iter().find(|(index, _)| *index == i) {
+                Some((_, temp)) => self.emit(Inst::LocalGet(*temp)),
+                None => self.expr(arg),
+            }
+        }
+        // the VM leaves missing arguments unset
+        for _ in func_call.args.len()..arity {
+            self.emit(Inst::I32Const(0));
+        }
+        self.emit(Inst::Call(index));
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::IntLit(int) => self.emit(Inst::I32Const(*int as i32)),
+            Expr::BoolLit(truthy) => self.emit(Inst::I32Const(*truthy as i32)),
+            Expr::StringLit(_) => panic!("string literals are not supported yet."),
+            Expr::ArrayIndex { id, expr } => {
+                self.element_addr(id, expr);
+                self.emit(Inst::I32Load);
+            }
+            Expr::Variable { id } => {
+                if let Some(val) = self.consts.get(id) {
+                    self.emit(Inst::I32Const(*val));
+                } else if let Some(Local::Scalar(local)) = self.find_local(id) {
+                    self.emit(Inst::LocalGet(local));
+                } else if let Some(global) = self.global(id) {
+                    self.emit(Inst::GlobalGet(global));
+                } else {
+                    panic!("could not find variable '{}'", id);
+                }
+            }
+            Expr::FunctionCall(func_call) => self.call(func_call),
+            Expr::BinExpr(bin_expr) => self.bin_expr(bin_expr),
+        }
+    }
+
+    fn bin_expr(&mut self, bin_expr: &BinExpr) {
+        // the VM evaluates the right operand first, which matters once it can call a function
+        if has_call(&bin_expr.op2) {
+            let rhs = self.temp();
+            self.expr(&bin_expr.op2);
+            self.emit(Inst::LocalSet(rhs));
+            self.expr(&bin_expr.op1);
+            self.emit(Inst::LocalGet(rhs));
+        } else {
+            self.expr(&bin_expr.op1);
+            self.expr(&bin_expr.op2);
+        }
+
+        match bin_expr.binop {
+            BinOp::Or | BinOp::And => {
+                // compare each side with zero, keeping the rhs in a scratch local
+                let rhs = self.temp();
+                self.emit(Inst::LocalSet(rhs));
+                self.emit(Inst::I32Const(0));
+                self.emit(Inst::Numeric(Numeric::Ne));
+                self.emit(Inst::LocalGet(rhs));
+                self.emit(Inst::I32Const(0));
+                self.emit(Inst::Numeric(Numeric::Ne));
+                self.emit(Inst::Numeric(match bin_expr.binop {
+                    BinOp::Or => Numeric::Or,
+                    _ => Numeric::And,
+                }));
+            }
+            binop => self.emit(Inst::Numeric(match binop {
+                BinOp::Plus => Numeric::Add,
+                BinOp::Minus => Numeric::Sub,
+                BinOp::Mul => Numeric::Mul,
+                BinOp::Div => Numeric::DivS,
+                BinOp::Less => Numeric::LtS,
+                BinOp::LessOrEqual => Numeric::LeS,
+                BinOp::Greater => Numeric::GtS,
+                BinOp::GreaterOrEqual => Numeric::GeS,
+                BinOp::Equal => Numeric::Eq,
+                _ => Numeric::Ne,
+            })),
+        }
+    }
+}
+
+impl WasmModule {
+    fn export_index(&self) -> u32 {
+        match self.functions.iter().position(|f| f.name == ENTRY_POINT) {
+            Some(index) => self.imports.len() as u32 + index as u32,
+            None => panic!("could not find main function."),
+        }
+    }
+
+    /// Write the module in the WebAssembly text format
+    pub fn to_wat(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "(module").unwrap();
+        for (index, name) in self.imports.iter().enumerate() {
+            writeln!(
+                out,
+                "  (import \"{}\" \"{}\" (func {} {}))",
+                IMPORT_MODULE,
+                name,
+                self.function_name(index as u32),
+                match *name {
+                    PRINT_INT | PRINT_CHAR => "(param i32)",
+                    EXIT => "(param i32) (result i32)",
+                    _ => "(result i32)",
+                }
+            )
+            .unwrap();
+        }
+        writeln!(out, "  (memory (export \"memory\") {})", MEMORY_PAGES).unwrap();
+        writeln!(
+            out,
+            "  (global {} (mut i32) (i32.const {}))",
+            self.global_name(STACK_POINTER),
+            self.stack_base
+        )
+        .unwrap();
+        for global in &self.globals {
+            writeln!(out, "  (global ${} (mut i32) (i32.const 0))", global).unwrap();
+        }
+        if let Some(state) = self.rand_state {
+            writeln!(
+                out,
+                "  (global {} (mut i32) (i32.const {}))",
+                self.global_name(1 + self.globals.len() as u32),
+                state as i32
+            )
+            .unwrap();
+        }
+
+        for function in &self.functions {
+            writeln!(out, "  (func ${}", function.name).unwrap();
+            for param in &function.locals[..function.arity] {
+                writeln!(out, "    (param ${} i32)", param).unwrap();
+            }
+            writeln!(out, "    (result i32)").unwrap();
+            for local in &function.locals[function.arity..] {
+                writeln!(out, "    (local ${} i32)", local).unwrap();
+            }
+
+            let mut indent = 2;
+            for inst in &function.body {
+                if matches!(inst, Inst::Else | Inst::End) {
+                    indent -= 1;
+                }
+                let text = match inst {
+                    Inst::I32Const(val) => format!("i32.const {}", val),
+                    Inst::LocalGet(index) => {
+                        format!("local.get {}", self.local_name(function, *index))
+                    }
+                    Inst::LocalSet(index) => {
+                        format!("local.set {}", self.local_name(function, *index))
+                    }
+                    Inst::GlobalGet(index) => format!("global.get {}", self.global_name(*index)),
+                    Inst::GlobalSet(index) => format!("global.set {}", self.global_name(*index)),
+                    Inst::I32Load => "i32.load".to_string(),
+                    Inst::I32Store => "i32.store".to_string(),
+                    Inst::Numeric(numeric) => numeric.name().to_string(),
+                    Inst::Call(index) => format!("call {}", self.function_name(*index)),
+                    Inst::Drop => "drop".to_string(),
+                    Inst::Block => "block".to_string(),
+                    Inst::Loop => "loop".to_string(),
+                    Inst::If => "if".to_string(),
+                    Inst::Else => "else".to_string(),
+                    Inst::End => "end".to_string(),
+                    Inst::Br(depth) => format!("br {}", depth),
+                    Inst::BrIf(depth) => format!("br_if {}", depth),
+                    Inst::Return => "return".to_string(),
+                    Inst::Unreachable => "unreachable".to_string(),
+                    Inst::Select => "select".to_string(),
+                };
+                writeln!(out, "{}{}", "  ".repeat(indent), text).unwrap();
+                if matches!(inst, Inst::Block | Inst::Loop | Inst::If | Inst::Else) {
+                    indent += 1;
+                }
+            }
+            // every path ends in a return, but the validator still wants a value here
+            writeln!(out, "    unreachable").unwrap();
+            writeln!(out, "  )").unwrap();
+        }
+        writeln!(
+            out,
+            "  (export \"{}\" (func {}))",
+            ENTRY_POINT,
+            self.function_name(self.export_index())
+        )
+        .unwrap();
+        writeln!(out, ")").unwrap();
+
+        out
+    }
+
+    fn local_name(&self, function: &WasmFunction, index: u32) -> String {
+        format!("${}", function.locals[index as usize])
+    }
+
+    fn global_name(&self, index: u32) -> String {
+        match index {
+            STACK_POINTER => "$stack.ptr".to_string(),
+            _ => match self.globals.get(index as usize - 1) {
+                Some(global) => format!("${}", global),
+                None => "$rand.state".to_string(),
+            },
+        }
+    }
+
+    fn function_name(&self, index: u32) -> String {
+        match (index as usize).checked_sub(self.imports.len()) {
+            Some(i) => format!("${}", self.functions[i].name),
+            None => format!("${}.{}", IMPORT_MODULE, self.imports[index as usize]),
+        }
+    }
+
+    /// Encode the module in the WebAssembly binary format
+    pub fn to_wasm(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend(1u32.to_le_bytes());
+
+        // one signature per distinct parameter count, after the print signature.
+        // The other imports share the signature of functions with as many parameters
+        let mut arities: Vec<usize> = self.functions.iter().map(|f| f.arity).collect();
+        arities.extend(self.imports[2..].iter().map(|name| import_arity(name)));
+        arities.push(0);
+        arities.sort_unstable();
+        arities.dedup();
+        let type_index =
+            |arity: usize| 1 + arities.iter().position(|a| *a == arity).unwrap() as u32;
+
+        let mut types = Vec::new();
+        uleb(&mut types, 1 + arities.len() as u32);
+        types.extend([0x60, 1, I32, 0]);
+        for arity in &arities {
+            types.push(0x60);
+            uleb(&mut types, *arity as u32);
+            types.extend(std::iter::repeat_n(I32, *arity));
+            types.extend([1, I32]);
+        }
+        section(&mut out, 1, types);
+
+        let mut imports = Vec::new();
+        uleb(&mut imports, self.imports.len() as u32);
+        for (index, name) in self.imports.iter().enumerate() {
+            name_bytes(&mut imports, IMPORT_MODULE);
+            name_bytes(&mut imports, name);
+            imports.push(0x00);
+            match index as u32 {
+                PRINT_INT_FN | PRINT_CHAR_FN => uleb(&mut imports, 0),
+                _ => uleb(&mut imports, type_index(import_arity(name))),
+            }
+        }
+        section(&mut out, 2, imports);
+
+        let mut functions = Vec::new();
+        uleb(&mut functions, self.functions.len() as u32);
+        for function in &self.functions {
+            uleb(&mut functions, type_index(function.arity));
+        }
+        section(&mut out, 3, functions);
+
+        let mut memory = vec![1, 0];
+        uleb(&mut memory, MEMORY_PAGES);
+        section(&mut out, 5, memory);
+
+        let mut globals = Vec::new();
+        let inits: Vec<i32> = std::iter::once(self.stack_base as i32)
+            .chain(self.globals.iter().map(|_| 0))
+            .chain(self.rand_state.map(|state| state as i32))
+            .collect();
+        uleb(&mut globals, inits.len() as u32);
+        for init in inits {
+            globals.extend([I32, 1, 0x41]);
+            sleb(&mut globals, init);
+            globals.push(0x0B);
+        }
+        section(&mut out, 6, globals);
+
+        let mut exports = Vec::new();
+        uleb(&mut exports, 2);
+        name_bytes(&mut exports, ENTRY_POINT);
+        exports.push(0x00);
+        uleb(&mut exports, self.export_index());
+        name_bytes(&mut exports, "memory");
+        exports.extend([0x02, 0]);
+        section(&mut out, 7, exports);
+
+        let mut code = Vec::new();
+        uleb(&mut code, self.functions.len() as u32);
+        for function in &self.functions {
+            let mut body = Vec::new();
+            let locals = (function.locals.len() - function.arity) as u32;
+            match locals {
+                0 => body.push(0),
+                _ => {
+                    body.push(1);
+                    uleb(&mut body, locals);
+                    body.push(I32);
+                }
+            }
+            for inst in &function.body {
+                encode(&mut body, inst);
+            }
+            body.extend([0x00, 0x0B]);
+
+            uleb(&mut code, body.len() as u32);
+            code.extend(body);
+        }
+        section(&mut out, 10, code);
+
+        out
+    }
+}
+
+const I32: u8 = 0x7F;
+/// Block type for blocks which leave nothing on the stack
+const EMPTY_BLOCK: u8 = 0x40;
+
+fn encode(out: &mut Vec<u8>, inst: &Inst) {
+    match inst {
+        Inst::I32Const(val) => {
+            out.push(0x41);
+            sleb(out, *val);
+        }
+        Inst::LocalGet(index) => {
+            out.push(0x20);
+            uleb(out, *index);
+        }
+        Inst::LocalSet(index) => {
+            out.push(0x21);
+            uleb(out, *index);
+        }
+        Inst::GlobalGet(index) => {
+            out.push(0x23);
+            uleb(out, *index);
+        }
+        Inst::GlobalSet(index) => {
+            out.push(0x24);
+            uleb(out, *index);
+        }
+        // alignment of 2^2 bytes and no offset
+        Inst::I32Load => out.extend([0x28, 2, 0]),
+        Inst::I32Store => out.extend([0x36, 2, 0]),
+        Inst::Numeric(numeric) => out.push(numeric.opcode()),
+        Inst::Call(index) => {
+            out.push(0x10);
+            uleb(out, *index);
+        }
+        Inst::Drop => out.push(0x1A),
+        Inst::Block => out.extend([0x02, EMPTY_BLOCK]),
+        Inst::Loop => out.extend([0x03, EMPTY_BLOCK]),
+        Inst::If => out.extend([0x04, EMPTY_BLOCK]),
+        Inst::Else => out.push(0x05),
+        Inst::End => out.push(0x0B),
+        Inst::Br(depth) => {
+            out.push(0x0C);
+            uleb(out, *depth);
+        }
+        Inst::BrIf(depth) => {
+            out.push(0x0D);
+            uleb(out, *depth);
+        }
+        Inst::Return => out.push(0x0F),
+        Inst::Unreachable => out.push(0x00),
+        Inst::Select => out.push(0x1B),
+    }
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: Vec<u8>) {
+    out.push(id);
+    uleb(out, contents.len() as u32);
+    out.extend(contents);
+}
+
+fn name_bytes(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u32);
+    out.extend(name.as_bytes());
+}
+
+/// Unsigned LEB128
+fn uleb(out: &mut Vec<u8>, mut val: u32) {
+    loop {
+        let byte = (val & 0x7F) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Signed LEB128
+fn sleb(out: &mut Vec<u8>, mut val: i32) {
+    loop {
+        let byte = (val & 0x7F) as u8;
+        val >>= 7;
+        let done = (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
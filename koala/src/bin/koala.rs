@@ -1,20 +1,277 @@
 use byteorder::{BigEndian, ByteOrder};
-use koala::kvm::VirtualMachine;
-use std::{env::args, path::Path};
+use koala::compiler::CompilerContext;
+use koala::kvm::{self, VirtualMachine};
+use koala::link::Object;
+use koala::modules;
+use koala::observer::DebugPrinter;
+use koala::profile::Profile;
+use koala::rng::DEFAULT_SEED;
+use koala::rvm::{self, RegisterMachine};
+use koala::symbols::SymbolTable;
+use koala::trace::{self, Recorder, Replay};
+use koala::verify::verify;
+use std::io::{self, BufRead, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::{
+    env::args,
+    path::{Path, PathBuf},
+};
 
 fn main() {
     let files: Vec<String> = args().collect();
-    if files.len() > 1 {
-        let code = read_file(&files[1]);
+    if files.len() > 2 && files[1] == "bench" {
+        let runs = files
+            .iter()
+            .position(|arg| arg == "--runs")
+            .and_then(|i| files.get(i + 1))
+            .map(|runs| runs.parse().expect("--runs takes a number"))
+            .unwrap_or(10);
+        bench(&files[2], runs);
+    } else if files.len() > 2 && files[1] == "link" {
+        link(&files[2..]);
+    } else if files.len() > 2 && files[1] == "replay" {
+        replay(&files[2]);
+    } else if files.len() > 2 && files[1] == "run" {
+        run(&files[2], &files[3..]);
+    } else if files.len() > 1 {
+        run(&files[1], &files[2..]);
+    }
+}
+
+/// Run a bytecode file, checking it with the verifier first unless `--no-verify` is given.
+/// The process exits with the code the program passed to `exit()`
+fn run(file_path: &str, options: &[String]) {
+    let flag = |name: &str| options.iter().any(|arg| arg == name);
+    let value = |name: &str| {
+        options
+            .iter()
+            .position(|arg| arg == name)
+            .map(|i| match options.get(i + 1) {
+                Some(value) => value.as_str(),
+                None => panic!("{} takes a value", name),
+            })
+    };
+
+    let code = read_file(file_path);
+    if !flag("--no-verify") {
+        if let Err(e) = verify(&code) {
+            eprintln!("{}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+
+    // function names come from the symbol table koalac writes next to the bytecode
+    let symbols_path = match value("--symbols") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(Path::new(file_path).with_extension("sym")).filter(|path| path.exists()),
+    };
+    let symbols = match symbols_path {
+        Some(path) => match std::fs::read_to_string(&path).map(|text| SymbolTable::parse(&text)) {
+            Ok(Ok(symbols)) => symbols,
+            Ok(Err(e)) => panic!("{}: {}", path.display(), e),
+            Err(e) => panic!("{}: {}", path.display(), e),
+        },
+        None => SymbolTable::default(),
+    };
+
+    let profile_json = value("--profile-json");
+    let flamegraph = value("--flamegraph");
+    let trace_path = value("--trace");
+    let profiling = flag("--profile") || profile_json.is_some() || flamegraph.is_some();
+    // rand() repeats its numbers when given a seed, and differs between runs otherwise
+    let seed = match value("--seed") {
+        Some(seed) => seed.parse().expect("--seed takes a number"),
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or(DEFAULT_SEED),
+    };
+
+    let debug_pipe = |msg: &str| print!("{}", msg);
+    let mut debugger = DebugPrinter::new(&debug_pipe);
+    let mut profile = Profile::new();
+    let mut recorder = Recorder::new();
+    // anything printed as a prompt is shown before waiting for the line
+    let mut read_stdin = || {
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    };
+
+    let mut vm = VirtualMachine::new(&|msg: &str| print!("{}", msg));
+    vm.set_input(&mut read_stdin);
+    vm.set_seed(seed);
+    if flag("--debug") {
+        vm.add_observer(&mut debugger);
+    }
+    if profiling {
+        vm.add_observer(&mut profile);
+    }
+    if trace_path.is_some() {
+        vm.add_observer(&mut recorder);
+    }
+    vm.run(&code);
+    let exit_code = vm.exit_code();
+    drop(vm);
+
+    if let Some(path) = trace_path {
+        if let Err(e) = std::fs::write(path, trace::to_json_lines(recorder.events())) {
+            panic!("{}", e);
+        }
+    }
+
+    if profiling {
+        if flag("--profile") {
+            eprintln!("\n{}", profile.table(&symbols));
+        }
+        if let Some(path) = profile_json {
+            if let Err(e) = std::fs::write(path, profile.to_json(&symbols)) {
+                panic!("{}", e);
+            }
+        }
+        if let Some(path) = flamegraph {
+            if let Err(e) = std::fs::write(path, profile.folded_stacks(&symbols)) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    // the program asked to stop with this code, through exit()
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Step through a trace recorded by `koala run --trace`, reading commands from stdin
+fn replay(file_path: &str) {
+    let events = match std::fs::read_to_string(file_path).map(|text| trace::from_json_lines(&text))
+    {
+        Ok(Ok(events)) => events,
+        Ok(Err(e)) => panic!("{}: {}", file_path, e),
+        Err(e) => panic!("{}: {}", file_path, e),
+    };
+    let mut replay = Replay::new(events);
+    println!(
+        "{} steps. [enter] or n: forward, b: back, g <step>: go to step, q: quit",
+        replay.len()
+    );
+
+    let stdin = io::stdin();
+    loop {
+        let state = replay.state();
+        if let Some(event) = replay.next_event() {
+            println!("next: {}", event);
+        }
+        println!("step {}/{}", replay.position(), replay.len());
+        println!("  stack:   {:?}", state.stack);
+        for frame in &state.frames {
+            println!("  frame @{}: {:?}", frame.fn_addr, frame.locals);
+        }
+        println!("  globals: {:?}", state.globals);
+        println!("  output:  {:?}", state.output);
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None | Some("n") => {
+                replay.forward();
+            }
+            Some("b") => {
+                replay.back();
+            }
+            Some("g") => match words.next().map(str::parse) {
+                Some(Ok(step)) => replay.seek(step),
+                _ => println!("g takes a step number"),
+            },
+            Some("q") => break,
+            Some(command) => println!("unknown command {}", command),
+        }
+    }
+}
+
+/// Run a Koala source file on both the stack VM and the register VM,
+/// comparing how many instructions each executes and how long they take
+fn bench(file_path: &str, runs: u32) {
+    let program = match modules::load(Path::new(file_path)) {
+        Ok(program) => program,
+        Err(e) => panic!("{}", e),
+    };
+    let module = program.to_ir(&mut CompilerContext::new());
+    let stack_code = match kvm::load(&module.link()) {
+        Ok(code) => code,
+        Err(e) => panic!("{}", e),
+    };
+    let register_code = rvm::translate(&module);
+
+    let mut stack_count = 0;
+    let start = Instant::now();
+    for _ in 0..runs {
+        let mut vm = VirtualMachine::new(&|_: &str| {});
+        vm.run_program(&stack_code);
+        stack_count = vm.instruction_count();
+    }
+    let stack_time = start.elapsed() / runs.max(1);
+
+    let mut register_count = 0;
+    let start = Instant::now();
+    for _ in 0..runs {
+        let mut vm = RegisterMachine::new(&|_: &str| {}, None);
+        vm.run(&register_code);
+        register_count = vm.instruction_count();
+    }
+    let register_time = start.elapsed() / runs.max(1);
+
+    println!("{:<12} {:>14} {:>12}", "", "instructions", "time/run");
+    for (name, count, time) in [
+        ("stack VM", stack_count, stack_time),
+        ("register VM", register_count, register_time),
+    ] {
+        println!(
+            "{:<12} {:>14} {:>10.3}ms",
+            name,
+            count,
+            time.as_secs_f64() * 1000.0
+        );
+    }
+}
 
-        let debug_pipe = |msg: &str| {
-            if files.iter().any(|arg| arg == "--debug") {
-                print!("{}", msg);
+/// Link objects written by `koalac -c` into `test.kvm`, with its symbol table in `test.sym`
+fn link(file_paths: &[String]) {
+    let objects: Vec<Object> = file_paths
+        .iter()
+        .map(|path| {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) => panic!("{}: {}", path, e),
+            };
+            match serde_json::from_str(&text) {
+                Ok(object) => object,
+                Err(e) => panic!("{}: {}", path, e),
             }
-        };
+        })
+        .collect();
+    let (code, symbols) = match koala::link::link(&objects) {
+        Ok(linked) => linked,
+        Err(e) => {
+            eprintln!("ʕ •ᴥ•ʔ {}", e);
+            std::process::exit(1);
+        }
+    };
 
-        let mut vm = VirtualMachine::new(&|msg: &str| print!("{}", msg), &debug_pipe);
-        vm.run(&code);
+    let bytes: Vec<u8> = code.iter().flat_map(|inst| inst.to_be_bytes()).collect();
+    if let Err(e) = std::fs::write("test.kvm", bytes) {
+        panic!("test.kvm: {}", e);
+    }
+    if let Err(e) = std::fs::write("test.sym", symbols.to_string()) {
+        panic!("test.sym: {}", e);
     }
 }
 
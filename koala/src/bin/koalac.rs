@@ -1,5 +1,9 @@
-use koala::compiler::{CodeGen, CompilerContext};
-use koala::parser::parse_code;
+use koala::backend::{asm, c, llvm, wasm};
+use koala::compiler::CompilerContext;
+use koala::link::Object;
+use koala::modules;
+use koala::optimizer::Optimize;
+use koala::peephole;
 use std::fs::{self, File};
 use std::io::Write;
 use std::{env::args, ffi::OsStr, path::Path};
@@ -16,26 +20,57 @@ fn main() -> std::io::Result<()> {
         };
 
         if extension == "koala" {
-            let file_string = match fs::read_to_string(&args[1]) {
-                Ok(file_contents) => file_contents,
-                Err(_) => return Ok(()),
-            };
-
-            let program = match parse_code(&file_string) {
+            // the modules it imports are loaded along with it
+            let mut program = match modules::load(file_path) {
                 Ok(prog) => prog,
                 Err(e) => panic!("{}", e),
             };
 
+            let optimize = args.iter().any(|arg| arg == "-O");
+            if optimize {
+                program = program.optimize();
+            }
+
+            let emit = args
+                .iter()
+                .find_map(|arg| arg.strip_prefix("--emit="))
+                .unwrap_or("kvm");
+
             if args.iter().any(|arg| arg == "--ast") {
                 let ast = serde_json::to_string_pretty(&program)?;
                 fs::write("test.kast", ast)?;
-            } else {
-                let vm_code = program.code_gen(&mut CompilerContext::new(), 0);
+            } else if args.iter().any(|arg| arg == "-c") {
+                // a relocatable object for `koala link`, named after the source file
+                let object = Object::compile(&program, &mut CompilerContext::new());
+                let object_path = file_path.with_extension("ko");
+                let object_path = object_path.file_name().unwrap_or_default();
+                fs::write(object_path, serde_json::to_string(&object)?)?;
+            } else if emit == "c" {
+                fs::write("test.c", c::emit(&program))?;
+            } else if emit == "wat" {
+                fs::write("test.wat", wasm::compile(&program).to_wat())?;
+            } else if emit == "wasm" {
+                fs::write("test.wasm", wasm::compile(&program).to_wasm())?;
+            } else if emit == "kvm" {
+                let module = program.to_ir(&mut CompilerContext::new());
+                let (mut vm_code, mut symbols) = module.link_with_symbols();
+                if optimize {
+                    (vm_code, symbols) = peephole::optimize_with_symbols(&vm_code, &symbols);
+                }
 
                 let mut output = File::create("test.kvm")?;
                 for inst in vm_code {
                     output.write_all(&inst.to_be_bytes())?;
                 }
+                fs::write("test.sym", symbols.to_string())?;
+            } else {
+                let module = program.to_ir(&mut CompilerContext::new());
+                match emit {
+                    "ir" => fs::write("test.kir", module.to_string())?,
+                    "llvm" => fs::write("test.ll", llvm::emit(&module))?,
+                    "asm" => fs::write("test.s", asm::emit(&module))?,
+                    _ => eprintln!("ʕ •ᴥ•ʔ can't emit {}", emit),
+                }
             }
         } else {
             eprintln!("ʕ•ᴥ• ʔ {:?} wasnt a .koala", file_path);
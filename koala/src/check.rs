@@ -0,0 +1,394 @@
+//! Checking a program for the errors the compiler reports, without compiling it, so that
+//! the backends which translate the syntax tree directly reject the same programs
+
+use crate::compiler::{array_size, builtin_argc, const_eval, find_loop, CompilerContext};
+use crate::grammar::{Expr, ForIter, FunctionCall, Program, Statement};
+use std::collections::HashMap;
+
+/// A variable in one of the blocks enclosing a statement
+struct Local {
+    mutable: bool,
+    /// Length of the variable when it is an array
+    len: Option<usize>,
+}
+
+struct Checker<'a> {
+    context: &'a CompilerContext,
+    consts: HashMap<String, i32>,
+    /// Globals by name, with their length when they are arrays
+    globals: HashMap<String, Option<usize>>,
+    /// Names of the program's functions
+    functions: Vec<&'a str>,
+    /// The blocks enclosing the current statement, innermost last
+    scopes: Vec<HashMap<String, Local>>,
+    /// Labels of the loops enclosing the current statement, innermost last
+    loops: Vec<Option<String>>,
+}
+
+impl Program {
+    /// Check the program for the errors `to_ir` reports when it is compiled with a context,
+    /// whose natives it can call
+    pub fn check(&self, context: &CompilerContext) -> Result<(), String> {
+        let consts = self.eval_consts()?;
+        let mut globals = HashMap::new();
+        // globals are declared by the statements at the top of a function
+        for def in &self.0 {
+            for stmt in &def.body {
+                let (id, len) = match stmt {
+                    Statement::ArrayInstantiation {
+                        id,
+                        size,
+                        global: true,
+                        ..
+                    } => match array_size(size, &consts)? {
+                        Some(len) => (id, Some(len as usize)),
+                        None => continue,
+                    },
+                    Statement::VarAssignment {
+                        id, global: true, ..
+                    } => (id, None),
+                    _ => continue,
+                };
+                if consts.contains_key(id) {
+                    return Err(format!(
+                        "cannot declare a global named '{}', which is a constant",
+                        id
+                    ));
+                }
+                // an array keeps its length if a number is assigned to its name
+                let global = globals.entry(id.clone()).or_insert(None);
+                *global = len.or(*global);
+            }
+        }
+
+        let mut functions = Vec::new();
+        for def in &self.0 {
+            if functions.contains(&def.id.as_str()) {
+                return Err(format!("duplicate function definition for {}().", def.id));
+            }
+            functions.push(def.id.as_str());
+        }
+
+        let mut checker = Checker {
+            context,
+            consts,
+            globals,
+            functions,
+            scopes: Vec::new(),
+            loops: Vec::new(),
+        };
+        for def in &self.0 {
+            // the arguments are the first variables of the function's outermost block
+            checker.scopes.push(HashMap::new());
+            for arg in &def.args {
+                checker.declare(arg, true, None)?;
+            }
+            checker.stmts(&def.body)?;
+            checker.scopes.pop();
+        }
+        Ok(())
+    }
+}
+
+impl Checker<'_> {
+    /// Declare a variable in the current block, shadowing any with the same name
+    fn declare(&mut self, id: &str, mutable: bool, len: Option<usize>) -> Result<(), String> {
+        if self.consts.contains_key(id) {
+            return Err(format!(
+                "cannot declare a variable named '{}', which is a constant",
+                id
+            ));
+        }
+        let scope = self.scopes.last_mut().unwrap();
+        scope.insert(id.to_string(), Local { mutable, len });
+        Ok(())
+    }
+
+    /// Search the enclosing blocks for a local, from the innermost outwards
+    fn local(&self, id: &str) -> Option<&Local> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(id))
+    }
+
+    /// Whether a local or a global has the name
+    fn is_var(&self, id: &str) -> bool {
+        self.local(id).is_some() || self.globals.contains_key(id)
+    }
+
+    /// Check the statements of a block of their own
+    fn block(&mut self, stmts: &[Statement]) -> Result<(), String> {
+        self.scopes.push(HashMap::new());
+        let checked = self.stmts(stmts);
+        self.scopes.pop();
+        checked
+    }
+
+    fn stmts(&mut self, stmts: &[Statement]) -> Result<(), String> {
+        stmts.iter().try_for_each(|stmt| self.stmt(stmt))
+    }
+
+    fn stmt(&mut self, stmt: &Statement) -> Result<(), String> {
+        match stmt {
+            Statement::Print { expr, .. } => expr.iter().try_for_each(|expr| self.expr(expr)),
+            Statement::Let { id, expr, mutable } => {
+                // the value can read a variable the new one shadows
+                self.expr(expr)?;
+                self.declare(id, *mutable, None)
+            }
+            Statement::VarAssignment { id, expr, global } => {
+                if self.consts.contains_key(id) {
+                    return Err(format!("cannot assign to the constant '{}'", id));
+                }
+                self.expr(expr)?;
+                if *global {
+                    if !self.globals.contains_key(id) {
+                        return Err(format!("failed to find global variable by id: {}", id));
+                    }
+                    return Ok(());
+                }
+                match self.local(id) {
+                    Some(local) if !local.mutable => Err(format!(
+                        "cannot assign twice to immutable variable '{}', declare it with `let mut {} = ...`",
+                        id, id
+                    )),
+                    None if !self.globals.contains_key(id) => Err(format!(
+                        "cannot assign to undeclared variable '{}', declare it with `let mut {} = ...`",
+                        id, id
+                    )),
+                    _ => Ok(()),
+                }
+            }
+            Statement::ArrayIndexAssignment { id, index, expr } => {
+                self.expr(expr)?;
+                self.expr(index)?;
+                if !self.is_var(id) {
+                    return Err("array index failure".to_string());
+                }
+                Ok(())
+            }
+            Statement::ArrayInstantiation {
+                id,
+                size,
+                elements,
+                global,
+            } => {
+                // arrays whose size isn't known at compile time are left out
+                let size = match array_size(size, &self.consts)? {
+                    Some(size) => size as usize,
+                    None => return Ok(()),
+                };
+                if let Some(elements) = elements {
+                    if elements.len() != size {
+                        return Err(
+                            "cannot have specified array size different from array literal."
+                                .to_string(),
+                        );
+                    }
+                }
+                if !*global {
+                    self.declare(id, true, Some(size))?;
+                } else if !self.globals.contains_key(id) {
+                    return Err(format!("could not find global index for array id: {}", id));
+                }
+                // the elements are evaluated once the array is declared
+                elements
+                    .iter()
+                    .flatten()
+                    .try_for_each(|expr| self.expr(expr))
+            }
+            Statement::FunctionCall(func_call) => self.call(func_call),
+            Statement::If(if_data) => {
+                self.expr(&if_data.expr)?;
+                self.block(&if_data.stmts)
+            }
+            Statement::IfElse(if_else) => {
+                self.expr(&if_else.expr)?;
+                self.block(&if_else.stmts)?;
+                self.block(&if_else.else_stmts)
+            }
+            Statement::Return => Ok(()),
+            Statement::ReturnExpr(expr) => self.expr(expr),
+            Statement::While { cond, stmts, label } => {
+                self.while_loop(label, cond, None, stmts, None)
+            }
+            Statement::For(for_data) => {
+                if let ForIter::Range {
+                    step: Some(step), ..
+                } = &for_data.iter
+                {
+                    if const_eval(step, &self.consts) == Some(0) {
+                        return Err("the step of a for loop cannot be 0.".to_string());
+                    }
+                }
+                let len = match &for_data.iter {
+                    ForIter::Array(id) => self.array_len(&Expr::Variable { id: id.clone() })?,
+                    ForIter::Range { .. } => 0,
+                };
+                let for_loop = for_data.desugar(&self.consts, |_| len as u32);
+                // the counter and bounds are only visible to the loop
+                self.scopes.push(HashMap::new());
+                let checked = self.stmts(&for_loop.init).and_then(|_| {
+                    self.while_loop(
+                        &for_data.label,
+                        &for_loop.cond,
+                        Some(&for_loop.bind),
+                        &for_data.stmts,
+                        Some(&for_loop.step),
+                    )
+                });
+                self.scopes.pop();
+                checked
+            }
+            Statement::Break(label) => self.find_loop(label, "break"),
+            Statement::Continue(label) => self.find_loop(label, "continue"),
+            Statement::When(_) => Ok(()),
+        }
+    }
+
+    /// Check a loop the way `while_code_gen` lowers it
+    fn while_loop(
+        &mut self,
+        label: &Option<String>,
+        cond: &Expr,
+        bind: Option<&Statement>,
+        stmts: &[Statement],
+        step: Option<&Statement>,
+    ) -> Result<(), String> {
+        self.expr(cond)?;
+        self.loops.push(label.clone());
+        self.scopes.push(HashMap::new());
+        let checked = bind
+            .into_iter()
+            .chain(stmts)
+            .try_for_each(|stmt| self.stmt(stmt));
+        self.scopes.pop();
+        self.loops.pop();
+        checked?;
+        step.map_or(Ok(()), |step| self.stmt(step))
+    }
+
+    fn find_loop(&self, label: &Option<String>, keyword: &str) -> Result<(), String> {
+        let labels = self.loops.iter().map(Option::as_ref);
+        find_loop(labels, label, keyword).map(|_| ())
+    }
+
+    fn call(&mut self, func_call: &FunctionCall) -> Result<(), String> {
+        let id = func_call.id.as_str();
+        let args = |checker: &mut Self| {
+            func_call
+                .args
+                .iter()
+                .rev()
+                .try_for_each(|arg| checker.expr(arg))
+        };
+        // the program's own functions shadow builtins and natives of the same name
+        if self.functions.contains(&id) {
+            return args(self);
+        }
+        if let Some(argc) = builtin_argc(id) {
+            if func_call.args.len() != argc {
+                return Err(format!("{}() takes {} arguments.", id, argc));
+            }
+        }
+        // the length of an array is known from its declaration
+        if id == "len" {
+            return self.array_len(&func_call.args[0]).map(|_| ());
+        }
+        args(self)?;
+        if builtin_argc(id).is_some() {
+            return Ok(());
+        }
+        if let Some(index) = self.context.find_native(id) {
+            let arity = self.context.natives[index].1;
+            if func_call.args.len() != arity {
+                return Err(format!("{}() takes {} arguments.", id, arity));
+            }
+            return Ok(());
+        }
+        if !self.context.external_calls {
+            return Err("No function found to jump to".to_string());
+        }
+        Ok(())
+    }
+
+    /// Length of the array passed to `len()`, preferring a local array to a global one
+    fn array_len(&self, arg: &Expr) -> Result<usize, String> {
+        let id = match arg {
+            Expr::Variable { id } => id,
+            _ => return Err("len() takes the name of an array".to_string()),
+        };
+        let len = match self.local(id) {
+            Some(local) => local.len,
+            None => self.globals.get(id).copied().flatten(),
+        };
+        len.ok_or_else(|| format!("len() takes an array, but '{}' is not one", id))
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::IntLit(_) | Expr::BoolLit(_) => Ok(()),
+            Expr::StringLit(_) => Err("string literals are not supported yet.".to_string()),
+            Expr::ArrayIndex { id, expr } => {
+                if !self.is_var(id) {
+                    return Err(format!(
+                        "cound not find array index to load for id: {}.",
+                        id
+                    ));
+                }
+                self.expr(expr)
+            }
+            // constants are inlined rather than stored
+            Expr::Variable { id } if self.consts.contains_key(id) => Ok(()),
+            Expr::Variable { id } => match self.is_var(id) {
+                true => Ok(()),
+                false => Err(format!("could not find variable '{}'", id)),
+            },
+            Expr::FunctionCall(func_call) => self.call(func_call),
+            Expr::BinExpr(bin_expr) => {
+                // the right hand side is generated first
+                self.expr(&bin_expr.op2)?;
+                self.expr(&bin_expr.op1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_code;
+
+    fn check(code: &str) -> Result<(), String> {
+        parse_code(code).unwrap().check(&CompilerContext::new())
+    }
+
+    #[test]
+    fn check_test() {
+        assert_eq!(
+            check("fn main() { let a[2] = [1, 2] for x in a { print(x + len(a)) } }"),
+            Ok(())
+        );
+        assert_eq!(
+            check("fn main() { let x = 1 x = 2 }"),
+            Err(
+                "cannot assign twice to immutable variable 'x', declare it with `let mut x = ...`"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check("fn main() { while 1 { break 'outer } }"),
+            Err("`break 'outer` is not inside a loop labeled 'outer.".to_string())
+        );
+        assert_eq!(
+            check("fn main() { print(len(x)) } fn f() { global x = 1 }"),
+            Err("len() takes an array, but 'x' is not one".to_string())
+        );
+        assert_eq!(
+            check("fn main() { print(len(1, 2)) } fn len(a, b) { return a + b }"),
+            Ok(())
+        );
+        assert_eq!(
+            check("fn main() { g(1) }"),
+            Err("No function found to jump to".to_string())
+        );
+    }
+}
@@ -1,38 +1,158 @@
 use super::grammar::{
-    BinExpr, BinOp, Expr, FunctionCall, FunctionDefinition, If, Program, Statement,
-};
-use crate::instructions::{
-    AND, BEQZ, CALL, END, EQ, GLOBAL_ARR_LOAD, GLOBAL_ARR_STORE, GLOBAL_LOAD, GLOBAL_STORE, GT,
-    GTE, IADD, IDIV, IMUL, ISUB, JUMP, LOCAL_ARR_LOAD, LOCAL_ARR_STORE, LOCAL_LOAD, LOCAL_STORE,
-    LT, LTE, NEQ, OR, POP, PRINT, PUSH, RET,
+    BinExpr, BinOp, Expr, For, ForIter, FunctionCall, FunctionDefinition, If, IfElse, Program,
+    Statement,
 };
+use crate::ir::{self, FunctionBuilder, Inst, Label, PrintType, ReadType, Terminator};
+use crate::natives;
+use crate::optimizer::fold;
 use core::panic;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub struct CompilerContext {
-    /// Table of Function names and their address
+    /// Table of Function names and their argument counts
     pub fn_table: HashMap<String, usize>,
-    /// A Frame-based set of indexes for local variables
+    /// The blocks enclosing the current statement, innermost last, each with the indexes
+    /// of the local variables it declares and the next free index in the frame
     pub local_var_scope: Vec<(HashMap<String, usize>, usize)>,
+    /// Number of frame slots the current function needs, across all of its blocks
+    pub frame_size: usize,
+    /// Indexes of the locals in the current function which cannot be assigned again
+    pub immutable_locals: HashSet<usize>,
+    /// Values of the program's constants
+    pub consts: HashMap<String, i32>,
+    /// The loops enclosing the current statement, innermost last
+    pub loops: Vec<LoopContext>,
     /// A Table with global variable addresses paired with the current memory offset
     pub global_vars: (HashMap<String, usize>, usize),
+    /// Lengths of the global arrays by name, and of the local arrays in the current function by index
+    pub array_lens: (HashMap<String, usize>, HashMap<usize, usize>),
+    /// Names and argument counts of the natives, in the order of their indexes
+    pub natives: Vec<(String, usize)>,
+    /// Leave calls to functions the program doesn't define for the linker to resolve
+    pub external_calls: bool,
+}
+
+/// Where `break` and `continue` jump to in a loop
+pub struct LoopContext {
+    pub label: Option<String>,
+    /// The block after the loop
+    pub break_label: Label,
+    /// The block which starts the next iteration, by stepping a for loop's counter
+    /// or checking the condition
+    pub continue_label: Label,
+}
+
+/// Find which of the enclosing loops, given innermost last by their labels,
+/// a `break` or `continue` refers to. That is the innermost one unless it names a label.
+pub fn find_loop<'a>(
+    labels: impl DoubleEndedIterator<Item = Option<&'a String>> + ExactSizeIterator,
+    label: &Option<String>,
+    keyword: &str,
+) -> Result<usize, String> {
+    let mut loops = labels.enumerate().rev();
+    let found = match label {
+        Some(label) => loops.find(|(_, name)| *name == Some(label)),
+        None => loops.next(),
+    };
+    match (found, label) {
+        (Some((index, _)), _) => Ok(index),
+        (None, Some(label)) => Err(format!(
+            "`{} '{}` is not inside a loop labeled '{}.",
+            keyword, label, label
+        )),
+        (None, None) => Err(format!("`{}` cannot be used outside of a loop.", keyword)),
+    }
 }
 
+/// Failures of lowering which `Program::check` rules out first
+const CHECKED: &str = "the program was checked before it was lowered";
+
 /// Denote between Global and Local Variables during Code Gen
 pub enum ScopeType {
     Global,
     Local,
 }
 
+impl Default for CompilerContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CompilerContext {
     pub fn new() -> Self {
         CompilerContext {
             fn_table: HashMap::new(),
             local_var_scope: Vec::new(),
+            frame_size: 0,
+            immutable_locals: HashSet::new(),
+            consts: HashMap::new(),
+            loops: Vec::new(),
             global_vars: (HashMap::new(), 0),
+            array_lens: (HashMap::new(), HashMap::new()),
+            natives: natives::STANDARD
+                .iter()
+                .map(|(name, arity)| (name.to_string(), *arity))
+                .collect(),
+            external_calls: false,
         }
     }
 
+    /// Make a native the VM was given with `Natives::register` callable by name,
+    /// registering them in the same order
+    pub fn register_native(&mut self, name: &str, arity: usize) {
+        self.natives.push((name.to_string(), arity));
+    }
+
+    /// Index of a native by name
+    pub fn find_native(&self, name: &str) -> Option<usize> {
+        self.natives.iter().position(|(native, _)| native == name)
+    }
+
+    /// Length of the array passed to `len()`, preferring a local array to a global one
+    pub fn array_len(&self, arg: &Expr) -> Option<usize> {
+        let id = match arg {
+            Expr::Variable { id } => id,
+            _ => return None,
+        };
+        let (global_lens, local_lens) = &self.array_lens;
+        match self.find_local_var_index(id) {
+            Ok(index) => local_lens.get(&index).copied(),
+            Err(_) => global_lens.get(id).copied(),
+        }
+    }
+
+    /// Enter a block, whose variables are allocated after those of the enclosing blocks
+    pub fn push_scope(&mut self) {
+        let next = self.local_var_scope.last().map_or(0, |scope| scope.1);
+        self.local_var_scope.push((HashMap::new(), next));
+    }
+
+    /// Leave a block, so that the frame slots of its variables can be reused
+    pub fn pop_scope(&mut self) {
+        self.local_var_scope.pop();
+    }
+
+    /// Declare a local variable of some size in the current block, returning its index.
+    /// A variable declared earlier with the same name is shadowed until the block ends
+    pub fn declare_local(&mut self, id: &str, size: usize) -> usize {
+        let scope = self.local_var_scope.last_mut().unwrap();
+        let index = scope.1;
+        scope.0.insert(id.to_string(), index);
+        scope.1 += size;
+        self.frame_size = self.frame_size.max(scope.1);
+        // the slot may have held an array or immutable local of a block which has ended
+        self.array_lens.1.remove(&index);
+        self.immutable_locals.remove(&index);
+        index
+    }
+
+    /// The loop a `break` or `continue` refers to
+    pub fn find_loop(&self, label: &Option<String>, keyword: &str) -> &LoopContext {
+        let labels = self.loops.iter().map(|context| context.label.as_ref());
+        &self.loops[find_loop(labels, label, keyword).expect(CHECKED)]
+    }
+
     /// Search both variables for an ID. (first local then global)
     pub fn find_var_index(&self, var_name: &str) -> Result<(ScopeType, usize), String> {
         if let Ok(index) = self.find_local_var_index(var_name) {
@@ -42,12 +162,17 @@ impl CompilerContext {
             return Ok((ScopeType::Global, index));
         }
 
-        return Err(format!("could not find variable with id: {}", var_name));
+        Err(format!("could not find variable with id: {}", var_name))
     }
 
-    /// search the current Frame's local variable set for an ID
+    /// Search the enclosing blocks for an ID, from the innermost outwards
     pub fn find_local_var_index(&self, var_name: &str) -> Result<usize, String> {
-        match self.local_var_scope.last().unwrap().0.get(var_name) {
+        match self
+            .local_var_scope
+            .iter()
+            .rev()
+            .find_map(|scope| scope.0.get(var_name))
+        {
             Some(index) => Ok(*index),
             None => Err(format!("usage of undefined local variable! '{}'", var_name)),
         }
@@ -65,171 +190,182 @@ impl CompilerContext {
     }
 }
 
-/// Trait for Productions and Terminals which generate code
-pub trait CodeGen {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32>;
-}
+/// Functions which compile to instructions of their own rather than calls
+pub const BUILTINS: [&str; 4] = ["read_int", "read_line", "rand", "len"];
 
-/// Function ID to bootstrap the executable
-const ENTRY_POINT: &str = "main";
+/// Number of arguments a builtin takes, or None when the function isn't a builtin
+pub fn builtin_argc(id: &str) -> Option<usize> {
+    match id {
+        "read_int" | "read_line" => Some(0),
+        "len" => Some(1),
+        "rand" => Some(2),
+        _ => None,
+    }
+}
 
-impl CodeGen for Program {
-    fn code_gen(&self, context: &mut CompilerContext, _: usize) -> Vec<u32> {
-        context.global_vars = self.create_global_var_table();
+impl FunctionCall {
+    /// Whether the call is to a builtin, checking that it is passed the arguments it takes
+    pub fn is_builtin(&self) -> bool {
+        let argc = match builtin_argc(&self.id) {
+            Some(argc) => argc,
+            None => return false,
+        };
+        if self.args.len() != argc {
+            panic!("{}() takes {} arguments.", self.id, argc);
+        }
+        true
+    }
+}
 
-        let mut code = Vec::new();
+/// Trait for Productions and Terminals which generate code
+pub trait CodeGen {
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder);
+}
 
-        const BOOTSTRAP_LENGTH: usize = 4;
-        // generate code for every definition
+impl Program {
+    /// Lower every function definition into the intermediate representation,
+    /// panicking with the first error `check` finds in the program
+    pub fn to_ir(&self, context: &mut CompilerContext) -> ir::Module {
+        if let Err(e) = self.check(context) {
+            panic!("{}", e);
+        }
+        context.consts = self.const_values();
+        context.global_vars = self.create_global_var_table(&context.consts);
+        context.array_lens.0 = self.global_array_lens(&context.consts);
+        // register every function up front so calls can come before definitions
         for def in &self.0 {
-            code.extend(def.code_gen(context, code.len() + BOOTSTRAP_LENGTH));
+            context.fn_table.insert(def.id.clone(), def.args.len());
         }
-        // generate procedure that executes only main
-        let main_addr = match context.fn_table.get(ENTRY_POINT) {
-            Some(address) => *address as u32,
-            None => panic!("could not find main function."),
-        };
-        let entry_point_code: [u32; BOOTSTRAP_LENGTH] = [CALL, 0, main_addr, END];
 
-        // prefix the code with main entrypoint
-        return entry_point_code
-            .iter()
-            .cloned()
-            .chain(code.into_iter())
-            .collect();
+        ir::Module {
+            functions: self.0.iter().map(|def| def.to_ir(context)).collect(),
+            globals_size: context.global_vars.1,
+        }
     }
-}
 
-impl CodeGen for FunctionDefinition {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32> {
-        // check already existing function name
-        match context.fn_table.get(&self.id) {
-            None => {
-                /*
-                 * We can keep track of the function addresses by labels this way,
-                 * and then load their address in the FunctionCall production more easily
-                 */
-                context.fn_table.insert(self.id.clone(), start_addr);
-
-                let mut code = vec![];
-                let mut scope_size = 0;
-                // Create a new scope for this function Enclosure
-                let new_scope = self
-                    .args
-                    .iter()
-                    .cloned()
-                    .map(|id| {
-                        scope_size += 1;
-                        (id, scope_size - 1)
-                    })
-                    .collect::<HashMap<String, usize>>();
-                // push new scope
-                context.local_var_scope.push((new_scope, scope_size));
-                // Recursively Generate Code
-                for stmt in &self.body {
-                    code.extend(stmt.code_gen(context, start_addr + code.len() + 1));
-                }
-                // Pop scope since we are leaving function
-                context.local_var_scope.pop();
-                // return to caller
-                code.extend([RET]);
+    /// Compile the program into KVM bytecode
+    pub fn code_gen(&self, context: &mut CompilerContext) -> Vec<u32> {
+        self.to_ir(context).link()
+    }
+}
 
-                return code;
-            }
-            Some(_) => panic!("duplicate function definition for {}().", self.id),
+impl FunctionDefinition {
+    pub fn to_ir(&self, context: &mut CompilerContext) -> ir::Function {
+        let mut function = FunctionBuilder::new(&self.id, self.args.len());
+        // the arguments are the first variables of the function's outermost block
+        context.frame_size = 0;
+        context.array_lens.1.clear();
+        context.push_scope();
+        for arg in &self.args {
+            context.declare_local(arg, 1);
+        }
+        // Recursively Generate Code
+        for stmt in &self.body {
+            stmt.code_gen(context, &mut function);
         }
+        // Pop scope since we are leaving function
+        context.pop_scope();
+
+        function.finish(context.frame_size)
     }
 }
 
 impl CodeGen for FunctionCall {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32> {
-        let mut code = Vec::new();
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder) {
+        // the program's own functions shadow builtins and natives of the same name
+        let defined = context.fn_table.contains_key(&self.id);
+        let builtin = !defined && builtin_argc(&self.id).is_some();
+        // the length of an array is known from its declaration
+        if builtin && self.id == "len" {
+            let len = context.array_len(&self.args[0]).expect(CHECKED);
+            function.emit(Inst::Push(len as i32));
+            return;
+        }
         // load every expression onto stack
         for arg in self.args.iter().rev() {
-            code.extend(arg.code_gen(context, start_addr + code.len()));
+            arg.code_gen(context, function);
+        }
+        // builtins are instructions of their own rather than calls
+        if builtin {
+            function.emit(match ReadType::builtin(&self.id) {
+                Some(read_type) => Inst::Read(read_type),
+                None => Inst::Rand,
+            });
+            return;
+        }
+        if let Some(index) = context.find_native(&self.id).filter(|_| !defined) {
+            function.emit(Inst::CallNative {
+                index,
+                argc: self.args.len(),
+            });
+            return;
         }
-        // Search function table for address
-        let fn_addr = match context.fn_table.get(&self.id) {
-            Some(addr) => *addr as u32,
-            None => panic!("No function found to jump to"),
-        };
         // Tell the Call inst how many args are in the frame
-        code.extend([CALL, self.args.len() as u32, fn_addr]);
-
-        return code;
+        function.emit(Inst::Call {
+            id: self.id.clone(),
+            argc: self.args.len(),
+        });
     }
 }
 
 impl CodeGen for Statement {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32> {
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder) {
         match self {
-            Self::Print { expr, newline } => match expr {
-                Some(e) => {
-                    let mut code = e.code_gen(context, start_addr);
-                    code.extend([PRINT, 1]);
-                    code
+            Self::Print { expr, newline } => {
+                // Append the codegen for non-empty print statements
+                if let Some(e) = expr {
+                    e.code_gen(context, function);
+                    function.emit(Inst::Print(PrintType::Int));
                 }
-                None => vec![],
+                // Conditionally append the newline printing steps
+                if *newline {
+                    function.emit(Inst::Push('\n' as i32));
+                    function.emit(Inst::Print(PrintType::Char));
+                }
+            }
+            Self::Let { id, expr, mutable } => {
+                // the value is generated first, so it can read a variable the new one shadows
+                expr.code_gen(context, function);
+                let offset = context.declare_local(id, 1);
+                if !*mutable {
+                    context.immutable_locals.insert(offset);
+                }
+                function.emit(Inst::LocalStore(offset));
             }
-            // Append the codegen for non-empty print statements
-            .into_iter()
-            // Conditionally append the newline printing steps
-            .chain(match *newline {
-                true => vec![PUSH, 10, PRINT, 2],
-                false => vec![],
-            })
-            .collect(),
             Self::VarAssignment { id, expr, global } => {
                 // generate value to be stored
-                let mut code = expr.code_gen(context, start_addr);
+                expr.code_gen(context, function);
 
                 // find the offset of the variable in either the global or local set
-                let offset = match context.find_var_index(id) {
-                    Ok(pair) => pair.1,
-                    Err(_) => {
-                        if *global {
-                            // global variables are accounted for in the AST prescan, so we shouldnt see this fail.
-                            panic!("failed to find global variable by id: {}", id);
-                        } else {
-                            // add the local variable to the frame if we are seeing it for the first time
-                            let scope = context.local_var_scope.last_mut().unwrap();
-                            scope.0.insert(id.clone(), scope.1);
-                            scope.1 += 1;
-                            scope.1 - 1
-                        }
-                    }
+                let (scope_type, offset) = if *global {
+                    let offset = context.find_global_var_index(id).expect(CHECKED);
+                    (ScopeType::Global, offset)
+                } else {
+                    context.find_var_index(id).expect(CHECKED)
                 };
                 // append the appropriate Store procedure for global or local variables, with the given offset
-                code.extend([
-                    match global {
-                        true => GLOBAL_STORE,
-                        false => LOCAL_STORE,
-                    },
-                    offset as u32,
-                ]);
-
-                return code;
+                function.emit(match scope_type {
+                    ScopeType::Global => Inst::GlobalStore(offset),
+                    ScopeType::Local => Inst::LocalStore(offset),
+                });
             }
             Self::ArrayIndexAssignment { id, index, expr } => {
                 // generate value to be stored
-                let mut code = expr.code_gen(context, start_addr);
+                expr.code_gen(context, function);
                 // generate the value of the array subscript index
-                code.extend(index.code_gen(context, start_addr));
+                index.code_gen(context, function);
                 // fetch the array index
-                // since we cannot arbitrarily define new array values, this failure should not be accepted.
-                let (scope_type, offset) = match context.find_var_index(id) {
-                    Ok(pair) => pair,
-                    Err(_) => panic!("array index failure"),
-                };
+                let (scope_type, offset) = context.find_var_index(id).expect(CHECKED);
 
                 // push the offset onto the stack in order to read it in the ARRAY_STORE procedures
-                code.extend([PUSH, offset as u32]);
-                code.push(match scope_type {
-                    ScopeType::Global => GLOBAL_ARR_STORE,
-                    ScopeType::Local => LOCAL_ARR_STORE,
+                function.emit(match scope_type {
+                    ScopeType::Global => Inst::GlobalAddr(offset),
+                    ScopeType::Local => Inst::Push(offset as i32),
+                });
+                function.emit(match scope_type {
+                    ScopeType::Global => Inst::GlobalArrStore,
+                    ScopeType::Local => Inst::LocalArrStore,
                 });
-
-                return code;
             }
             Self::ArrayInstantiation {
                 id,
@@ -237,239 +373,573 @@ impl CodeGen for Statement {
                 elements,
                 global,
             } => {
-                let mut code = Vec::new();
-
-                // read the size to loop over it
-                if let Some(Expr::IntLit(array_size)) = size {
-                    // check that the size is equal to the element length !
-                    if let Some(elements_vec) = elements {
-                        if elements_vec.len() as u32 != *array_size {
-                            panic!("cannot have specified array size different from array literal.")
-                        }
-                    }
+                // read the size to loop over it, which may be computed from constants
+                if let Some(array_size) = const_size(size, &context.consts) {
                     // fetch the starting variable
-                    let offset = match context.find_var_index(id) {
-                        Ok(pair) => pair.1,
-                        Err(_) => {
-                            if *global {
-                                // cannot happen since we do an AST prescan for globals
-                                panic!("could not find global index for array id: {}", id);
-                            } else {
-                                // add the local variable to the frame if we are seeing it for the first time
-                                let scope = context.local_var_scope.last_mut().unwrap();
-                                scope.0.insert(id.clone(), scope.1);
-                                // this involves moving the memory boundary along by the size of the array
-                                scope.1 += *array_size as usize;
-                                scope.1 - *array_size as usize
-                            }
-                        }
+                    let offset = if *global {
+                        context.find_global_var_index(id).expect(CHECKED)
+                    } else {
+                        // a local array is declared in the current block like `let`,
+                        // which involves moving the memory boundary along by the size of the array
+                        let offset = context.declare_local(id, array_size as usize);
+                        context.array_lens.1.insert(offset, array_size as usize);
+                        offset
                     };
 
                     // loop over size
-                    for index in 0..*array_size {
+                    for index in 0..array_size as usize {
                         // if the values were specified, then go ahead and load them,
                         // otherwise default them to 0
-                        code.extend(if let Some(elements_vec) = elements {
-                            elements_vec[index as usize].code_gen(context, start_addr)
-                        } else {
-                            vec![PUSH, 0]
-                        });
+                        match elements {
+                            Some(elements_vec) => elements_vec[index].code_gen(context, function),
+                            None => function.emit(Inst::Push(0)),
+                        }
                         // load the LOCAL or GLOBAL store procedure with its correcponding aray index
-                        code.extend([
-                            match *global {
-                                true => GLOBAL_STORE,
-                                false => LOCAL_STORE,
-                            },
-                            index + offset as u32,
-                        ]);
+                        function.emit(match *global {
+                            true => Inst::GlobalStore(index + offset),
+                            false => Inst::LocalStore(index + offset),
+                        });
                     }
                 }
-
-                return code;
             }
-            Self::FunctionCall(func_call) => func_call
-                .code_gen(context, start_addr)
-                .into_iter()
-                .chain([POP])
-                .collect(),
-            Self::If(if_data) => if_data.code_gen(context, start_addr),
-            Self::Return => vec![PUSH, 0, RET],
-            Self::ReturnExpr(expr) => expr
-                .code_gen(context, start_addr)
-                .into_iter()
-                .chain([RET])
-                .collect(),
-            Self::While { cond, stmts } => {
-                // generate code for the comparison Expression
-                let mut code = cond.code_gen(context, start_addr);
-                // const offsets for jumps around the code,
-                // since they arent accounted for in the statement block.
-                const BRANCH_CODE_OFFSET: usize = 2;
-                const JUMP_CODE_OFFSET: usize = 2;
-                // helper
-                let calc_offset = |base_code: &Vec<_>, stmt_code: &Vec<_>| {
-                    start_addr + base_code.len() + stmt_code.len()
-                };
-                // generate code for the statements and update the start address
-                let mut code_to_execute = Vec::new();
-                for stmt in stmts {
-                    code_to_execute.extend(stmt.code_gen(
-                        context,
-                        BRANCH_CODE_OFFSET + calc_offset(&code, &code_to_execute),
-                    ));
+            Self::FunctionCall(func_call) => {
+                func_call.code_gen(context, function);
+                // discard the return value
+                function.emit(Inst::Pop);
+            }
+            Self::If(if_data) => if_data.code_gen(context, function),
+            Self::IfElse(if_else) => if_else.code_gen(context, function),
+            Self::Return => {
+                function.emit(Inst::Push(0));
+                function.terminate_unreachable(Terminator::Ret);
+            }
+            Self::ReturnExpr(expr) => {
+                expr.code_gen(context, function);
+                function.terminate_unreachable(Terminator::Ret);
+            }
+            Self::While { cond, stmts, label } => {
+                while_code_gen(context, function, label, cond, None, stmts, None)
+            }
+            Self::For(for_data) => {
+                let for_loop = for_data.desugar(&context.consts, |array| {
+                    context.array_len(array).expect(CHECKED) as u32
+                });
+                // the counter and bounds are only visible to the loop
+                context.push_scope();
+                for stmt in &for_loop.init {
+                    stmt.code_gen(context, function);
                 }
-                // prefix the statements with the branch
-                let branch_code: [u32; BRANCH_CODE_OFFSET] = [
-                    BEQZ,
-                    1 + JUMP_CODE_OFFSET as u32 + calc_offset(&code, &code_to_execute) as u32,
-                ];
-                // jump back to the start of the while for the last step
-                let jump_code: [u32; JUMP_CODE_OFFSET] = [JUMP, (start_addr - 1) as u32];
-
-                // assemble the full code block
-                code.extend(branch_code);
-                code.extend(code_to_execute);
-                code.extend(jump_code);
-
-                return code;
+                while_code_gen(
+                    context,
+                    function,
+                    &for_data.label,
+                    &for_loop.cond,
+                    Some(&for_loop.bind),
+                    &for_data.stmts,
+                    Some(&for_loop.step),
+                );
+                context.pop_scope();
+            }
+            Self::Break(label) => {
+                let target = context.find_loop(label, "break").break_label;
+                function.terminate_unreachable(Terminator::Jump(target));
+            }
+            Self::Continue(label) => {
+                let target = context.find_loop(label, "continue").continue_label;
+                function.terminate_unreachable(Terminator::Jump(target));
+            }
+            Self::When(_) => { /* no-op */ }
+        }
+    }
+}
+
+/// Lower a loop which checks its condition before every iteration. The body runs in a block
+/// of its own, which starts by declaring `bind`, and is followed by `step`
+fn while_code_gen(
+    context: &mut CompilerContext,
+    function: &mut FunctionBuilder,
+    label: &Option<String>,
+    cond: &Expr,
+    bind: Option<&Statement>,
+    stmts: &[Statement],
+    step: Option<&Statement>,
+) {
+    let cond_label = function.new_label();
+    let body_label = function.new_label();
+    let end_label = function.new_label();
+    // `continue` skips to the step, so that it still runs
+    let step_label = match step {
+        Some(_) => function.new_label(),
+        None => cond_label,
+    };
+    // the condition is re-evaluated at the start of every iteration
+    function.terminate(Terminator::Jump(cond_label), cond_label);
+    cond.code_gen(context, function);
+    function.terminate(
+        Terminator::Branch {
+            nonzero: body_label,
+            zero: end_label,
+        },
+        body_label,
+    );
+    context.loops.push(LoopContext {
+        label: label.clone(),
+        break_label: end_label,
+        continue_label: step_label,
+    });
+    context.push_scope();
+    for stmt in bind.into_iter().chain(stmts) {
+        stmt.code_gen(context, function);
+    }
+    context.pop_scope();
+    context.loops.pop();
+    if let Some(step) = step {
+        function.terminate(Terminator::Jump(step_label), step_label);
+        step.code_gen(context, function);
+    }
+    // jump back to the condition for the next iteration
+    function.terminate(Terminator::Jump(cond_label), end_label);
+}
+
+/// Hidden variables of a for loop, whose `.` keeps them apart from Koala identifiers
+const FOR_COUNTER: &str = "for.counter";
+const FOR_END: &str = "for.end";
+const FOR_STEP: &str = "for.step";
+
+/// A for loop in terms of the while loop it is lowered to
+pub struct ForLoop {
+    /// Declarations of the counter, and of the bound and step when they aren't constant
+    pub init: Vec<Statement>,
+    pub cond: Expr,
+    /// Declaration of the loop variable at the start of the body
+    pub bind: Statement,
+    /// Advances the counter after the body
+    pub step: Statement,
+}
+
+impl For {
+    /// Rewrite the loop over a counter, which counts down when the step is negative. A step
+    /// that isn't constant is checked for its sign as the loop runs, so a step of 0 ends it.
+    /// The length of an array the loop walks is looked up with `array_len`, rather than
+    /// through a call to `len()` the program may have shadowed
+    pub fn desugar(
+        &self,
+        consts: &HashMap<String, i32>,
+        array_len: impl FnOnce(&Expr) -> u32,
+    ) -> ForLoop {
+        let var = |id: &str| Expr::Variable { id: id.to_string() };
+        let (start, end, inclusive, step) = match &self.iter {
+            ForIter::Range {
+                start,
+                end,
+                inclusive,
+                step,
+            } => (start.clone(), end, *inclusive, step.as_ref()),
+            ForIter::Array(id) => {
+                let len = Expr::IntLit(array_len(&var(id)));
+                let element = Expr::ArrayIndex {
+                    id: id.clone(),
+                    expr: Box::new(var(FOR_COUNTER)),
+                };
+                return ForLoop {
+                    init: vec![Statement::Let {
+                        id: FOR_COUNTER.to_string(),
+                        expr: Expr::IntLit(0),
+                        mutable: true,
+                    }],
+                    cond: bin_expr(var(FOR_COUNTER), BinOp::Less, len),
+                    bind: Statement::Let {
+                        id: self.id.clone(),
+                        expr: element,
+                        mutable: false,
+                    },
+                    step: advance(Expr::IntLit(1)),
+                };
             }
-            _ => Vec::new(),
+        };
+
+        let mut init = vec![Statement::Let {
+            id: FOR_COUNTER.to_string(),
+            expr: start,
+            mutable: true,
+        }];
+        // bounds and steps which aren't constant are evaluated once, in order
+        let mut once = |id: &str, expr: &Expr| match const_eval(expr, consts) {
+            Some(val) => Expr::IntLit(val as u32),
+            None => {
+                init.push(Statement::Let {
+                    id: id.to_string(),
+                    expr: expr.clone(),
+                    mutable: false,
+                });
+                var(id)
+            }
+        };
+        let end = once(FOR_END, end);
+        let (up, down) = if inclusive {
+            (BinOp::LessOrEqual, BinOp::GreaterOrEqual)
+        } else {
+            (BinOp::Less, BinOp::Greater)
+        };
+        let (step, cond) = match step {
+            Some(step) => match const_eval(step, consts) {
+                Some(0) => panic!("the step of a for loop cannot be 0."),
+                Some(val) => {
+                    let binop = if val < 0 { down } else { up };
+                    (
+                        Expr::IntLit(val as u32),
+                        bin_expr(var(FOR_COUNTER), binop, end),
+                    )
+                }
+                None => {
+                    let step = once(FOR_STEP, step);
+                    // (step > 0 && counter < end) || (step < 0 && counter > end)
+                    let ascending = bin_expr(
+                        bin_expr(step.clone(), BinOp::Greater, Expr::IntLit(0)),
+                        BinOp::And,
+                        bin_expr(var(FOR_COUNTER), up, end.clone()),
+                    );
+                    let descending = bin_expr(
+                        bin_expr(step.clone(), BinOp::Less, Expr::IntLit(0)),
+                        BinOp::And,
+                        bin_expr(var(FOR_COUNTER), down, end),
+                    );
+                    (step, bin_expr(ascending, BinOp::Or, descending))
+                }
+            },
+            None => (Expr::IntLit(1), bin_expr(var(FOR_COUNTER), up, end)),
+        };
+
+        ForLoop {
+            init,
+            cond,
+            bind: Statement::Let {
+                id: self.id.clone(),
+                expr: var(FOR_COUNTER),
+                mutable: false,
+            },
+            step: advance(step),
         }
     }
 }
 
+fn bin_expr(op1: Expr, binop: BinOp, op2: Expr) -> Expr {
+    Expr::BinExpr(Box::new(BinExpr { binop, op1, op2 }))
+}
+
+/// Add the step to the counter of a for loop
+fn advance(step: Expr) -> Statement {
+    let counter = Expr::Variable {
+        id: FOR_COUNTER.to_string(),
+    };
+    Statement::VarAssignment {
+        id: FOR_COUNTER.to_string(),
+        expr: bin_expr(counter, BinOp::Plus, step),
+        global: false,
+    }
+}
+
 impl CodeGen for If {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32> {
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder) {
+        let body_label = function.new_label();
+        let end_label = function.new_label();
         // generate code for the comparison Expression
-        let mut code = self.expr.code_gen(context, start_addr);
-        // const offset
-        const BRANCH_CODE_OFFSET: usize = 2;
-        // helper
-        let calc_offset =
-            |base_code: &Vec<_>, stmt_code: &Vec<_>| start_addr + base_code.len() + stmt_code.len();
-        // generate code for the statements and update the start address
-        let mut code_to_execute = Vec::new();
+        self.expr.code_gen(context, function);
+        function.terminate(
+            Terminator::Branch {
+                nonzero: body_label,
+                zero: end_label,
+            },
+            body_label,
+        );
+        context.push_scope();
         for stmt in &self.stmts {
-            code_to_execute.extend(stmt.code_gen(
-                context,
-                BRANCH_CODE_OFFSET + calc_offset(&code, &code_to_execute),
-            ));
+            stmt.code_gen(context, function);
         }
-        // prefix the statements with the branch
-        let branch_code: [u32; BRANCH_CODE_OFFSET] =
-            [BEQZ, 1 + calc_offset(&code, &code_to_execute) as u32];
-
-        code.extend(branch_code);
-        code.extend(code_to_execute);
+        context.pop_scope();
+        function.terminate(Terminator::Jump(end_label), end_label);
+    }
+}
 
-        return code;
+impl CodeGen for IfElse {
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder) {
+        let body_label = function.new_label();
+        let else_label = function.new_label();
+        let end_label = function.new_label();
+        // generate code for the comparison Expression
+        self.expr.code_gen(context, function);
+        function.terminate(
+            Terminator::Branch {
+                nonzero: body_label,
+                zero: else_label,
+            },
+            body_label,
+        );
+        context.push_scope();
+        for stmt in &self.stmts {
+            stmt.code_gen(context, function);
+        }
+        context.pop_scope();
+        function.terminate(Terminator::Jump(end_label), else_label);
+        context.push_scope();
+        for stmt in &self.else_stmts {
+            stmt.code_gen(context, function);
+        }
+        context.pop_scope();
+        function.terminate(Terminator::Jump(end_label), end_label);
     }
 }
 
 impl CodeGen for Expr {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32> {
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder) {
         match self {
-            Self::IntLit(int) => vec![PUSH, *int],
-            Self::BoolLit(truthy) => vec![PUSH, *truthy as u32],
-            Self::StringLit(string) => string // TODO
-                .chars()
-                .into_iter()
-                .map(|a| a.to_digit(10))
-                .take_while(|a| a.is_some())
-                .map(|a| a.unwrap())
-                .collect(),
+            // the VM reinterprets immediates as signed values
+            Self::IntLit(int) => function.emit(Inst::Push(*int as i32)),
+            Self::BoolLit(truthy) => function.emit(Inst::Push(*truthy as i32)),
+            Self::StringLit(_) => unreachable!("{}", CHECKED),
             Self::ArrayIndex { id, expr } => {
                 // find scope type and index of array pointer by id
-                let (scope_type, index) = match context.find_var_index(id) {
-                    Ok(pair) => pair,
-                    Err(_) => panic!("cound not find array index to load for id: {}.", id),
-                };
+                let (scope_type, index) = context.find_var_index(id).expect(CHECKED);
                 // push index onto stack and then load subscript index
-                let mut code = vec![PUSH, index as u32];
-                code.extend(expr.code_gen(context, start_addr));
+                function.emit(match scope_type {
+                    ScopeType::Global => Inst::GlobalAddr(index),
+                    ScopeType::Local => Inst::Push(index as i32),
+                });
+                expr.code_gen(context, function);
                 // call to array load procedure
-                code.push(match scope_type {
-                    ScopeType::Local => LOCAL_ARR_LOAD,
-                    ScopeType::Global => GLOBAL_ARR_LOAD,
+                function.emit(match scope_type {
+                    ScopeType::Local => Inst::LocalArrLoad,
+                    ScopeType::Global => Inst::GlobalArrLoad,
                 });
-
-                return code;
+            }
+            // constants are inlined rather than stored
+            Self::Variable { id } if context.consts.contains_key(id) => {
+                function.emit(Inst::Push(context.consts[id]))
             }
             Self::Variable { id } => {
                 // fetch scope type and index of variable by id
-                let (scope_type, index) = match context.find_var_index(id) {
-                    Ok(pair) => pair,
-                    Err(_) => panic!("could not find variable '{}'", id),
-                };
-                // return instructions to load the given index onto the stack
-                vec![
-                    match scope_type {
-                        ScopeType::Global => GLOBAL_LOAD,
-                        ScopeType::Local => LOCAL_LOAD,
-                    },
-                    index as u32,
-                ]
+                let (scope_type, index) = context.find_var_index(id).expect(CHECKED);
+                // load the given index onto the stack
+                function.emit(match scope_type {
+                    ScopeType::Global => Inst::GlobalLoad(index),
+                    ScopeType::Local => Inst::LocalLoad(index),
+                });
             }
-            Self::FunctionCall(func_call) => func_call.code_gen(context, start_addr),
-            Self::BinExpr(bin_expr) => bin_expr.code_gen(context, start_addr),
+            Self::FunctionCall(func_call) => func_call.code_gen(context, function),
+            Self::BinExpr(bin_expr) => bin_expr.code_gen(context, function),
         }
     }
 }
 
 impl CodeGen for BinExpr {
-    fn code_gen(&self, context: &mut CompilerContext, start_addr: usize) -> Vec<u32> {
-        let mut code = Vec::new();
-        // generate both operands
-        code.extend(self.op2.code_gen(context, start_addr + 1));
-        code.extend(self.op1.code_gen(context, start_addr + code.len() + 1));
+    fn code_gen(&self, context: &mut CompilerContext, function: &mut FunctionBuilder) {
+        // generate both operands, leaving the left hand side on top
+        self.op2.code_gen(context, function);
+        self.op1.code_gen(context, function);
         // push operator code
-        match self.binop {
-            BinOp::Plus => code.push(IADD),
-            BinOp::Minus => code.push(ISUB),
-            BinOp::Mul => code.push(IMUL),
-            BinOp::Div => code.push(IDIV),
-            BinOp::Less => code.push(LT),
-            BinOp::LessOrEqual => code.push(LTE),
-            BinOp::Greater => code.push(GT),
-            BinOp::GreaterOrEqual => code.push(GTE),
-            BinOp::Equal => code.push(EQ),
-            BinOp::NotEqual => code.push(NEQ),
-            BinOp::Or => code.push(OR),
-            BinOp::And => code.push(AND),
-        };
+        function.emit(Inst::BinOp(self.binop));
+    }
+}
+
+/// Evaluate an expression of literals and constants at compile time
+pub fn const_eval(expr: &Expr, consts: &HashMap<String, i32>) -> Option<i32> {
+    match expr {
+        // the VM reinterprets immediates as signed values
+        Expr::IntLit(int) => Some(*int as i32),
+        Expr::BoolLit(truthy) => Some(*truthy as i32),
+        Expr::Variable { id } => consts.get(id).copied(),
+        Expr::BinExpr(bin_expr) => fold(
+            &bin_expr.binop,
+            const_eval(&bin_expr.op1, consts)?,
+            const_eval(&bin_expr.op2, consts)?,
+        ),
+        _ => None,
+    }
+}
 
-        return code;
+/// Size of an array, when it is known at compile time
+pub fn const_size(size: &Option<Expr>, consts: &HashMap<String, i32>) -> Option<u32> {
+    match array_size(size, consts) {
+        Ok(size) => size,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Size of an array, when it is known at compile time, which can't be negative
+pub fn array_size(
+    size: &Option<Expr>,
+    consts: &HashMap<String, i32>,
+) -> Result<Option<u32>, String> {
+    match size.as_ref().and_then(|size| const_eval(size, consts)) {
+        Some(size) if size < 0 => Err("array sizes cannot be negative.".to_string()),
+        size => Ok(size.map(|size| size as u32)),
     }
 }
 
 impl Program {
-    fn create_global_var_table(&self) -> (HashMap<String, usize>, usize) {
+    /// Evaluate the constants in the order they are declared
+    pub fn const_values(&self) -> HashMap<String, i32> {
+        match self.eval_consts() {
+            Ok(consts) => consts,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Evaluate the constants in the order they are declared, or report the first
+    /// which isn't constant or is declared twice
+    pub fn eval_consts(&self) -> Result<HashMap<String, i32>, String> {
+        let mut consts = HashMap::new();
+        for constant in &self.1 {
+            let val = match const_eval(&constant.expr, &consts) {
+                Some(val) => val,
+                None => {
+                    return Err(format!(
+                        "the value of constant '{}' is not constant.",
+                        constant.id
+                    ))
+                }
+            };
+            if consts.insert(constant.id.clone(), val).is_some() {
+                return Err(format!(
+                    "duplicate constant definition for '{}'.",
+                    constant.id
+                ));
+            }
+        }
+
+        Ok(consts)
+    }
+
+    fn create_global_var_table(
+        &self,
+        consts: &HashMap<String, i32>,
+    ) -> (HashMap<String, usize>, usize) {
         let mut table: HashMap<String, usize> = HashMap::new();
         let mut index: usize = 0;
         for def in &self.0 {
             for stmt in &def.body {
-                match stmt {
+                let (id, incr) = match stmt {
                     Statement::ArrayInstantiation {
-                        id, size, global, ..
-                    } => {
-                        if *global {
-                            if let Some(Expr::IntLit(incr)) = size {
-                                table.insert(id.to_string(), index as usize);
-                                index += *incr as usize;
-                            }
-                        }
-                    }
-                    Statement::VarAssignment { id, global, .. } => {
-                        if *global {
-                            table.insert(id.to_string(), index);
-                            index += 1;
-                        }
+                        id,
+                        size,
+                        global: true,
+                        ..
+                    } => match const_size(size, consts) {
+                        Some(incr) => (id, incr as usize),
+                        None => continue,
+                    },
+                    Statement::VarAssignment {
+                        id, global: true, ..
+                    } => (id, 1),
+                    _ => continue,
+                };
+                table.insert(id.to_string(), index);
+                index += incr;
+            }
+        }
+
+        (table, index)
+    }
+
+    fn global_array_lens(&self, consts: &HashMap<String, i32>) -> HashMap<String, usize> {
+        let mut lens = HashMap::new();
+        for def in &self.0 {
+            for stmt in &def.body {
+                if let Statement::ArrayInstantiation {
+                    id,
+                    size,
+                    global: true,
+                    ..
+                } = stmt
+                {
+                    if let Some(size) = const_size(size, consts) {
+                        lens.insert(id.clone(), size as usize);
                     }
-                    _ => { /* no-op */ }
                 }
             }
         }
 
-        return (table, index);
+        lens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_code;
+
+    fn compile(code: &str) -> ir::Module {
+        parse_code(code).unwrap().to_ir(&mut CompilerContext::new())
+    }
+
+    #[test]
+    fn frame_reuse_test() {
+        let module = compile(
+            "
+            fn main() {
+              let mut a = 1
+              if a == 1 {
+                let b[3] = [1,2,3]
+                print(b[2])
+              }
+              if a == 2 {
+                let c = 2
+                let a = c
+              }
+              while a < 3 {
+                let d = a
+                a = d + 1
+              }
+            }
+            ",
+        );
+        // the blocks each start after `a`, so the largest of them decides the frame size
+        assert_eq!(module.functions[0].frame_size, 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot assign to undeclared variable 'b'")]
+    fn undeclared_assignment_test() {
+        compile("fn main() { if 1 { let b = 1 } b = 2 }");
+    }
+
+    #[test]
+    fn const_inline_test() {
+        let module = compile("const N = 4 const M = N * 2 fn main() { let a[M] print(N) }");
+        assert_eq!(module.globals_size, 0);
+        assert_eq!(module.functions[0].frame_size, 8);
+        let insts = &module.functions[0].blocks[0].insts;
+        assert!(insts.contains(&Inst::Push(4)));
+        assert!(!insts.iter().any(|inst| matches!(inst, Inst::GlobalLoad(_))));
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot assign twice to immutable variable 'x'")]
+    fn immutable_assignment_test() {
+        compile("fn main() { let x = 1 var y = 2 y = 3 x = 4 }");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot assign twice to immutable variable 'i'")]
+    fn for_variable_test() {
+        compile("fn main() { for i in 0..3 { i = i + 1 } }");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot assign to the constant 'N'")]
+    fn const_assignment_test() {
+        compile("const N = 1 fn main() { N = 2 }");
+    }
+
+    #[test]
+    #[should_panic(expected = "`break` cannot be used outside of a loop")]
+    fn break_outside_loop_test() {
+        compile("fn main() { while 0 {} if 1 { break } }");
+    }
+
+    #[test]
+    #[should_panic(expected = "`continue 'outer` is not inside a loop labeled 'outer")]
+    fn unknown_loop_label_test() {
+        compile("fn main() { 'inner: while 1 { continue 'outer } }");
     }
 }
@@ -0,0 +1,245 @@
+//! Embedding Koala as a scripting language: compile source into a module,
+//! then call its functions and read or write its globals by name from Rust
+
+use crate::compiler::CompilerContext;
+use crate::kvm::{self, VirtualMachine};
+use crate::parser::parse_code;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A compiled program, with its functions and globals named so a host can use them.
+///
+/// The module only holds code, and every call runs on a `VirtualMachine` the host
+/// configured, which keeps the globals between calls
+pub struct Module {
+    program: kvm::Program,
+    /// Address and argument count of each function
+    functions: HashMap<String, (usize, usize)>,
+    /// Offset of each global, and its length when it is an array
+    globals: HashMap<String, (usize, Option<usize>)>,
+}
+
+/// Reasons a host's use of a module can fail
+#[derive(Debug, PartialEq)]
+pub enum EmbedError {
+    Parse(String),
+    UnknownFunction(String),
+    Arity {
+        name: String,
+        expected: usize,
+        given: usize,
+    },
+    UnknownGlobal(String),
+    ArrayGlobal(String),
+    /// The program called `exit()` with a code before the function returned
+    Exit(i32),
+}
+
+impl fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmbedError::Parse(e) => write!(f, "could not parse the program: {}", e),
+            EmbedError::UnknownFunction(name) => write!(f, "no function named {}()", name),
+            EmbedError::Arity {
+                name,
+                expected,
+                given,
+            } => write!(
+                f,
+                "{}() takes {} arguments, but was given {}",
+                name, expected, given
+            ),
+            EmbedError::UnknownGlobal(name) => write!(f, "no global named '{}'", name),
+            EmbedError::ArrayGlobal(name) => write!(f, "'{}' is an array, not a number", name),
+            EmbedError::Exit(code) => write!(f, "the program exited with code {}", code),
+        }
+    }
+}
+
+impl Module {
+    /// Compile source with the standard natives, panicking if it doesn't compile
+    pub fn compile(source: &str) -> Result<Self, EmbedError> {
+        Self::compile_with(source, &mut CompilerContext::new())
+    }
+
+    /// Compile source with a context, such as one which natives were registered with
+    pub fn compile_with(source: &str, context: &mut CompilerContext) -> Result<Self, EmbedError> {
+        let program = parse_code(source).map_err(|e| EmbedError::Parse(e.to_string()))?;
+        let module = program.to_ir(context);
+        let (code, symbols) = module.link_with_symbols();
+
+        let arities: HashMap<&str, usize> = module
+            .functions
+            .iter()
+            .map(|function| (function.id.as_str(), function.arity))
+            .collect();
+        let functions = symbols
+            .iter()
+            .map(|(addr, name)| (name.to_string(), (addr, arities[name])))
+            .collect();
+
+        let (global_lens, _) = &context.array_lens;
+        let globals = context
+            .global_vars
+            .0
+            .iter()
+            .map(|(name, offset)| (name.clone(), (*offset, global_lens.get(name).copied())))
+            .collect();
+
+        let program = match kvm::load(&code) {
+            Ok(program) => program,
+            Err(e) => panic!("{}", e),
+        };
+        Ok(Module {
+            program,
+            functions,
+            globals,
+        })
+    }
+
+    /// Whether the module defines a function
+    pub fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// Call a function by name on the VM, returning what it returned
+    pub fn call(
+        &self,
+        vm: &mut VirtualMachine,
+        name: &str,
+        args: &[i32],
+    ) -> Result<i32, EmbedError> {
+        let (addr, arity) = match self.functions.get(name) {
+            Some(function) => *function,
+            None => return Err(EmbedError::UnknownFunction(name.to_string())),
+        };
+        if args.len() != arity {
+            return Err(EmbedError::Arity {
+                name: name.to_string(),
+                expected: arity,
+                given: args.len(),
+            });
+        }
+        match vm.call_function(&self.program, addr, args) {
+            Some(val) => Ok(val),
+            None => Err(EmbedError::Exit(vm.exit_code())),
+        }
+    }
+
+    /// Value of a global on the VM, which is 0 until it is assigned
+    pub fn global(&self, vm: &VirtualMachine, name: &str) -> Result<i32, EmbedError> {
+        Ok(vm.global(self.global_offset(name)?))
+    }
+
+    /// Assign a global on the VM, as if the program had
+    pub fn set_global(
+        &self,
+        vm: &mut VirtualMachine,
+        name: &str,
+        val: i32,
+    ) -> Result<(), EmbedError> {
+        vm.store_global(self.global_offset(name)?, val);
+        Ok(())
+    }
+
+    fn global_offset(&self, name: &str) -> Result<usize, EmbedError> {
+        match self.globals.get(name) {
+            Some((offset, None)) => Ok(*offset),
+            Some((_, Some(_))) => Err(EmbedError::ArrayGlobal(name.to_string())),
+            None => Err(EmbedError::UnknownGlobal(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    const SCRIPT: &str = "
+    fn main() {
+      global score = 0
+      global board[3] = [0,0,0]
+    }
+
+    fn gcd(a, b) {
+      if b == 0 {
+        return a
+      }
+      return gcd(b, a - (a / b) * b)
+    }
+
+    fn add_score(points) {
+      global score = score + points
+      print(score)
+      return score
+    }
+
+    fn give_up() {
+      exit(2)
+    }
+    ";
+
+    #[test]
+    fn call_test() {
+        let module = Module::compile(SCRIPT).unwrap();
+        let output = RefCell::new(String::new());
+        let print = |msg: &str| *output.borrow_mut() += msg;
+        let mut vm = VirtualMachine::new(&print);
+
+        assert_eq!(module.call(&mut vm, "gcd", &[12, 18]), Ok(6));
+        assert_eq!(module.call(&mut vm, "main", &[]), Ok(0));
+        assert_eq!(module.call(&mut vm, "add_score", &[5]), Ok(5));
+        assert_eq!(module.call(&mut vm, "add_score", &[2]), Ok(7));
+        assert_eq!(
+            module.call(&mut vm, "give_up", &[]),
+            Err(EmbedError::Exit(2))
+        );
+        // the VM can still be called after the program exits
+        assert_eq!(module.call(&mut vm, "gcd", &[7, 21]), Ok(7));
+        drop(vm);
+        assert_eq!(output.take(), "57");
+    }
+
+    #[test]
+    fn global_test() {
+        let module = Module::compile(SCRIPT).unwrap();
+        let mut vm = VirtualMachine::new(&|_| {});
+
+        assert_eq!(module.global(&vm, "score"), Ok(0));
+        module.set_global(&mut vm, "score", 40).unwrap();
+        assert_eq!(module.call(&mut vm, "add_score", &[2]), Ok(42));
+        assert_eq!(module.global(&vm, "score"), Ok(42));
+        assert_eq!(
+            module.global(&vm, "board"),
+            Err(EmbedError::ArrayGlobal("board".to_string()))
+        );
+    }
+
+    #[test]
+    fn errors_test() {
+        let module = Module::compile(SCRIPT).unwrap();
+        let mut vm = VirtualMachine::new(&|_| {});
+
+        assert_eq!(
+            module.call(&mut vm, "lcm", &[1, 2]),
+            Err(EmbedError::UnknownFunction("lcm".to_string()))
+        );
+        assert_eq!(
+            module.call(&mut vm, "gcd", &[1]),
+            Err(EmbedError::Arity {
+                name: "gcd".to_string(),
+                expected: 2,
+                given: 1
+            })
+        );
+        assert_eq!(
+            module.set_global(&mut vm, "lives", 3),
+            Err(EmbedError::UnknownGlobal("lives".to_string()))
+        );
+        assert!(matches!(
+            Module::compile("fn main( {"),
+            Err(EmbedError::Parse(_))
+        ));
+    }
+}
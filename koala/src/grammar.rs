@@ -2,11 +2,47 @@ use serde::{Deserialize, Serialize};
 
 /// Program:
 /// | FunctionDefinition Program
+/// | Const Program
 /// | ε
 ///
 /// the main() function will be the entrypoint
 #[derive(Deserialize, Serialize)]
-pub struct Program(pub Vec<FunctionDefinition>);
+pub struct Program(pub Vec<FunctionDefinition>, pub Vec<Const>);
+
+/// Const:
+/// | CONST identifier = Expr
+///
+/// a value known at compile time, which is inlined wherever it is used.
+/// The value can refer to constants declared before it
+#[derive(Deserialize, Serialize)]
+pub struct Const {
+    pub id: String,
+    pub expr: Expr,
+}
+
+/// SourceFile:
+/// | Import SourceFile
+/// | Program
+///
+/// one file of a program, which can use the functions of the files it imports
+#[derive(Deserialize, Serialize)]
+pub struct SourceFile {
+    pub imports: Vec<Import>,
+    pub program: Program,
+}
+
+/// Import:
+/// | IMPORT "path"
+/// | USE identifier
+///
+/// the imported functions are called through the namespace, as in `math::gcd`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Import {
+    /// Path of the file, relative to the file importing it
+    pub path: String,
+    /// Name of the file without its extension
+    pub namespace: String,
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct FunctionDefinition {
@@ -19,10 +55,15 @@ pub struct FunctionDefinition {
 /// Statement:
 /// | If
 /// | IfElse
+/// | While
+/// | For
+/// | Break
+/// | Continue
 /// | When
 /// | Print
 /// | Return
 /// | ReturnExpr
+/// | Let
 /// | Assignment
 #[derive(Deserialize, Serialize)]
 pub enum Statement {
@@ -31,7 +72,14 @@ pub enum Statement {
     While {
         cond: Expr,
         stmts: Vec<Statement>,
+        /// Name `break` and `continue` can give the loop, written as `'outer: while ...`
+        label: Option<String>,
     },
+    For(Box<For>),
+    /// Leave the innermost loop, or the loop with the label
+    Break(Option<String>),
+    /// Start the next iteration of the innermost loop, or of the loop with the label
+    Continue(Option<String>),
     When(Box<When>),
     Print {
         expr: Option<Expr>,
@@ -39,6 +87,14 @@ pub enum Statement {
     },
     Return,
     ReturnExpr(Expr),
+    /// Declare a local in the current block, shadowing any variable with the same name.
+    /// Only a mutable local can be assigned again
+    Let {
+        id: String,
+        expr: Expr,
+        mutable: bool,
+    },
+    /// Assign a variable which was already declared
     VarAssignment {
         id: String,
         expr: Expr,
@@ -75,6 +131,36 @@ pub struct IfElse {
     pub else_stmts: Vec<Statement>,
 }
 
+/// For:
+/// | FOR identifier IN Expr .. Expr Statement
+/// | FOR identifier IN Expr ..= Expr Statement
+/// | FOR identifier IN Expr .. Expr STEP Expr Statement
+/// | FOR identifier IN identifier Statement
+///
+/// the loop variable is declared for the body, which cannot assign it
+#[derive(Deserialize, Serialize)]
+pub struct For {
+    pub id: String,
+    pub iter: ForIter,
+    pub stmts: Vec<Statement>,
+    pub label: Option<String>,
+}
+
+/// What a for loop iterates over
+#[derive(Deserialize, Serialize)]
+pub enum ForIter {
+    /// Numbers from `start` up to `end`, `step` apart, where the bounds and step
+    /// are evaluated once before the loop
+    Range {
+        start: Expr,
+        end: Expr,
+        inclusive: bool,
+        step: Option<Expr>,
+    },
+    /// The elements of an array
+    Array(String),
+}
+
 /// When:
 /// | WHEN
 #[derive(Deserialize, Serialize)]
@@ -101,7 +187,7 @@ pub enum WhenElse {
 /// | string
 /// | int
 /// | Variable
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Expr {
     BoolLit(bool),
     StringLit(String),
@@ -112,14 +198,14 @@ pub enum Expr {
     FunctionCall(FunctionCall),
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct BinExpr {
     pub op1: Expr,
     pub op2: Expr,
     pub binop: BinOp,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
 pub enum BinOp {
     Plus,
     Minus,
@@ -135,7 +221,7 @@ pub enum BinOp {
     And,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FunctionCall {
     pub id: String,
     pub args: Vec<Expr>,
@@ -46,9 +46,11 @@ pub const BNEZ: u32 = 0x32; // branch not equal zero
 // Functions
 pub const CALL: u32 = 0x40; // jump and link
 pub const RET: u32 = 0x41; // jump return
+pub const CALL_NATIVE: u32 = 0x42; // call a host function
 
 // I/O
 pub const PRINT: u32 = 0x50; // print
+pub const READ: u32 = 0x51; // read an integer, then for read_line() skip the rest of its line
 
 // Stack Ops
 pub const PUSH: u32 = 0x60; // load immediate
@@ -56,3 +58,65 @@ pub const POP: u32 = 0x61; // pop stack
 
 // RNG
 pub const RAND: u32 = 0xFF; // get random number
+
+/// Number of operand words which follow an opcode in the code memory,
+/// or None for an unknown opcode
+pub fn operand_count(opcode: u32) -> Option<usize> {
+    match opcode {
+        END | IADD | ISUB | IMUL | IDIV | FADD | FSUB | FMUL | FDIV => Some(0),
+        LOCAL_LOAD | LOCAL_STORE | GLOBAL_LOAD | GLOBAL_STORE => Some(1),
+        LOCAL_ARR_LOAD | LOCAL_ARR_STORE | GLOBAL_ARR_LOAD | GLOBAL_ARR_STORE => Some(0),
+        LT | LTE | GT | GTE | EQ | NEQ | OR | AND => Some(0),
+        JUMP | BEQZ | BNEZ => Some(1),
+        CALL | CALL_NATIVE => Some(2),
+        RET => Some(0),
+        PRINT | READ => Some(1),
+        PUSH => Some(1),
+        POP => Some(0),
+        RAND => Some(0),
+        _ => None,
+    }
+}
+
+/// Name of an opcode, as written in this file
+pub fn mnemonic(opcode: u32) -> &'static str {
+    match opcode {
+        END => "END",
+        IADD => "IADD",
+        ISUB => "ISUB",
+        IMUL => "IMUL",
+        IDIV => "IDIV",
+        FADD => "FADD",
+        FSUB => "FSUB",
+        FMUL => "FMUL",
+        FDIV => "FDIV",
+        LOCAL_LOAD => "LOCAL_LOAD",
+        LOCAL_STORE => "LOCAL_STORE",
+        GLOBAL_LOAD => "GLOBAL_LOAD",
+        GLOBAL_STORE => "GLOBAL_STORE",
+        LOCAL_ARR_LOAD => "LOCAL_ARR_LOAD",
+        LOCAL_ARR_STORE => "LOCAL_ARR_STORE",
+        GLOBAL_ARR_LOAD => "GLOBAL_ARR_LOAD",
+        GLOBAL_ARR_STORE => "GLOBAL_ARR_STORE",
+        LT => "LT",
+        LTE => "LTE",
+        GT => "GT",
+        GTE => "GTE",
+        EQ => "EQ",
+        NEQ => "NEQ",
+        OR => "OR",
+        AND => "AND",
+        JUMP => "JUMP",
+        BEQZ => "BEQZ",
+        BNEZ => "BNEZ",
+        CALL => "CALL",
+        RET => "RET",
+        CALL_NATIVE => "CALL_NATIVE",
+        PRINT => "PRINT",
+        READ => "READ",
+        PUSH => "PUSH",
+        POP => "POP",
+        RAND => "RAND",
+        _ => "UNKNOWN",
+    }
+}
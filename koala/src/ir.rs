@@ -0,0 +1,508 @@
+use crate::grammar::BinOp;
+use crate::instructions::{
+    AND, BEQZ, BNEZ, CALL, CALL_NATIVE, EQ, GLOBAL_ARR_LOAD, GLOBAL_ARR_STORE, GLOBAL_LOAD,
+    GLOBAL_STORE, GT, GTE, IADD, IDIV, IMUL, ISUB, JUMP, LOCAL_ARR_LOAD, LOCAL_ARR_STORE,
+    LOCAL_LOAD, LOCAL_STORE, LT, LTE, NEQ, OR, POP, PRINT, PUSH, RAND, READ, RET,
+};
+use crate::link::{self, Object};
+use crate::natives;
+use crate::symbols::SymbolTable;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Function ID to bootstrap the executable
+pub const ENTRY_POINT: &str = "main";
+
+/// Symbolic reference to a basic block within a function,
+/// which is only turned into an address when the function is laid out
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label(pub usize);
+
+/// Straight-line stack machine instructions, mirroring the KVM instruction set
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inst {
+    Push(i32),
+    Pop,
+    /// Pops the left hand operand, then the right, and pushes the result
+    BinOp(BinOp),
+    LocalLoad(usize),
+    LocalStore(usize),
+    GlobalLoad(usize),
+    GlobalStore(usize),
+    /// Pushes the offset of a global array, which is linked like the operands of global loads and stores
+    GlobalAddr(usize),
+    /// Pops an index and a base offset, and pushes the local at their sum
+    LocalArrLoad,
+    /// Pops a base offset, an index, and then the value to store
+    LocalArrStore,
+    GlobalArrLoad,
+    GlobalArrStore,
+    /// Pops a value and prints it as an integer or a character
+    Print(PrintType),
+    /// Reads an integer from the host's input and pushes it
+    Read(ReadType),
+    /// Pops `lo` and then `hi`, and pushes a random number from `lo` to `hi` inclusive
+    Rand,
+    /// Pops `argc` arguments into a new frame and pushes the callee's return value
+    Call {
+        id: String,
+        argc: usize,
+    },
+    /// Pops `argc` arguments and pushes the return value of the native at `index`
+    CallNative {
+        index: usize,
+        argc: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrintType {
+    Int = 1,
+    Char = 2,
+}
+
+/// How much of the input a read consumes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadType {
+    /// The next whitespace separated integer, `read_int()`
+    Int = 1,
+    /// The next integer and the rest of the line it is on, `read_line()`
+    Line = 2,
+}
+
+impl ReadType {
+    /// The read performed by a builtin function, if `id` names one
+    pub fn builtin(id: &str) -> Option<ReadType> {
+        match id {
+            "read_int" => Some(ReadType::Int),
+            "read_line" => Some(ReadType::Line),
+            _ => None,
+        }
+    }
+}
+
+/// The single control flow instruction ending each basic block
+#[derive(Clone, Debug, PartialEq)]
+pub enum Terminator {
+    Jump(Label),
+    /// Pops the condition and moves to `zero` when it is 0, otherwise `nonzero`
+    Branch {
+        nonzero: Label,
+        zero: Label,
+    },
+    /// Pops the return value and returns to the caller
+    Ret,
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<Label> {
+        match self {
+            Self::Jump(label) => vec![*label],
+            Self::Branch { nonzero, zero } => vec![*nonzero, *zero],
+            Self::Ret => vec![],
+        }
+    }
+}
+
+/// A run of instructions with a single entry at the top and a single exit at the bottom.
+/// The compiler only branches between statements, so blocks start with an empty operand stack.
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub label: Label,
+    pub insts: Vec<Inst>,
+    pub terminator: Terminator,
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub id: String,
+    /// Number of arguments, which occupy the first local slots
+    pub arity: usize,
+    /// Number of local slots the function will use, including arrays
+    pub frame_size: usize,
+    /// Blocks in layout order, where the first block is the entry
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    /// Number of global slots, including arrays
+    pub globals_size: usize,
+}
+
+/// Incrementally builds the blocks of a function
+pub struct FunctionBuilder {
+    id: String,
+    arity: usize,
+    /// Block contents indexed by label, filled in once the block is terminated
+    blocks: Vec<Option<Block>>,
+    /// Labels in the order their blocks were started, which becomes the layout order
+    order: Vec<Label>,
+    current: Label,
+    insts: Vec<Inst>,
+}
+
+impl FunctionBuilder {
+    pub fn new(id: &str, arity: usize) -> Self {
+        FunctionBuilder {
+            id: id.to_string(),
+            arity,
+            blocks: vec![None],
+            order: vec![Label(0)],
+            current: Label(0),
+            insts: Vec::new(),
+        }
+    }
+
+    /// Reserve a label for a block which will be started later
+    pub fn new_label(&mut self) -> Label {
+        self.blocks.push(None);
+        Label(self.blocks.len() - 1)
+    }
+
+    pub fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    /// Finish the current block, and continue emitting into `next`
+    pub fn terminate(&mut self, terminator: Terminator, next: Label) {
+        let insts = std::mem::take(&mut self.insts);
+        self.blocks[self.current.0] = Some(Block {
+            label: self.current,
+            insts,
+            terminator,
+        });
+        self.order.push(next);
+        self.current = next;
+    }
+
+    /// Finish the current block without a successor,
+    /// so anything emitted afterwards lands in an unreachable block
+    pub fn terminate_unreachable(&mut self, terminator: Terminator) {
+        let next = self.new_label();
+        self.terminate(terminator, next);
+    }
+
+    pub fn finish(mut self, frame_size: usize) -> Function {
+        // falling off the end of a function returns 0
+        self.emit(Inst::Push(0));
+        let insts = std::mem::take(&mut self.insts);
+        self.blocks[self.current.0] = Some(Block {
+            label: self.current,
+            insts,
+            terminator: Terminator::Ret,
+        });
+
+        let mut blocks = self.blocks;
+        Function {
+            id: self.id,
+            arity: self.arity,
+            frame_size,
+            blocks: self
+                .order
+                .iter()
+                .map(|label| match blocks[label.0].take() {
+                    Some(block) => block,
+                    None => panic!("block {:?} was never terminated", label),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Function {
+    /// Labels of blocks which can be reached from the entry block
+    pub fn reachable(&self) -> HashSet<Label> {
+        let by_label: HashMap<Label, &Block> = self.blocks.iter().map(|b| (b.label, b)).collect();
+        let mut seen = HashSet::new();
+        let mut work = vec![self.blocks[0].label];
+        while let Some(label) = work.pop() {
+            if seen.insert(label) {
+                work.extend(by_label[&label].terminator.successors());
+            }
+        }
+
+        seen
+    }
+
+    /// Append the function's code, recording the operands the linker has to fill in or move.
+    /// Blocks that can never run are dropped, and jumps to the following block become fall throughs.
+    pub fn assemble(&self, code: &mut Vec<u32>, relocations: &mut Relocations) {
+        let reachable = self.reachable();
+        let blocks: Vec<&Block> = self
+            .blocks
+            .iter()
+            .filter(|block| reachable.contains(&block.label))
+            .collect();
+
+        let mut block_addrs = HashMap::new();
+        let mut fixups = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            block_addrs.insert(block.label, code.len());
+            for inst in &block.insts {
+                inst.assemble(code, relocations);
+            }
+
+            let next = blocks.get(i + 1).map(|b| b.label);
+            let mut jump = |opcode: u32, label: Label, code: &mut Vec<u32>| {
+                code.push(opcode);
+                fixups.push((code.len(), label));
+                code.push(0);
+            };
+            match block.terminator {
+                Terminator::Jump(label) if Some(label) == next => {}
+                Terminator::Jump(label) => jump(JUMP, label, code),
+                Terminator::Branch { nonzero, zero } if Some(nonzero) == next => {
+                    jump(BEQZ, zero, code)
+                }
+                Terminator::Branch { nonzero, zero } if Some(zero) == next => {
+                    jump(BNEZ, nonzero, code)
+                }
+                Terminator::Branch { nonzero, zero } => {
+                    jump(BEQZ, zero, code);
+                    jump(JUMP, nonzero, code);
+                }
+                Terminator::Ret => code.push(RET),
+            }
+        }
+
+        for (pos, label) in fixups {
+            code[pos] = block_addrs[&label] as u32;
+            relocations.jumps.push(pos);
+        }
+    }
+}
+
+/// Positions of the operands in assembled code which depend on where things are placed
+#[derive(Debug, Default)]
+pub struct Relocations {
+    /// Call targets, by the function called
+    pub calls: Vec<(usize, String)>,
+    /// Jump targets, which are addresses in the same code
+    pub jumps: Vec<usize>,
+    /// Offsets of globals
+    pub globals: Vec<usize>,
+}
+
+impl Inst {
+    fn assemble(&self, code: &mut Vec<u32>, relocations: &mut Relocations) {
+        match self {
+            Self::Push(val) => code.extend([PUSH, *val as u32]),
+            Self::Pop => code.push(POP),
+            Self::BinOp(binop) => code.push(match binop {
+                BinOp::Plus => IADD,
+                BinOp::Minus => ISUB,
+                BinOp::Mul => IMUL,
+                BinOp::Div => IDIV,
+                BinOp::Less => LT,
+                BinOp::LessOrEqual => LTE,
+                BinOp::Greater => GT,
+                BinOp::GreaterOrEqual => GTE,
+                BinOp::Equal => EQ,
+                BinOp::NotEqual => NEQ,
+                BinOp::Or => OR,
+                BinOp::And => AND,
+            }),
+            Self::LocalLoad(offset) => code.extend([LOCAL_LOAD, *offset as u32]),
+            Self::LocalStore(offset) => code.extend([LOCAL_STORE, *offset as u32]),
+            Self::GlobalLoad(offset) => {
+                code.extend([GLOBAL_LOAD, *offset as u32]);
+                relocations.globals.push(code.len() - 1);
+            }
+            Self::GlobalStore(offset) => {
+                code.extend([GLOBAL_STORE, *offset as u32]);
+                relocations.globals.push(code.len() - 1);
+            }
+            Self::GlobalAddr(offset) => {
+                code.extend([PUSH, *offset as u32]);
+                relocations.globals.push(code.len() - 1);
+            }
+            Self::LocalArrLoad => code.push(LOCAL_ARR_LOAD),
+            Self::LocalArrStore => code.push(LOCAL_ARR_STORE),
+            Self::GlobalArrLoad => code.push(GLOBAL_ARR_LOAD),
+            Self::GlobalArrStore => code.push(GLOBAL_ARR_STORE),
+            Self::Print(print_type) => code.extend([PRINT, *print_type as u32]),
+            Self::Read(read_type) => code.extend([READ, *read_type as u32]),
+            Self::Rand => code.push(RAND),
+            Self::Call { id, argc } => {
+                code.extend([CALL, *argc as u32]);
+                relocations.calls.push((code.len(), id.clone()));
+                code.push(0);
+            }
+            Self::CallNative { index, argc } => {
+                code.extend([CALL_NATIVE, *argc as u32, *index as u32])
+            }
+        }
+    }
+}
+
+impl Module {
+    /// Whether any function reads from the host's input
+    pub fn reads_input(&self) -> bool {
+        self.insts().any(|inst| matches!(inst, Inst::Read(_)))
+    }
+
+    /// Whether any function draws random numbers
+    pub fn uses_rand(&self) -> bool {
+        self.insts().any(|inst| *inst == Inst::Rand)
+    }
+
+    /// Whether any function divides, which can fail at runtime
+    pub fn divides(&self) -> bool {
+        self.insts().any(|inst| *inst == Inst::BinOp(BinOp::Div))
+    }
+
+    /// Whether any function calls the standard native with the given name
+    pub fn calls_native(&self, name: &str) -> bool {
+        let index = natives::standard_index(name);
+        self.insts()
+            .any(|inst| matches!(inst, Inst::CallNative { index: i, .. } if Some(*i) == index))
+    }
+
+    fn insts(&self) -> impl Iterator<Item = &Inst> {
+        self.functions
+            .iter()
+            .flat_map(|function| &function.blocks)
+            .flat_map(|block| &block.insts)
+    }
+
+    /// Lay out every function and resolve call targets into KVM bytecode,
+    /// prefixed with a bootstrap that calls main and shuts down the VM
+    pub fn link(&self) -> Vec<u32> {
+        self.link_with_symbols().0
+    }
+
+    /// Link the module, also returning the address each function was placed at
+    pub fn link_with_symbols(&self) -> (Vec<u32>, SymbolTable) {
+        // the globals have already been laid out, so they are linked as a single block
+        let globals = vec![(String::new(), 0, self.globals_size)];
+        match link::link(&[Object::new(self, globals)]) {
+            Ok(linked) => linked,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "globals {}", self.globals_size)?;
+        for function in &self.functions {
+            write!(f, "\n{}", function)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "fn {}(args: {}, frame: {}) {{",
+            self.id, self.arity, self.frame_size
+        )?;
+        for block in &self.blocks {
+            writeln!(f, "L{}:", block.label.0)?;
+            for inst in &block.insts {
+                writeln!(f, "    {:?}", inst)?;
+            }
+            match &block.terminator {
+                Terminator::Jump(label) => writeln!(f, "    Jump L{}", label.0)?,
+                Terminator::Branch { nonzero, zero } => {
+                    writeln!(f, "    Branch L{} else L{}", nonzero.0, zero.0)?
+                }
+                Terminator::Ret => writeln!(f, "    Ret")?,
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::END;
+
+    /// fn main() { let mut i = 0 while i < 3 { print(i) i = i + 1 } }
+    fn counting_loop() -> Module {
+        let mut function = FunctionBuilder::new("main", 0);
+        let cond = function.new_label();
+        let body = function.new_label();
+        let end = function.new_label();
+        function.emit(Inst::Push(0));
+        function.emit(Inst::LocalStore(0));
+        function.terminate(Terminator::Jump(cond), cond);
+        function.emit(Inst::Push(3));
+        function.emit(Inst::LocalLoad(0));
+        function.emit(Inst::BinOp(BinOp::Less));
+        function.terminate(
+            Terminator::Branch {
+                nonzero: body,
+                zero: end,
+            },
+            body,
+        );
+        function.emit(Inst::LocalLoad(0));
+        function.emit(Inst::Print(PrintType::Int));
+        function.emit(Inst::Push(1));
+        function.emit(Inst::LocalLoad(0));
+        function.emit(Inst::BinOp(BinOp::Plus));
+        function.emit(Inst::LocalStore(0));
+        function.terminate(Terminator::Jump(cond), end);
+
+        Module {
+            functions: vec![function.finish(1)],
+            globals_size: 0,
+        }
+    }
+
+    #[test]
+    fn link_loop_test() {
+        #[rustfmt::skip]
+        let expected = vec![
+            CALL, 0, 4, END,
+            PUSH, 0, LOCAL_STORE, 0,
+            PUSH, 3, LOCAL_LOAD, 0, LT, BEQZ, 28,
+            LOCAL_LOAD, 0, PRINT, 1, PUSH, 1, LOCAL_LOAD, 0, IADD, LOCAL_STORE, 0, JUMP, 8,
+            PUSH, 0, RET,
+        ];
+        assert_eq!(counting_loop().link(), expected);
+    }
+
+    #[test]
+    fn unreachable_block_test() {
+        let mut function = FunctionBuilder::new("main", 0);
+        function.emit(Inst::Push(1));
+        function.terminate_unreachable(Terminator::Ret);
+        function.emit(Inst::Push(2));
+        function.emit(Inst::Print(PrintType::Int));
+        let module = Module {
+            functions: vec![function.finish(0)],
+            globals_size: 0,
+        };
+
+        assert_eq!(module.link(), vec![CALL, 0, 4, END, PUSH, 1, RET]);
+    }
+
+    #[test]
+    fn call_before_definition_test() {
+        let mut main = FunctionBuilder::new("main", 0);
+        main.emit(Inst::Call {
+            id: "f".to_string(),
+            argc: 0,
+        });
+        main.emit(Inst::Pop);
+        let module = Module {
+            functions: vec![main.finish(0), FunctionBuilder::new("f", 0).finish(0)],
+            globals_size: 0,
+        };
+
+        #[rustfmt::skip]
+        let expected = vec![
+            CALL, 0, 4, END,
+            CALL, 0, 11, POP, PUSH, 0, RET,
+            PUSH, 0, RET,
+        ];
+        assert_eq!(module.link(), expected);
+    }
+}
@@ -1,342 +1,1037 @@
 use crate::instructions;
-use std::{collections::HashMap, panic};
+use crate::natives::{ClockCallback, NativeError, NativeFn, Natives};
+use crate::observer::{Step, VmObserver};
+use crate::rng::{Rng, DEFAULT_SEED};
+use std::fmt;
 
 /// The Koala Language Virtual Machine
 pub struct VirtualMachine<'a> {
-    /// Program Counter
+    /// Program Counter, as an index into the loaded instructions
     pc: usize,
-    // Code Memory
-    code: &'a [u32],
+    /// Bytecode address of the last instruction executed, recorded when the VM stops running
+    addr: usize,
     /// Frames indicate the start of a function call,
     /// which will automatically take care of the need to track Frame Pointers
     call_stack: Vec<Frame>,
+    /// Locals of frames which have returned, kept to be reused by the next calls
+    spare_locals: Vec<Vec<i32>>,
     // Executtion/Data Stack
     stack: Vec<i32>,
-    /// Globals
-    globals: HashMap<usize, i32>,
+    /// Globals, indexed by their offset
+    globals: Vec<i32>,
     /// Running flag
     running: bool,
+    /// Number of instructions executed
+    executed: usize,
     /// Callback for Interaction with the outside world
     output_pipe: OutputCallback<'a>,
-    /// Callback for debugging output
-    debug_pipe: OutputCallback<'a>,
+    /// Input from the outside world, read by `read_int()` and `read_line()`
+    input: Input<'a>,
+    /// Generator for `rand()`
+    rng: Rng,
+    /// Host functions the program can call
+    natives: Natives<'a>,
+    /// Code the program passed to `exit()`, or 0 when it ran to the end
+    exit_code: i32,
+    /// Observers notified of everything the VM does, such as a debugger or a profiler
+    observers: Vec<&'a mut dyn VmObserver>,
 }
 
 /// Callback used to interact with the outside
-pub type OutputCallback<'a> = &'a dyn Fn(&str) -> ();
+pub type OutputCallback<'a> = &'a dyn Fn(&str);
+
+/// Callback which provides the next line of input, or None once the input has ended
+pub type InputCallback<'a> = &'a mut dyn FnMut() -> Option<String>;
+
+/// Input which has been read from the host, but not yet consumed by the program
+pub struct Input<'a> {
+    source: Option<InputCallback<'a>>,
+    pending: String,
+}
+
+impl<'a> Input<'a> {
+    /// Input read from `source`, or no input at all
+    pub fn new(source: Option<InputCallback<'a>>) -> Self {
+        Input {
+            source,
+            pending: String::new(),
+        }
+    }
+
+    /// Read the next integer, skipping any whitespace and lines before it
+    pub fn read_int(&mut self) -> Result<i32, String> {
+        while self.pending.trim_start().is_empty() {
+            let line = self.source.as_mut().and_then(|source| source());
+            match line {
+                Some(line) => self.pending = line,
+                None => return Err("read past the end of the input".to_string()),
+            }
+        }
+        let rest = self.pending.trim_start();
+        let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let val = match rest[..len].parse() {
+            Ok(val) => val,
+            Err(_) => return Err(format!("expected an integer but read '{}'", &rest[..len])),
+        };
+        self.pending = rest[len..].to_string();
+        Ok(val)
+    }
+
+    /// Read the next integer, then discard whatever else is on the line it was on,
+    /// whether or not it is an integer. This is what the `read_line()` builtin does,
+    /// which reads one integer per line rather than returning the line itself
+    pub fn read_int_line(&mut self) -> Result<i32, String> {
+        let val = self.read_int()?;
+        self.pending = match self.pending.find('\n') {
+            Some(end) => self.pending[end + 1..].to_string(),
+            None => String::new(),
+        };
+        Ok(val)
+    }
+}
 
 #[derive(Debug)]
 pub struct Frame {
+    /// Bytecode address of the function
     pub fn_addr: usize,
     pub locals: Vec<i32>,
+    /// Index of the decoded instruction to continue from
     pub return_addr: usize,
 }
 
+/// An instruction with its operands decoded, and code addresses
+/// resolved into indexes of the loaded instructions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    End,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    LocalLoad(usize),
+    LocalStore(usize),
+    GlobalLoad(usize),
+    GlobalStore(usize),
+    LocalArrLoad,
+    LocalArrStore,
+    GlobalArrLoad,
+    GlobalArrStore,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Eq,
+    Neq,
+    Or,
+    And,
+    Jump(usize),
+    Beqz(usize),
+    Bnez(usize),
+    Call { argc: usize, target: usize },
+    CallNative { argc: usize, index: usize },
+    Ret,
+    Print(u32),
+    Read(u32),
+    Push(i32),
+    Pop,
+    Rand,
+}
+
+impl Instruction {
+    /// The opcode the instruction is encoded with
+    pub fn opcode(&self) -> u32 {
+        match self {
+            Instruction::End => instructions::END,
+            Instruction::Add => instructions::IADD,
+            Instruction::Sub => instructions::ISUB,
+            Instruction::Mul => instructions::IMUL,
+            Instruction::Div => instructions::IDIV,
+            Instruction::LocalLoad(_) => instructions::LOCAL_LOAD,
+            Instruction::LocalStore(_) => instructions::LOCAL_STORE,
+            Instruction::GlobalLoad(_) => instructions::GLOBAL_LOAD,
+            Instruction::GlobalStore(_) => instructions::GLOBAL_STORE,
+            Instruction::LocalArrLoad => instructions::LOCAL_ARR_LOAD,
+            Instruction::LocalArrStore => instructions::LOCAL_ARR_STORE,
+            Instruction::GlobalArrLoad => instructions::GLOBAL_ARR_LOAD,
+            Instruction::GlobalArrStore => instructions::GLOBAL_ARR_STORE,
+            Instruction::Lt => instructions::LT,
+            Instruction::Lte => instructions::LTE,
+            Instruction::Gt => instructions::GT,
+            Instruction::Gte => instructions::GTE,
+            Instruction::Eq => instructions::EQ,
+            Instruction::Neq => instructions::NEQ,
+            Instruction::Or => instructions::OR,
+            Instruction::And => instructions::AND,
+            Instruction::Jump(_) => instructions::JUMP,
+            Instruction::Beqz(_) => instructions::BEQZ,
+            Instruction::Bnez(_) => instructions::BNEZ,
+            Instruction::Call { .. } => instructions::CALL,
+            Instruction::CallNative { .. } => instructions::CALL_NATIVE,
+            Instruction::Ret => instructions::RET,
+            Instruction::Print(_) => instructions::PRINT,
+            Instruction::Read(_) => instructions::READ,
+            Instruction::Push(_) => instructions::PUSH,
+            Instruction::Pop => instructions::POP,
+            Instruction::Rand => instructions::RAND,
+        }
+    }
+}
+
+/// Bytecode which has been validated and decoded, ready to be run
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<Instruction>,
+    /// Bytecode address each instruction was read from
+    pub addrs: Vec<usize>,
+}
+
+/// Reasons bytecode can be rejected when it is loaded
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    BadOpcode { addr: usize, opcode: u32 },
+    TruncatedOperand { addr: usize },
+    BadJumpTarget { addr: usize, target: u32 },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadOpcode { addr, opcode } => {
+                write!(f, "bad opcode {:#x} at {}", opcode, addr)
+            }
+            LoadError::TruncatedOperand { addr } => {
+                write!(f, "truncated operand for instruction at {}", addr)
+            }
+            LoadError::BadJumpTarget { addr, target } => write!(
+                f,
+                "instruction at {} jumps to {}, which is not the start of an instruction",
+                addr, target
+            ),
+        }
+    }
+}
+
+/// Validate and decode bytecode, so that running it only has to dispatch.
+///
+/// Operands are read into the instructions, and code addresses are resolved into
+/// indexes of the decoded instructions. The end of the code is a valid target.
+pub fn load(code: &[u32]) -> Result<Program, LoadError> {
+    let mut addrs = Vec::new();
+    let mut addr = 0;
+    while addr < code.len() {
+        let opcode = code[addr];
+        let operand_count = match instructions::operand_count(opcode) {
+            Some(count) => count,
+            None => return Err(LoadError::BadOpcode { addr, opcode }),
+        };
+        if addr + operand_count >= code.len() {
+            return Err(LoadError::TruncatedOperand { addr });
+        }
+        addrs.push(addr);
+        addr += 1 + operand_count;
+    }
+
+    let index_of = |addr: usize, target: u32| match addrs.binary_search(&(target as usize)) {
+        Ok(index) => Ok(index),
+        Err(index) if target as usize == code.len() => Ok(index),
+        Err(_) => Err(LoadError::BadJumpTarget { addr, target }),
+    };
+
+    let code = addrs
+        .iter()
+        .map(|&addr| {
+            let operand = |i: usize| code[addr + 1 + i];
+            Ok(match code[addr] {
+                instructions::END => Instruction::End,
+                instructions::IADD | instructions::FADD => Instruction::Add,
+                instructions::ISUB | instructions::FSUB => Instruction::Sub,
+                instructions::IMUL | instructions::FMUL => Instruction::Mul,
+                instructions::IDIV | instructions::FDIV => Instruction::Div,
+                instructions::LOCAL_LOAD => Instruction::LocalLoad(operand(0) as usize),
+                instructions::LOCAL_STORE => Instruction::LocalStore(operand(0) as usize),
+                instructions::GLOBAL_LOAD => Instruction::GlobalLoad(operand(0) as usize),
+                instructions::GLOBAL_STORE => Instruction::GlobalStore(operand(0) as usize),
+                instructions::LOCAL_ARR_LOAD => Instruction::LocalArrLoad,
+                instructions::LOCAL_ARR_STORE => Instruction::LocalArrStore,
+                instructions::GLOBAL_ARR_LOAD => Instruction::GlobalArrLoad,
+                instructions::GLOBAL_ARR_STORE => Instruction::GlobalArrStore,
+                instructions::LT => Instruction::Lt,
+                instructions::LTE => Instruction::Lte,
+                instructions::GT => Instruction::Gt,
+                instructions::GTE => Instruction::Gte,
+                instructions::EQ => Instruction::Eq,
+                instructions::NEQ => Instruction::Neq,
+                instructions::OR => Instruction::Or,
+                instructions::AND => Instruction::And,
+                instructions::JUMP => Instruction::Jump(index_of(addr, operand(0))?),
+                instructions::BEQZ => Instruction::Beqz(index_of(addr, operand(0))?),
+                instructions::BNEZ => Instruction::Bnez(index_of(addr, operand(0))?),
+                instructions::CALL => Instruction::Call {
+                    argc: operand(0) as usize,
+                    target: index_of(addr, operand(1))?,
+                },
+                instructions::CALL_NATIVE => Instruction::CallNative {
+                    argc: operand(0) as usize,
+                    index: operand(1) as usize,
+                },
+                instructions::RET => Instruction::Ret,
+                instructions::PRINT => Instruction::Print(operand(0)),
+                instructions::READ => Instruction::Read(operand(0)),
+                instructions::PUSH => Instruction::Push(operand(0) as i32),
+                instructions::POP => Instruction::Pop,
+                instructions::RAND => Instruction::Rand,
+                opcode => unreachable!("unknown opcode {:#x} was not rejected", opcode),
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Program { code, addrs })
+}
+
 impl<'a> VirtualMachine<'a> {
-    pub fn new(output_pipe: OutputCallback<'a>, debug_pipe: OutputCallback<'a>) -> Self {
+    pub fn new(output_pipe: OutputCallback<'a>) -> Self {
         VirtualMachine {
             pc: 0,
-            code: &[],
-            call_stack: Vec::new(),
+            addr: 0,
+            // the entry point runs in a frame of its own, which is never popped
+            call_stack: vec![Frame {
+                fn_addr: 0,
+                locals: Vec::new(),
+                return_addr: 0,
+            }],
+            spare_locals: Vec::new(),
             stack: Vec::new(),
-            globals: HashMap::new(),
+            globals: Vec::new(),
             running: false,
+            executed: 0,
             output_pipe,
-            debug_pipe,
+            input: Input::new(None),
+            rng: Rng::new(DEFAULT_SEED),
+            natives: Natives::new(),
+            exit_code: 0,
+            observers: Vec::new(),
         }
     }
 
-    pub fn run(&mut self, code: &'a [u32]) {
-        // Take a reference to the Binary Code
-        self.code = code;
+    /// Let programs call a host function by name.
+    /// It has to be registered with `CompilerContext::register_native` as well, in the same order
+    pub fn register_native(&mut self, name: &str, arity: usize, function: NativeFn<'a>) {
+        self.natives.register(name, arity, function);
+    }
+
+    /// Measure time for `clock()` with a callback, instead of from when the program started
+    pub fn set_clock(&mut self, clock: ClockCallback<'a>) {
+        self.natives.set_clock(clock);
+    }
+
+    /// Code the program passed to `exit()`, or 0 when it ran to the end
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    /// Restart the generator behind `rand()` from a seed
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Read input from a callback, instead of the VM having no input
+    pub fn set_input(&mut self, input: InputCallback<'a>) {
+        self.input = Input::new(Some(input));
+    }
+
+    /// Notify an observer of everything the VM does from now on
+    pub fn add_observer(&mut self, observer: &'a mut dyn VmObserver) {
+        self.observers.push(observer);
+    }
+
+    /// Load and run bytecode, panicking if it is malformed
+    pub fn run(&mut self, code: &[u32]) {
+        match load(code) {
+            Ok(program) => self.run_program(&program),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    pub fn run_program(&mut self, program: &Program) {
+        self.natives.start_clock();
         // Set Running Flag
         self.running = true;
         // Continue executing until finished
-        while self.running {
-            self.execute();
+        self.execute(program, None);
+    }
+
+    /// Call the function starting at a bytecode address with arguments,
+    /// running until it returns and giving back its return value.
+    /// Globals are kept between calls, and there is no return value when the program exits
+    pub fn call_function(
+        &mut self,
+        program: &Program,
+        fn_addr: usize,
+        args: &[i32],
+    ) -> Option<i32> {
+        let target = match program.addrs.binary_search(&fn_addr) {
+            Ok(target) => target,
+            Err(_) => panic!("no function starts at {}", fn_addr),
+        };
+        self.natives.start_clock();
+        for observer in self.observers.iter_mut() {
+            observer.on_call(self.addr, fn_addr, args);
+        }
+        let (depth, height) = (self.call_stack.len(), self.stack.len());
+        self.call_stack.push(Frame {
+            fn_addr,
+            locals: args.to_vec(),
+            return_addr: self.pc,
+        });
+        self.pc = target;
+        self.running = true;
+        self.execute(program, Some(depth));
+        if self.running {
+            return match self.stack.pop() {
+                Some(val) => Some(val),
+                None => self.error(self.addr, format!("stack underflow at {}", self.addr)),
+            };
         }
+        // exiting leaves the frames and operands of the call behind
+        self.call_stack.truncate(depth);
+        self.stack.truncate(height);
+        None
     }
 
-    fn fetch(&mut self) -> u32 {
-        // Increment PC
-        self.pc += 1;
-        // Fetch the Data at the PC we stepped over
-        self.code[self.pc - 1]
+    /// Global at an offset, where globals which were never stored read as 0
+    pub fn global(&self, offset: usize) -> i32 {
+        self.globals.get(offset).copied().unwrap_or(0)
     }
 
-    fn execute(&mut self) {
-        // Pull the opcode fetched prior
-        let opcode = self.fetch();
+    /// Set the global at an offset, as if the program stored it
+    pub fn store_global(&mut self, offset: usize, val: i32) {
+        let old = self.global(offset);
+        for observer in self.observers.iter_mut() {
+            observer.on_store_global(self.addr, offset, old, val);
+        }
+        store(&mut self.globals, offset, val);
+    }
 
-        self.debug(&format!(
-            "\nPC: {:<3} IR: {:<#6x} SP: {:<3} stack: {:?} frame: {:#?} globals: {:?}\n",
-            self.pc - 1,
-            opcode,
-            self.sp(),
-            self.stack,
-            self.call_stack,
-            self.globals,
-        ));
+    /// Number of instructions executed so far
+    pub fn instruction_count(&self) -> usize {
+        self.executed
+    }
 
-        match opcode {
-            instructions::END => {
-                self.running = false;
-            }
-            instructions::PUSH => {
-                // Fetch the value to Load onto the Stack
-                let immediate_val = self.fetch() as i32;
-                // Push the immediate Value
-                self.stack.push(immediate_val);
-            }
-            instructions::POP => {
-                self.stack.pop();
-            }
-            instructions::GT
-            | instructions::GTE
-            | instructions::LT
-            | instructions::LTE
-            | instructions::EQ
-            | instructions::NEQ => {
-                let first = self.stack.pop().unwrap();
-                let second = self.stack.pop().unwrap();
-
-                let result = match opcode {
-                    instructions::GT => first > second,
-                    instructions::GTE => first >= second,
-                    instructions::LT => first < second,
-                    instructions::LTE => first <= second,
-                    instructions::EQ => first == second,
-                    instructions::NEQ => first != second,
-                    _ => return,
-                };
-
-                self.stack.push(result as i32);
-            }
-            instructions::OR | instructions::AND => {
-                let first = self.stack.pop().unwrap() != 0;
-                let second = self.stack.pop().unwrap() != 0;
+    /// Run instructions until the program ends, or returns from the frame
+    /// which left `stop_depth` frames on the call stack
+    fn execute(&mut self, program: &Program, stop_depth: Option<usize>) {
+        // observers can't be added while the program runs, so only the loop
+        // for when there are some has to check for them
+        if self.observers.is_empty() {
+            self.dispatch::<false>(program, stop_depth);
+        } else {
+            self.dispatch::<true>(program, stop_depth);
+        }
+    }
 
-                let result = match opcode {
-                    instructions::OR => first || second,
-                    instructions::AND => first && second,
-                    _ => return,
-                };
+    /// The loop behind `execute`. The program counter, operand stack and locals of the
+    /// running frame are kept in variables while instructions run, so that they can stay
+    /// in registers, and are written back once the VM stops. The frame on top of the call
+    /// stack holds no locals until then
+    fn dispatch<const OBSERVED: bool>(&mut self, program: &Program, stop_depth: Option<usize>) {
+        let mut pc = self.pc;
+        let mut stack = std::mem::take(&mut self.stack);
+        // the operand stack's length is tracked in `sp` rather than the Vec, which isn't
+        // shrunk as values are popped, so that the length doesn't have to be written to
+        // memory by every instruction
+        let mut sp = stack.len();
+        let mut locals = std::mem::take(&mut self.frame().locals);
+        // instructions are counted a straight run at a time, whenever control moves
+        // somewhere else, instead of one at a time
+        let mut run_start = pc;
 
-                self.stack.push(result as i32);
-            }
-            instructions::IADD | instructions::IMUL | instructions::ISUB | instructions::IDIV => {
-                let first = self.stack.pop().unwrap();
-                let second = self.stack.pop().unwrap();
-
-                let result = match opcode {
-                    instructions::IADD => first + second,
-                    instructions::IMUL => first * second,
-                    instructions::ISUB => first - second,
-                    instructions::IDIV => first / second,
-                    _ => return,
-                };
-
-                self.stack.push(result);
-            }
-            instructions::FADD | instructions::FMUL | instructions::FSUB | instructions::FDIV => {
-                let first = self.stack.pop().unwrap();
-                let second = self.stack.pop().unwrap();
-
-                let result = match opcode {
-                    instructions::FADD => first + second,
-                    instructions::FMUL => first * second,
-                    instructions::FSUB => first - second,
-                    instructions::FDIV => first / second,
-                    _ => return,
-                };
-
-                self.stack.push(result);
-            }
-            instructions::JUMP => {
-                // Fetch the address to Jump to
-                let jump_addr = self.fetch() as usize;
-                // Move the Instruction Pointer to the Address
-                self.pc = jump_addr;
-            }
-            instructions::BEQZ | instructions::BNEZ => {
-                // Fetch the address to jump to when branching
-                let branch_addr = self.fetch() as usize;
-                // Get the value on the Stack and evaluate condition based on type (opcode)
-                let val = self.stack.pop().unwrap() as u32;
-                // conditionally branch when corresponding statement evaluates
-                if match opcode {
-                    instructions::BEQZ => val == 0,
-                    instructions::BNEZ => val != 0,
-                    _ => panic!("impossible path."),
-                } {
-                    self.pc = branch_addr;
+        // Move the program counter, counting the run of instructions which ends here
+        macro_rules! jump {
+            ($target:expr) => {{
+                self.executed += pc - run_start;
+                pc = $target;
+                run_start = pc;
+            }};
+        }
+
+        macro_rules! push {
+            ($val:expr) => {{
+                let val = $val;
+                match stack.get_mut(sp) {
+                    Some(slot) => *slot = val,
+                    None => stack.push(val),
                 }
-            }
-            instructions::CALL => {
-                // Fetch arg count from the stack
-                let arg_count = self.fetch();
-                // Fetch the address of the call
-                let fn_addr = self.fetch() as usize;
-                // Copy the args from the stack into Frame Locals
-                let locals = (0..arg_count)
-                    .into_iter()
-                    .map(|_| self.stack.pop().unwrap())
-                    .collect();
-                // Capture the current PC
-                let return_addr = self.pc;
-                // Push a new Stack Frame
-                self.call_stack.push(Frame {
-                    fn_addr,
-                    locals,
-                    return_addr,
-                });
-                // Move the PC to the function address
-                self.pc = fn_addr;
-            }
-            instructions::RET => {
-                // Pop the state of the Call Stack when exiting function
-                let exiting_frame = self.call_stack.pop().unwrap();
-                // Move the Program Counter back to the previous address
-                self.pc = exiting_frame.return_addr;
-                // Pop any return address
-            }
-            instructions::PRINT => {
-                // Read the print Type (encoded/value)
-                let print_type = self.fetch();
-                // Get Value to Print
-                let val = self.stack.pop().unwrap();
-                // Print based on Type
-                let msg = match print_type {
-                    1 => val.to_string(),
-                    _ => char::from_u32(val as u32)
-                        .unwrap_or_else(|| panic!("bad character parsing in print!"))
-                        .to_string(),
-                };
-                // Use outisde callback to pipe output
-                self.print(&msg);
-            }
-            instructions::LOCAL_LOAD => {
-                // Fetch the Load offset
-                let offset = self.fetch() as usize;
+                sp += 1;
+            }};
+        }
+        macro_rules! pop {
+            () => {
+                match sp.checked_sub(1) {
+                    Some(top) => {
+                        sp = top;
+                        stack[top]
+                    }
+                    None => self.error_at(program, pc, "stack underflow"),
+                }
+            };
+        }
+        // Pop the first and second operands, and push the result of the operator
+        macro_rules! binary_op {
+            ($op:expr) => {{
+                let first = pop!();
+                let second = pop!();
+                push!($op(first, second));
+            }};
+        }
 
-                self.debug(&format!("loading with offset: {}\n", offset));
+        loop {
+            let instruction = match program.code.get(pc) {
+                Some(instruction) => *instruction,
+                None => self.past_end(program, pc),
+            };
+            // Increment PC
+            pc += 1;
 
-                // Push a variable in the current Frame onto the Stack
-                self.stack
-                    .push(self.call_stack.last().unwrap().locals[offset]);
+            if OBSERVED {
+                let addr = executing_addr(program, pc);
+                self.notify_step(addr, instruction, &stack[..sp], &mut locals);
             }
-            instructions::LOCAL_STORE => {
-                // Fetch the Load offset
-                let offset = self.fetch() as usize;
 
-                self.debug(&format!("storing with offset: {}\n", offset));
+            match instruction {
+                Instruction::End => {
+                    self.running = false;
+                    break;
+                }
+                Instruction::Push(immediate_val) => {
+                    // Push the immediate Value
+                    push!(immediate_val);
+                }
+                Instruction::Pop => {
+                    pop!();
+                }
+                Instruction::Gt => binary_op!(|first, second| (first > second) as i32),
+                Instruction::Gte => binary_op!(|first, second| (first >= second) as i32),
+                Instruction::Lt => binary_op!(|first, second| (first < second) as i32),
+                Instruction::Lte => binary_op!(|first, second| (first <= second) as i32),
+                Instruction::Eq => binary_op!(|first, second| (first == second) as i32),
+                Instruction::Neq => binary_op!(|first, second| (first != second) as i32),
+                Instruction::Or => binary_op!(|first, second| (first != 0 || second != 0) as i32),
+                Instruction::And => {
+                    binary_op!(|first, second| (first != 0 && second != 0) as i32)
+                }
+                // arithmetic wraps on overflow, as it does in the compiled backends
+                Instruction::Add => binary_op!(|first: i32, second| first.wrapping_add(second)),
+                Instruction::Mul => binary_op!(|first: i32, second| first.wrapping_mul(second)),
+                Instruction::Sub => binary_op!(|first: i32, second| first.wrapping_sub(second)),
+                Instruction::Div => {
+                    let first = pop!();
+                    let second = pop!();
+                    match first.checked_div(second) {
+                        Some(val) => push!(val),
+                        None if second == 0 => self.error_at(program, pc, "division by zero"),
+                        None => self.error_at(program, pc, "division overflow"),
+                    }
+                }
+                Instruction::Jump(target) => {
+                    // Move the Instruction Pointer to the Address
+                    jump!(target);
+                }
+                Instruction::Beqz(target) => {
+                    if pop!() == 0 {
+                        jump!(target);
+                    }
+                }
+                Instruction::Bnez(target) => {
+                    if pop!() != 0 {
+                        jump!(target);
+                    }
+                }
+                Instruction::Call { argc, target } => {
+                    stack.truncate(sp);
+                    self.call(program, pc, argc, target, &mut stack, &mut locals);
+                    sp = stack.len();
+                    // Move the PC to the function address
+                    jump!(target);
+                }
+                Instruction::CallNative { argc, index } => {
+                    stack.truncate(sp);
+                    let result = self.call_native(program, pc, argc, index, &mut stack);
+                    sp = stack.len();
+                    if let Err(code) = result {
+                        self.exit_code = code;
+                        self.running = false;
+                        break;
+                    }
+                }
+                Instruction::Ret => {
+                    let return_addr = self.ret(program, pc, &mut locals);
+                    if Some(self.call_stack.len()) == stop_depth {
+                        self.addr = executing_addr(program, pc);
+                        jump!(return_addr);
+                        break;
+                    }
+                    // Move the Program Counter back to the previous address
+                    jump!(return_addr);
+                }
+                Instruction::Print(print_type) => {
+                    // Get Value to Print
+                    let val = pop!();
+                    self.print(program, pc, print_type, val);
+                }
+                Instruction::Read(read_type) => {
+                    let val = match read_type {
+                        1 => self.input.read_int(),
+                        _ => self.input.read_int_line(),
+                    };
+                    match val {
+                        Ok(val) => push!(val),
+                        Err(e) => self.error_at(program, pc, &e),
+                    }
+                }
+                Instruction::LocalLoad(offset) => {
+                    // Push a variable in the current Frame onto the Stack, where
+                    // locals which were never stored read as 0
+                    push!(locals.get(offset).copied().unwrap_or(0));
+                }
+                Instruction::LocalStore(offset) => {
+                    // Set a variable in the current Frame fromn the Stack
+                    let val = pop!();
+                    if OBSERVED {
+                        self.notify_store_local(program, pc, &locals, offset, val);
+                    }
+                    store(&mut locals, offset, val);
+                }
+                Instruction::LocalArrLoad => {
+                    let index = pop!() as usize;
+                    let offset = pop!() as usize;
 
-                // Set a variable in the current Frame fromn the Stack
-                let val = self.stack.pop().unwrap();
+                    // Push a variable in the current Frame onto the Stack
+                    push!(locals.get(offset + index).copied().unwrap_or(0));
+                }
+                Instruction::LocalArrStore => {
+                    let offset = pop!() as usize;
+                    let index = pop!() as usize;
 
-                let frame = self.call_stack.last_mut().unwrap();
-                // if we a referencing a new variable, then make more space
-                if offset == frame.locals.len() {
-                    frame.locals.push(val);
-                } else {
-                    frame.locals[offset] = val;
+                    // Set a variable in the current Frame fromn the Stack
+                    let val = pop!();
+                    if OBSERVED {
+                        self.notify_store_local(program, pc, &locals, offset + index, val);
+                    }
+                    store(&mut locals, offset + index, val);
                 }
-            }
-            instructions::LOCAL_ARR_LOAD => {
-                // Fetch the Load offset
-                let index = self.stack.pop().unwrap() as usize;
-                let offset = self.stack.pop().unwrap() as usize;
-
-                self.debug(&format!(
-                    "loading with offset: {} and index {}\n",
-                    offset, index
-                ));
-
-                // Push a variable in the current Frame onto the Stack
-                self.stack
-                    .push(self.call_stack.last().unwrap().locals[offset + index]);
-            }
-            instructions::LOCAL_ARR_STORE => {
-                // Fetch the Load offset
-                let offset = self.fetch() as usize;
-                let index = self.fetch() as usize;
-
-                self.debug(&format!(
-                    "storing with offset: {} and index {}\n",
-                    offset, index
-                ));
-
-                // Fetch current frame
-                let frame = self.call_stack.last_mut().unwrap();
-                // if we a referencing a new variable, then make more space
-                if offset == frame.locals.len() {
-                    for _ in 0..index {
-                        frame.locals.push(0);
+                Instruction::GlobalLoad(offset) => {
+                    // Push a global onto the Stack
+                    push!(self.global(offset));
+                }
+                Instruction::GlobalStore(offset) => {
+                    // Set a global from the Stack
+                    let val = pop!();
+                    self.set_global::<OBSERVED>(program, pc, offset, val);
+                }
+                Instruction::GlobalArrLoad => {
+                    let index = pop!() as usize;
+                    let offset = pop!() as usize;
+
+                    push!(self.global(offset + index));
+                }
+                Instruction::GlobalArrStore => {
+                    let offset = pop!() as usize;
+                    let index = pop!() as usize;
+
+                    // Set a variable in the global set
+                    let val = pop!();
+                    self.set_global::<OBSERVED>(program, pc, offset + index, val);
+                }
+                Instruction::Rand => {
+                    let lo = pop!();
+                    let hi = pop!();
+                    match self.rng.range(lo, hi) {
+                        Some(val) => push!(val),
+                        None => self.error_at(
+                            program,
+                            pc,
+                            &format!("empty range for rand({}, {})", lo, hi),
+                        ),
                     }
                 }
-                // Set a variable in the current Frame fromn the Stack
-                frame.locals[offset + index] = self.stack.pop().unwrap();
+            };
+        }
+
+        if !self.running {
+            self.addr = executing_addr(program, pc);
+        }
+        self.executed += pc - run_start;
+        self.pc = pc;
+        stack.truncate(sp);
+        self.stack = stack;
+        self.frame().locals = locals;
+    }
+
+    /// Let the observers know an instruction is about to execute, which is kept out of the
+    /// dispatch loop so that the loop's state can stay in registers
+    #[inline(never)]
+    fn notify_step(
+        &mut self,
+        addr: usize,
+        instruction: Instruction,
+        stack: &[i32],
+        locals: &mut Vec<i32>,
+    ) {
+        // the observers see the locals in their frame
+        std::mem::swap(&mut self.frame().locals, locals);
+        let step = Step {
+            pc: addr,
+            instruction,
+            stack,
+            frames: &self.call_stack[1..],
+            globals: &self.globals,
+        };
+        for observer in self.observers.iter_mut() {
+            observer.on_step(&step);
+        }
+        std::mem::swap(&mut self.frame().locals, locals);
+    }
+
+    /// Push a frame for a call, whose arguments are moved from the stack into its locals,
+    /// which replace the caller's `locals` as those of the running frame
+    #[inline(never)]
+    fn call(
+        &mut self,
+        program: &Program,
+        pc: usize,
+        argc: usize,
+        target: usize,
+        stack: &mut Vec<i32>,
+        locals: &mut Vec<i32>,
+    ) {
+        if stack.len() < argc {
+            self.error_at(program, pc, "stack underflow");
+        }
+        // reuse the locals of a frame which has returned
+        let mut args = self.spare_locals.pop().unwrap_or_default();
+        args.extend(stack.drain(stack.len() - argc..).rev());
+        let fn_addr = program.addrs.get(target).copied().unwrap_or(usize::MAX);
+        if !self.observers.is_empty() {
+            let addr = executing_addr(program, pc);
+            for observer in self.observers.iter_mut() {
+                observer.on_call(addr, fn_addr, &args);
             }
-            instructions::GLOBAL_LOAD => {
-                // Fetch the Load offset
-                let offset = self.fetch() as usize;
+        }
+        self.frame().locals = std::mem::replace(locals, args);
+        // Push a new Stack Frame, capturing the current PC
+        self.call_stack.push(Frame {
+            fn_addr,
+            locals: Vec::new(),
+            return_addr: pc,
+        });
+    }
 
-                self.debug(&format!("global loading with offset: {}\n", offset));
+    /// Call a host function with arguments from the stack, pushing its return value,
+    /// or giving back the exit code when it was `exit()`
+    #[inline(never)]
+    fn call_native(
+        &mut self,
+        program: &Program,
+        pc: usize,
+        argc: usize,
+        index: usize,
+        stack: &mut Vec<i32>,
+    ) -> Result<(), i32> {
+        if stack.len() < argc {
+            self.error_at(program, pc, "stack underflow");
+        }
+        let args: Vec<i32> = stack.drain(stack.len() - argc..).rev().collect();
+        match self.natives.call(index, &args) {
+            Ok(val) => stack.push(val),
+            Err(NativeError::Exit(code)) => return Err(code),
+            Err(NativeError::Failed(e)) => self.error_at(program, pc, &e),
+        }
+        Ok(())
+    }
 
-                // Push a variable in the current Frame onto the Stack
-                self.stack.push(self.globals[&offset]);
+    /// Pop the frame of the function which is returning, whose `locals` are replaced by the
+    /// caller's, giving back where to continue from
+    #[inline(never)]
+    fn ret(&mut self, program: &Program, pc: usize, locals: &mut Vec<i32>) -> usize {
+        if self.call_stack.len() == 1 {
+            self.error_at(program, pc, "returned with an empty call stack");
+        }
+        let exiting_frame = self.call_stack.pop().unwrap();
+        if !self.observers.is_empty() {
+            let addr = executing_addr(program, pc);
+            for observer in self.observers.iter_mut() {
+                observer.on_return(addr, exiting_frame.fn_addr, locals);
             }
-            instructions::GLOBAL_STORE => {
-                // Fetch the Load offset
-                let offset = self.fetch() as usize;
+        }
+        let mut exiting_locals = std::mem::take(&mut self.frame().locals);
+        std::mem::swap(locals, &mut exiting_locals);
+        exiting_locals.clear();
+        self.spare_locals.push(exiting_locals);
+        exiting_frame.return_addr
+    }
 
-                self.debug(&format!("global storing with offset: {}\n", offset));
+    /// The frame of the function being run, or the entry point's frame outside of functions
+    fn frame(&mut self) -> &mut Frame {
+        self.call_stack
+            .last_mut()
+            .expect("the entry point's frame is never popped")
+    }
 
-                // Set a variable in the current Frame from the Stack
-                let val = self.stack.pop().unwrap();
+    fn notify_store_local(
+        &mut self,
+        program: &Program,
+        pc: usize,
+        locals: &[i32],
+        offset: usize,
+        val: i32,
+    ) {
+        let addr = executing_addr(program, pc);
+        let old = locals.get(offset).copied().unwrap_or(0);
+        for observer in self.observers.iter_mut() {
+            observer.on_store_local(addr, offset, old, val);
+        }
+    }
 
-                self.globals.insert(offset, val);
+    /// Store a global for the instruction before `pc`
+    fn set_global<const OBSERVED: bool>(
+        &mut self,
+        program: &Program,
+        pc: usize,
+        offset: usize,
+        val: i32,
+    ) {
+        if OBSERVED {
+            let (addr, old) = (executing_addr(program, pc), self.global(offset));
+            for observer in self.observers.iter_mut() {
+                observer.on_store_global(addr, offset, old, val);
             }
-            instructions::GLOBAL_ARR_LOAD => {
-                // Fetch the Load offset
-                let index = self.stack.pop().unwrap() as usize;
-                let offset = self.stack.pop().unwrap() as usize;
-
-                self.debug(&format!(
-                    "loading with offset: {} and index {}\n",
-                    offset, index
-                ));
-
-                // Push a variable in the current Frame onto the Stack
-                self.stack.push(self.globals[&(offset + index)]);
+        }
+        store(&mut self.globals, offset, val);
+    }
+
+    /// Print a value as an integer, or as the character it is the code of
+    #[inline(never)]
+    fn print(&mut self, program: &Program, pc: usize, print_type: u32, val: i32) {
+        let message = match print_type {
+            1 => val.to_string(),
+            _ => match char::from_u32(val as u32) {
+                Some(c) => c.to_string(),
+                None => self.error_at(program, pc, "bad character parsing in print"),
+            },
+        };
+        if !self.observers.is_empty() {
+            let addr = executing_addr(program, pc);
+            for observer in self.observers.iter_mut() {
+                observer.on_print(addr, &message);
             }
-            instructions::GLOBAL_ARR_STORE => {
-                // Fetch the Load offset
-                let offset = self.stack.pop().unwrap() as usize;
-                let index = self.stack.pop().unwrap() as usize;
-
-                self.debug(&format!(
-                    "storing with offset: {} and index {}\n",
-                    offset, index
-                ));
-
-                // Set a variable in the global set
-                self.globals
-                    .insert(offset + index, self.stack.pop().unwrap());
+        }
+        // Use outisde callback to pipe output
+        (self.output_pipe)(&message);
+    }
+
+    /// Stop the machine when the program counter runs off the end of the code, which
+    /// is kept apart so that the dispatch loop's `pc` isn't borrowed to format it
+    #[cold]
+    fn past_end(&mut self, program: &Program, pc: usize) -> ! {
+        let message = format!("program counter {} is past the end of the code", pc);
+        self.error_at(program, pc, &message)
+    }
+
+    /// Stop the machine on an error in the instruction before `pc`
+    #[cold]
+    fn error_at(&mut self, program: &Program, pc: usize, message: &str) -> ! {
+        let addr = executing_addr(program, pc);
+        self.error(addr, format!("{} at {}", message, addr))
+    }
+
+    /// Stop the machine, letting the observers know why
+    #[cold]
+    fn error(&mut self, addr: usize, message: String) -> ! {
+        for observer in self.observers.iter_mut() {
+            observer.on_error(addr, &message);
+        }
+        panic!("{}", message);
+    }
+}
+
+/// Store a local or global, making space for it when it is past the end of those stored so far
+fn store(values: &mut Vec<i32>, offset: usize, val: i32) {
+    if offset >= values.len() {
+        values.resize(offset + 1, 0);
+    }
+    values[offset] = val;
+}
+
+/// Bytecode address of the instruction being executed, which is the one before the
+/// program counter until the instruction moves it
+fn executing_addr(program: &Program, pc: usize) -> usize {
+    program.addrs.get(pc.wrapping_sub(1)).copied().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerContext;
+    use crate::instructions::{
+        CALL, END, IADD, JUMP, LOCAL_ARR_LOAD, LOCAL_ARR_STORE, PRINT, PUSH, RET,
+    };
+    use crate::parser::parse_code;
+    use std::cell::RefCell;
+
+    #[test]
+    fn load_resolves_targets_test() {
+        let program = load(&[CALL, 0, 4, END, PUSH, 1, PRINT, 1, JUMP, 11, RET]).unwrap();
+        assert_eq!(
+            program.code,
+            vec![
+                Instruction::Call { argc: 0, target: 2 },
+                Instruction::End,
+                Instruction::Push(1),
+                Instruction::Print(1),
+                Instruction::Jump(6),
+                Instruction::Ret,
+            ]
+        );
+        assert_eq!(program.addrs, vec![0, 3, 4, 6, 8, 10]);
+    }
+
+    #[test]
+    fn load_errors_test() {
+        assert_eq!(
+            load(&[PUSH, 1, 0x9a]).unwrap_err(),
+            LoadError::BadOpcode {
+                addr: 2,
+                opcode: 0x9a
             }
-            _ => { /* no-op */ }
-        };
+        );
+        assert_eq!(
+            load(&[IADD, PUSH]).unwrap_err(),
+            LoadError::TruncatedOperand { addr: 1 }
+        );
+        assert_eq!(
+            load(&[JUMP, 3, PUSH, 1, END]).unwrap_err(),
+            LoadError::BadJumpTarget { addr: 0, target: 3 }
+        );
+        assert_eq!(
+            load(&[JUMP, 9, END]).unwrap_err(),
+            LoadError::BadJumpTarget { addr: 0, target: 9 }
+        );
+    }
+
+    #[test]
+    fn local_array_store_test() {
+        // the value, the index and then the array's offset are popped from the stack,
+        // and the frame grows to fit the slot
+        #[rustfmt::skip]
+        let code = [
+            CALL, 0, 4, END,
+            PUSH, 7, PUSH, 2, PUSH, 1, LOCAL_ARR_STORE,
+            PUSH, 1, PUSH, 2, LOCAL_ARR_LOAD, PRINT, 1,
+            PUSH, 1, PUSH, 0, LOCAL_ARR_LOAD, PRINT, 1,
+            RET,
+        ];
+        let output = RefCell::new(String::new());
+        let print = |msg: &str| *output.borrow_mut() += msg;
+        let mut vm = VirtualMachine::new(&print);
+        vm.run(&code);
+
+        drop(vm);
+        assert_eq!(output.take(), "70");
+    }
+
+    #[test]
+    fn overflow_test() {
+        // arithmetic wraps rather than panicking, like the compiled backends
+        let code = "
+        fn main() {
+          print(f(2147483647) + 1)
+          print((0 - f(2147483647)) - 2)
+          print(f(65536) * 65536)
+        }
+
+        fn f(n) {
+          return n
+        }
+        ";
+        let bin = parse_code(code)
+            .unwrap()
+            .code_gen(&mut CompilerContext::new());
+        let output = RefCell::new(String::new());
+        let print = |msg: &str| *output.borrow_mut() += msg;
+        let mut vm = VirtualMachine::new(&print);
+        vm.run(&bin);
+
+        drop(vm);
+        assert_eq!(output.take(), "-214748364821474836470");
     }
 
-    fn print(&self, message: &str) {
-        (self.output_pipe)(message);
+    #[test]
+    #[should_panic(expected = "division overflow")]
+    fn division_overflow_test() {
+        let code = "fn main() { print(f((0 - 2147483647) - 1) / (0 - 1)) } fn f(n) { return n }";
+        let bin = parse_code(code)
+            .unwrap()
+            .code_gen(&mut CompilerContext::new());
+        VirtualMachine::new(&|_| {}).run(&bin);
     }
 
-    fn debug(&self, message: &str) {
-        (self.debug_pipe)(message);
+    #[test]
+    fn input_test() {
+        let mut lines = vec!["  12 -3 tail\n", "\n", "x 7\n"]
+            .into_iter()
+            .map(str::to_string);
+        let mut source = || lines.next();
+        let mut input = Input::new(Some(&mut source));
+
+        assert_eq!(input.read_int(), Ok(12));
+        // ' tail' is discarded, along with the blank line after it
+        assert_eq!(input.read_int_line(), Ok(-3));
+        assert_eq!(
+            input.read_int(),
+            Err("expected an integer but read 'x'".to_string())
+        );
+        assert_eq!(
+            Input::new(None).read_int_line(),
+            Err("read past the end of the input".to_string())
+        );
     }
 
-    fn sp(&self) -> usize {
-        self.stack.len()
+    #[test]
+    fn read_int_line_test() {
+        let mut lines = vec!["5 6 seven\n", "8"].into_iter().map(str::to_string);
+        let mut source = || lines.next();
+        let mut input = Input::new(Some(&mut source));
+
+        // only the first integer on a line is returned, and the rest of it is discarded
+        assert_eq!(input.read_int_line(), Ok(5));
+        assert_eq!(input.read_int_line(), Ok(8));
+        assert_eq!(
+            input.read_int(),
+            Err("read past the end of the input".to_string())
+        );
+    }
+
+    #[test]
+    fn registered_native_test() {
+        let code = "
+        fn main() {
+          print(double(21))
+          exit(3)
+          print(0)
+        }
+        ";
+        let mut context = CompilerContext::new();
+        context.register_native("double", 1);
+        let bin = parse_code(code).unwrap().code_gen(&mut context);
+
+        let output = RefCell::new(String::new());
+        let print = |msg: &str| *output.borrow_mut() += msg;
+        let mut vm = VirtualMachine::new(&print);
+        vm.register_native("double", 1, Box::new(|args| Ok(args[0] * 2)));
+        vm.run(&bin);
+
+        assert_eq!(vm.exit_code(), 3);
+        drop(vm);
+        assert_eq!(output.take(), "42");
     }
 }
@@ -1,5 +1,21 @@
+pub mod backend;
+pub mod check;
 pub mod compiler;
+pub mod embed;
 pub mod grammar;
 pub mod instructions;
+pub mod ir;
 pub mod kvm;
+pub mod link;
+pub mod modules;
+pub mod natives;
+pub mod observer;
+pub mod optimizer;
 pub mod parser;
+pub mod peephole;
+pub mod profile;
+pub mod rng;
+pub mod rvm;
+pub mod symbols;
+pub mod trace;
+pub mod verify;
@@ -0,0 +1,267 @@
+//! Separate compilation, where each source file is compiled into a relocatable object,
+//! and the linker combines the objects into KVM bytecode
+
+use crate::compiler::CompilerContext;
+use crate::grammar::Program;
+use crate::instructions::{CALL, END};
+use crate::ir::{self, Relocations, ENTRY_POINT};
+use crate::symbols::SymbolTable;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+/// KVM code which has not been placed yet. Its addresses start from 0, calls are left
+/// for the linker to resolve by name, and global offsets are into the object's own globals
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct Object {
+    pub code: Vec<u32>,
+    /// Functions the object defines, with the address each one starts at
+    pub functions: Vec<(String, usize)>,
+    /// Globals the object declares, with their offset and size
+    pub globals: Vec<(String, usize, usize)>,
+    /// Positions of call targets, with the function called
+    pub calls: Vec<(usize, String)>,
+    /// Positions of jump targets
+    pub jumps: Vec<usize>,
+    /// Positions of global offsets
+    pub global_refs: Vec<usize>,
+}
+
+/// Reasons objects can fail to link
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    NoEntryPoint,
+    UndefinedFunction(String),
+    DuplicateFunction(String),
+    /// A global which is declared with different sizes
+    GlobalSize {
+        id: String,
+        sizes: [usize; 2],
+    },
+    /// A global offset outside of every global its object declares
+    BadGlobal {
+        pos: usize,
+        offset: usize,
+    },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::NoEntryPoint => write!(f, "could not find main function."),
+            LinkError::UndefinedFunction(id) => write!(f, "no function named {}() to call", id),
+            LinkError::DuplicateFunction(id) => {
+                write!(f, "{}() is defined by more than one object", id)
+            }
+            LinkError::GlobalSize { id, sizes } => write!(
+                f,
+                "global {} is declared with sizes {} and {}",
+                id, sizes[0], sizes[1]
+            ),
+            LinkError::BadGlobal { pos, offset } => write!(
+                f,
+                "global offset {} at {} is outside of the object's globals",
+                offset, pos
+            ),
+        }
+    }
+}
+
+impl Object {
+    /// Assemble a module, whose globals are laid out as given
+    pub fn new(module: &ir::Module, globals: Vec<(String, usize, usize)>) -> Self {
+        let mut code = Vec::new();
+        let mut relocations = Relocations::default();
+        let mut functions = Vec::new();
+        for function in &module.functions {
+            functions.push((function.id.clone(), code.len()));
+            function.assemble(&mut code, &mut relocations);
+        }
+
+        Object {
+            code,
+            functions,
+            globals,
+            calls: relocations.calls,
+            jumps: relocations.jumps,
+            global_refs: relocations.globals,
+        }
+    }
+
+    /// Compile a program on its own, so that it can call functions defined by other objects
+    pub fn compile(program: &Program, context: &mut CompilerContext) -> Self {
+        context.external_calls = true;
+        let module = program.to_ir(context);
+
+        let (global_lens, _) = &context.array_lens;
+        let mut globals: Vec<(String, usize, usize)> = context
+            .global_vars
+            .0
+            .iter()
+            .map(|(id, offset)| (id.clone(), *offset, *global_lens.get(id).unwrap_or(&1)))
+            .collect();
+        globals.sort_by_key(|(_, offset, _)| *offset);
+
+        Object::new(&module, globals)
+    }
+
+    /// The global an offset is into, and how far into it
+    fn global_at(&self, offset: usize) -> Option<(&str, usize)> {
+        self.globals
+            .iter()
+            .find(|(_, start, size)| (*start..start + size).contains(&offset))
+            .map(|(id, start, _)| (id.as_str(), offset - start))
+    }
+}
+
+/// Place the objects one after another behind a bootstrap that calls main,
+/// returning the bytecode and the address each function was placed at.
+///
+/// Globals with the same name are shared by every object which declares them
+pub fn link(objects: &[Object]) -> Result<(Vec<u32>, SymbolTable), LinkError> {
+    let mut layout: HashMap<&str, (usize, usize)> = HashMap::new();
+    let mut globals_size = 0;
+    for (id, _, size) in objects.iter().flat_map(|object| &object.globals) {
+        match layout.get(id.as_str()) {
+            Some((_, other)) if other != size => {
+                return Err(LinkError::GlobalSize {
+                    id: id.clone(),
+                    sizes: [*other, *size],
+                })
+            }
+            Some(_) => {}
+            None => {
+                layout.insert(id, (globals_size, *size));
+                globals_size += size;
+            }
+        }
+    }
+
+    let mut code = vec![CALL, 0, 0, END];
+    let mut calls = vec![(2, ENTRY_POINT)];
+    let mut fn_addrs = HashMap::new();
+    for object in objects {
+        let base = code.len();
+        code.extend(&object.code);
+        for (id, addr) in &object.functions {
+            if fn_addrs.insert(id.as_str(), base + addr).is_some() {
+                return Err(LinkError::DuplicateFunction(id.clone()));
+            }
+        }
+        for pos in &object.jumps {
+            code[base + pos] += base as u32;
+        }
+        for pos in &object.global_refs {
+            let offset = object.code[*pos] as usize;
+            let (id, within) = match object.global_at(offset) {
+                Some(global) => global,
+                None => return Err(LinkError::BadGlobal { pos: *pos, offset }),
+            };
+            code[base + pos] = (layout[id].0 + within) as u32;
+        }
+        calls.extend(
+            object
+                .calls
+                .iter()
+                .map(|(pos, id)| (base + pos, id.as_str())),
+        );
+    }
+
+    for (pos, id) in calls {
+        code[pos] = match fn_addrs.get(id) {
+            Some(addr) => *addr as u32,
+            None if id == ENTRY_POINT => return Err(LinkError::NoEntryPoint),
+            None => return Err(LinkError::UndefinedFunction(id.to_string())),
+        };
+    }
+
+    let mut symbols = SymbolTable::default();
+    for (id, addr) in fn_addrs {
+        symbols.insert(addr, id);
+    }
+
+    Ok((code, symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kvm::VirtualMachine;
+    use crate::parser::parse_code;
+    use std::cell::RefCell;
+
+    fn compile(code: &str) -> Object {
+        Object::compile(&parse_code(code).unwrap(), &mut CompilerContext::new())
+    }
+
+    fn run(code: &[u32]) -> String {
+        let output = RefCell::new(String::new());
+        let print = |msg: &str| *output.borrow_mut() += msg;
+        VirtualMachine::new(&print).run(code);
+        output.take()
+    }
+
+    #[test]
+    fn link_test() {
+        let main = compile(
+            "
+            fn main() {
+              global total = 0
+              global seen[3] = [0,0,0]
+              add(5)
+              add(gcd(12, 18))
+              print(total)
+              print(seen[1])
+            }
+
+            fn add(n) {
+              global total = total + n
+            }
+            ",
+        );
+        let math = compile(
+            "
+            fn gcd(a, b) {
+              global seen[3] = [1,2,3]
+              while b != 0 {
+                let t = b
+                b = a - (a / b) * b
+                a = t
+              }
+              return a
+            }
+            ",
+        );
+        assert_eq!(main.calls.iter().filter(|(_, id)| id == "gcd").count(), 1);
+
+        let (code, symbols) = link(&[main, math]).unwrap();
+        assert_eq!(run(&code), "112");
+        // the objects are placed in order, after the bootstrap
+        let names: Vec<&str> = symbols.iter().map(|(_, name)| name).collect();
+        assert_eq!(names.last(), Some(&"gcd"));
+    }
+
+    #[test]
+    fn errors_test() {
+        let main = || compile("fn main() { global size = 1 print(double(size)) }");
+        assert_eq!(
+            link(&[main()]).unwrap_err(),
+            LinkError::UndefinedFunction("double".to_string())
+        );
+        assert_eq!(
+            link(&[compile("fn double(n) { return n * 2 }")]).unwrap_err(),
+            LinkError::NoEntryPoint
+        );
+        assert_eq!(
+            link(&[main(), compile("fn main() {}")]).unwrap_err(),
+            LinkError::DuplicateFunction("main".to_string())
+        );
+        assert_eq!(
+            link(&[main(), compile("fn double(n) { global size[2] = [n,n] }")]).unwrap_err(),
+            LinkError::GlobalSize {
+                id: "size".to_string(),
+                sizes: [1, 2]
+            }
+        );
+    }
+}
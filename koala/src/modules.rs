@@ -0,0 +1,555 @@
+//! Loading programs which are split across files, where each file is a module
+//! that imports others with `import "math.koala"` or `use math`.
+//!
+//! The modules are combined into a single program. Functions of imported modules are
+//! named through their namespace, as in `math::gcd`, and each module's globals are only
+//! visible to its own functions, so they are renamed the same way
+
+use crate::grammar::{Const, Expr, ForIter, FunctionCall, FunctionDefinition, Program, Statement};
+use crate::parser::parse_source_file;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::iter;
+use std::path::{Component, Path, PathBuf};
+
+/// Reasons a program's modules can fail to load
+#[derive(Debug, PartialEq)]
+pub enum ModuleError {
+    Read {
+        path: PathBuf,
+        error: String,
+    },
+    Parse {
+        path: PathBuf,
+        error: String,
+    },
+    /// The files which import each other, starting and ending with the same one
+    Cycle(Vec<PathBuf>),
+    /// Two different files which would share a namespace
+    DuplicateNamespace {
+        namespace: String,
+        paths: [PathBuf; 2],
+    },
+    UnknownModule {
+        path: PathBuf,
+        namespace: String,
+    },
+    UnknownFunction {
+        path: PathBuf,
+        id: String,
+    },
+}
+
+impl fmt::Display for ModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ModuleError::Read { path, error } => write!(f, "{}: {}", path.display(), error),
+            ModuleError::Parse { path, error } => write!(f, "{}: {}", path.display(), error),
+            ModuleError::Cycle(paths) => {
+                let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "import cycle: {}", paths.join(" -> "))
+            }
+            ModuleError::DuplicateNamespace { namespace, paths } => write!(
+                f,
+                "{} and {} are both imported as {}",
+                paths[0].display(),
+                paths[1].display(),
+                namespace
+            ),
+            ModuleError::UnknownModule { path, namespace } => write!(
+                f,
+                "{}: {} is used, but not imported",
+                path.display(),
+                namespace
+            ),
+            ModuleError::UnknownFunction { path, id } => {
+                write!(f, "{}: no function named {}()", path.display(), id)
+            }
+        }
+    }
+}
+
+/// Load a program from its main file, along with every module it imports
+pub fn load(path: &Path) -> Result<Program, ModuleError> {
+    load_with(path, &mut |path| std::fs::read_to_string(path))
+}
+
+/// Load a program, reading each file through a callback
+pub fn load_with(
+    path: &Path,
+    read: &mut dyn FnMut(&Path) -> io::Result<String>,
+) -> Result<Program, ModuleError> {
+    let mut loader = Loader {
+        read,
+        loading: Vec::new(),
+        namespaces: HashMap::new(),
+        exports: HashMap::new(),
+        functions: Vec::new(),
+        consts: Vec::new(),
+    };
+    loader.load(normalize(path), None)?;
+    Ok(Program(loader.functions, loader.consts))
+}
+
+struct Loader<'r> {
+    read: &'r mut dyn FnMut(&Path) -> io::Result<String>,
+    /// Files being loaded, each imported by the one before it
+    loading: Vec<PathBuf>,
+    /// Path of the module behind each namespace
+    namespaces: HashMap<String, PathBuf>,
+    /// Functions of each module which has been loaded, by its path
+    exports: HashMap<PathBuf, HashSet<String>>,
+    functions: Vec<FunctionDefinition>,
+    consts: Vec<Const>,
+}
+
+impl Loader<'_> {
+    /// Load a file and the modules it imports, where the main file has no namespace
+    fn load(&mut self, path: PathBuf, namespace: Option<&str>) -> Result<(), ModuleError> {
+        if let Some(start) = self.loading.iter().position(|loading| *loading == path) {
+            let mut cycle = self.loading[start..].to_vec();
+            cycle.push(path);
+            return Err(ModuleError::Cycle(cycle));
+        }
+        if self.exports.contains_key(&path) {
+            return Ok(());
+        }
+        if let Some(namespace) = namespace {
+            if let Some(other) = self.namespaces.get(namespace) {
+                return Err(ModuleError::DuplicateNamespace {
+                    namespace: namespace.to_string(),
+                    paths: [other.clone(), path],
+                });
+            }
+            self.namespaces.insert(namespace.to_string(), path.clone());
+        }
+
+        let source = (self.read)(&path).map_err(|e| ModuleError::Read {
+            path: path.clone(),
+            error: e.to_string(),
+        })?;
+        let file = parse_source_file(&source).map_err(|e| ModuleError::Parse {
+            path: path.clone(),
+            error: e.to_string(),
+        })?;
+
+        // imports are found relative to the file importing them
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        self.loading.push(path.clone());
+        let mut imported = HashMap::new();
+        for import in &file.imports {
+            let import_path = normalize(&dir.join(&import.path));
+            self.load(import_path.clone(), Some(&import.namespace))?;
+            imported.insert(import.namespace.clone(), import_path);
+        }
+        self.loading.pop();
+
+        let module = Module {
+            path: &path,
+            namespace,
+            imported: &imported,
+            exports: &self.exports,
+            functions: file.program.0.iter().map(|def| def.id.clone()).collect(),
+            globals: module_globals(&file.program),
+        };
+        let mut functions = Vec::new();
+        for mut def in file.program.0 {
+            module.rename_function(&mut def)?;
+            functions.push(def);
+        }
+        // constants are private to their module like globals
+        for mut constant in file.program.1 {
+            constant.id = module.qualify(&constant.id);
+            module.rename_expr(&mut constant.expr, &[])?;
+            self.consts.push(constant);
+        }
+
+        self.exports.insert(path.clone(), module.functions);
+        self.functions.extend(functions);
+        Ok(())
+    }
+}
+
+/// What the names in one module's functions refer to
+struct Module<'a> {
+    path: &'a Path,
+    namespace: Option<&'a str>,
+    /// Paths of the modules it imports, by their namespaces
+    imported: &'a HashMap<String, PathBuf>,
+    exports: &'a HashMap<PathBuf, HashSet<String>>,
+    functions: HashSet<String>,
+    globals: HashSet<String>,
+}
+
+impl Module<'_> {
+    fn qualify(&self, id: &str) -> String {
+        match self.namespace {
+            Some(namespace) => format!("{}::{}", namespace, id),
+            None => id.to_string(),
+        }
+    }
+
+    fn rename_function(&self, def: &mut FunctionDefinition) -> Result<(), ModuleError> {
+        def.id = self.qualify(&def.id);
+        let mut scopes = vec![def.args.iter().cloned().collect()];
+        for stmt in def.body.iter_mut() {
+            self.rename_statement(stmt, &mut scopes)?;
+        }
+        Ok(())
+    }
+
+    /// Rename a global the function refers to, unless a local of an enclosing block hides it
+    fn rename_var(&self, id: &mut String, scopes: &[HashSet<String>]) {
+        let local = scopes.iter().any(|scope| scope.contains(id));
+        if self.namespace.is_some() && self.globals.contains(id) && !local {
+            *id = self.qualify(id);
+        }
+    }
+
+    fn rename_statement(
+        &self,
+        stmt: &mut Statement,
+        scopes: &mut Vec<HashSet<String>>,
+    ) -> Result<(), ModuleError> {
+        match stmt {
+            Statement::If(if_data) => {
+                self.rename_expr(&mut if_data.expr, scopes)?;
+                self.rename_statements(&mut if_data.stmts, scopes)?;
+            }
+            Statement::IfElse(if_else) => {
+                self.rename_expr(&mut if_else.expr, scopes)?;
+                self.rename_statements(&mut if_else.stmts, scopes)?;
+                self.rename_statements(&mut if_else.else_stmts, scopes)?;
+            }
+            Statement::While { cond, stmts, .. } => {
+                self.rename_expr(cond, scopes)?;
+                self.rename_statements(stmts, scopes)?;
+            }
+            Statement::For(for_data) => {
+                match &mut for_data.iter {
+                    ForIter::Range {
+                        start, end, step, ..
+                    } => {
+                        for expr in iter::once(start).chain(iter::once(end)).chain(step) {
+                            self.rename_expr(expr, scopes)?;
+                        }
+                    }
+                    ForIter::Array(id) => self.rename_var(id, scopes),
+                }
+                // the loop variable is declared in the body's block
+                scopes.push(HashSet::from([for_data.id.clone()]));
+                self.rename_statements(&mut for_data.stmts, scopes)?;
+                scopes.pop();
+            }
+            Statement::Print { expr, .. } => {
+                if let Some(expr) = expr {
+                    self.rename_expr(expr, scopes)?;
+                }
+            }
+            Statement::ReturnExpr(expr) => self.rename_expr(expr, scopes)?,
+            Statement::Let { id, expr, .. } => {
+                self.rename_expr(expr, scopes)?;
+                scopes.last_mut().unwrap().insert(id.clone());
+            }
+            Statement::VarAssignment { id, expr, global } => {
+                self.rename_expr(expr, scopes)?;
+                match global {
+                    true => *id = self.qualify(id),
+                    false => self.rename_var(id, scopes),
+                }
+            }
+            Statement::ArrayInstantiation {
+                id,
+                size,
+                elements,
+                global,
+            } => {
+                for expr in size.iter_mut().chain(elements.iter_mut().flatten()) {
+                    self.rename_expr(expr, scopes)?;
+                }
+                match global {
+                    true => *id = self.qualify(id),
+                    false => {
+                        scopes.last_mut().unwrap().insert(id.clone());
+                    }
+                }
+            }
+            Statement::ArrayIndexAssignment { id, index, expr } => {
+                self.rename_expr(index, scopes)?;
+                self.rename_expr(expr, scopes)?;
+                self.rename_var(id, scopes);
+            }
+            Statement::FunctionCall(func_call) => self.rename_call(func_call, scopes)?,
+            Statement::Return
+            | Statement::Break(_)
+            | Statement::Continue(_)
+            | Statement::When(_) => {}
+        }
+        Ok(())
+    }
+
+    fn rename_statements(
+        &self,
+        stmts: &mut [Statement],
+        scopes: &mut Vec<HashSet<String>>,
+    ) -> Result<(), ModuleError> {
+        // each block declares variables of its own
+        scopes.push(HashSet::new());
+        for stmt in stmts {
+            self.rename_statement(stmt, scopes)?;
+        }
+        scopes.pop();
+        Ok(())
+    }
+
+    fn rename_expr(&self, expr: &mut Expr, scopes: &[HashSet<String>]) -> Result<(), ModuleError> {
+        match expr {
+            Expr::FunctionCall(func_call) => self.rename_call(func_call, scopes)?,
+            Expr::ArrayIndex { id, expr } => {
+                self.rename_expr(expr, scopes)?;
+                self.rename_var(id, scopes);
+            }
+            Expr::Variable { id } => self.rename_var(id, scopes),
+            Expr::BinExpr(bin_expr) => {
+                self.rename_expr(&mut bin_expr.op1, scopes)?;
+                self.rename_expr(&mut bin_expr.op2, scopes)?;
+            }
+            Expr::BoolLit(_) | Expr::StringLit(_) | Expr::IntLit(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Check that a call through a namespace is to a function of an imported module,
+    /// and qualify calls to the module's own functions
+    fn rename_call(
+        &self,
+        func_call: &mut FunctionCall,
+        scopes: &[HashSet<String>],
+    ) -> Result<(), ModuleError> {
+        for arg in func_call.args.iter_mut() {
+            self.rename_expr(arg, scopes)?;
+        }
+        match func_call.id.split_once("::") {
+            Some((namespace, id)) => {
+                let exports = match self.imported.get(namespace) {
+                    Some(path) => &self.exports[path],
+                    None => {
+                        return Err(ModuleError::UnknownModule {
+                            path: self.path.to_path_buf(),
+                            namespace: namespace.to_string(),
+                        })
+                    }
+                };
+                if !exports.contains(id) {
+                    return Err(ModuleError::UnknownFunction {
+                        path: self.path.to_path_buf(),
+                        id: func_call.id.clone(),
+                    });
+                }
+            }
+            None if self.functions.contains(&func_call.id) => {
+                func_call.id = self.qualify(&func_call.id);
+            }
+            // builtins and natives are shared by every module
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// Constants of a module, and globals declared by any of its functions
+fn module_globals(program: &Program) -> HashSet<String> {
+    let mut globals: HashSet<String> = program.1.iter().map(|c| c.id.clone()).collect();
+    for def in &program.0 {
+        for stmt in &def.body {
+            match stmt {
+                Statement::VarAssignment {
+                    id, global: true, ..
+                }
+                | Statement::ArrayInstantiation {
+                    id, global: true, ..
+                } => {
+                    globals.insert(id.clone());
+                }
+                _ => { /* no-op */ }
+            }
+        }
+    }
+    globals
+}
+
+/// Remove `.` and `..` from a path without touching the file system,
+/// so that one file is always known by the same path
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(normal.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normal.pop();
+            }
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerContext;
+    use crate::kvm::VirtualMachine;
+    use std::cell::RefCell;
+
+    /// Load a program from files held in memory
+    fn load_files(main: &str, files: &[(&str, &str)]) -> Result<Program, ModuleError> {
+        let files: HashMap<PathBuf, &str> = files
+            .iter()
+            .map(|(path, source)| (PathBuf::from(path), *source))
+            .collect();
+        load_with(Path::new(main), &mut |path| match files.get(path) {
+            Some(source) => Ok(source.to_string()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "not found")),
+        })
+    }
+
+    fn load_error(main: &str, files: &[(&str, &str)]) -> ModuleError {
+        match load_files(main, files) {
+            Ok(_) => panic!("{} loaded", main),
+            Err(e) => e,
+        }
+    }
+
+    fn run(program: Program) -> String {
+        let output = RefCell::new(String::new());
+        let print = |msg: &str| *output.borrow_mut() += msg;
+        let bin = program.code_gen(&mut CompilerContext::new());
+        VirtualMachine::new(&print).run(&bin);
+        output.take()
+    }
+
+    #[test]
+    fn import_test() {
+        let program = load_files(
+            "game/main.koala",
+            &[
+                (
+                    "game/main.koala",
+                    "
+                    import \"lib/math.koala\"
+                    use counter
+
+                    fn main() {
+                      global count = 100
+                      print(math::gcd(12, 18))
+                      counter::bump()
+                      counter::bump()
+                      print(counter::total())
+                      print(count)
+                    }
+                    ",
+                ),
+                (
+                    "game/lib/math.koala",
+                    "
+                    fn gcd(a, b) {
+                      if b == 0 {
+                        return a
+                      }
+                      return gcd(b, a - (a / b) * b)
+                    }
+                    ",
+                ),
+                (
+                    "game/counter.koala",
+                    "
+                    import \"./lib/../lib/math.koala\"
+
+                    fn bump() {
+                      global count = total() + math::gcd(4, 6)
+                    }
+
+                    fn total() {
+                      return count
+                    }
+                    ",
+                ),
+            ],
+        )
+        .unwrap();
+
+        let mut ids: Vec<&str> = program.0.iter().map(|def| def.id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(
+            ids,
+            ["counter::bump", "counter::total", "main", "math::gcd"]
+        );
+        // each module has its own global named count
+        assert_eq!(run(program), "64100");
+    }
+
+    #[test]
+    fn cycle_test() {
+        let err = load_error(
+            "main.koala",
+            &[
+                ("main.koala", "use a\nfn main() {}"),
+                ("a.koala", "use b\nfn f() {}"),
+                ("b.koala", "import \"a.koala\"\nfn g() {}"),
+            ],
+        );
+        assert_eq!(
+            err,
+            ModuleError::Cycle(vec!["a.koala".into(), "b.koala".into(), "a.koala".into()])
+        );
+        assert_eq!(
+            err.to_string(),
+            "import cycle: a.koala -> b.koala -> a.koala"
+        );
+    }
+
+    #[test]
+    fn errors_test() {
+        let files = [
+            (
+                "main.koala",
+                "use math\nfn main() { print(math::lcm(1, 2)) }",
+            ),
+            ("math.koala", "fn gcd(a, b) { return a }"),
+            ("other.koala", "fn main() { print(math::gcd(1, 2)) }"),
+            (
+                "twice.koala",
+                "use math\nimport \"lib/math.koala\"\nfn main() {}",
+            ),
+            ("lib/math.koala", "fn gcd(a, b) { return b }"),
+        ];
+        assert_eq!(
+            load_error("main.koala", &files),
+            ModuleError::UnknownFunction {
+                path: "main.koala".into(),
+                id: "math::lcm".to_string()
+            }
+        );
+        assert_eq!(
+            load_error("other.koala", &files),
+            ModuleError::UnknownModule {
+                path: "other.koala".into(),
+                namespace: "math".to_string()
+            }
+        );
+        assert_eq!(
+            load_error("twice.koala", &files),
+            ModuleError::DuplicateNamespace {
+                namespace: "math".to_string(),
+                paths: ["math.koala".into(), "lib/math.koala".into()]
+            }
+        );
+        assert!(matches!(
+            load_files("missing.koala", &files),
+            Err(ModuleError::Read { .. })
+        ));
+    }
+}
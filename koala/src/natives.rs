@@ -0,0 +1,201 @@
+//! Host functions which programs call by name, through the `CALL_NATIVE` instruction
+
+use std::time::Instant;
+
+/// Natives every program can call, with their argument counts, in the order of their indexes.
+/// `len()` is not among them, since the compiler replaces it with the length of the array
+pub const STANDARD: [(&str, usize); 8] = [
+    ("abs", 1),
+    ("min", 2),
+    ("max", 2),
+    ("pow", 2),
+    ("sqrt", 1),
+    ("assert", 1),
+    ("exit", 1),
+    ("clock", 0),
+];
+
+/// Index of a standard native by name
+pub fn standard_index(id: &str) -> Option<usize> {
+    STANDARD.iter().position(|(name, _)| *name == id)
+}
+
+/// Why a native did not return a value
+#[derive(Debug, PartialEq)]
+pub enum NativeError {
+    /// The program asked to stop with an exit code
+    Exit(i32),
+    /// The program cannot continue, such as when an assertion fails
+    Failed(String),
+}
+
+/// A native receives its arguments first to last
+pub type NativeFn<'a> = Box<dyn FnMut(&[i32]) -> Result<i32, NativeError> + 'a>;
+
+/// Callback returning the milliseconds since the program started, for `clock()`
+pub type ClockCallback<'a> = Box<dyn FnMut() -> i32 + 'a>;
+
+struct Native<'a> {
+    name: String,
+    arity: usize,
+    function: NativeFn<'a>,
+}
+
+/// The natives a VM can call: the standard ones, followed by any the embedder registered
+pub struct Natives<'a> {
+    clock: Option<ClockCallback<'a>>,
+    /// When the program started, for `clock()` when there is no callback
+    start: Option<Instant>,
+    registered: Vec<Native<'a>>,
+}
+
+impl<'a> Default for Natives<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Natives<'a> {
+    pub fn new() -> Self {
+        Natives {
+            clock: None,
+            start: None,
+            registered: Vec::new(),
+        }
+    }
+
+    /// Measure time for `clock()` with a callback, such as on hosts without `Instant`
+    pub fn set_clock(&mut self, clock: ClockCallback<'a>) {
+        self.clock = Some(clock);
+    }
+
+    /// Start timing the program, unless a callback measures the time instead
+    /// or the program is already being timed
+    pub fn start_clock(&mut self) {
+        if self.clock.is_none() && self.start.is_none() {
+            self.start = Some(Instant::now());
+        }
+    }
+
+    /// Add a native after the ones already registered, returning its index.
+    /// Programs have to be compiled with the same natives registered in the same order,
+    /// see `CompilerContext::register_native`
+    pub fn register(&mut self, name: &str, arity: usize, function: NativeFn<'a>) -> usize {
+        self.registered.push(Native {
+            name: name.to_string(),
+            arity,
+            function,
+        });
+        STANDARD.len() + self.registered.len() - 1
+    }
+
+    /// Names and argument counts of the registered natives, in the order of their indexes
+    pub fn signatures(&self) -> Vec<(String, usize)> {
+        self.registered
+            .iter()
+            .map(|native| (native.name.clone(), native.arity))
+            .collect()
+    }
+
+    pub fn call(&mut self, index: usize, args: &[i32]) -> Result<i32, NativeError> {
+        let registered = index
+            .checked_sub(STANDARD.len())
+            .and_then(|index| self.registered.get_mut(index));
+        if let Some(native) = registered {
+            check_arity(&native.name, native.arity, args)?;
+            return (native.function)(args);
+        }
+        let (name, arity) = match STANDARD.get(index) {
+            Some(native) => *native,
+            None => {
+                return Err(NativeError::Failed(format!(
+                    "no native with index {}",
+                    index
+                )))
+            }
+        };
+        check_arity(name, arity, args)?;
+
+        match (name, args) {
+            ("abs", [x]) => Ok(x.wrapping_abs()),
+            ("min", [a, b]) => Ok(*a.min(b)),
+            ("max", [a, b]) => Ok(*a.max(b)),
+            ("pow", [base, exp]) => match *exp >= 0 {
+                true => Ok(base.wrapping_pow(*exp as u32)),
+                false => Err(NativeError::Failed(format!(
+                    "negative exponent in pow({}, {})",
+                    base, exp
+                ))),
+            },
+            // every i32 is exact as a double, and so is the floor of its square root
+            ("sqrt", [x]) => match *x >= 0 {
+                true => Ok((*x as f64).sqrt() as i32),
+                false => Err(NativeError::Failed(format!(
+                    "sqrt({}) of a negative number",
+                    x
+                ))),
+            },
+            ("assert", [cond]) => match *cond != 0 {
+                true => Ok(0),
+                false => Err(NativeError::Failed("assertion failed".to_string())),
+            },
+            ("exit", [code]) => Err(NativeError::Exit(*code)),
+            ("clock", []) => Ok(match (&mut self.clock, self.start) {
+                (Some(clock), _) => clock(),
+                (None, Some(start)) => start.elapsed().as_millis() as i32,
+                (None, None) => 0,
+            }),
+            _ => unreachable!("standard native {}() is not implemented", name),
+        }
+    }
+}
+
+fn check_arity(name: &str, arity: usize, args: &[i32]) -> Result<(), NativeError> {
+    match args.len() == arity {
+        true => Ok(()),
+        false => Err(NativeError::Failed(format!(
+            "{}() takes {} arguments but was passed {}",
+            name,
+            arity,
+            args.len()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_test() {
+        let mut natives = Natives::new();
+        let mut call = |name: &str, args: &[i32]| natives.call(standard_index(name).unwrap(), args);
+        assert_eq!(call("abs", &[-4]), Ok(4));
+        assert_eq!(call("abs", &[i32::MIN]), Ok(i32::MIN));
+        assert_eq!(call("min", &[3, -2]), Ok(-2));
+        assert_eq!(call("max", &[3, -2]), Ok(3));
+        assert_eq!(call("pow", &[-3, 3]), Ok(-27));
+        assert_eq!(call("pow", &[5, 0]), Ok(1));
+        assert!(call("pow", &[2, -1]).is_err());
+        assert_eq!(call("sqrt", &[99]), Ok(9));
+        assert_eq!(call("sqrt", &[i32::MAX]), Ok(46340));
+        assert!(call("sqrt", &[-1]).is_err());
+        assert_eq!(call("assert", &[2]), Ok(0));
+        assert!(call("assert", &[0]).is_err());
+        assert_eq!(call("exit", &[3]), Err(NativeError::Exit(3)));
+        assert!(call("min", &[1]).is_err());
+    }
+
+    #[test]
+    fn registered_test() {
+        let mut natives = Natives::new();
+        let index = natives.register("double", 1, Box::new(|args| Ok(args[0] * 2)));
+        assert_eq!(index, STANDARD.len());
+        assert_eq!(natives.signatures(), vec![("double".to_string(), 1)]);
+        assert_eq!(natives.call(index, &[21]), Ok(42));
+        assert!(natives.call(index + 1, &[]).is_err());
+
+        natives.set_clock(Box::new(|| 7));
+        assert_eq!(natives.call(standard_index("clock").unwrap(), &[]), Ok(7));
+    }
+}
@@ -0,0 +1,102 @@
+use crate::instructions;
+use crate::kvm::{Frame, Instruction, OutputCallback};
+
+/// The state of the VM just before it executes an instruction
+pub struct Step<'s> {
+    /// Bytecode address of the instruction
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub stack: &'s [i32],
+    pub frames: &'s [Frame],
+    pub globals: &'s [i32],
+}
+
+impl<'s> Step<'s> {
+    pub fn opcode(&self) -> u32 {
+        self.instruction.opcode()
+    }
+}
+
+/// Receives typed events from the VM as it runs.
+///
+/// Every event carries the bytecode address of the instruction which caused it.
+/// All of the methods do nothing by default, so an observer only implements the ones it needs
+#[allow(unused_variables)]
+pub trait VmObserver {
+    /// An instruction is about to execute
+    fn on_step(&mut self, step: &Step) {}
+    /// A frame was pushed for the function at `fn_addr`, holding the arguments
+    fn on_call(&mut self, pc: usize, fn_addr: usize, args: &[i32]) {}
+    /// The frame of the function at `fn_addr` was popped
+    fn on_return(&mut self, pc: usize, fn_addr: usize, locals: &[i32]) {}
+    fn on_print(&mut self, pc: usize, message: &str) {}
+    fn on_store_local(&mut self, pc: usize, offset: usize, old: i32, new: i32) {}
+    fn on_store_global(&mut self, pc: usize, offset: usize, old: i32, new: i32) {}
+    /// The VM hit an error it cannot continue from, and is about to panic
+    fn on_error(&mut self, pc: usize, message: &str) {}
+}
+
+/// Writes a line of text for every event, which is what `koala --debug` prints
+pub struct DebugPrinter<'a> {
+    pipe: OutputCallback<'a>,
+}
+
+impl<'a> DebugPrinter<'a> {
+    pub fn new(pipe: OutputCallback<'a>) -> Self {
+        DebugPrinter { pipe }
+    }
+}
+
+impl<'a> VmObserver for DebugPrinter<'a> {
+    fn on_step(&mut self, step: &Step) {
+        (self.pipe)(&format!(
+            "\nPC: {:<4} {:<16} {:<12} stack: {:?} locals: {:?} globals: {:?}\n",
+            step.pc,
+            instructions::mnemonic(step.opcode()),
+            operands(step.instruction),
+            step.stack,
+            step.frames
+                .last()
+                .map_or(&[][..], |frame| &frame.locals[..]),
+            step.globals,
+        ));
+    }
+
+    fn on_call(&mut self, _pc: usize, fn_addr: usize, args: &[i32]) {
+        (self.pipe)(&format!("calling @{} with {:?}\n", fn_addr, args));
+    }
+
+    fn on_return(&mut self, _pc: usize, fn_addr: usize, _locals: &[i32]) {
+        (self.pipe)(&format!("returning from @{}\n", fn_addr));
+    }
+
+    fn on_store_local(&mut self, _pc: usize, offset: usize, old: i32, new: i32) {
+        (self.pipe)(&format!("storing local {}: {} -> {}\n", offset, old, new));
+    }
+
+    fn on_store_global(&mut self, _pc: usize, offset: usize, old: i32, new: i32) {
+        (self.pipe)(&format!("storing global {}: {} -> {}\n", offset, old, new));
+    }
+
+    fn on_error(&mut self, pc: usize, message: &str) {
+        (self.pipe)(&format!("error at {}: {}\n", pc, message));
+    }
+}
+
+/// Operands of an instruction as they would be written after its mnemonic
+fn operands(instruction: Instruction) -> String {
+    match instruction {
+        Instruction::LocalLoad(offset)
+        | Instruction::LocalStore(offset)
+        | Instruction::GlobalLoad(offset)
+        | Instruction::GlobalStore(offset) => offset.to_string(),
+        Instruction::Jump(target) | Instruction::Beqz(target) | Instruction::Bnez(target) => {
+            format!("#{}", target)
+        }
+        Instruction::Call { argc, target } => format!("{} #{}", argc, target),
+        Instruction::CallNative { argc, index } => format!("{} {}", argc, index),
+        Instruction::Print(operand) | Instruction::Read(operand) => operand.to_string(),
+        Instruction::Push(val) => val.to_string(),
+        _ => String::new(),
+    }
+}
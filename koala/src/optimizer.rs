@@ -0,0 +1,334 @@
+use super::grammar::{
+    BinExpr, BinOp, Expr, For, ForIter, FunctionCall, FunctionDefinition, If, IfElse, Program,
+    Statement,
+};
+
+/// Trait for AST nodes which can be rewritten into a cheaper but equivalent form
+pub trait Optimize {
+    fn optimize(self) -> Self;
+}
+
+impl Optimize for Program {
+    fn optimize(self) -> Self {
+        Program(self.0.into_iter().map(Optimize::optimize).collect(), self.1)
+    }
+}
+
+impl Optimize for FunctionDefinition {
+    fn optimize(self) -> Self {
+        FunctionDefinition {
+            body: optimize_block(self.body),
+            ..self
+        }
+    }
+}
+
+impl Optimize for FunctionCall {
+    fn optimize(self) -> Self {
+        FunctionCall {
+            id: self.id,
+            args: self.args.into_iter().map(Optimize::optimize).collect(),
+        }
+    }
+}
+
+/// Optimize a list of statements,
+/// splicing in the bodies of branches whose condition is known at compile time
+fn optimize_block(stmts: Vec<Statement>) -> Vec<Statement> {
+    let mut block = Vec::new();
+    for stmt in stmts {
+        match stmt {
+            Statement::If(if_data) => {
+                let If { expr, stmts } = *if_data;
+                let expr = expr.optimize();
+                match expr.constant_value() {
+                    // the branch is always taken, so the condition check can be dropped
+                    Some(val) if val != 0 => splice_block(&mut block, stmts),
+                    // the branch is never taken
+                    Some(_) => {}
+                    None => block.push(Statement::If(Box::new(If {
+                        expr,
+                        stmts: optimize_block(stmts),
+                    }))),
+                }
+            }
+            Statement::IfElse(if_else) => {
+                let IfElse {
+                    expr,
+                    stmts,
+                    else_stmts,
+                } = *if_else;
+                let expr = expr.optimize();
+                match expr.constant_value() {
+                    Some(val) if val != 0 => splice_block(&mut block, stmts),
+                    Some(_) => splice_block(&mut block, else_stmts),
+                    None => block.push(Statement::IfElse(Box::new(IfElse {
+                        expr,
+                        stmts: optimize_block(stmts),
+                        else_stmts: optimize_block(else_stmts),
+                    }))),
+                }
+            }
+            Statement::While { cond, stmts, label } => {
+                let cond = cond.optimize();
+                // a loop which is never entered can be removed entirely
+                if cond.constant_value() != Some(0) {
+                    block.push(Statement::While {
+                        cond,
+                        stmts: optimize_block(stmts),
+                        label,
+                    });
+                }
+            }
+            stmt => block.push(stmt.optimize()),
+        }
+    }
+
+    block
+}
+
+/// Splice the body of a branch which is always taken into the enclosing block,
+/// unless it declares variables, which would then outlive the branch
+fn splice_block(block: &mut Vec<Statement>, stmts: Vec<Statement>) {
+    let stmts = optimize_block(stmts);
+    let declares = stmts.iter().any(|stmt| {
+        matches!(
+            stmt,
+            Statement::Let { .. } | Statement::ArrayInstantiation { global: false, .. }
+        )
+    });
+    if declares {
+        block.push(Statement::If(Box::new(If {
+            expr: Expr::BoolLit(true),
+            stmts,
+        })));
+    } else {
+        block.extend(stmts);
+    }
+}
+
+impl Optimize for Statement {
+    fn optimize(self) -> Self {
+        match self {
+            Self::Print { expr, newline } => Self::Print {
+                expr: expr.map(Optimize::optimize),
+                newline,
+            },
+            Self::ReturnExpr(expr) => Self::ReturnExpr(expr.optimize()),
+            Self::Let { id, expr, mutable } => Self::Let {
+                id,
+                expr: expr.optimize(),
+                mutable,
+            },
+            Self::VarAssignment { id, expr, global } => Self::VarAssignment {
+                id,
+                expr: expr.optimize(),
+                global,
+            },
+            Self::ArrayInstantiation {
+                id,
+                size,
+                elements,
+                global,
+            } => Self::ArrayInstantiation {
+                id,
+                size: size.map(Optimize::optimize),
+                elements: elements
+                    .map(|elements| elements.into_iter().map(Optimize::optimize).collect()),
+                global,
+            },
+            Self::ArrayIndexAssignment { id, index, expr } => Self::ArrayIndexAssignment {
+                id,
+                index: index.optimize(),
+                expr: expr.optimize(),
+            },
+            Self::FunctionCall(func_call) => Self::FunctionCall(func_call.optimize()),
+            Self::For(for_data) => {
+                let For {
+                    id,
+                    iter,
+                    stmts,
+                    label,
+                } = *for_data;
+                let iter = match iter {
+                    ForIter::Range {
+                        start,
+                        end,
+                        inclusive,
+                        step,
+                    } => ForIter::Range {
+                        start: start.optimize(),
+                        end: end.optimize(),
+                        inclusive,
+                        step: step.map(Optimize::optimize),
+                    },
+                    iter => iter,
+                };
+                Self::For(Box::new(For {
+                    id,
+                    iter,
+                    stmts: optimize_block(stmts),
+                    label,
+                }))
+            }
+            // branches are handled in `optimize_block` since they may be removed
+            stmt => stmt,
+        }
+    }
+}
+
+impl Optimize for Expr {
+    fn optimize(self) -> Self {
+        match self {
+            Self::ArrayIndex { id, expr } => Self::ArrayIndex {
+                id,
+                expr: Box::new(expr.optimize()),
+            },
+            Self::FunctionCall(func_call) => Self::FunctionCall(func_call.optimize()),
+            Self::BinExpr(bin_expr) => bin_expr.simplify(),
+            expr => expr,
+        }
+    }
+}
+
+impl Expr {
+    /// The value this expression will place on the stack, when it is known at compile time
+    fn constant_value(&self) -> Option<i32> {
+        match self {
+            // the VM reinterprets immediates as signed values
+            Self::IntLit(int) => Some(*int as i32),
+            Self::BoolLit(truthy) => Some(*truthy as i32),
+            _ => None,
+        }
+    }
+
+    /// Whether dropping this expression is unobservable.
+    /// Function calls may print, and indexing or division may fail at runtime.
+    fn is_pure(&self) -> bool {
+        match self {
+            Self::IntLit(_) | Self::BoolLit(_) | Self::Variable { .. } => true,
+            Self::BinExpr(bin_expr) => {
+                !matches!(bin_expr.binop, BinOp::Div)
+                    && bin_expr.op1.is_pure()
+                    && bin_expr.op2.is_pure()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl BinExpr {
+    /// Fold or reduce the expression after optimizing both operands
+    fn simplify(self) -> Expr {
+        let op1 = self.op1.optimize();
+        let op2 = self.op2.optimize();
+
+        match (op1.constant_value(), op2.constant_value()) {
+            (Some(lhs), Some(rhs)) => {
+                if let Some(val) = fold(&self.binop, lhs, rhs) {
+                    return Expr::IntLit(val as u32);
+                }
+            }
+            // x + 0, x - 0, x * 1, x / 1
+            (None, Some(0)) if matches!(self.binop, BinOp::Plus | BinOp::Minus) => return op1,
+            (None, Some(1)) if matches!(self.binop, BinOp::Mul | BinOp::Div) => return op1,
+            // 0 + x, 1 * x
+            (Some(0), None) if matches!(self.binop, BinOp::Plus) => return op2,
+            (Some(1), None) if matches!(self.binop, BinOp::Mul) => return op2,
+            // x * 0, 0 * x
+            (None, Some(0)) if matches!(self.binop, BinOp::Mul) && op1.is_pure() => {
+                return Expr::IntLit(0)
+            }
+            (Some(0), None) if matches!(self.binop, BinOp::Mul) && op2.is_pure() => {
+                return Expr::IntLit(0)
+            }
+            _ => {}
+        }
+
+        Expr::BinExpr(Box::new(BinExpr {
+            op1,
+            op2,
+            binop: self.binop,
+        }))
+    }
+}
+
+/// Evaluate an operator the same way the VM would.
+/// Operations which would fault at runtime are left for the VM to report.
+pub(crate) fn fold(binop: &BinOp, lhs: i32, rhs: i32) -> Option<i32> {
+    match binop {
+        BinOp::Plus => lhs.checked_add(rhs),
+        BinOp::Minus => lhs.checked_sub(rhs),
+        BinOp::Mul => lhs.checked_mul(rhs),
+        BinOp::Div => lhs.checked_div(rhs),
+        BinOp::Less => Some((lhs < rhs) as i32),
+        BinOp::LessOrEqual => Some((lhs <= rhs) as i32),
+        BinOp::Greater => Some((lhs > rhs) as i32),
+        BinOp::GreaterOrEqual => Some((lhs >= rhs) as i32),
+        BinOp::Equal => Some((lhs == rhs) as i32),
+        BinOp::NotEqual => Some((lhs != rhs) as i32),
+        BinOp::Or => Some((lhs != 0 || rhs != 0) as i32),
+        BinOp::And => Some((lhs != 0 && rhs != 0) as i32),
+    }
+}
+
+#[cfg(test)]
+use crate::{compiler::CompilerContext, parser::parse_code};
+
+/// Each case is a pair of programs that should compile to identical code
+/// once the first has been optimized
+#[cfg(test)]
+macro_rules! optimizer_tests {
+    ($($name:ident: $value:expr,)*) => {$(
+        #[test]
+        fn $name() {
+            let (code, expected) = $value;
+            let optimized = parse_code(code).unwrap().optimize();
+            let expected = parse_code(expected).unwrap();
+            assert_eq!(
+                optimized.code_gen(&mut CompilerContext::new()),
+                expected.code_gen(&mut CompilerContext::new()),
+            );
+        }
+    )*}
+}
+
+#[cfg(test)]
+optimizer_tests! {
+    fold_add_test: ("fn main() { print(2+5) }", "fn main() { print(7) }"),
+    fold_nested_test: (
+        "fn main() { print((2 + 5 + 3) - ((2 * 3) - (10/2))) }",
+        "fn main() { print(9) }",
+    ),
+    fold_comparison_test: ("fn main() { print(1 < 2) }", "fn main() { print(1) }"),
+    fold_logical_test: ("fn main() { print(0 || 3) }", "fn main() { print(1) }"),
+    no_fold_div_zero_test: ("fn main() { print(1 / 0) }", "fn main() { print(1 / 0) }"),
+    add_zero_test: (
+        "fn main() { let x = 3 print(x + 0) print(0 + x) }",
+        "fn main() { let x = 3 print(x) print(x) }",
+    ),
+    mul_one_test: (
+        "fn main() { let x = 3 print(x * 1) print(1 * x) }",
+        "fn main() { let x = 3 print(x) print(x) }",
+    ),
+    mul_zero_test: (
+        "fn main() { let x = 3 print(x * 0) print(0 * x) }",
+        "fn main() { let x = 3 print(0) print(0) }",
+    ),
+    mul_zero_call_test: (
+        "fn main() { print(f() * 0) } fn f() { print(1) return 1 }",
+        "fn main() { print(f() * 0) } fn f() { print(1) return 1 }",
+    ),
+    if_true_test: (
+        "fn main() { if 1 < 2 { print(3) } }",
+        "fn main() { print(3) }",
+    ),
+    if_false_test: (
+        "fn main() { if 2 - 2 { print(3) } print(4) }",
+        "fn main() { print(4) }",
+    ),
+    while_false_test: (
+        "fn main() { while 0 { print(3) } }",
+        "fn main() { }",
+    ),
+}
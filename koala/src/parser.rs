@@ -1,7 +1,9 @@
 use super::grammar::{
-    BinExpr, BinOp, Expr, FunctionCall, FunctionDefinition, If, Program, Statement,
+    BinExpr, BinOp, Const, Expr, For, ForIter, FunctionCall, FunctionDefinition, If, Import,
+    Program, SourceFile, Statement,
 };
 use peg::{error::ParseError, str::LineCol};
+use std::path::Path;
 use std::vec;
 
 peg::parser! {
@@ -19,6 +21,14 @@ peg::parser! {
         rule identifier() -> String
             = id:$(['a'..='z' | 'A'..='Z']+['a'..='z' | 'A'..='Z' |  '0'..='9' | '_']*) { id.to_string() }
 
+        /// Functions of other modules are named through their namespace
+        rule function_name() -> String
+            = namespace:identifier() "::" id:identifier() { format!("{}::{}", namespace, id) }
+            / identifier()
+
+        rule path() -> String
+            = "\"" path:$([^'"' | '\n']+) "\"" { path.to_string() }
+
         rule binop() -> BinOp
             = "*" { BinOp::Mul }
             / "/" { BinOp::Div }
@@ -43,7 +53,7 @@ peg::parser! {
             / _ { vec![] }
 
         rule function_call() -> FunctionCall
-            = id:identifier() "(" args:args() ")" { FunctionCall { id, args } }
+            = id:function_name() "(" args:args() ")" { FunctionCall { id, args } }
 
         rule arg_defs() -> Vec<String>
             = _ id:identifier() _ "," _ args:arg_defs() {
@@ -116,8 +126,37 @@ peg::parser! {
                 }))
             }
 
+        /// Loops can be named for `break` and `continue`, as in `'outer: while ...`
+        rule label() -> String
+            = "'" id:identifier() { id }
+
+        rule loop_label() -> String
+            = label:label() _ ":" _ { label }
+
         rule while() -> Statement
-            = "while" _ cond:compound_expr() _ "{" stmts:statements() "}" { Statement::While { cond, stmts } }
+            = label:loop_label()? "while" _ cond:compound_expr() _ "{" stmts:statements() "}" {
+                Statement::While { cond, stmts, label }
+            }
+
+        rule for() -> Statement
+            = label:loop_label()? "for " _ id:identifier() _ "in " _ start:compound_expr() _ ".." inclusive:"="? _ end:compound_expr() _
+              step:("step " _ step:compound_expr() _ { step })? "{" stmts:statements() "}" {
+                let iter = ForIter::Range { start, end, inclusive: inclusive.is_some(), step };
+                Statement::For(Box::new(For { id, iter, stmts, label }))
+            }
+            / label:loop_label()? "for " _ id:identifier() _ "in " _ array:identifier() _ "{" stmts:statements() "}" {
+                Statement::For(Box::new(For { id, iter: ForIter::Array(array), stmts, label }))
+            }
+
+        /// Keywords which can't be followed by the rest of an identifier
+        rule keyword_end()
+            = !['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
+
+        rule break() -> Statement
+            = "break" keyword_end() label:([' ']+ label:label() { label })? { Statement::Break(label) }
+
+        rule continue() -> Statement
+            = "continue" keyword_end() label:([' ']+ label:label() { label })? { Statement::Continue(label) }
 
         rule return() -> Statement
             = "return" _ expr:compound_expr() { Statement::ReturnExpr(expr) }
@@ -127,12 +166,20 @@ peg::parser! {
             = print()
             / if()
             / while()
+            / for()
+            / break()
+            / continue()
             / return()
             / global_statement()
             // Function Call
             / f:function_call() { Statement::FunctionCall(f) }
+            // Variable Declaration
+            / ("let " _ "mut " / "var ") _ id:identifier() _ "=" _ expr:compound_expr() {
+                Statement::Let { id, expr, mutable: true }
+            }
+            / "let " _ id:identifier() _ "=" _ expr:compound_expr() { Statement::Let { id, expr, mutable: false } }
             // Variable Assignment
-            / "let "? _ id:identifier() _ "=" _ expr:compound_expr() { Statement::VarAssignment { id, expr, global: false } }
+            / id:identifier() _ "=" _ expr:compound_expr() { Statement::VarAssignment { id, expr, global: false } }
             / array()
 
         rule statements() -> Vec<Statement>
@@ -150,13 +197,33 @@ peg::parser! {
             /  "fn " _ id:identifier() "(" args:arg_defs() ")" _ "{" body:statements() "}" { FunctionDefinition { id, args, body, has_return_val: false } }
 
         /// Top Level list of function definitions
+        rule constant() -> Const
+            = "const " _ id:identifier() _ "=" _ expr:compound_expr() { Const { id, expr } }
+
         pub rule program() -> Program
             = _ f:function_definition() _ p:program() {
                 let mut program = p;
                 program.0.push(f);
                 return program;
             }
-            / _ f:function_definition() _ { Program(vec![f]) }
+            / _ c:constant() _ p:program() {
+                let mut program = p;
+                program.1.insert(0, c);
+                return program;
+            }
+            / _ f:function_definition() _ { Program(vec![f], vec![]) }
+            / _ c:constant() _ { Program(vec![], vec![c]) }
+
+        rule import() -> Import
+            = "import" _ path:path() {
+                let namespace = Path::new(&path).file_stem().unwrap_or_default().to_string_lossy().to_string();
+                Import { path, namespace }
+            }
+            / "use " _ namespace:identifier() { Import { path: format!("{}.koala", namespace), namespace } }
+
+        /// A file of a program, starting with the files it imports
+        pub rule source_file() -> SourceFile
+            = imports:(_ import:import() { import })* program:program() { SourceFile { imports, program } }
     }
 }
 
@@ -165,6 +232,11 @@ pub fn parse_code(code: &str) -> Result<Program, ParseError<LineCol>> {
     koala_parser::program(code)
 }
 
+/// Parse one file of a program, which can import others
+pub fn parse_source_file(code: &str) -> Result<SourceFile, ParseError<LineCol>> {
+    koala_parser::source_file(code)
+}
+
 macro_rules! parser_tests {
     ($($name:ident: $value:expr,)*) => {$(
         #[test]
@@ -262,6 +334,37 @@ parser_tests! {
         print(a+b)
     }
     ",
+    constants_test: "
+    const N = 10
+    fn main() {
+        let mut a = N
+        var b = 3
+        print(a+b)
+    }
+    const M = N * 2
+    ",
+    for_test: "
+    fn main() {
+        for i in 0..10 { print(i) }
+        for i in 1..=n step 2 {}
+        for x in arr {
+            print(x)
+        }
+    }
+    ",
+    break_continue_test: "
+    fn main() {
+        'outer: while 1 {
+            'inner: for i in 0..3 {
+                if i { continue 'outer }
+                continue
+            }
+            break 'outer
+            break
+        }
+        let breaking = 1
+    }
+    ",
     comparisons_parser_test: "
     fn main() {
         if 1 < 2 { }
@@ -273,3 +376,28 @@ parser_tests! {
     }
     ",
 }
+
+#[test]
+fn source_file_test() {
+    let file = parse_source_file(
+        "
+    import \"lib/math.koala\"
+    // imports come before any functions
+    use counter
+    fn main() {
+        print(math::gcd(4, 6))
+    }
+    ",
+    )
+    .unwrap();
+    let imports: Vec<(&str, &str)> = file
+        .imports
+        .iter()
+        .map(|import| (import.path.as_str(), import.namespace.as_str()))
+        .collect();
+    assert_eq!(
+        imports,
+        [("lib/math.koala", "math"), ("counter.koala", "counter")]
+    );
+    assert!(parse_code("use counter\nfn main() {}").is_err());
+}
@@ -0,0 +1,247 @@
+use crate::instructions::{self, BEQZ, BNEZ, CALL, END, JUMP, POP, PUSH, RET};
+use crate::symbols::SymbolTable;
+use std::collections::HashSet;
+
+/// A decoded instruction, remembering the address it was loaded from
+/// so that jump targets can be relocated once instructions are removed
+struct Inst {
+    addr: usize,
+    opcode: u32,
+    operands: Vec<u32>,
+}
+
+impl Inst {
+    /// Index of the operand holding a code address, if the instruction has one
+    fn target_operand(&self) -> Option<usize> {
+        match self.opcode {
+            JUMP | BEQZ | BNEZ => Some(0),
+            CALL => Some(1),
+            _ => None,
+        }
+    }
+
+    fn target(&self) -> Option<usize> {
+        self.target_operand().map(|i| self.operands[i] as usize)
+    }
+
+    /// Control never falls through to the following instruction
+    fn is_terminator(&self) -> bool {
+        matches!(self.opcode, JUMP | RET | END)
+    }
+
+    fn len(&self) -> usize {
+        1 + self.operands.len()
+    }
+}
+
+/// Run peephole optimizations over emitted KVM bytecode until nothing changes.
+///
+/// Code which cannot be decoded is returned untouched.
+pub fn optimize(code: &[u32]) -> Vec<u32> {
+    optimize_with_symbols(code, &SymbolTable::default()).0
+}
+
+/// Optimize bytecode, moving the symbols along with the functions they name
+pub fn optimize_with_symbols(code: &[u32], symbols: &SymbolTable) -> (Vec<u32>, SymbolTable) {
+    let mut insts = match decode(code) {
+        Some(insts) => insts,
+        None => return (code.to_vec(), symbols.clone()),
+    };
+
+    while thread_jumps(&mut insts) | remove_dead_code(&mut insts, code.len()) {}
+
+    relocate(&insts, code.len(), symbols)
+}
+
+fn decode(code: &[u32]) -> Option<Vec<Inst>> {
+    let mut insts = Vec::new();
+    let mut addr = 0;
+    while addr < code.len() {
+        let opcode = code[addr];
+        let operand_count = instructions::operand_count(opcode)?;
+        let operands = code.get(addr + 1..addr + 1 + operand_count)?.to_vec();
+        insts.push(Inst {
+            addr,
+            opcode,
+            operands,
+        });
+        addr += 1 + operand_count;
+    }
+
+    Some(insts)
+}
+
+/// Point jumps which land on another JUMP directly at its destination
+fn thread_jumps(insts: &mut [Inst]) -> bool {
+    let mut changed = false;
+    for i in 0..insts.len() {
+        if !matches!(insts[i].opcode, JUMP | BEQZ | BNEZ) {
+            continue;
+        }
+        let mut target = insts[i].operands[0] as usize;
+        // bound the number of hops, so that a loop of jumps cannot hang the optimizer
+        for _ in 0..insts.len() {
+            match find(insts, target) {
+                Some(next) if next.opcode == JUMP && next.operands[0] as usize != target => {
+                    target = next.operands[0] as usize;
+                }
+                _ => break,
+            }
+        }
+        if target != insts[i].operands[0] as usize {
+            insts[i].operands[0] = target as u32;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+/// Remove instructions that can never execute or have no effect:
+/// - anything after a JUMP, RET or END that is not a jump target
+/// - `PUSH x; POP`
+/// - a JUMP to the instruction directly after it
+fn remove_dead_code(insts: &mut Vec<Inst>, code_len: usize) -> bool {
+    let targets = jump_targets(insts);
+    let before = insts.len();
+
+    let mut kept: Vec<Inst> = Vec::with_capacity(insts.len());
+    let mut reachable = true;
+    for inst in insts.drain(..) {
+        if targets.contains(&inst.addr) {
+            reachable = true;
+        }
+        if !reachable {
+            continue;
+        }
+        reachable = !inst.is_terminator();
+
+        match kept.last() {
+            // the value pushed is discarded straight away
+            Some(prev)
+                if prev.opcode == PUSH && inst.opcode == POP && !targets.contains(&inst.addr) =>
+            {
+                kept.pop();
+            }
+            _ => kept.push(inst),
+        }
+    }
+    *insts = kept;
+
+    // jumps which would land on the next instruction anyway
+    let fallthrough_jumps: Vec<usize> = insts
+        .windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[0].opcode == JUMP && pair[0].target() == Some(pair[1].addr))
+        .map(|(i, _)| i)
+        .collect();
+    for i in fallthrough_jumps.into_iter().rev() {
+        insts.remove(i);
+    }
+
+    // keep targets pointing at a live instruction, so the next pass still sees them
+    let addrs: Vec<usize> = insts.iter().map(|inst| inst.addr).collect();
+    for inst in insts.iter_mut() {
+        if let Some(i) = inst.target_operand() {
+            let target = inst.operands[i] as usize;
+            let next = addrs.partition_point(|addr| *addr < target);
+            inst.operands[i] = addrs.get(next).copied().unwrap_or(code_len) as u32;
+        }
+    }
+
+    insts.len() != before
+}
+
+fn jump_targets(insts: &[Inst]) -> HashSet<usize> {
+    insts.iter().filter_map(Inst::target).collect()
+}
+
+fn find(insts: &[Inst], addr: usize) -> Option<&Inst> {
+    insts
+        .binary_search_by_key(&addr, |inst| inst.addr)
+        .ok()
+        .map(|i| &insts[i])
+}
+
+/// Lay the remaining instructions out contiguously and rewrite every code address.
+/// An address whose instruction was removed maps to the next instruction that was kept.
+fn relocate(insts: &[Inst], code_len: usize, symbols: &SymbolTable) -> (Vec<u32>, SymbolTable) {
+    let mut new_addrs = Vec::with_capacity(insts.len());
+    let mut addr = 0;
+    for inst in insts {
+        new_addrs.push(addr);
+        addr += inst.len();
+    }
+    let relocate_addr = |old: usize| -> u32 {
+        if old >= code_len {
+            return addr as u32;
+        }
+        match insts.binary_search_by_key(&old, |inst| inst.addr) {
+            Ok(i) | Err(i) => new_addrs.get(i).copied().unwrap_or(addr) as u32,
+        }
+    };
+
+    let mut code = Vec::with_capacity(addr);
+    for inst in insts {
+        code.push(inst.opcode);
+        let mut operands = inst.operands.clone();
+        if let Some(i) = inst.target_operand() {
+            operands[i] = relocate_addr(operands[i] as usize);
+        }
+        code.extend(operands);
+    }
+
+    let mut relocated = SymbolTable::default();
+    for (addr, name) in symbols.iter() {
+        relocated.insert(relocate_addr(addr) as usize, name);
+    }
+
+    (code, relocated)
+}
+
+#[cfg(test)]
+use crate::instructions::{IADD, LOCAL_LOAD, PRINT};
+
+#[cfg(test)]
+macro_rules! peephole_tests {
+    ($($name:ident: $value:expr,)*) => {$(
+        #[test]
+        fn $name() {
+            let (code, expected): (Vec<u32>, Vec<u32>) = $value;
+            assert_eq!(optimize(&code), expected);
+        }
+    )*}
+}
+
+#[cfg(test)]
+peephole_tests! {
+    push_pop_test: (
+        vec![CALL, 0, 4, END, PUSH, 3, POP, RET],
+        vec![CALL, 0, 4, END, RET],
+    ),
+    push_pop_target_test: (
+        // the POP is a branch target, so the PUSH cannot be paired with it
+        vec![PUSH, 1, BEQZ, 6, PUSH, 3, POP, END],
+        vec![PUSH, 1, BEQZ, 6, PUSH, 3, POP, END],
+    ),
+    double_ret_test: (
+        vec![CALL, 0, 4, END, PUSH, 0, RET, RET],
+        vec![CALL, 0, 4, END, PUSH, 0, RET],
+    ),
+    unreachable_after_jump_test: (
+        vec![JUMP, 4, PUSH, 1, PUSH, 2, PRINT, 1, END],
+        vec![PUSH, 2, PRINT, 1, END],
+    ),
+    jump_to_jump_test: (
+        vec![PUSH, 0, BEQZ, 8, PUSH, 1, PRINT, 1, JUMP, 12, PUSH, 2, END],
+        vec![PUSH, 0, BEQZ, 8, PUSH, 1, PRINT, 1, END],
+    ),
+    relocate_call_test: (
+        vec![CALL, 0, 7, END, PUSH, 9, POP, LOCAL_LOAD, 0, LOCAL_LOAD, 0, IADD, RET],
+        vec![CALL, 0, 4, END, LOCAL_LOAD, 0, LOCAL_LOAD, 0, IADD, RET],
+    ),
+    undecodable_test: (
+        vec![PUSH],
+        vec![PUSH],
+    ),
+}
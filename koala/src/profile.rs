@@ -0,0 +1,321 @@
+use crate::instructions;
+use crate::observer::{Step, VmObserver};
+use crate::symbols::SymbolTable;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Execution statistics, gathered by observing the VM as it runs.
+///
+/// Functions are keyed by the bytecode address they start at, the same as `Frame::fn_addr`,
+/// and the code which runs outside of any function is counted under address 0
+#[derive(Debug)]
+pub struct Profile {
+    instructions: usize,
+    opcodes: HashMap<u32, usize>,
+    functions: BTreeMap<usize, FunctionProfile>,
+    max_stack_depth: usize,
+    max_call_depth: usize,
+    /// Number of frames each function currently has on the call stack,
+    /// and the instruction count when the outermost one was entered
+    active: HashMap<usize, (usize, usize)>,
+    /// Every distinct call stack seen, as a tree rooted at the code outside of any function
+    stacks: Vec<StackNode>,
+    children: HashMap<(usize, usize), usize>,
+    /// Node of the call stack currently executing
+    current_stack: usize,
+}
+
+#[derive(Debug)]
+struct StackNode {
+    parent: usize,
+    fn_addr: usize,
+    /// Instructions executed with exactly this call stack
+    instructions: usize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub calls: usize,
+    /// Instructions executed while the function was anywhere on the call stack
+    pub inclusive: usize,
+    /// Instructions executed in the function itself
+    pub exclusive: usize,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            instructions: 0,
+            opcodes: HashMap::new(),
+            functions: BTreeMap::new(),
+            max_stack_depth: 0,
+            max_call_depth: 0,
+            active: HashMap::new(),
+            stacks: vec![StackNode {
+                parent: 0,
+                fn_addr: 0,
+                instructions: 0,
+            }],
+            children: HashMap::new(),
+            current_stack: 0,
+        }
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.instructions
+    }
+
+    /// Number of times each opcode was executed by mnemonic, the most frequent first
+    pub fn opcode_counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = self
+            .opcodes
+            .iter()
+            .map(|(opcode, count)| (instructions::mnemonic(*opcode), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts
+    }
+
+    /// Statistics for each function by address.
+    /// Functions which are still running count everything executed since they were entered
+    pub fn functions(&self) -> BTreeMap<usize, FunctionProfile> {
+        let mut functions = self.functions.clone();
+        // everything was executed by the code outside of the functions
+        functions.entry(0).or_default().inclusive = self.instructions;
+        for (fn_addr, (_, entered)) in &self.active {
+            functions.entry(*fn_addr).or_default().inclusive += self.instructions - entered;
+        }
+        functions
+    }
+
+    pub fn max_stack_depth(&self) -> usize {
+        self.max_stack_depth
+    }
+
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth
+    }
+
+    /// Instruction counts for each distinct call stack, as folded stack lines
+    /// (`main;fib;fib 1234`) which flamegraph tools take as input.
+    /// Instructions run outside of any function are left out
+    pub fn folded_stacks(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, node)| node.instructions > 0)
+            .map(|(mut i, node)| {
+                let mut names = Vec::new();
+                while i != 0 {
+                    names.push(symbols.name(self.stacks[i].fn_addr));
+                    i = self.stacks[i].parent;
+                }
+                names.reverse();
+                format!("{} {}", names.join(";"), node.instructions)
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    pub fn to_json(&self, symbols: &SymbolTable) -> String {
+        let opcodes: serde_json::Map<String, serde_json::Value> = self
+            .opcode_counts()
+            .into_iter()
+            .map(|(mnemonic, count)| (mnemonic.to_string(), json!(count)))
+            .collect();
+        let functions: Vec<serde_json::Value> = self
+            .functions()
+            .iter()
+            .map(|(addr, function)| {
+                json!({
+                    "addr": addr,
+                    "name": function_name(*addr, symbols),
+                    "calls": function.calls,
+                    "inclusive": function.inclusive,
+                    "exclusive": function.exclusive,
+                })
+            })
+            .collect();
+        json!({
+            "instructions": self.instructions,
+            "max_stack_depth": self.max_stack_depth,
+            "max_call_depth": self.max_call_depth,
+            "opcodes": opcodes,
+            "functions": functions,
+        })
+        .to_string()
+    }
+
+    /// The statistics as a table, naming functions from the symbols
+    pub fn table(&self, symbols: &SymbolTable) -> String {
+        let mut table = String::new();
+        let mut line = |line: String| {
+            table.push_str(&line);
+            table.push('\n');
+        };
+
+        line(format!("{:<18} {:>12}", "instructions", self.instructions));
+        line(format!(
+            "{:<18} {:>12}",
+            "max stack depth", self.max_stack_depth
+        ));
+        line(format!(
+            "{:<18} {:>12}",
+            "max call depth", self.max_call_depth
+        ));
+
+        line(String::new());
+        line(format!("{:<18} {:>12}", "opcode", "count"));
+        for (mnemonic, count) in self.opcode_counts() {
+            line(format!("{:<18} {:>12}", mnemonic, count));
+        }
+
+        line(String::new());
+        line(format!(
+            "{:<18} {:>12} {:>12} {:>12}",
+            "function", "calls", "inclusive", "exclusive"
+        ));
+        for (addr, function) in &self.functions() {
+            line(format!(
+                "{:<18} {:>12} {:>12} {:>12}",
+                function_name(*addr, symbols),
+                function.calls,
+                function.inclusive,
+                function.exclusive
+            ));
+        }
+
+        table
+    }
+}
+
+impl VmObserver for Profile {
+    fn on_step(&mut self, step: &Step) {
+        let fn_addr = step.frames.last().map_or(0, |frame| frame.fn_addr);
+        self.instructions += 1;
+        *self.opcodes.entry(step.opcode()).or_insert(0) += 1;
+        self.functions.entry(fn_addr).or_default().exclusive += 1;
+        self.stacks[self.current_stack].instructions += 1;
+        self.max_stack_depth = self.max_stack_depth.max(step.stack.len());
+        self.max_call_depth = self.max_call_depth.max(step.frames.len());
+    }
+
+    fn on_call(&mut self, _pc: usize, fn_addr: usize, _args: &[i32]) {
+        self.functions.entry(fn_addr).or_default().calls += 1;
+        let next_stack = self.stacks.len();
+        let parent = self.current_stack;
+        self.current_stack = *self.children.entry((parent, fn_addr)).or_insert(next_stack);
+        if self.current_stack == next_stack {
+            self.stacks.push(StackNode {
+                parent,
+                fn_addr,
+                instructions: 0,
+            });
+        }
+        let (frames, entered) = self.active.entry(fn_addr).or_insert((0, 0));
+        if *frames == 0 {
+            *entered = self.instructions;
+        }
+        *frames += 1;
+    }
+
+    /// Recursive calls only count towards the inclusive total once, when the outermost returns
+    fn on_return(&mut self, _pc: usize, fn_addr: usize, _locals: &[i32]) {
+        self.current_stack = self.stacks[self.current_stack].parent;
+        if let Some((frames, entered)) = self.active.get_mut(&fn_addr) {
+            *frames -= 1;
+            if *frames == 0 {
+                let inclusive = self.instructions - *entered;
+                self.active.remove(&fn_addr);
+                self.functions.entry(fn_addr).or_default().inclusive += inclusive;
+            }
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.table(&SymbolTable::default()))
+    }
+}
+
+/// Address 0 is the code which calls the entry point, rather than a function
+fn function_name(addr: usize, symbols: &SymbolTable) -> String {
+    match addr {
+        0 => "(entry)".to_string(),
+        _ => symbols.name(addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerContext;
+    use crate::kvm::VirtualMachine;
+    use crate::parser::parse_code;
+
+    #[test]
+    fn recursive_profile_test() {
+        let (code, symbols) = parse_code(
+            "
+            fn main() { print(fib(4)) }
+            fn fib(n) {
+              if n < 2 { return 1 }
+              return fib(n - 1) + fib(n - 2)
+            }
+            ",
+        )
+        .unwrap()
+        .to_ir(&mut CompilerContext::new())
+        .link_with_symbols();
+        let mut profile = Profile::new();
+        let mut vm = VirtualMachine::new(&|_: &str| {});
+        vm.add_observer(&mut profile);
+        vm.run(&code);
+        let instruction_count = vm.instruction_count();
+        drop(vm);
+
+        assert_eq!(profile.instruction_count(), instruction_count);
+        assert_eq!(profile.max_call_depth(), 5);
+
+        let opcodes = profile.opcode_counts();
+        assert!(opcodes.contains(&("CALL", 10)));
+        assert!(opcodes.contains(&("RET", 10)));
+        assert!(opcodes.contains(&("END", 1)));
+
+        // main and fib, after the entry code
+        let functions = profile.functions();
+        let functions: Vec<_> = functions.values().collect();
+        let (entry, fib, main) = (functions[0], functions[1], functions[2]);
+        assert_eq!((entry.calls, fib.calls, main.calls), (0, 9, 1));
+        assert_eq!(entry.inclusive, profile.instruction_count());
+        // recursion is counted once in the inclusive total
+        assert_eq!(fib.inclusive, fib.exclusive);
+        assert_eq!(main.inclusive, main.exclusive + fib.inclusive);
+        assert_eq!(
+            entry.exclusive + main.exclusive + fib.exclusive,
+            profile.instruction_count()
+        );
+
+        let folded = profile.folded_stacks(&symbols);
+        let lines: Vec<&str> = folded.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("main "));
+        assert!(lines[4].starts_with("main;fib;fib;fib;fib "));
+        let total: usize = lines
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(total, main.exclusive + fib.exclusive);
+    }
+}
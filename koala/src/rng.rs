@@ -0,0 +1,77 @@
+/// Seed the machines start from, so that runs are repeatable unless another seed is chosen.
+/// Compiled programs always start from it
+pub const DEFAULT_SEED: u64 = 0;
+
+/// The xorshift generator behind `rand()`.
+///
+/// It only needs a few shifts and a remainder, so every backend implements the same one,
+/// and compiled programs draw the same numbers as the VMs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves a state of 0, so the seed is mixed into a constant
+        let state = (seed ^ (seed >> 32)) as u32 ^ 0x9E37_79B9;
+        Rng {
+            state: match state {
+                0 => 1,
+                state => state,
+            },
+        }
+    }
+
+    /// The state the next number is drawn from, for backends to start their generator in
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// A number from `lo` to `hi` inclusive, or None when `hi` is less than `lo`
+    pub fn range(&mut self, lo: i32, hi: i32) -> Option<i32> {
+        if hi < lo {
+            return None;
+        }
+        let x = self.next_u32();
+        // the span wraps to 0 when it covers every i32
+        let span = (hi.wrapping_sub(lo) as u32).wrapping_add(1);
+        let offset = match span {
+            0 => x,
+            span => x % span,
+        };
+        Some(lo.wrapping_add(offset as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_test() {
+        let mut rng = Rng::new(42);
+        let rolls: Vec<i32> = (0..100).map(|_| rng.range(1, 6).unwrap()).collect();
+        assert!(rolls.iter().all(|roll| (1..=6).contains(roll)));
+        assert!((1..=6).all(|side| rolls.contains(&side)));
+
+        // the same seed gives the same numbers
+        let mut again = Rng::new(42);
+        let repeated: Vec<i32> = (0..100).map(|_| again.range(1, 6).unwrap()).collect();
+        assert_eq!(rolls, repeated);
+        assert_ne!(Rng::new(42), Rng::new(43));
+
+        assert_eq!(rng.range(5, 5), Some(5));
+        assert_eq!(rng.range(2, 1), None);
+        assert!(rng.range(i32::MIN, i32::MAX).is_some());
+    }
+}
@@ -0,0 +1,887 @@
+use crate::grammar::BinOp;
+use crate::ir::{self, Label, PrintType, ReadType, Terminator, ENTRY_POINT};
+use crate::kvm::{Input, InputCallback, OutputCallback};
+use crate::natives::{NativeError, NativeFn, Natives};
+use crate::rng::{Rng, DEFAULT_SEED};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A register holding a local or a temporary, numbered within the current frame
+pub type Reg = usize;
+
+/// Source of a value for an instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i32),
+}
+
+/// Instructions of the register machine.
+/// Jump targets and function entry points are indexes into the program's code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inst {
+    Move {
+        dst: Reg,
+        src: Operand,
+    },
+    BinOp {
+        op: BinOp,
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    GlobalLoad {
+        dst: Reg,
+        offset: usize,
+    },
+    GlobalStore {
+        src: Operand,
+        offset: usize,
+    },
+    /// Read the register at `offset + index`, which is how local arrays are laid out
+    LocalArrLoad {
+        dst: Reg,
+        offset: Operand,
+        index: Operand,
+    },
+    LocalArrStore {
+        src: Operand,
+        offset: Operand,
+        index: Operand,
+    },
+    GlobalArrLoad {
+        dst: Reg,
+        offset: Operand,
+        index: Operand,
+    },
+    GlobalArrStore {
+        src: Operand,
+        offset: Operand,
+        index: Operand,
+    },
+    Print {
+        src: Operand,
+        print_type: PrintType,
+    },
+    Read {
+        dst: Reg,
+        read_type: ReadType,
+    },
+    Rand {
+        dst: Reg,
+        lo: Operand,
+        hi: Operand,
+    },
+    /// Copy the arguments into the first registers of a new frame,
+    /// and write the return value to `dst` once it returns
+    Call {
+        dst: Reg,
+        function: usize,
+        args: Vec<Operand>,
+    },
+    /// Call a host function, writing its return value to `dst`
+    CallNative {
+        dst: Reg,
+        index: usize,
+        args: Vec<Operand>,
+    },
+    Jump(usize),
+    BranchZero {
+        cond: Operand,
+        target: usize,
+    },
+    BranchNonZero {
+        cond: Operand,
+        target: usize,
+    },
+    Ret(Operand),
+    End,
+}
+
+/// Entry in the function table
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub id: String,
+    pub addr: usize,
+    /// Size of the register file: the locals, followed by temporaries
+    pub registers: usize,
+}
+
+/// Register machine code, beginning with a bootstrap that calls main and stops the machine
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub code: Vec<Inst>,
+    pub functions: Vec<Function>,
+    pub globals_size: usize,
+}
+
+/// Translate a stack machine module into register code.
+///
+/// The first registers of a frame hold the function's locals, so loads and stores of locals
+/// disappear, and the operand stack is mapped onto the registers after them,
+/// one per stack slot. Constants and locals are used directly as operands where possible.
+pub fn translate(module: &ir::Module) -> Program {
+    let fn_indexes: HashMap<&str, usize> = module
+        .functions
+        .iter()
+        .enumerate()
+        .map(|(i, function)| (function.id.as_str(), i))
+        .collect();
+
+    let entry = match fn_indexes.get(ENTRY_POINT) {
+        Some(index) => *index,
+        None => panic!("could not find main function."),
+    };
+    let mut code = vec![
+        Inst::Call {
+            dst: 0,
+            function: entry,
+            args: Vec::new(),
+        },
+        Inst::End,
+    ];
+
+    let functions = module
+        .functions
+        .iter()
+        .map(|function| {
+            let addr = code.len();
+            let registers = Translator {
+                fn_indexes: &fn_indexes,
+                frame_size: function.frame_size,
+                registers: function.frame_size,
+                stack: Vec::new(),
+                code: &mut code,
+            }
+            .function(function);
+            Function {
+                id: function.id.clone(),
+                addr,
+                registers,
+            }
+        })
+        .collect();
+
+    Program {
+        code,
+        functions,
+        globals_size: module.globals_size,
+    }
+}
+
+struct Translator<'a> {
+    fn_indexes: &'a HashMap<&'a str, usize>,
+    frame_size: usize,
+    /// Registers used so far
+    registers: usize,
+    /// Values on the operand stack at this point of the block
+    stack: Vec<Operand>,
+    code: &'a mut Vec<Inst>,
+}
+
+impl<'a> Translator<'a> {
+    /// Translate the function, returning the number of registers it needs
+    fn function(mut self, function: &ir::Function) -> usize {
+        let reachable = function.reachable();
+        let blocks: Vec<&ir::Block> = function
+            .blocks
+            .iter()
+            .filter(|block| reachable.contains(&block.label))
+            .collect();
+
+        let mut block_addrs = HashMap::new();
+        let mut fixups: Vec<(usize, Label)> = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            block_addrs.insert(block.label, self.code.len());
+            for inst in &block.insts {
+                self.inst(inst);
+            }
+
+            let next = blocks.get(i + 1).map(|next| next.label);
+            match &block.terminator {
+                Terminator::Jump(label) => {
+                    if next != Some(*label) {
+                        fixups.push((self.code.len(), *label));
+                        self.code.push(Inst::Jump(0));
+                    }
+                }
+                Terminator::Branch { nonzero, zero } => {
+                    let cond = self.pop();
+                    if next == Some(*nonzero) {
+                        fixups.push((self.code.len(), *zero));
+                        self.code.push(Inst::BranchZero { cond, target: 0 });
+                    } else {
+                        fixups.push((self.code.len(), *nonzero));
+                        self.code.push(Inst::BranchNonZero { cond, target: 0 });
+                        if next != Some(*zero) {
+                            fixups.push((self.code.len(), *zero));
+                            self.code.push(Inst::Jump(0));
+                        }
+                    }
+                }
+                Terminator::Ret => {
+                    let val = self.pop();
+                    self.code.push(Inst::Ret(val));
+                }
+            }
+            self.stack.clear();
+        }
+
+        for (pos, label) in fixups {
+            let addr = block_addrs[&label];
+            match &mut self.code[pos] {
+                Inst::Jump(target)
+                | Inst::BranchZero { target, .. }
+                | Inst::BranchNonZero { target, .. } => *target = addr,
+                _ => {}
+            }
+        }
+
+        self.registers
+    }
+
+    fn pop(&mut self) -> Operand {
+        match self.stack.pop() {
+            Some(val) => val,
+            None => panic!("operand stack underflow"),
+        }
+    }
+
+    /// The register backing the next stack slot
+    fn push_reg(&mut self) -> Reg {
+        let reg = self.frame_size + self.stack.len();
+        self.registers = self.registers.max(reg + 1);
+        self.stack.push(Operand::Reg(reg));
+        reg
+    }
+
+    /// Locals are pushed as references to their register, so copy any which are still on
+    /// the stack into the stack slot's own register before the local is overwritten
+    fn materialize(&mut self, local: impl Fn(Reg) -> bool) {
+        for depth in 0..self.stack.len() {
+            if let Operand::Reg(reg) = self.stack[depth] {
+                if reg < self.frame_size && local(reg) {
+                    let dst = self.frame_size + depth;
+                    self.code.push(Inst::Move {
+                        dst,
+                        src: Operand::Reg(reg),
+                    });
+                    self.stack[depth] = Operand::Reg(dst);
+                }
+            }
+        }
+    }
+
+    fn inst(&mut self, inst: &ir::Inst) {
+        match inst {
+            ir::Inst::Push(val) => self.stack.push(Operand::Imm(*val)),
+            ir::Inst::GlobalAddr(offset) => self.stack.push(Operand::Imm(*offset as i32)),
+            ir::Inst::Pop => {
+                self.pop();
+            }
+            ir::Inst::BinOp(op) => {
+                let lhs = self.pop();
+                let rhs = self.pop();
+                let dst = self.push_reg();
+                self.code.push(Inst::BinOp {
+                    op: *op,
+                    dst,
+                    lhs,
+                    rhs,
+                });
+            }
+            ir::Inst::LocalLoad(offset) => {
+                self.registers = self.registers.max(offset + 1);
+                self.stack.push(Operand::Reg(*offset));
+            }
+            ir::Inst::LocalStore(offset) => {
+                let src = self.pop();
+                self.materialize(|reg| reg == *offset);
+                self.registers = self.registers.max(offset + 1);
+                // write the result of the last instruction straight into the local
+                if let (Operand::Reg(reg), Some(last)) = (src, self.code.last_mut()) {
+                    if reg >= self.frame_size {
+                        match last {
+                            Inst::Move { dst, .. }
+                            | Inst::BinOp { dst, .. }
+                            | Inst::GlobalLoad { dst, .. }
+                            | Inst::LocalArrLoad { dst, .. }
+                            | Inst::GlobalArrLoad { dst, .. }
+                            | Inst::Read { dst, .. }
+                            | Inst::Rand { dst, .. }
+                            | Inst::Call { dst, .. }
+                            | Inst::CallNative { dst, .. }
+                                if *dst == reg =>
+                            {
+                                *dst = *offset;
+                                return;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                self.code.push(Inst::Move { dst: *offset, src });
+            }
+            ir::Inst::GlobalLoad(offset) => {
+                let dst = self.push_reg();
+                self.code.push(Inst::GlobalLoad {
+                    dst,
+                    offset: *offset,
+                });
+            }
+            ir::Inst::GlobalStore(offset) => {
+                let src = self.pop();
+                self.code.push(Inst::GlobalStore {
+                    src,
+                    offset: *offset,
+                });
+            }
+            ir::Inst::LocalArrLoad | ir::Inst::GlobalArrLoad => {
+                let index = self.pop();
+                let offset = self.pop();
+                let dst = self.push_reg();
+                self.code.push(match inst {
+                    ir::Inst::LocalArrLoad => Inst::LocalArrLoad { dst, offset, index },
+                    _ => Inst::GlobalArrLoad { dst, offset, index },
+                });
+            }
+            ir::Inst::LocalArrStore => {
+                let offset = self.pop();
+                let index = self.pop();
+                let src = self.pop();
+                // the element written is only known at runtime
+                self.materialize(|_| true);
+                self.code.push(Inst::LocalArrStore { src, offset, index });
+            }
+            ir::Inst::GlobalArrStore => {
+                let offset = self.pop();
+                let index = self.pop();
+                let src = self.pop();
+                self.code.push(Inst::GlobalArrStore { src, offset, index });
+            }
+            ir::Inst::Print(print_type) => {
+                let src = self.pop();
+                self.code.push(Inst::Print {
+                    src,
+                    print_type: *print_type,
+                });
+            }
+            ir::Inst::Read(read_type) => {
+                let dst = self.push_reg();
+                self.code.push(Inst::Read {
+                    dst,
+                    read_type: *read_type,
+                });
+            }
+            ir::Inst::Rand => {
+                let lo = self.pop();
+                let hi = self.pop();
+                let dst = self.push_reg();
+                self.code.push(Inst::Rand { dst, lo, hi });
+            }
+            ir::Inst::Call { id, argc } => {
+                // the first argument is on top of the stack
+                let args = (0..*argc).map(|_| self.pop()).collect();
+                let function = match self.fn_indexes.get(id.as_str()) {
+                    Some(index) => *index,
+                    None => panic!("No function found to jump to"),
+                };
+                let dst = self.push_reg();
+                self.code.push(Inst::Call {
+                    dst,
+                    function,
+                    args,
+                });
+            }
+            ir::Inst::CallNative { index, argc } => {
+                let args = (0..*argc).map(|_| self.pop()).collect();
+                let dst = self.push_reg();
+                self.code.push(Inst::CallNative {
+                    dst,
+                    index: *index,
+                    args,
+                });
+            }
+        }
+    }
+}
+
+/// The Koala Register Machine
+pub struct RegisterMachine<'a> {
+    /// Program Counter
+    pc: usize,
+    program: Option<&'a Program>,
+    call_stack: Vec<Frame>,
+    globals: Vec<i32>,
+    /// Running flag
+    running: bool,
+    /// Number of instructions executed
+    executed: usize,
+    /// Callback for Interaction with the outside world
+    output_pipe: OutputCallback<'a>,
+    /// Callback for debugging output, only formatted when one is given
+    debug_pipe: Option<OutputCallback<'a>>,
+    input: Input<'a>,
+    rng: Rng,
+    natives: Natives<'a>,
+    /// Code the program passed to `exit()`, or 0 when it ran to the end
+    exit_code: i32,
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub registers: Vec<i32>,
+    pub return_addr: usize,
+    /// Register of the caller which receives the return value
+    pub dst: Reg,
+}
+
+impl<'a> RegisterMachine<'a> {
+    pub fn new(output_pipe: OutputCallback<'a>, debug_pipe: Option<OutputCallback<'a>>) -> Self {
+        RegisterMachine {
+            pc: 0,
+            program: None,
+            call_stack: Vec::new(),
+            globals: Vec::new(),
+            running: false,
+            executed: 0,
+            output_pipe,
+            debug_pipe,
+            input: Input::new(None),
+            rng: Rng::new(DEFAULT_SEED),
+            natives: Natives::new(),
+            exit_code: 0,
+        }
+    }
+
+    /// Let programs call a host function by name, see `VirtualMachine::register_native`
+    pub fn register_native(&mut self, name: &str, arity: usize, function: NativeFn<'a>) {
+        self.natives.register(name, arity, function);
+    }
+
+    /// Code the program passed to `exit()`, or 0 when it ran to the end
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    /// Restart the generator behind `rand()` from a seed
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Read input from a callback, instead of the machine having no input
+    pub fn set_input(&mut self, input: InputCallback<'a>) {
+        self.input = Input::new(Some(input));
+    }
+
+    pub fn run(&mut self, program: &'a Program) {
+        self.program = Some(program);
+        self.globals = vec![0; program.globals_size];
+        self.natives.start_clock();
+        // the bootstrap runs in a frame of its own, to receive main's return value
+        self.call_stack.push(Frame {
+            registers: vec![0],
+            return_addr: 0,
+            dst: 0,
+        });
+        self.running = true;
+        while self.running {
+            self.execute(program);
+        }
+    }
+
+    /// Number of instructions executed so far
+    pub fn instruction_count(&self) -> usize {
+        self.executed
+    }
+
+    fn read(&self, operand: Operand) -> i32 {
+        match operand {
+            Operand::Reg(reg) => match self.frame().registers.get(reg) {
+                Some(val) => *val,
+                None => self.error(format!("register r{} is out of range", reg)),
+            },
+            Operand::Imm(val) => val,
+        }
+    }
+
+    /// Register or global slot of an array element, which can't be before the start
+    fn slot(&self, offset: Operand, index: Operand) -> usize {
+        let (offset, index) = (self.read(offset), self.read(index));
+        match offset.checked_add(index) {
+            Some(slot) if slot >= 0 => slot as usize,
+            _ => self.error(format!(
+                "index {} is out of range of the array at {}",
+                index, offset
+            )),
+        }
+    }
+
+    fn global(&self, offset: usize) -> i32 {
+        match self.globals.get(offset) {
+            Some(val) => *val,
+            None => self.error(format!("global {} is out of range", offset)),
+        }
+    }
+
+    fn store_global(&mut self, offset: usize, val: i32) {
+        match self.globals.get_mut(offset) {
+            Some(global) => *global = val,
+            None => self.error(format!("global {} is out of range", offset)),
+        }
+    }
+
+    fn frame(&self) -> &Frame {
+        self.call_stack.last().unwrap()
+    }
+
+    fn write(&mut self, reg: Reg, val: i32) {
+        let registers = &mut self.call_stack.last_mut().unwrap().registers;
+        // local arrays can be indexed past the end of the frame, like on the KVM
+        if reg >= registers.len() {
+            registers.resize(reg + 1, 0);
+        }
+        registers[reg] = val;
+    }
+
+    fn execute(&mut self, program: &Program) {
+        let inst = &program.code[self.pc];
+        self.pc += 1;
+        self.executed += 1;
+
+        if let Some(debug_pipe) = self.debug_pipe {
+            debug_pipe(&format!(
+                "\nPC: {:<3} IR: {} registers: {:?} globals: {:?}\n",
+                self.pc - 1,
+                inst,
+                self.frame().registers,
+                self.globals,
+            ));
+        }
+
+        match inst {
+            Inst::End => {
+                self.running = false;
+            }
+            Inst::Move { dst, src } => {
+                let val = self.read(*src);
+                self.write(*dst, val);
+            }
+            Inst::BinOp { op, dst, lhs, rhs } => {
+                let first = self.read(*lhs);
+                let second = self.read(*rhs);
+                let result = match op {
+                    // arithmetic wraps on overflow, like on the KVM
+                    BinOp::Plus => first.wrapping_add(second),
+                    BinOp::Minus => first.wrapping_sub(second),
+                    BinOp::Mul => first.wrapping_mul(second),
+                    BinOp::Div => match first.checked_div(second) {
+                        Some(val) => val,
+                        None if second == 0 => self.error("division by zero".to_string()),
+                        None => self.error("division overflow".to_string()),
+                    },
+                    BinOp::Less => (first < second) as i32,
+                    BinOp::LessOrEqual => (first <= second) as i32,
+                    BinOp::Greater => (first > second) as i32,
+                    BinOp::GreaterOrEqual => (first >= second) as i32,
+                    BinOp::Equal => (first == second) as i32,
+                    BinOp::NotEqual => (first != second) as i32,
+                    BinOp::Or => (first != 0 || second != 0) as i32,
+                    BinOp::And => (first != 0 && second != 0) as i32,
+                };
+                self.write(*dst, result);
+            }
+            Inst::GlobalLoad { dst, offset } => {
+                let val = self.global(*offset);
+                self.write(*dst, val);
+            }
+            Inst::GlobalStore { src, offset } => {
+                let val = self.read(*src);
+                self.store_global(*offset, val);
+            }
+            Inst::LocalArrLoad { dst, offset, index } => {
+                let slot = self.slot(*offset, *index);
+                let val = self.read(Operand::Reg(slot));
+                self.write(*dst, val);
+            }
+            Inst::LocalArrStore { src, offset, index } => {
+                let slot = self.slot(*offset, *index);
+                let val = self.read(*src);
+                self.write(slot, val);
+            }
+            Inst::GlobalArrLoad { dst, offset, index } => {
+                let slot = self.slot(*offset, *index);
+                let val = self.global(slot);
+                self.write(*dst, val);
+            }
+            Inst::GlobalArrStore { src, offset, index } => {
+                let slot = self.slot(*offset, *index);
+                let val = self.read(*src);
+                self.store_global(slot, val);
+            }
+            Inst::Print { src, print_type } => {
+                let val = self.read(*src);
+                let msg = match print_type {
+                    PrintType::Int => val.to_string(),
+                    PrintType::Char => match char::from_u32(val as u32) {
+                        Some(c) => c.to_string(),
+                        None => self.error("bad character parsing in print".to_string()),
+                    },
+                };
+                self.print(&msg);
+            }
+            Inst::Read { dst, read_type } => {
+                let val = match read_type {
+                    ReadType::Int => self.input.read_int(),
+                    ReadType::Line => self.input.read_int_line(),
+                };
+                match val {
+                    Ok(val) => self.write(*dst, val),
+                    Err(e) => self.error(e),
+                }
+            }
+            Inst::Rand { dst, lo, hi } => {
+                let (lo, hi) = (self.read(*lo), self.read(*hi));
+                match self.rng.range(lo, hi) {
+                    Some(val) => self.write(*dst, val),
+                    None => self.error(format!("empty range for rand({}, {})", lo, hi)),
+                }
+            }
+            Inst::Call {
+                dst,
+                function,
+                args,
+            } => {
+                let function = &program.functions[*function];
+                let mut registers = vec![0; function.registers.max(args.len())];
+                for (register, arg) in registers.iter_mut().zip(args) {
+                    *register = self.read(*arg);
+                }
+                self.call_stack.push(Frame {
+                    registers,
+                    return_addr: self.pc,
+                    dst: *dst,
+                });
+                self.pc = function.addr;
+            }
+            Inst::CallNative { dst, index, args } => {
+                let args: Vec<i32> = args.iter().map(|arg| self.read(*arg)).collect();
+                match self.natives.call(*index, &args) {
+                    Ok(val) => self.write(*dst, val),
+                    Err(NativeError::Exit(code)) => {
+                        self.exit_code = code;
+                        self.running = false;
+                    }
+                    Err(NativeError::Failed(e)) => self.error(e),
+                }
+            }
+            Inst::Ret(src) => {
+                let val = self.read(*src);
+                let exiting_frame = self.call_stack.pop().unwrap();
+                self.pc = exiting_frame.return_addr;
+                self.write(exiting_frame.dst, val);
+            }
+            Inst::Jump(target) => {
+                self.pc = *target;
+            }
+            Inst::BranchZero { cond, target } => {
+                if self.read(*cond) == 0 {
+                    self.pc = *target;
+                }
+            }
+            Inst::BranchNonZero { cond, target } => {
+                if self.read(*cond) != 0 {
+                    self.pc = *target;
+                }
+            }
+        }
+    }
+
+    fn print(&self, message: &str) {
+        (self.output_pipe)(message);
+    }
+
+    /// Stop the machine on an error in the instruction being executed, like the KVM does
+    #[cold]
+    fn error(&self, message: String) -> ! {
+        panic!("{} at {}", message, self.pc - 1)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Reg(reg) => write!(f, "r{}", reg),
+            Self::Imm(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Move { dst, src } => write!(f, "r{} = {}", dst, src),
+            Self::BinOp { op, dst, lhs, rhs } => {
+                write!(f, "r{} = {} {:?} {}", dst, lhs, op, rhs)
+            }
+            Self::GlobalLoad { dst, offset } => write!(f, "r{} = global[{}]", dst, offset),
+            Self::GlobalStore { src, offset } => write!(f, "global[{}] = {}", offset, src),
+            Self::LocalArrLoad { dst, offset, index } => {
+                write!(f, "r{} = r[{} + {}]", dst, offset, index)
+            }
+            Self::LocalArrStore { src, offset, index } => {
+                write!(f, "r[{} + {}] = {}", offset, index, src)
+            }
+            Self::GlobalArrLoad { dst, offset, index } => {
+                write!(f, "r{} = global[{} + {}]", dst, offset, index)
+            }
+            Self::GlobalArrStore { src, offset, index } => {
+                write!(f, "global[{} + {}] = {}", offset, index, src)
+            }
+            Self::Print { src, print_type } => write!(f, "print {:?} {}", print_type, src),
+            Self::Read { dst, read_type } => write!(f, "r{} = read {:?}", dst, read_type),
+            Self::Rand { dst, lo, hi } => write!(f, "r{} = rand {} {}", dst, lo, hi),
+            Self::Call {
+                dst,
+                function,
+                args,
+            } => {
+                let args: Vec<String> = args.iter().map(Operand::to_string).collect();
+                write!(f, "r{} = call f{}({})", dst, function, args.join(", "))
+            }
+            Self::CallNative { dst, index, args } => {
+                let args: Vec<String> = args.iter().map(Operand::to_string).collect();
+                write!(f, "r{} = native {}({})", dst, index, args.join(", "))
+            }
+            Self::Jump(target) => write!(f, "jump {}", target),
+            Self::BranchZero { cond, target } => write!(f, "beqz {}, {}", cond, target),
+            Self::BranchNonZero { cond, target } => write!(f, "bnez {}, {}", cond, target),
+            Self::Ret(src) => write!(f, "ret {}", src),
+            Self::End => write!(f, "end"),
+        }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, inst) in self.code.iter().enumerate() {
+            if let Some((index, function)) = self
+                .functions
+                .iter()
+                .enumerate()
+                .find(|(_, function)| function.addr == addr)
+            {
+                writeln!(
+                    f,
+                    "f{} {}(registers: {}):",
+                    index, function.id, function.registers
+                )?;
+            }
+            writeln!(f, "{:04}  {}", addr, inst)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerContext;
+    use crate::parser::parse_code;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn translate_code(code: &str) -> Program {
+        translate(&parse_code(code).unwrap().to_ir(&mut CompilerContext::new()))
+    }
+
+    fn rvm_run(program: &Program) -> String {
+        let output = Rc::new(RefCell::new(String::new()));
+        let captured = output.clone();
+        let print_callback = &move |msg: &str| *captured.borrow_mut() += msg;
+        let mut rvm = RegisterMachine::new(print_callback, None);
+        rvm.run(program);
+        output.take()
+    }
+
+    #[test]
+    fn translate_loop_test() {
+        let program =
+            translate_code("fn main() { let mut i = 0 while i < 3 { print(i) i = i + 1 } }");
+        let expected = "\
+0000  r0 = call f0()
+0001  end
+f0 main(registers: 2):
+0002  r0 = 0
+0003  r1 = r0 Less 3
+0004  beqz r1, 8
+0005  print Int r0
+0006  r0 = r0 Plus 1
+0007  jump 3
+0008  ret 0
+";
+        assert_eq!(program.to_string(), expected);
+    }
+
+    #[test]
+    fn stale_local_test() {
+        // the old value of the local is still on the stack when it is overwritten
+        let mut function = ir::FunctionBuilder::new("main", 0);
+        function.emit(ir::Inst::Push(1));
+        function.emit(ir::Inst::LocalStore(0));
+        function.emit(ir::Inst::LocalLoad(0));
+        function.emit(ir::Inst::Push(2));
+        function.emit(ir::Inst::LocalStore(0));
+        function.emit(ir::Inst::Print(PrintType::Int));
+        function.emit(ir::Inst::LocalLoad(0));
+        function.emit(ir::Inst::Print(PrintType::Int));
+        let module = ir::Module {
+            functions: vec![function.finish(1)],
+            globals_size: 0,
+        };
+
+        assert_eq!(rvm_run(&translate(&module)), "12");
+    }
+
+    #[test]
+    fn call_test() {
+        let code = "fn main() { print(add(1, 2)) } fn add(a, b) { return a * 10 + b }";
+        assert_eq!(rvm_run(&translate_code(code)), "12");
+    }
+
+    #[test]
+    fn overflow_test() {
+        let code = "
+        fn main() {
+          print(f(2147483647) + 1)
+          print((0 - f(2147483647)) - 2)
+          print(f(65536) * 65536)
+        }
+
+        fn f(n) {
+          return n
+        }
+        ";
+        assert_eq!(rvm_run(&translate_code(code)), "-214748364821474836470");
+    }
+
+    #[test]
+    #[should_panic(expected = "division overflow")]
+    fn division_overflow_test() {
+        let code = "fn main() { print(f((0 - 2147483647) - 1) / (0 - 1)) } fn f(n) { return n }";
+        rvm_run(&translate_code(code));
+    }
+
+    #[test]
+    #[should_panic(expected = "division by zero")]
+    fn division_by_zero_test() {
+        let code = "fn main() { print(1 / f(0)) } fn f(n) { return n }";
+        rvm_run(&translate_code(code));
+    }
+
+    #[test]
+    #[should_panic(expected = "global 3 is out of range")]
+    fn global_out_of_range_test() {
+        let code = "fn main() { global g[2] = [1, 2] print(g[f(3)]) } fn f(n) { return n }";
+        rvm_run(&translate_code(code));
+    }
+}
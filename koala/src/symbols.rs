@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Names of the functions in linked bytecode, by the address each one starts at.
+///
+/// `koalac` writes the table next to the bytecode, one `<address> <name>` pair per line,
+/// so that tools running the code can report functions by name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolTable {
+    functions: BTreeMap<usize, String>,
+}
+
+impl SymbolTable {
+    pub fn insert(&mut self, addr: usize, name: &str) {
+        self.functions.insert(addr, name.to_string());
+    }
+
+    pub fn get(&self, addr: usize) -> Option<&str> {
+        self.functions.get(&addr).map(String::as_str)
+    }
+
+    /// Name of the function at `addr`, or its address when it has no symbol
+    pub fn name(&self, addr: usize) -> String {
+        match self.get(addr) {
+            Some(name) => name.to_string(),
+            None => format!("@{}", addr),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.functions
+            .iter()
+            .map(|(addr, name)| (*addr, name.as_str()))
+    }
+
+    /// Read a table written by its `Display` implementation
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = SymbolTable::default();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (addr, name) = match line.split_once(' ') {
+                Some((addr, name)) => (addr.parse::<usize>(), name.trim()),
+                None => return Err(format!("line {}: expected an address and a name", i + 1)),
+            };
+            match addr {
+                Ok(addr) if !name.is_empty() => symbols.insert(addr, name),
+                _ => return Err(format!("line {}: expected an address and a name", i + 1)),
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, name) in &self.functions {
+            writeln!(f, "{} {}", addr, name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert(32, "main");
+        symbols.insert(4, "fib");
+
+        assert_eq!(symbols.to_string(), "4 fib\n32 main\n");
+        assert_eq!(
+            SymbolTable::parse(&symbols.to_string()),
+            Ok(symbols.clone())
+        );
+        assert_eq!(symbols.name(4), "fib");
+        assert_eq!(symbols.name(8), "@8");
+        assert!(SymbolTable::parse("main 32").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use crate::instructions;
+use crate::kvm::Instruction;
+use crate::observer::{Step, VmObserver};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Everything one instruction did to the state of the VM, which is enough to replay it
+/// in either direction
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    /// Bytecode address of the instruction
+    pub pc: usize,
+    /// Mnemonic of the instruction, as in `instructions.rs`
+    pub opcode: String,
+    /// Values the instruction took off the operand stack, bottom first
+    pub popped: Vec<i32>,
+    /// Values the instruction left on the operand stack, bottom first
+    pub pushed: Vec<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame: Option<FrameChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<Store>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum FrameChange {
+    /// A frame was pushed for the function at `fn_addr`
+    Call { fn_addr: usize, locals: Vec<i32> },
+    /// The frame of the function at `fn_addr` was popped, holding `locals`
+    Return { fn_addr: usize, locals: Vec<i32> },
+}
+
+/// A local in the current frame or a global was written
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Store {
+    pub global: bool,
+    pub offset: usize,
+    pub old: i32,
+    pub new: i32,
+    /// Number of slots before the store, which grows them to reach `offset`
+    pub size: usize,
+}
+
+/// Records a `TraceEvent` for every instruction the VM it observes executes
+#[derive(Debug, Default)]
+pub struct Recorder {
+    events: Vec<TraceEvent>,
+    /// Depth of the stack once the current instruction has popped its operands,
+    /// so that anything above it at the next step was pushed
+    base: usize,
+    /// Number of locals in the current frame and globals before the current instruction
+    locals_size: usize,
+    globals_size: usize,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    pub fn into_events(self) -> Vec<TraceEvent> {
+        self.events
+    }
+}
+
+impl VmObserver for Recorder {
+    fn on_step(&mut self, step: &Step) {
+        if let Some(event) = self.events.last_mut() {
+            event.pushed = step.stack[self.base.min(step.stack.len())..].to_vec();
+        }
+        self.base = step.stack.len().saturating_sub(pops(step.instruction));
+        self.locals_size = step.frames.last().map_or(0, |frame| frame.locals.len());
+        self.globals_size = step.globals.len();
+        self.events.push(TraceEvent {
+            pc: step.pc,
+            opcode: instructions::mnemonic(step.opcode()).to_string(),
+            popped: step.stack[self.base..].to_vec(),
+            ..TraceEvent::default()
+        });
+    }
+
+    fn on_call(&mut self, _pc: usize, fn_addr: usize, args: &[i32]) {
+        if let Some(event) = self.events.last_mut() {
+            event.frame = Some(FrameChange::Call {
+                fn_addr,
+                locals: args.to_vec(),
+            });
+        }
+    }
+
+    fn on_return(&mut self, _pc: usize, fn_addr: usize, locals: &[i32]) {
+        if let Some(event) = self.events.last_mut() {
+            event.frame = Some(FrameChange::Return {
+                fn_addr,
+                locals: locals.to_vec(),
+            });
+        }
+    }
+
+    fn on_print(&mut self, _pc: usize, message: &str) {
+        if let Some(event) = self.events.last_mut() {
+            event.output = Some(message.to_string());
+        }
+    }
+
+    fn on_store_local(&mut self, _pc: usize, offset: usize, old: i32, new: i32) {
+        let size = self.locals_size;
+        if let Some(event) = self.events.last_mut() {
+            event.store = Some(Store {
+                global: false,
+                offset,
+                old,
+                new,
+                size,
+            });
+        }
+    }
+
+    fn on_store_global(&mut self, _pc: usize, offset: usize, old: i32, new: i32) {
+        let size = self.globals_size;
+        if let Some(event) = self.events.last_mut() {
+            event.store = Some(Store {
+                global: true,
+                offset,
+                old,
+                new,
+                size,
+            });
+        }
+    }
+}
+
+/// Number of values an instruction takes off the operand stack when it runs
+fn pops(instruction: Instruction) -> usize {
+    match instruction {
+        Instruction::End
+        | Instruction::Jump(_)
+        | Instruction::LocalLoad(_)
+        | Instruction::GlobalLoad(_)
+        | Instruction::Push(_)
+        | Instruction::Read(_)
+        | Instruction::Ret => 0,
+        Instruction::LocalStore(_)
+        | Instruction::GlobalStore(_)
+        | Instruction::Beqz(_)
+        | Instruction::Bnez(_)
+        | Instruction::Print(_)
+        | Instruction::Pop => 1,
+        Instruction::LocalArrStore | Instruction::GlobalArrStore => 3,
+        Instruction::Call { argc, .. } | Instruction::CallNative { argc, .. } => argc,
+        _ => 2,
+    }
+}
+
+/// Write events one JSON object per line
+pub fn to_json_lines(events: &[TraceEvent]) -> String {
+    events
+        .iter()
+        .map(|event| match serde_json::to_string(event) {
+            Ok(line) => line + "\n",
+            Err(e) => panic!("{}", e),
+        })
+        .collect()
+}
+
+pub fn from_json_lines(text: &str) -> Result<Vec<TraceEvent>, serde_json::Error> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect()
+}
+
+/// State of the VM reconstructed from a trace
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct State {
+    pub stack: Vec<i32>,
+    pub frames: Vec<ReplayFrame>,
+    pub globals: Vec<i32>,
+    pub output: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayFrame {
+    pub fn_addr: usize,
+    pub locals: Vec<i32>,
+}
+
+/// Steps through a recorded trace, forwards or backwards, without running the program again
+pub struct Replay {
+    events: Vec<TraceEvent>,
+    /// Number of events applied to the state
+    position: usize,
+    state: State,
+}
+
+impl Replay {
+    pub fn new(events: Vec<TraceEvent>) -> Self {
+        Replay {
+            events,
+            position: 0,
+            state: State::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The event which the next step forward applies
+    pub fn next_event(&self) -> Option<&TraceEvent> {
+        self.events.get(self.position)
+    }
+
+    /// Apply the next event, returning it, or None at the end of the trace
+    pub fn forward(&mut self) -> Option<&TraceEvent> {
+        let event = self.events.get(self.position)?;
+        let state = &mut self.state;
+
+        state
+            .stack
+            .truncate(state.stack.len().saturating_sub(event.popped.len()));
+        match &event.frame {
+            Some(FrameChange::Call { fn_addr, locals }) => state.frames.push(ReplayFrame {
+                fn_addr: *fn_addr,
+                locals: locals.clone(),
+            }),
+            Some(FrameChange::Return { .. }) => {
+                state.frames.pop();
+            }
+            None => {}
+        }
+        if let Some(store) = &event.store {
+            let slots = match store.global {
+                true => Some(&mut state.globals),
+                false => state.frames.last_mut().map(|frame| &mut frame.locals),
+            };
+            if let Some(slots) = slots {
+                if store.offset >= slots.len() {
+                    slots.resize(store.offset + 1, 0);
+                }
+                slots[store.offset] = store.new;
+            }
+        }
+        state.stack.extend(&event.pushed);
+        if let Some(output) = &event.output {
+            state.output.push_str(output);
+        }
+
+        self.position += 1;
+        Some(event)
+    }
+
+    /// Undo the last event applied, returning it, or None at the start of the trace
+    pub fn back(&mut self) -> Option<&TraceEvent> {
+        if self.position == 0 {
+            return None;
+        }
+        self.position -= 1;
+        let event = &self.events[self.position];
+        let state = &mut self.state;
+
+        if let Some(output) = &event.output {
+            let len = state.output.len() - output.len();
+            state.output.truncate(len);
+        }
+        state
+            .stack
+            .truncate(state.stack.len().saturating_sub(event.pushed.len()));
+        if let Some(store) = &event.store {
+            let slots = match store.global {
+                true => Some(&mut state.globals),
+                false => state.frames.last_mut().map(|frame| &mut frame.locals),
+            };
+            if let Some(slots) = slots {
+                if let Some(slot) = slots.get_mut(store.offset) {
+                    *slot = store.old;
+                }
+                slots.truncate(store.size);
+            }
+        }
+        match &event.frame {
+            Some(FrameChange::Call { .. }) => {
+                state.frames.pop();
+            }
+            Some(FrameChange::Return { fn_addr, locals }) => state.frames.push(ReplayFrame {
+                fn_addr: *fn_addr,
+                locals: locals.clone(),
+            }),
+            None => {}
+        }
+        state.stack.extend(&event.popped);
+
+        Some(event)
+    }
+
+    /// Move to the state after `position` events have been applied
+    pub fn seek(&mut self, position: usize) {
+        let position = position.min(self.events.len());
+        while self.position < position {
+            self.forward();
+        }
+        while self.position > position {
+            self.back();
+        }
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}  {:<16}", self.pc, self.opcode)?;
+        if !self.popped.is_empty() {
+            write!(f, " pop {:?}", self.popped)?;
+        }
+        if !self.pushed.is_empty() {
+            write!(f, " push {:?}", self.pushed)?;
+        }
+        match &self.frame {
+            Some(FrameChange::Call { fn_addr, locals }) => {
+                write!(f, " call @{} with {:?}", fn_addr, locals)?
+            }
+            Some(FrameChange::Return { fn_addr, .. }) => write!(f, " return from @{}", fn_addr)?,
+            None => {}
+        }
+        if let Some(store) = &self.store {
+            let place = match store.global {
+                true => "global",
+                false => "local",
+            };
+            write!(
+                f,
+                " {} {}: {} -> {}",
+                place, store.offset, store.old, store.new
+            )?;
+        }
+        if let Some(output) = &self.output {
+            write!(f, " print {:?}", output)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerContext;
+    use crate::kvm::VirtualMachine;
+    use crate::parser::parse_code;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn record(code: &str) -> (Vec<TraceEvent>, String) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let captured = output.clone();
+        let print_callback = &move |msg: &str| *captured.borrow_mut() += msg;
+
+        let code = parse_code(code)
+            .unwrap()
+            .code_gen(&mut CompilerContext::new());
+        let mut recorder = Recorder::new();
+        let mut vm = VirtualMachine::new(print_callback);
+        vm.add_observer(&mut recorder);
+        vm.run(&code);
+        drop(vm);
+        (recorder.into_events(), output.take())
+    }
+
+    #[test]
+    fn replay_test() {
+        let (events, output) = record(
+            "
+            fn main() {
+              global total[2]
+              let a[3]
+              let mut i = 0
+              while i < 3 {
+                a[i] = square(i)
+                total[1] = total[1] + a[i]
+                i = i + 1
+              }
+              print(total[1])
+            }
+            fn square(n) { return n * n }
+            ",
+        );
+        assert_eq!(
+            events.first().map(|event| event.opcode.as_str()),
+            Some("CALL")
+        );
+        assert_eq!(
+            events.last().map(|event| event.opcode.as_str()),
+            Some("END")
+        );
+
+        let mut replay = Replay::new(from_json_lines(&to_json_lines(&events)).unwrap());
+        assert_eq!(replay.len(), events.len());
+
+        // remember every state on the way forward, and check they come back in reverse
+        let mut states = vec![replay.state().clone()];
+        while replay.forward().is_some() {
+            states.push(replay.state().clone());
+        }
+        let end = replay.state().clone();
+        assert_eq!(end.output, output);
+        assert_eq!(end.globals, vec![0, 5]);
+        assert!(end.frames.is_empty());
+        // main's return value is left behind by the entry code
+        assert_eq!(end.stack, vec![0]);
+
+        while replay.back().is_some() {
+            assert_eq!(replay.state(), &states[replay.position()]);
+        }
+        assert_eq!(replay.state(), &State::default());
+
+        replay.seek(events.len() / 2);
+        assert_eq!(replay.state(), &states[events.len() / 2]);
+        replay.seek(events.len() + 10);
+        assert_eq!(replay.state(), &end);
+    }
+}
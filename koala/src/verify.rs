@@ -0,0 +1,345 @@
+use crate::kvm::{self, Instruction, LoadError};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Reasons bytecode can fail verification.
+/// Addresses are positions in the bytecode, not instruction indexes
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    Load(LoadError),
+    StackUnderflow {
+        addr: usize,
+    },
+    StackMismatch {
+        addr: usize,
+        expected: usize,
+        found: usize,
+    },
+    LocalOutOfFrame {
+        addr: usize,
+        offset: usize,
+        frame_size: usize,
+    },
+    NoFrame {
+        addr: usize,
+    },
+    FallsOffEnd {
+        addr: usize,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Load(e) => write!(f, "{}", e),
+            VerifyError::StackUnderflow { addr } => write!(f, "stack underflow at {}", addr),
+            VerifyError::StackMismatch {
+                addr,
+                expected,
+                found,
+            } => write!(
+                f,
+                "stack depth at {} is {}, but was {} on another path",
+                addr, found, expected
+            ),
+            VerifyError::LocalOutOfFrame {
+                addr,
+                offset,
+                frame_size,
+            } => write!(
+                f,
+                "local {} loaded at {} is outside of the frame of size {}",
+                offset, addr, frame_size
+            ),
+            VerifyError::NoFrame { addr } => {
+                write!(
+                    f,
+                    "instruction at {} needs a frame, but is not in a function",
+                    addr
+                )
+            }
+            VerifyError::FallsOffEnd { addr } => {
+                write!(f, "execution runs past the end of the code after {}", addr)
+            }
+        }
+    }
+}
+
+impl From<LoadError> for VerifyError {
+    fn from(e: LoadError) -> Self {
+        VerifyError::Load(e)
+    }
+}
+
+/// Check that bytecode is safe to run before handing it to the VM.
+///
+/// Every jump and call must land on an instruction, and every path must end in a `RET`
+/// or `END`. Execution begins outside of any function at address 0, and each `CALL` target
+/// is checked as a function, tracking the depth of its operand stack from the point it was
+/// entered: the depth may never go negative, must agree wherever paths merge, and a `RET`
+/// leaves exactly its return value. `LOCAL_LOAD` must read an argument or a local the function
+/// stores to.
+pub fn verify(code: &[u32]) -> Result<(), VerifyError> {
+    let program = kvm::load(code)?;
+
+    // the entry point has no frame, while functions are found from the calls made
+    let mut functions: Vec<usize> = Vec::new();
+    let mut argcs: HashMap<usize, usize> = HashMap::new();
+    let mut checked: HashSet<usize> = HashSet::new();
+
+    let entry = walk(&program, 0, false)?;
+    for (target, argc) in entry.calls {
+        functions.push(target);
+        let max_argc = argcs.entry(target).or_insert(0);
+        *max_argc = (*max_argc).max(argc);
+    }
+
+    let mut walks = Vec::new();
+    while let Some(start) = functions.pop() {
+        if !checked.insert(start) {
+            continue;
+        }
+        let function = walk(&program, start, true)?;
+        for &(target, argc) in &function.calls {
+            functions.push(target);
+            let max_argc = argcs.entry(target).or_insert(0);
+            *max_argc = (*max_argc).max(argc);
+        }
+        walks.push((start, function));
+    }
+
+    // frames are only known once every call site has been seen
+    for (start, function) in walks {
+        let frame_size = function.frame_size.max(argcs[&start]);
+        for (addr, offset) in function.loads {
+            if offset >= frame_size {
+                return Err(VerifyError::LocalOutOfFrame {
+                    addr,
+                    offset,
+                    frame_size,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What was found while following every path from the start of a function
+struct Walk {
+    /// Target index and argument count of each call
+    calls: Vec<(usize, usize)>,
+    /// Address and offset of each LOCAL_LOAD
+    loads: Vec<(usize, usize)>,
+    /// Number of locals stored to
+    frame_size: usize,
+}
+
+fn walk(program: &kvm::Program, start: usize, in_function: bool) -> Result<Walk, VerifyError> {
+    let mut result = Walk {
+        calls: Vec::new(),
+        loads: Vec::new(),
+        frame_size: 0,
+    };
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    let mut pending = vec![(start, 0, start)];
+
+    while let Some((index, depth, from)) = pending.pop() {
+        let instruction = match program.code.get(index) {
+            Some(instruction) => *instruction,
+            // empty code falls off the end before reaching any instruction
+            None => {
+                return Err(VerifyError::FallsOffEnd {
+                    addr: program.addrs.get(from).copied().unwrap_or(0),
+                })
+            }
+        };
+        let addr = program.addrs[index];
+
+        match depths.get(&index) {
+            Some(&expected) if expected == depth => continue,
+            Some(&expected) => {
+                return Err(VerifyError::StackMismatch {
+                    addr,
+                    expected,
+                    found: depth,
+                })
+            }
+            None => {
+                depths.insert(index, depth);
+            }
+        }
+
+        let (pops, pushes) = stack_effect(instruction);
+        if depth < pops {
+            return Err(VerifyError::StackUnderflow { addr });
+        }
+        let next_depth = depth - pops + pushes;
+
+        match instruction {
+            Instruction::LocalLoad(_)
+            | Instruction::LocalStore(_)
+            | Instruction::LocalArrLoad
+            | Instruction::LocalArrStore
+            | Instruction::Ret
+                if !in_function =>
+            {
+                return Err(VerifyError::NoFrame { addr });
+            }
+            _ => {}
+        }
+
+        match instruction {
+            Instruction::End => {}
+            Instruction::Ret => {
+                if depth != 1 {
+                    return Err(VerifyError::StackMismatch {
+                        addr,
+                        expected: 1,
+                        found: depth,
+                    });
+                }
+            }
+            Instruction::Jump(target) => pending.push((target, next_depth, index)),
+            Instruction::Beqz(target) | Instruction::Bnez(target) => {
+                pending.push((target, next_depth, index));
+                pending.push((index + 1, next_depth, index));
+            }
+            // loading accepts the end of the code as a target, but a function can't start there
+            Instruction::Call { target, .. } if target == program.code.len() => {
+                return Err(VerifyError::FallsOffEnd { addr });
+            }
+            Instruction::Call { argc, target } => {
+                result.calls.push((target, argc));
+                pending.push((index + 1, next_depth, index));
+            }
+            Instruction::LocalLoad(offset) => {
+                result.loads.push((addr, offset));
+                pending.push((index + 1, next_depth, index));
+            }
+            Instruction::LocalStore(offset) => {
+                result.frame_size = result.frame_size.max(offset + 1);
+                pending.push((index + 1, next_depth, index));
+            }
+            _ => pending.push((index + 1, next_depth, index)),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Number of values an instruction pops from the operand stack, and the number it pushes
+fn stack_effect(instruction: Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::End | Instruction::Jump(_) => (0, 0),
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Lt
+        | Instruction::Lte
+        | Instruction::Gt
+        | Instruction::Gte
+        | Instruction::Eq
+        | Instruction::Neq
+        | Instruction::Or
+        | Instruction::And
+        | Instruction::Rand
+        | Instruction::LocalArrLoad
+        | Instruction::GlobalArrLoad => (2, 1),
+        Instruction::LocalArrStore | Instruction::GlobalArrStore => (3, 0),
+        Instruction::LocalLoad(_)
+        | Instruction::GlobalLoad(_)
+        | Instruction::Push(_)
+        | Instruction::Read(_) => (0, 1),
+        Instruction::LocalStore(_)
+        | Instruction::GlobalStore(_)
+        | Instruction::Beqz(_)
+        | Instruction::Bnez(_)
+        | Instruction::Print(_)
+        | Instruction::Pop
+        | Instruction::Ret => (1, 0),
+        Instruction::Call { argc, .. } | Instruction::CallNative { argc, .. } => (argc, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerContext;
+    use crate::instructions::*;
+    use crate::parser::parse_code;
+
+    #[test]
+    fn compiled_code_test() {
+        let code = parse_code(
+            "
+            fn main() {
+              let mut x = 0
+              while x < 3 {
+                if x == 1 { print(fib(x)) }
+                x = x + 1
+              }
+            }
+            fn fib(n) {
+              if n < 2 { return 1 }
+              return fib(n - 1) + fib(n - 2)
+            }
+            ",
+        )
+        .unwrap()
+        .code_gen(&mut CompilerContext::new());
+        assert_eq!(verify(&code), Ok(()));
+    }
+
+    #[test]
+    fn errors_test() {
+        // PUSH 0; CALL f; END; f: ...
+        let function = |body: &[u32]| [&[PUSH, 0, CALL, 1, 6, END][..], body].concat();
+
+        assert_eq!(
+            verify(&function(&[JUMP, 7, RET])),
+            Err(VerifyError::Load(LoadError::BadJumpTarget {
+                addr: 6,
+                target: 7
+            }))
+        );
+        assert_eq!(
+            verify(&function(&[IADD, RET])),
+            Err(VerifyError::StackUnderflow { addr: 6 })
+        );
+        assert_eq!(
+            verify(&function(&[PUSH, 1, BEQZ, 12, PUSH, 1, RET])),
+            Err(VerifyError::StackMismatch {
+                addr: 12,
+                expected: 1,
+                found: 0
+            })
+        );
+        assert_eq!(
+            verify(&function(&[LOCAL_LOAD, 1, RET])),
+            Err(VerifyError::LocalOutOfFrame {
+                addr: 6,
+                offset: 1,
+                frame_size: 1
+            })
+        );
+        assert_eq!(
+            verify(&[PUSH, 1, LOCAL_STORE, 0, END]),
+            Err(VerifyError::NoFrame { addr: 2 })
+        );
+        assert_eq!(
+            verify(&function(&[PUSH, 1])),
+            Err(VerifyError::FallsOffEnd { addr: 6 })
+        );
+        assert_eq!(
+            verify(&function(&[JUMP, 8])),
+            Err(VerifyError::FallsOffEnd { addr: 6 })
+        );
+        assert_eq!(verify(&[]), Err(VerifyError::FallsOffEnd { addr: 0 }));
+        assert_eq!(
+            verify(&[CALL, 0, 4, END]),
+            Err(VerifyError::FallsOffEnd { addr: 0 })
+        );
+    }
+}
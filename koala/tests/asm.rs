@@ -0,0 +1,27 @@
+mod common;
+
+use common::{asm_run_code, golden_test, kvm_run_code, tool_available};
+use koala::{backend::asm, compiler::CompilerContext, parser::parse_code};
+
+macro_rules! asm_tests {
+  ($($name:ident: $value:expr,)*) => {$(
+      #[test]
+      fn $name() {
+        let program = $value;
+        let source = common::read_program(program);
+        let module = parse_code(&source).unwrap().to_ir(&mut CompilerContext::new());
+        golden_test(&format!("{}.s", program), &asm::emit(&module));
+
+        // assemble, link and run it when a C compiler is installed
+        if tool_available("cc") {
+          assert_eq!(asm_run_code(&source, ""), kvm_run_code(&source));
+        }
+      }
+  )*}
+}
+
+asm_tests! {
+  fib_asm_test: "fib",
+  arrays_asm_test: "arrays",
+  logic_asm_test: "logic",
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{c_run_code, c_run_program, c_run_status, golden_test, kvm_run_code, tool_available};
+use koala::{backend::c, parser::parse_code};
+
+macro_rules! c_tests {
+  ($($name:ident: $value:expr,)*) => {$(
+      #[test]
+      fn $name() {
+        let program = $value;
+        let source = common::read_program(program);
+        golden_test(&format!("{}.c", program), &c::emit(&parse_code(&source).unwrap()));
+
+        // build and run the C when a compiler is installed
+        if tool_available("cc") {
+          assert_eq!(c_run_code(&source, ""), kvm_run_code(&source));
+        }
+      }
+  )*}
+}
+
+c_tests! {
+  fib_c_test: "fib",
+  arrays_c_test: "arrays",
+  logic_c_test: "logic",
+}
+
+#[test]
+fn arithmetic_c_test() {
+    if !tool_available("cc") {
+        return;
+    }
+    // overflow wraps, as it does on the KVM
+    let source =
+        "fn main() { print(f(2147483647) + 1) print((0 - f(2147483647)) - 2) } fn f(n) { return n }";
+    assert_eq!(c_run_code(source, ""), "-21474836482147483647");
+    assert_eq!(kvm_run_code(source), "-21474836482147483647");
+    let source = "fn main() { print(1) print(f(3) / (3 - f(3))) } fn f(n) { return n }";
+    assert_eq!(c_run_status(source, ""), Some(1));
+}
+
+#[test]
+fn shadowed_natives_c_test() {
+    if !tool_available("cc") {
+        return;
+    }
+    // the program's own functions are called rather than the helpers of the same name
+    let source = "fn main() { print(max(3, 9)) print(read_int()) } fn max(a, b) { return a + b } fn read_int() { return 7 }";
+    assert_eq!(c_run_code(source, ""), "127");
+    assert_eq!(kvm_run_code(source), "127");
+}
+
+#[test]
+fn names_c_test() {
+    if !tool_available("cc") {
+        return;
+    }
+    // neither the counter of the for loop nor the function of the module are renamed
+    // to a name the program already uses
+    let source = "
+    fn main() {
+      global for_counter = 7
+      for i in 0..2 {
+        print(for_counter)
+      }
+      print(m::f())
+      print(m__f())
+    }
+    fn f() { return 1 }
+    fn m__f() { return 2 }
+    ";
+    let mut program = parse_code(source).unwrap();
+    program.0[1].id = "m::f".to_string();
+    assert_eq!(c_run_program(&program, ""), "7712");
+}
+
+#[test]
+#[should_panic(expected = "cannot assign to undeclared variable 'b'")]
+fn compiler_errors_c_test() {
+    // the program is checked by the compiler before it is translated
+    c::emit(&parse_code("fn main() { if 1 { let b = 1 } b = 2 }").unwrap());
+}
@@ -0,0 +1,229 @@
+#![allow(dead_code)]
+
+use std::{
+    cell::RefCell,
+    env, fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use koala::{
+    backend::{asm, c, wasm},
+    compiler::CompilerContext,
+    grammar::Program,
+    kvm::VirtualMachine,
+    parser::parse_code,
+};
+
+/// Host for compiled WebAssembly modules, providing the print imports,
+/// read imports which take integers from stdin, and the `exit()` and `clock()` natives
+const WASM_HOST: &str = r#"
+const fs = require('fs');
+const bytes = fs.readFileSync(process.argv[1]);
+let input = fs.readFileSync(0, 'utf8');
+const readInt = () => {
+  const match = /^\s*(-?\d+)/.exec(input);
+  if (!match) process.exit(1);
+  input = input.slice(match[0].length);
+  return Number(match[1]);
+};
+const env = {
+  print_int: (val) => process.stdout.write(String(val)),
+  print_char: (val) => process.stdout.write(String.fromCharCode(val)),
+  read_int: readInt,
+  read_line: () => {
+    const val = readInt();
+    input = input.replace(/^[^\n]*\n?/, '');
+    return val;
+  },
+  exit: (code) => process.exit(code),
+  clock: () => Math.floor(performance.now()),
+};
+WebAssembly.instantiate(bytes, { env }).then(({ instance }) => instance.exports.main());
+"#;
+
+fn tests_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+/// Read one of the sample programs in `tests/programs`
+pub fn read_program(name: &str) -> String {
+    let path = tests_dir().join("programs").join(format!("{}.koala", name));
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+/// Compare generated output with the file in `tests/golden`.
+/// Set `KOALA_BLESS=1` to write the current output as the new golden file.
+pub fn golden_test(file_name: &str, actual: &str) {
+    let path = tests_dir().join("golden").join(file_name);
+    if env::var("KOALA_BLESS").is_ok() {
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (run with KOALA_BLESS=1)", path.display(), e));
+    assert_eq!(
+        expected.replace("\r\n", "\n"),
+        actual,
+        "output differs from {}",
+        path.display()
+    );
+}
+
+/// Compile and run the code on the KVM, capturing what it prints
+pub fn kvm_run_code(code: &str) -> String {
+    let value = Rc::new(RefCell::new(String::new()));
+    let captured_value = value.clone();
+
+    let print_callback = &move |msg: &str| *captured_value.borrow_mut() += msg;
+
+    let bin = parse_code(code)
+        .unwrap()
+        .code_gen(&mut CompilerContext::new());
+    let mut kvm = VirtualMachine::new(print_callback);
+    kvm.run(&bin);
+
+    value.take()
+}
+
+/// Whether an external tool can be found on the PATH
+pub fn tool_available(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+/// Run a tool with `input` on its stdin, returning what it printed
+pub fn run_command(tool: &str, args: &[&str], input: &str) -> String {
+    let output = command_output(tool, args, input);
+    assert!(output.status.success(), "{} failed", tool);
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Run a tool with `input` on its stdin, returning its exit status
+pub fn command_status(tool: &str, args: &[&str], input: &str) -> Option<i32> {
+    command_output(tool, args, input).status.code()
+}
+
+fn command_output(tool: &str, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to start {}: {}", tool, e));
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Arguments for `lli` to read opaque pointers. They are the default from LLVM 15,
+/// and LLVM 17 removed the option
+pub fn lli_args() -> &'static [&'static str] {
+    let version = run_command("lli", &["--version"], "");
+    let major = version
+        .split("LLVM version ")
+        .nth(1)
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|major| major.parse::<u32>().ok());
+    match major {
+        Some(major) if major < 15 => &["-opaque-pointers"],
+        _ => &[],
+    }
+}
+
+/// Translate the code to C, then build and run it with the system C compiler
+pub fn c_run_code(code: &str, input: &str) -> String {
+    c_run_program(&parse_code(code).unwrap(), input)
+}
+
+pub fn c_run_program(program: &Program, input: &str) -> String {
+    cc_run("program.c", &c::emit(program), input)
+}
+
+/// Translate the code to x86-64 assembly, then assemble and run it with the system C compiler
+pub fn asm_run_code(code: &str, input: &str) -> String {
+    asm_run_program(&parse_code(code).unwrap(), input)
+}
+
+pub fn asm_run_program(program: &Program, input: &str) -> String {
+    let module = program.to_ir(&mut CompilerContext::new());
+    cc_run("program.s", &asm::emit(&module), input)
+}
+
+/// Translate the code to C, then build and run it, returning the program's exit status
+pub fn c_run_status(code: &str, input: &str) -> Option<i32> {
+    let dir = cc_build("program.c", &c::emit(&parse_code(code).unwrap()));
+    let status = command_status(dir.join("program").to_str().unwrap(), &[], input);
+
+    fs::remove_dir_all(&dir).unwrap();
+    status
+}
+
+/// Build a source file with `cc` and run the resulting program with `input` on its stdin
+fn cc_run(file_name: &str, contents: &str, input: &str) -> String {
+    let dir = cc_build(file_name, contents);
+    let output = run_command(dir.join("program").to_str().unwrap(), &[], input);
+
+    fs::remove_dir_all(&dir).unwrap();
+    output
+}
+
+/// Build a source file with `cc` into `program`, in a directory of its own
+fn cc_build(file_name: &str, contents: &str) -> PathBuf {
+    let dir = build_dir("cc");
+    let source = dir.join(file_name);
+    fs::write(&source, contents).unwrap();
+
+    // signed overflow in the generated code aborts, rather than going unnoticed
+    let status = Command::new("cc")
+        .arg("-ftrapv")
+        .arg("-o")
+        .arg(dir.join("program"))
+        .arg(&source)
+        .status()
+        .unwrap();
+    assert!(status.success(), "cc failed to build {}", source.display());
+    dir
+}
+
+/// Create an empty directory for building one program
+fn build_dir(name: &str) -> PathBuf {
+    static BUILD_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = env::temp_dir().join(format!(
+        "koala-{}-{}-{}",
+        name,
+        std::process::id(),
+        BUILD_ID.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Compile the code to a WebAssembly module and run it with node, reading from `input`
+pub fn wasm_run_code(code: &str, input: &str) -> String {
+    wasm_run_program(&parse_code(code).unwrap(), input)
+}
+
+pub fn wasm_run_program(program: &Program, input: &str) -> String {
+    let dir = build_dir("wasm");
+    let module = dir.join("program.wasm");
+    fs::write(&module, wasm::compile(program).to_wasm()).unwrap();
+
+    let output = run_command("node", &["-e", WASM_HOST, module.to_str().unwrap()], input);
+
+    fs::remove_dir_all(&dir).unwrap();
+    output
+}
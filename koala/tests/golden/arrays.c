@@ -0,0 +1,47 @@
+#include <stdio.h>
+
+static int squares[5];
+
+int koala_show(void);
+int koala_main(void);
+
+int koala_show(void) {
+    int i = 0;
+
+    i = 4;
+    while (i >= 0) {
+        printf("%d", squares[i]);
+        i = (int)((unsigned int)i - (unsigned int)1);
+    }
+    printf("\n");
+    return 0;
+}
+
+int koala_main(void) {
+    int a[3] = {0};
+    int i = 0;
+
+    squares[0] = 0;
+    squares[1] = 0;
+    squares[2] = 0;
+    squares[3] = 0;
+    squares[4] = 0;
+    a[0] = 1;
+    a[1] = 12;
+    a[2] = 123;
+    a[1] = (int)((unsigned int)a[1] * (unsigned int)2);
+    printf("%d", (int)((unsigned int)a[0] + (unsigned int)((int)((unsigned int)a[1] + (unsigned int)a[2]))));
+    printf("\n");
+    i = 0;
+    while (i < 5) {
+        squares[i] = (int)((unsigned int)i * (unsigned int)i);
+        i = (int)((unsigned int)i + (unsigned int)1);
+    }
+    koala_show();
+    return 0;
+}
+
+int main(void) {
+    koala_main();
+    return 0;
+}
@@ -0,0 +1,120 @@
+; ModuleID = 'koala'
+@globals = internal global [5 x i32] zeroinitializer
+@fmt.int = private unnamed_addr constant [3 x i8] c"%d\00"
+@fmt.char = private unnamed_addr constant [3 x i8] c"%c\00"
+
+declare i32 @printf(ptr, ...)
+
+define internal i32 @koala.show() {
+entry:
+  %frame = alloca [1 x i32]
+  store [1 x i32] zeroinitializer, ptr %frame
+  br label %L0
+L0:
+  %t0 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  store i32 4, ptr %t0
+  br label %L1
+L1:
+  %t1 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t2 = load i32, ptr %t1
+  %t3 = icmp sge i32 %t2, 0
+  %t4 = zext i1 %t3 to i32
+  %t5 = icmp ne i32 %t4, 0
+  br i1 %t5, label %L2, label %L3
+L2:
+  %t6 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t7 = load i32, ptr %t6
+  %t8 = add i32 0, %t7
+  %t9 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 %t8
+  %t10 = load i32, ptr %t9
+  %t11 = call i32 (ptr, ...) @printf(ptr @fmt.int, i32 %t10)
+  %t12 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t13 = load i32, ptr %t12
+  %t14 = sub i32 %t13, 1
+  %t15 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  store i32 %t14, ptr %t15
+  br label %L1
+L3:
+  %t16 = call i32 (ptr, ...) @printf(ptr @fmt.char, i32 10)
+  ret i32 0
+}
+
+define internal i32 @koala.main() {
+entry:
+  %frame = alloca [4 x i32]
+  store [4 x i32] zeroinitializer, ptr %frame
+  br label %L0
+L0:
+  %t0 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 0
+  store i32 0, ptr %t0
+  %t1 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 1
+  store i32 0, ptr %t1
+  %t2 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 2
+  store i32 0, ptr %t2
+  %t3 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 3
+  store i32 0, ptr %t3
+  %t4 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 4
+  store i32 0, ptr %t4
+  %t5 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 0
+  store i32 1, ptr %t5
+  %t6 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 1
+  store i32 12, ptr %t6
+  %t7 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 2
+  store i32 123, ptr %t7
+  %t8 = add i32 0, 1
+  %t9 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 %t8
+  %t10 = load i32, ptr %t9
+  %t11 = mul i32 %t10, 2
+  %t12 = add i32 0, 1
+  %t13 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 %t12
+  store i32 %t11, ptr %t13
+  %t14 = add i32 0, 2
+  %t15 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 %t14
+  %t16 = load i32, ptr %t15
+  %t17 = add i32 0, 1
+  %t18 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 %t17
+  %t19 = load i32, ptr %t18
+  %t20 = add i32 %t19, %t16
+  %t21 = add i32 0, 0
+  %t22 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 %t21
+  %t23 = load i32, ptr %t22
+  %t24 = add i32 %t23, %t20
+  %t25 = call i32 (ptr, ...) @printf(ptr @fmt.int, i32 %t24)
+  %t26 = call i32 (ptr, ...) @printf(ptr @fmt.char, i32 10)
+  %t27 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  store i32 0, ptr %t27
+  br label %L1
+L1:
+  %t28 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  %t29 = load i32, ptr %t28
+  %t30 = icmp slt i32 %t29, 5
+  %t31 = zext i1 %t30 to i32
+  %t32 = icmp ne i32 %t31, 0
+  br i1 %t32, label %L2, label %L3
+L2:
+  %t33 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  %t34 = load i32, ptr %t33
+  %t35 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  %t36 = load i32, ptr %t35
+  %t37 = mul i32 %t36, %t34
+  %t38 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  %t39 = load i32, ptr %t38
+  %t40 = add i32 0, %t39
+  %t41 = getelementptr inbounds [5 x i32], ptr @globals, i32 0, i32 %t40
+  store i32 %t37, ptr %t41
+  %t42 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  %t43 = load i32, ptr %t42
+  %t44 = add i32 %t43, 1
+  %t45 = getelementptr inbounds [4 x i32], ptr %frame, i32 0, i32 3
+  store i32 %t44, ptr %t45
+  br label %L1
+L3:
+  %t46 = call i32 @koala.show()
+  ret i32 0
+}
+
+define i32 @main() {
+entry:
+  %0 = call i32 @koala.main()
+  ret i32 0
+}
@@ -0,0 +1,244 @@
+    .text
+
+koala.show:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $16, %rsp
+    leaq -4(%rbp), %rdi
+    movl $1, %ecx
+    xorl %eax, %eax
+    rep stosl
+.Lkoala.show.0:
+    pushq $4
+    popq %rax
+    movl %eax, -4(%rbp)
+.Lkoala.show.1:
+    pushq $0
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setge %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.show.3
+.Lkoala.show.2:
+    pushq $0
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    leaq globals(%rip), %rcx
+    movl (%rcx,%rax,4), %eax
+    pushq %rax
+    popq %rsi
+    leaq fmt.int(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $1
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    subl %ecx, %eax
+    pushq %rax
+    popq %rax
+    movl %eax, -4(%rbp)
+    jmp .Lkoala.show.1
+.Lkoala.show.3:
+    pushq $10
+    popq %rsi
+    leaq fmt.char(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $0
+    popq %rax
+    leave
+    ret
+
+koala.main:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $16, %rsp
+    leaq -16(%rbp), %rdi
+    movl $4, %ecx
+    xorl %eax, %eax
+    rep stosl
+.Lkoala.main.0:
+    pushq $0
+    popq %rax
+    movl %eax, globals+0(%rip)
+    pushq $0
+    popq %rax
+    movl %eax, globals+4(%rip)
+    pushq $0
+    popq %rax
+    movl %eax, globals+8(%rip)
+    pushq $0
+    popq %rax
+    movl %eax, globals+12(%rip)
+    pushq $0
+    popq %rax
+    movl %eax, globals+16(%rip)
+    pushq $1
+    popq %rax
+    movl %eax, -16(%rbp)
+    pushq $12
+    popq %rax
+    movl %eax, -12(%rbp)
+    pushq $123
+    popq %rax
+    movl %eax, -8(%rbp)
+    pushq $2
+    pushq $0
+    pushq $1
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    movl -16(%rbp,%rax,4), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    imull %ecx, %eax
+    pushq %rax
+    pushq $1
+    pushq $0
+    popq %rax
+    popq %rcx
+    popq %rdx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    movl %edx, -16(%rbp,%rax,4)
+    pushq $0
+    pushq $2
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    movl -16(%rbp,%rax,4), %eax
+    pushq %rax
+    pushq $0
+    pushq $1
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    movl -16(%rbp,%rax,4), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    pushq %rax
+    pushq $0
+    pushq $0
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    movl -16(%rbp,%rax,4), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    pushq %rax
+    popq %rsi
+    leaq fmt.int(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $10
+    popq %rsi
+    leaq fmt.char(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $0
+    popq %rax
+    movl %eax, -4(%rbp)
+.Lkoala.main.1:
+    pushq $5
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setl %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.main.3
+.Lkoala.main.2:
+    movl -4(%rbp), %eax
+    pushq %rax
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    imull %ecx, %eax
+    pushq %rax
+    movl -4(%rbp), %eax
+    pushq %rax
+    pushq $0
+    popq %rax
+    popq %rcx
+    popq %rdx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    leaq globals(%rip), %rcx
+    movl %edx, (%rcx,%rax,4)
+    pushq $1
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    pushq %rax
+    popq %rax
+    movl %eax, -4(%rbp)
+    jmp .Lkoala.main.1
+.Lkoala.main.3:
+    call koala.show
+    pushq %rax
+    addq $8, %rsp
+    pushq $0
+    popq %rax
+    leave
+    ret
+
+    .globl main
+main:
+    pushq %rbx
+    call koala.main
+    popq %rbx
+    xorl %eax, %eax
+    ret
+
+    .section .rodata
+fmt.int:
+    .string "%d"
+fmt.char:
+    .string "%c"
+
+    .bss
+    .align 4
+globals:
+    .zero 20
+
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,191 @@
+(module
+  (import "env" "print_int" (func $env.print_int (param i32)))
+  (import "env" "print_char" (func $env.print_char (param i32)))
+  (memory (export "memory") 16)
+  (global $stack.ptr (mut i32) (i32.const 20))
+  (func $show
+    (result i32)
+    (local $i i32)
+    i32.const 4
+    local.set $i
+    block
+      loop
+        local.get $i
+        i32.const 0
+        i32.ge_s
+        i32.eqz
+        br_if 1
+        i32.const 0
+        local.get $i
+        i32.const 4
+        i32.mul
+        i32.add
+        i32.load
+        call $env.print_int
+        local.get $i
+        i32.const 1
+        i32.sub
+        local.set $i
+        br 0
+      end
+    end
+    i32.const 10
+    call $env.print_char
+    i32.const 0
+    return
+    unreachable
+  )
+  (func $main
+    (result i32)
+    (local $frame.ptr i32)
+    (local $i i32)
+    global.get $stack.ptr
+    local.set $frame.ptr
+    local.get $frame.ptr
+    i32.const 12
+    i32.add
+    global.set $stack.ptr
+    i32.const 0
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.store
+    i32.const 0
+    i32.const 1
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.store
+    i32.const 0
+    i32.const 2
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.store
+    i32.const 0
+    i32.const 3
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.store
+    i32.const 0
+    i32.const 4
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.store
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 1
+    i32.store
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 1
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 12
+    i32.store
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 2
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 123
+    i32.store
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 1
+    i32.const 4
+    i32.mul
+    i32.add
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 1
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.load
+    i32.const 2
+    i32.mul
+    i32.store
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.load
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 1
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.load
+    local.get $frame.ptr
+    i32.const 0
+    i32.add
+    i32.const 2
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.load
+    i32.add
+    i32.add
+    call $env.print_int
+    i32.const 10
+    call $env.print_char
+    i32.const 0
+    local.set $i
+    block
+      loop
+        local.get $i
+        i32.const 5
+        i32.lt_s
+        i32.eqz
+        br_if 1
+        i32.const 0
+        local.get $i
+        i32.const 4
+        i32.mul
+        i32.add
+        local.get $i
+        local.get $i
+        i32.mul
+        i32.store
+        local.get $i
+        i32.const 1
+        i32.add
+        local.set $i
+        br 0
+      end
+    end
+    call $show
+    drop
+    i32.const 0
+    local.get $frame.ptr
+    global.set $stack.ptr
+    return
+    unreachable
+  )
+  (export "main" (func $main))
+)
@@ -0,0 +1,30 @@
+#include <stdio.h>
+
+int koala_fib(int n);
+int koala_main(void);
+
+int koala_fib(int n) {
+    if (n < 2) {
+        return 1;
+    }
+    int _t0 = koala_fib((int)((unsigned int)n - (unsigned int)2));
+    return (int)((unsigned int)koala_fib((int)((unsigned int)n - (unsigned int)1)) + (unsigned int)_t0);
+}
+
+int koala_main(void) {
+    int n = 0;
+
+    n = 0;
+    while (n < 10) {
+        printf("%d", koala_fib(n));
+        printf("%d", n);
+        n = (int)((unsigned int)n + (unsigned int)1);
+    }
+    printf("\n");
+    return 0;
+}
+
+int main(void) {
+    koala_main();
+    return 0;
+}
@@ -0,0 +1,76 @@
+; ModuleID = 'koala'
+@globals = internal global [0 x i32] zeroinitializer
+@fmt.int = private unnamed_addr constant [3 x i8] c"%d\00"
+@fmt.char = private unnamed_addr constant [3 x i8] c"%c\00"
+
+declare i32 @printf(ptr, ...)
+
+define internal i32 @koala.fib(i32 %arg0) {
+entry:
+  %frame = alloca [1 x i32]
+  store [1 x i32] zeroinitializer, ptr %frame
+  %t0 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  store i32 %arg0, ptr %t0
+  br label %L0
+L0:
+  %t1 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t2 = load i32, ptr %t1
+  %t3 = icmp slt i32 %t2, 2
+  %t4 = zext i1 %t3 to i32
+  %t5 = icmp ne i32 %t4, 0
+  br i1 %t5, label %L1, label %L2
+L1:
+  ret i32 1
+L2:
+  %t6 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t7 = load i32, ptr %t6
+  %t8 = sub i32 %t7, 2
+  %t9 = call i32 @koala.fib(i32 %t8)
+  %t10 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t11 = load i32, ptr %t10
+  %t12 = sub i32 %t11, 1
+  %t13 = call i32 @koala.fib(i32 %t12)
+  %t14 = add i32 %t13, %t9
+  ret i32 %t14
+}
+
+define internal i32 @koala.main() {
+entry:
+  %frame = alloca [1 x i32]
+  store [1 x i32] zeroinitializer, ptr %frame
+  br label %L0
+L0:
+  %t0 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  store i32 0, ptr %t0
+  br label %L1
+L1:
+  %t1 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t2 = load i32, ptr %t1
+  %t3 = icmp slt i32 %t2, 10
+  %t4 = zext i1 %t3 to i32
+  %t5 = icmp ne i32 %t4, 0
+  br i1 %t5, label %L2, label %L3
+L2:
+  %t6 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t7 = load i32, ptr %t6
+  %t8 = call i32 @koala.fib(i32 %t7)
+  %t9 = call i32 (ptr, ...) @printf(ptr @fmt.int, i32 %t8)
+  %t10 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t11 = load i32, ptr %t10
+  %t12 = call i32 (ptr, ...) @printf(ptr @fmt.int, i32 %t11)
+  %t13 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  %t14 = load i32, ptr %t13
+  %t15 = add i32 %t14, 1
+  %t16 = getelementptr inbounds [1 x i32], ptr %frame, i32 0, i32 0
+  store i32 %t15, ptr %t16
+  br label %L1
+L3:
+  %t17 = call i32 (ptr, ...) @printf(ptr @fmt.char, i32 10)
+  ret i32 0
+}
+
+define i32 @main() {
+entry:
+  %0 = call i32 @koala.main()
+  ret i32 0
+}
@@ -0,0 +1,150 @@
+    .text
+
+koala.fib:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $16, %rsp
+    leaq -4(%rbp), %rdi
+    movl $1, %ecx
+    xorl %eax, %eax
+    rep stosl
+    movl 16(%rbp), %eax
+    movl %eax, -4(%rbp)
+.Lkoala.fib.0:
+    pushq $2
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setl %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.fib.2
+.Lkoala.fib.1:
+    pushq $1
+    popq %rax
+    leave
+    ret
+.Lkoala.fib.2:
+    pushq $2
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    subl %ecx, %eax
+    pushq %rax
+    call koala.fib
+    addq $8, %rsp
+    pushq %rax
+    pushq $1
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    subl %ecx, %eax
+    pushq %rax
+    call koala.fib
+    addq $8, %rsp
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    pushq %rax
+    popq %rax
+    leave
+    ret
+
+koala.main:
+    pushq %rbp
+    movq %rsp, %rbp
+    subq $16, %rsp
+    leaq -4(%rbp), %rdi
+    movl $1, %ecx
+    xorl %eax, %eax
+    rep stosl
+.Lkoala.main.0:
+    pushq $0
+    popq %rax
+    movl %eax, -4(%rbp)
+.Lkoala.main.1:
+    pushq $10
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setl %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.main.3
+.Lkoala.main.2:
+    movl -4(%rbp), %eax
+    pushq %rax
+    call koala.fib
+    addq $8, %rsp
+    pushq %rax
+    popq %rsi
+    leaq fmt.int(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rsi
+    leaq fmt.int(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $1
+    movl -4(%rbp), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    pushq %rax
+    popq %rax
+    movl %eax, -4(%rbp)
+    jmp .Lkoala.main.1
+.Lkoala.main.3:
+    pushq $10
+    popq %rsi
+    leaq fmt.char(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $0
+    popq %rax
+    leave
+    ret
+
+    .globl main
+main:
+    pushq %rbx
+    call koala.main
+    popq %rbx
+    xorl %eax, %eax
+    ret
+
+    .section .rodata
+fmt.int:
+    .string "%d"
+fmt.char:
+    .string "%c"
+
+    .bss
+    .align 4
+globals:
+    .zero 4
+
+    .section .note.GNU-stack,"",@progbits
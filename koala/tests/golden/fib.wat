@@ -0,0 +1,62 @@
+(module
+  (import "env" "print_int" (func $env.print_int (param i32)))
+  (import "env" "print_char" (func $env.print_char (param i32)))
+  (memory (export "memory") 16)
+  (global $stack.ptr (mut i32) (i32.const 0))
+  (func $fib
+    (param $n i32)
+    (result i32)
+    (local $tmp.1 i32)
+    local.get $n
+    i32.const 2
+    i32.lt_s
+    if
+      i32.const 1
+      return
+    end
+    local.get $n
+    i32.const 2
+    i32.sub
+    call $fib
+    local.set $tmp.1
+    local.get $n
+    i32.const 1
+    i32.sub
+    call $fib
+    local.get $tmp.1
+    i32.add
+    return
+    unreachable
+  )
+  (func $main
+    (result i32)
+    (local $n i32)
+    i32.const 0
+    local.set $n
+    block
+      loop
+        local.get $n
+        i32.const 10
+        i32.lt_s
+        i32.eqz
+        br_if 1
+        local.get $n
+        call $fib
+        call $env.print_int
+        local.get $n
+        call $env.print_int
+        local.get $n
+        i32.const 1
+        i32.add
+        local.set $n
+        br 0
+      end
+    end
+    i32.const 10
+    call $env.print_char
+    i32.const 0
+    return
+    unreachable
+  )
+  (export "main" (func $main))
+)
@@ -0,0 +1,46 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <limits.h>
+
+static int _div(int lhs, int rhs) {
+    if (rhs == 0 || (lhs == INT_MIN && rhs == -1)) {
+        exit(1);
+    }
+    return lhs / rhs;
+}
+
+static int count[1];
+
+int koala_bump(void);
+int koala_main(void);
+
+int koala_bump(void) {
+    count[0] = (int)((unsigned int)count[0] + (unsigned int)1);
+    return 0;
+}
+
+int koala_main(void) {
+    count[0] = 0;
+    if ((1 < 2) != 0 & (2 <= 2) != 0) {
+        koala_bump();
+    }
+    if ((3 > 2) != 0 | 0 != 0) {
+        koala_bump();
+    }
+    if (2 >= 3) {
+        koala_bump();
+    }
+    if ((1 == 1) != 0 & (1 != 2) != 0) {
+        koala_bump();
+    }
+    printf("%d", count[0]);
+    printf("\n");
+    printf("%d", (int)((unsigned int)(_div(100, 7)) - (unsigned int)((int)((unsigned int)3 * (unsigned int)2))));
+    printf("\n");
+    return 0;
+}
+
+int main(void) {
+    koala_main();
+    return 0;
+}
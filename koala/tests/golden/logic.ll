@@ -0,0 +1,114 @@
+; ModuleID = 'koala'
+@globals = internal global [1 x i32] zeroinitializer
+@fmt.int = private unnamed_addr constant [3 x i8] c"%d\00"
+@fmt.char = private unnamed_addr constant [3 x i8] c"%c\00"
+
+declare i32 @printf(ptr, ...)
+declare void @exit(i32)
+
+define internal i32 @int.div(i32 %lhs, i32 %rhs) {
+entry:
+  %zero = icmp eq i32 %rhs, 0
+  %min = icmp eq i32 %lhs, -2147483648
+  %minus.one = icmp eq i32 %rhs, -1
+  %overflow = and i1 %min, %minus.one
+  %fails = or i1 %zero, %overflow
+  br i1 %fails, label %error, label %divide
+error:
+  call void @exit(i32 1)
+  unreachable
+divide:
+  %result = sdiv i32 %lhs, %rhs
+  ret i32 %result
+}
+
+define internal i32 @koala.bump() {
+entry:
+  %frame = alloca [0 x i32]
+  store [0 x i32] zeroinitializer, ptr %frame
+  br label %L0
+L0:
+  %t0 = add i32 0, 0
+  %t1 = getelementptr inbounds [1 x i32], ptr @globals, i32 0, i32 %t0
+  %t2 = load i32, ptr %t1
+  %t3 = add i32 %t2, 1
+  %t4 = add i32 0, 0
+  %t5 = getelementptr inbounds [1 x i32], ptr @globals, i32 0, i32 %t4
+  store i32 %t3, ptr %t5
+  ret i32 0
+}
+
+define internal i32 @koala.main() {
+entry:
+  %frame = alloca [0 x i32]
+  store [0 x i32] zeroinitializer, ptr %frame
+  br label %L0
+L0:
+  %t0 = getelementptr inbounds [1 x i32], ptr @globals, i32 0, i32 0
+  store i32 0, ptr %t0
+  %t1 = icmp sle i32 2, 2
+  %t2 = zext i1 %t1 to i32
+  %t3 = icmp slt i32 1, 2
+  %t4 = zext i1 %t3 to i32
+  %t6 = icmp ne i32 %t4, 0
+  %t7 = icmp ne i32 %t2, 0
+  %t5 = and i1 %t6, %t7
+  %t8 = zext i1 %t5 to i32
+  %t9 = icmp ne i32 %t8, 0
+  br i1 %t9, label %L1, label %L2
+L1:
+  %t10 = call i32 @koala.bump()
+  br label %L2
+L2:
+  %t11 = icmp sgt i32 3, 2
+  %t12 = zext i1 %t11 to i32
+  %t14 = icmp ne i32 %t12, 0
+  %t15 = icmp ne i32 0, 0
+  %t13 = or i1 %t14, %t15
+  %t16 = zext i1 %t13 to i32
+  %t17 = icmp ne i32 %t16, 0
+  br i1 %t17, label %L3, label %L4
+L3:
+  %t18 = call i32 @koala.bump()
+  br label %L4
+L4:
+  %t19 = icmp sge i32 2, 3
+  %t20 = zext i1 %t19 to i32
+  %t21 = icmp ne i32 %t20, 0
+  br i1 %t21, label %L5, label %L6
+L5:
+  %t22 = call i32 @koala.bump()
+  br label %L6
+L6:
+  %t23 = icmp ne i32 1, 2
+  %t24 = zext i1 %t23 to i32
+  %t25 = icmp eq i32 1, 1
+  %t26 = zext i1 %t25 to i32
+  %t28 = icmp ne i32 %t26, 0
+  %t29 = icmp ne i32 %t24, 0
+  %t27 = and i1 %t28, %t29
+  %t30 = zext i1 %t27 to i32
+  %t31 = icmp ne i32 %t30, 0
+  br i1 %t31, label %L7, label %L8
+L7:
+  %t32 = call i32 @koala.bump()
+  br label %L8
+L8:
+  %t33 = add i32 0, 0
+  %t34 = getelementptr inbounds [1 x i32], ptr @globals, i32 0, i32 %t33
+  %t35 = load i32, ptr %t34
+  %t36 = call i32 (ptr, ...) @printf(ptr @fmt.int, i32 %t35)
+  %t37 = call i32 (ptr, ...) @printf(ptr @fmt.char, i32 10)
+  %t38 = mul i32 3, 2
+  %t39 = call i32 @int.div(i32 100, i32 7)
+  %t40 = sub i32 %t39, %t38
+  %t41 = call i32 (ptr, ...) @printf(ptr @fmt.int, i32 %t40)
+  %t42 = call i32 (ptr, ...) @printf(ptr @fmt.char, i32 10)
+  ret i32 0
+}
+
+define i32 @main() {
+entry:
+  %0 = call i32 @koala.main()
+  ret i32 0
+}
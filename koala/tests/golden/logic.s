@@ -0,0 +1,230 @@
+    .text
+
+koala.bump:
+    pushq %rbp
+    movq %rsp, %rbp
+.Lkoala.bump.0:
+    pushq $1
+    pushq $0
+    pushq $0
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    leaq globals(%rip), %rcx
+    movl (%rcx,%rax,4), %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    pushq %rax
+    pushq $0
+    pushq $0
+    popq %rax
+    popq %rcx
+    popq %rdx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    leaq globals(%rip), %rcx
+    movl %edx, (%rcx,%rax,4)
+    pushq $0
+    popq %rax
+    leave
+    ret
+
+koala.main:
+    pushq %rbp
+    movq %rsp, %rbp
+.Lkoala.main.0:
+    pushq $0
+    popq %rax
+    movl %eax, globals+0(%rip)
+    pushq $2
+    pushq $2
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setle %al
+    movzbl %al, %eax
+    pushq %rax
+    pushq $2
+    pushq $1
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setl %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    testl %eax, %eax
+    setne %al
+    testl %ecx, %ecx
+    setne %cl
+    andb %cl, %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.main.2
+.Lkoala.main.1:
+    call koala.bump
+    pushq %rax
+    addq $8, %rsp
+.Lkoala.main.2:
+    pushq $0
+    pushq $2
+    pushq $3
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setg %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    testl %eax, %eax
+    setne %al
+    testl %ecx, %ecx
+    setne %cl
+    orb %cl, %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.main.4
+.Lkoala.main.3:
+    call koala.bump
+    pushq %rax
+    addq $8, %rsp
+.Lkoala.main.4:
+    pushq $3
+    pushq $2
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setge %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.main.6
+.Lkoala.main.5:
+    call koala.bump
+    pushq %rax
+    addq $8, %rsp
+.Lkoala.main.6:
+    pushq $2
+    pushq $1
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    setne %al
+    movzbl %al, %eax
+    pushq %rax
+    pushq $1
+    pushq $1
+    popq %rax
+    popq %rcx
+    cmpl %ecx, %eax
+    sete %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    popq %rcx
+    testl %eax, %eax
+    setne %al
+    testl %ecx, %ecx
+    setne %cl
+    andb %cl, %al
+    movzbl %al, %eax
+    pushq %rax
+    popq %rax
+    testl %eax, %eax
+    je .Lkoala.main.8
+.Lkoala.main.7:
+    call koala.bump
+    pushq %rax
+    addq $8, %rsp
+.Lkoala.main.8:
+    pushq $0
+    pushq $0
+    popq %rax
+    popq %rcx
+    addl %ecx, %eax
+    movslq %eax, %rax
+    leaq globals(%rip), %rcx
+    movl (%rcx,%rax,4), %eax
+    pushq %rax
+    popq %rsi
+    leaq fmt.int(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $10
+    popq %rsi
+    leaq fmt.char(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $2
+    pushq $3
+    popq %rax
+    popq %rcx
+    imull %ecx, %eax
+    pushq %rax
+    pushq $7
+    pushq $100
+    popq %rax
+    popq %rcx
+    cltd
+    idivl %ecx
+    pushq %rax
+    popq %rax
+    popq %rcx
+    subl %ecx, %eax
+    pushq %rax
+    popq %rsi
+    leaq fmt.int(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $10
+    popq %rsi
+    leaq fmt.char(%rip), %rdi
+    xorl %eax, %eax
+    movq %rsp, %rbx
+    andq $-16, %rsp
+    call printf@PLT
+    movq %rbx, %rsp
+    pushq $0
+    popq %rax
+    leave
+    ret
+
+    .globl main
+main:
+    pushq %rbx
+    call koala.main
+    popq %rbx
+    xorl %eax, %eax
+    ret
+
+    .section .rodata
+fmt.int:
+    .string "%d"
+fmt.char:
+    .string "%c"
+
+    .bss
+    .align 4
+globals:
+    .zero 4
+
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,118 @@
+(module
+  (import "env" "print_int" (func $env.print_int (param i32)))
+  (import "env" "print_char" (func $env.print_char (param i32)))
+  (memory (export "memory") 16)
+  (global $stack.ptr (mut i32) (i32.const 4))
+  (func $bump
+    (result i32)
+    i32.const 0
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.load
+    i32.const 1
+    i32.add
+    i32.store
+    i32.const 0
+    return
+    unreachable
+  )
+  (func $main
+    (result i32)
+    (local $tmp.0 i32)
+    (local $tmp.1 i32)
+    (local $tmp.2 i32)
+    i32.const 0
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.const 0
+    i32.store
+    i32.const 1
+    i32.const 2
+    i32.lt_s
+    i32.const 2
+    i32.const 2
+    i32.le_s
+    local.set $tmp.0
+    i32.const 0
+    i32.ne
+    local.get $tmp.0
+    i32.const 0
+    i32.ne
+    i32.and
+    if
+      call $bump
+      drop
+    end
+    i32.const 3
+    i32.const 2
+    i32.gt_s
+    i32.const 0
+    local.set $tmp.1
+    i32.const 0
+    i32.ne
+    local.get $tmp.1
+    i32.const 0
+    i32.ne
+    i32.or
+    if
+      call $bump
+      drop
+    end
+    i32.const 2
+    i32.const 3
+    i32.ge_s
+    if
+      call $bump
+      drop
+    end
+    i32.const 1
+    i32.const 1
+    i32.eq
+    i32.const 1
+    i32.const 2
+    i32.ne
+    local.set $tmp.2
+    i32.const 0
+    i32.ne
+    local.get $tmp.2
+    i32.const 0
+    i32.ne
+    i32.and
+    if
+      call $bump
+      drop
+    end
+    i32.const 0
+    i32.const 0
+    i32.const 4
+    i32.mul
+    i32.add
+    i32.load
+    call $env.print_int
+    i32.const 10
+    call $env.print_char
+    i32.const 100
+    i32.const 7
+    i32.div_s
+    i32.const 3
+    i32.const 2
+    i32.mul
+    i32.sub
+    call $env.print_int
+    i32.const 10
+    call $env.print_char
+    i32.const 0
+    return
+    unreachable
+  )
+  (export "main" (func $main))
+)
@@ -1,9 +1,16 @@
+mod common;
+
 use std::{cell::RefCell, rc::Rc};
 
 use koala::{
-    compiler::{CodeGen, CompilerContext},
+    compiler::CompilerContext,
+    grammar::Program,
     kvm::VirtualMachine,
+    optimizer::Optimize,
     parser::parse_code,
+    peephole,
+    rvm::{self, RegisterMachine},
+    verify::verify,
 };
 
 macro_rules! code_tests {
@@ -11,23 +18,101 @@ macro_rules! code_tests {
       #[test]
       fn $name() {
         let (code, expected) = $value;
-        assert_eq!(kvm_run_code(&code), expected);
+        assert_eq!(kvm_run_code(&code, ""), expected);
+      }
+  )*}
+}
+
+/// Each case is a program, the input it is given, and what it should print
+macro_rules! input_tests {
+  ($($name:ident: $value:expr,)*) => {$(
+      #[test]
+      fn $name() {
+        let (code, input, expected) = $value;
+        assert_eq!(kvm_run_code(&code, input), expected);
       }
   )*}
 }
 
-fn kvm_run_code(code: &str) -> String {
+/// Run the code both as written and with each optimization enabled,
+/// checking that the optimizers did not change the program's output,
+/// and that the register VM agrees with the stack VM.
+/// All of the bytecode is checked by the verifier before it is run.
+/// When a C compiler or node is installed the C, assembly and WebAssembly backends
+/// are checked against the VM as well. Every run reads the same scripted input.
+fn kvm_run_code(code: &str, input: &str) -> String {
+    let output = kvm_run_program(parse_code(code).unwrap(), false, input);
+    for (program, peephole) in [
+        (parse_code(code).unwrap().optimize(), false),
+        (parse_code(code).unwrap(), true),
+        (parse_code(code).unwrap().optimize(), true),
+    ] {
+        assert_eq!(
+            output,
+            kvm_run_program(program, peephole, input),
+            "optimized output differs"
+        );
+    }
+    assert_eq!(
+        output,
+        rvm_run_program(parse_code(code).unwrap(), input),
+        "register VM output differs"
+    );
+    if common::tool_available("cc") {
+        assert_eq!(output, common::c_run_code(code, input), "C output differs");
+        assert_eq!(
+            output,
+            common::asm_run_code(code, input),
+            "assembly output differs"
+        );
+    }
+    if common::tool_available("node") {
+        assert_eq!(
+            output,
+            common::wasm_run_code(code, input),
+            "wasm output differs"
+        );
+    }
+
+    output
+}
+
+fn kvm_run_program(program: Program, peephole: bool, input: &str) -> String {
     let value = Rc::new(RefCell::new(String::new()));
     let captured_value = value.clone();
 
     let print_callback = &move |msg: &str| *captured_value.borrow_mut() += msg;
 
-    let program = parse_code(&code).unwrap();
-    let bin = program.code_gen(&mut CompilerContext::new(), 0);
-    let mut kvm = VirtualMachine::new(print_callback, &|msg: &str| println!("{}", msg));
+    let mut bin = program.code_gen(&mut CompilerContext::new());
+    if peephole {
+        bin = peephole::optimize(&bin);
+    }
+    if let Err(e) = verify(&bin) {
+        panic!("generated code failed verification: {}", e);
+    }
+    let mut lines = input.lines().map(str::to_string);
+    let mut read_input = || lines.next();
+    let mut kvm = VirtualMachine::new(print_callback);
+    kvm.set_input(&mut read_input);
     kvm.run(&bin);
 
-    return value.take();
+    value.take()
+}
+
+fn rvm_run_program(program: Program, input: &str) -> String {
+    let value = Rc::new(RefCell::new(String::new()));
+    let captured_value = value.clone();
+
+    let print_callback = &move |msg: &str| *captured_value.borrow_mut() += msg;
+
+    let register_code = rvm::translate(&program.to_ir(&mut CompilerContext::new()));
+    let mut lines = input.lines().map(str::to_string);
+    let mut read_input = || lines.next();
+    let mut rvm = RegisterMachine::new(print_callback, None);
+    rvm.set_input(&mut read_input);
+    rvm.run(&register_code);
+
+    value.take()
 }
 
 code_tests! {
@@ -180,7 +265,7 @@ code_tests! {
   "6"),
   while_control_test: ("
   fn main() {
-    let a = 2
+    let mut a = 2
     while a < 5 {
       print(a)
       a = a + 1
@@ -211,7 +296,7 @@ code_tests! {
   fn dfs(row) {
     print(row)
     visited[row] = 1
-    let goTo = 0
+    let mut goTo = 0
     while goTo < 5 {
       if (visited[goTo] == 0) && (graph[m2d(row, goTo, 5)] != 0) {
         dfs(goTo, row)
@@ -226,4 +311,329 @@ code_tests! {
 
   ",
   "03124"),
+  constant_folding_test: ("
+  fn main() {
+    let x = 6
+    print(x * 1 + 0)
+    print(x * (4 - 4))
+    if 2 > 3 { print(1) }
+    if 3 > 2 { print(2 * 2) }
+  }
+  ",
+  "604"),
+  local_array_assignment_test: ("
+  fn main() {
+    let a[3] = [1,2,3]
+    a[1] = 5
+    print(a[0] + a[1] + a[2])
+  }
+  ",
+  "9"),
+  void_calls_test: ("
+  fn main() {
+    f()
+    f()
+  }
+
+  fn f() {
+    print(1)
+    return
+  }
+  ",
+  "11"),
+  evaluation_order_test: ("
+  fn main() {
+    print(f(1) + f(2))
+    g(f(3), f(4))
+  }
+
+  fn f(n) {
+    print(n)
+    return n
+  }
+
+  fn g(a, b) {
+    print(a - b)
+  }
+  ",
+  "21343-1"),
+  rand_test: ("
+  fn main() {
+    let mut i = 0
+    while i < 5 {
+      print(rand(1, 6))
+      i = i + 1
+    }
+    println()
+    print(rand(0 - 100, 100))
+  }
+  ",
+  "61322\n80"),
+  rand_range_test: ("
+  fn main() {
+    let mut above_low = 1
+    let mut below_high = 1
+    let mut i = 0
+    while i < 200 {
+      let roll = rand(1, 6)
+      if (roll < 1) {
+        above_low = 0
+      }
+      if (roll > 6) {
+        below_high = 0
+      }
+      i = i + 1
+    }
+    print(above_low)
+    print(below_high)
+    print(rand(7, 7))
+  }
+  ",
+  "117"),
+  natives_test: ("
+  fn main() {
+    global g[4] = [1,2,3,4]
+    let a[3] = [5,6,7]
+    print(abs(0 - 5))
+    print(min(3, 9))
+    print(max(3, 9))
+    println()
+    print(pow(3, 4))
+    print(pow(2, 0))
+    print(sqrt(50))
+    println()
+    print(assert(1))
+    print(len(a) + len(g))
+  }
+  ",
+  "539\n8117\n07"),
+  shadowed_natives_test: ("
+  fn main() {
+    print(max(3, 9))
+    print(len(3))
+    print(rand(1, 2))
+    let a[3] = [5,6,7]
+    for x in a {
+      print(x)
+    }
+  }
+
+  fn max(a, b) {
+    return a + b
+  }
+
+  fn len(x) {
+    return x + 1
+  }
+
+  fn rand(lo, hi) {
+    return lo - hi
+  }
+  ",
+  "124-1567"),
+  exit_test: ("
+  fn main() {
+    print(1)
+    stop()
+    print(3)
+  }
+
+  fn stop() {
+    print(2)
+    exit(0)
+  }
+  ",
+  "12"),
+  shadowing_test: ("
+  fn main() {
+    global x = 1
+    let y = 2
+    if y > 1 {
+      let y = y * 10
+      let mut x = y + 1
+      x = x + 1
+      println(x)
+      println(y)
+    }
+    println(y)
+    x = x + 5
+    println(x)
+    let y = y + 1
+    println(y)
+    if 1 {
+      let y = 0
+    }
+    print(y)
+  }
+  ",
+  "22\n20\n2\n6\n3\n3"),
+  block_scope_test: ("
+  fn main() {
+    let mut i = 0
+    while i < 3 {
+      let a[2] = [i, i]
+      let sum = a[0] + a[1]
+      print(sum)
+      i = i + 1
+    }
+    if i == 3 {
+      let b[3] = [7,8,9]
+      print(len(b) + b[2])
+    }
+    if i != 3 {
+      let b = 1
+      print(b)
+    }
+  }
+  ",
+  "02412"),
+  constants_test: ("
+  const N = 3
+  const M = N * 2
+
+  fn main() {
+    global g[M]
+    let a[N] = [1,2,3]
+    var i = 0
+    while i < N {
+      g[i] = a[i] * M
+      i = i + 1
+    }
+    print(len(g) + len(a))
+    print(g[2])
+    let mut total = M
+    total = total - N
+    print(total)
+  }
+  ",
+  "9183"),
+  for_test: ("
+  const N = 4
+
+  fn main() {
+    global g[5] = [3,1,4,1,5]
+    for i in 0..N {
+      print(i)
+    }
+    println()
+    for i in 1..=3 {
+      print(i * i)
+    }
+    println()
+    for i in 0..10 step 3 {
+      print(i)
+    }
+    println()
+    for i in 5..=1 step 0 - 2 {
+      print(i)
+    }
+    println()
+    let mut total = 0
+    for x in g {
+      total = total + x
+    }
+    println(total)
+    let a[3] = [7,8,9]
+    for x in a {
+      for y in 0..x - 6 {
+        print(y)
+      }
+    }
+    println()
+    let n = 3
+    let s = 2
+    for i in n..n * 3 step s {
+      print(i)
+    }
+    println()
+    let down = 0 - 3
+    for i in 10..0 step down {
+      print(i)
+    }
+    println()
+    for i in 2..=0 step 0 - s {
+      print(i)
+    }
+  }
+  ",
+  "0123\n149\n0369\n531\n14\n001012\n357\n10741\n20"),
+  break_continue_test: ("
+  fn main() {
+    let mut i = 0
+    while 1 {
+      i = i + 1
+      if i == 2 { continue }
+      if i > 4 { break }
+      print(i)
+    }
+    println()
+    for i in 0..10 {
+      if (i == 3) || (i == 5) { continue }
+      if i == 7 { break }
+      print(i)
+    }
+    println()
+    'outer: for x in 1..=5 {
+      let mut y = 0
+      'inner: while y < 4 {
+        y = y + 1
+        if y == x { continue 'outer }
+        if x == 5 { break 'outer }
+        if y == 3 { break 'inner }
+        print((x * 10) + y)
+      }
+      print(0)
+    }
+    println()
+    'rows: for r in 0..3 step 1 {
+      for c in 0..3 {
+        if c > r { continue 'rows }
+        print(c)
+      }
+    }
+  }
+  ",
+  "134\n01246\n21313241420\n001012"),
+}
+
+input_tests! {
+  read_int_test: ("
+  fn main() {
+    let a = read_int()
+    let b = read_int()
+    print(a - b)
+  }
+  ",
+  "7 10\n",
+  "-3"),
+  read_line_test: ("
+  fn main() {
+    let a = read_line()
+    let b = read_line()
+    println(a)
+    println(b)
+  }
+  ",
+  "1 2 3\n\n  4\n",
+  "1\n4\n"),
+  read_loop_test: ("
+  fn main() {
+    let mut total = 0
+    let mut n = read_int()
+    while n > 0 {
+      total = total + read_int()
+      n = n - 1
+    }
+    print(total)
+  }
+  ",
+  "3\n5\n6\n-1\n",
+  "10"),
+  read_order_test: ("
+  fn main() {
+    print(read_int() - read_int())
+  }
+  ",
+  "1 2",
+  "1"),
 }
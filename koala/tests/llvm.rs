@@ -0,0 +1,42 @@
+mod common;
+
+use common::{command_status, golden_test, kvm_run_code, lli_args, run_command, tool_available};
+use koala::{backend::llvm, compiler::CompilerContext, parser::parse_code};
+
+macro_rules! llvm_tests {
+  ($($name:ident: $value:expr,)*) => {$(
+      #[test]
+      fn $name() {
+        let program = $value;
+        let source = common::read_program(program);
+        let ir = llvm::emit(&parse_code(&source).unwrap().to_ir(&mut CompilerContext::new()));
+        golden_test(&format!("{}.ll", program), &ir);
+
+        // execute the IR when an LLVM interpreter is installed
+        if tool_available("lli") {
+          assert_eq!(run_command("lli", lli_args(), &ir), kvm_run_code(&source));
+        }
+      }
+  )*}
+}
+
+#[test]
+fn division_by_zero_llvm_test() {
+    if !tool_available("lli") {
+        return;
+    }
+    // the divisor is only known at runtime, so the optimizer can't have left it out
+    let source = "fn main() { print(1) print(f(3) / (3 - f(3))) } fn f(n) { return n }";
+    let ir = llvm::emit(
+        &parse_code(source)
+            .unwrap()
+            .to_ir(&mut CompilerContext::new()),
+    );
+    assert_eq!(command_status("lli", lli_args(), &ir), Some(1));
+}
+
+llvm_tests! {
+  fib_llvm_test: "fib",
+  arrays_llvm_test: "arrays",
+  logic_llvm_test: "logic",
+}
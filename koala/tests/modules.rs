@@ -0,0 +1,40 @@
+mod common;
+
+use std::path::PathBuf;
+
+use koala::{compiler::CompilerContext, kvm::VirtualMachine, modules};
+
+/// The program in `tests/programs/modules`, which imports a module from its own directory
+/// and another from `lib`, which both of them use
+#[test]
+fn modules_test() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/programs/modules/main.koala");
+    let program = modules::load(&path).unwrap();
+
+    let output = std::cell::RefCell::new(String::new());
+    let print = |msg: &str| *output.borrow_mut() += msg;
+    let bin = program.code_gen(&mut CompilerContext::new());
+    VirtualMachine::new(&print).run(&bin);
+    let output = output.take();
+    assert_eq!(output, "12\n5\n100\n");
+
+    if common::tool_available("cc") {
+        assert_eq!(
+            output,
+            common::c_run_program(&program, ""),
+            "C output differs"
+        );
+        assert_eq!(
+            output,
+            common::asm_run_program(&program, ""),
+            "assembly output differs"
+        );
+    }
+    if common::tool_available("node") {
+        assert_eq!(
+            output,
+            common::wasm_run_program(&program, ""),
+            "wasm output differs"
+        );
+    }
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::{golden_test, kvm_run_code, tool_available, wasm_run_code};
+use koala::{backend::wasm, parser::parse_code};
+
+macro_rules! wasm_tests {
+  ($($name:ident: $value:expr,)*) => {$(
+      #[test]
+      fn $name() {
+        let program = $value;
+        let source = common::read_program(program);
+        let module = wasm::compile(&parse_code(&source).unwrap());
+        golden_test(&format!("{}.wat", program), &module.to_wat());
+
+        // both encodings are valid modules
+        wasmparser::validate(&module.to_wasm()).unwrap();
+        wasmparser::validate(&wat::parse_str(module.to_wat()).unwrap()).unwrap();
+
+        // run the binary encoding when node is installed
+        if tool_available("node") {
+          assert_eq!(wasm_run_code(&source, ""), kvm_run_code(&source));
+        }
+      }
+  )*}
+}
+
+wasm_tests! {
+  fib_wasm_test: "fib",
+  arrays_wasm_test: "arrays",
+  logic_wasm_test: "logic",
+}
@@ -8,11 +8,12 @@ import { VscDebugStepBack, VscRepo, VscTerminal } from 'react-icons/vsc'
 import { Box, Center, Container, Grid, HStack, Link, Stack, Text } from '@chakra-ui/layout'
 import { Button } from '@chakra-ui/button'
 
-import init, { run, sourceCodeGen, parseAst } from 'koala'
+import init, { run, sourceCodeGen, sourceWasmGen, sourceWatGen, parseAst } from 'koala'
 import { KoalaCodeExampe } from './examples'
 import { Tab, TabList, TabPanel, TabPanels, Tabs } from '@chakra-ui/tabs'
 import { Textarea } from '@chakra-ui/textarea'
 import { Checkbox } from '@chakra-ui/checkbox'
+import { Slider, SliderFilledTrack, SliderThumb, SliderTrack } from '@chakra-ui/slider'
 
 const repo = "https://github.com/ndbaker1/koala"
 
@@ -21,6 +22,25 @@ const minScreenHeight = 40
 
 function toRem(value: number) { return value + "rem" }
 
+/** Events reported by the VM while debugging, each from the instruction at `pc` */
+type VmEvent = { pc: number } & (
+  | { kind: 'step', opcode: string, stack: number[] }
+  | { kind: 'call', fn_addr: number, args: number[] }
+  | { kind: 'return', fn_addr: number, locals: number[] }
+  | { kind: 'print' | 'error', message: string }
+  | { kind: 'store_local' | 'store_global', offset: number, old: number, new: number }
+)
+
+type StepEvent = Extract<VmEvent, { kind: 'step' }>
+
+type TraceState = {
+  step?: StepEvent
+  stack: number[]
+  frames: { fn_addr: number, locals: number[] }[]
+  globals: number[]
+  output: string
+}
+
 enum KoalaState {
   open = "ʕ •ᴥ•ʔ",
   closed = "ʕ -ᴥ-ʔ",
@@ -39,10 +59,17 @@ export default function App() {
   const [ast, setAst] = React.useState("")
   const vmCodeRef = React.useRef(new Uint32Array)
   const [output, setOutput] = React.useState("")
+  const [wat, setWat] = React.useState("")
+  const [timings, setTimings] = React.useState<{ vm?: number, wasm?: number }>({})
 
   const [outputConfig, setOutputConfig] = React.useState({ output: true, debug: false })
+  // read by read_int() and read_line(), the same way a terminal's input would be
+  const [input, setInput] = React.useState("")
   const outputCallback = (str: string) => setOutput(cur => cur + str)
-  const debugCallback = (str: string) => setOutput(cur => cur + str)
+  const [trace, setTrace] = React.useState<VmEvent[]>([])
+  const [tracePosition, setTracePosition] = React.useState(0)
+  const traceSteps = React.useMemo(() => trace.filter(event => event.kind == 'step').length, [trace])
+  const traceState = React.useMemo(() => replayTrace(trace, tracePosition), [trace, tracePosition])
 
   const [mainKoala, setMainKoala] = React.useState(KoalaState.open)
 
@@ -169,7 +196,14 @@ export default function App() {
                     options={{ fontFamily: '"Consolas"' }}
                   />
                 </Box>
-                <Grid gap="5" gridTemplateColumns='1fr auto'>
+                <Textarea
+                  marginBottom="1rem"
+                  fontFamily="monospace"
+                  placeholder="Input for read_int() and read_line()"
+                  value={input}
+                  onChange={e => setInput(e.target.value)}
+                />
+                <Grid gap="5" gridTemplateColumns='1fr 1fr auto'>
                   <Button onClick={() => {
                     let stage
                     try {
@@ -179,16 +213,58 @@ export default function App() {
                       vmCodeRef.current = sourceCodeGen(codeRef.current)
                       setOutput('')
                       stage = 'execution'
+                      const events: VmEvent[] = []
+                      const start = performance.now()
                       run(
                         vmCodeRef.current,
                         outputConfig.output ? outputCallback : () => { },
-                        outputConfig.debug ? debugCallback : () => { },
+                        outputConfig.debug ? (event: VmEvent) => { events.push(event) } : undefined,
+                        inputLines(input),
                       )
+                      setTimings(cur => ({ ...cur, vm: performance.now() - start }))
+                      setTrace(events)
+                      setTracePosition(0)
                       smoothScrollTo('#output')
                     } catch (e) {
                       alert('encountered error in ' + stage + ' stage:\n' + e)
                     }
                   }}>Compile & Run</Button>
+                  <Button onClick={async () => {
+                    let stage
+                    try {
+                      stage = 'parsing'
+                      setAst(parseAst(codeRef.current))
+                      stage = 'code generation'
+                      setWat(sourceWatGen(codeRef.current))
+                      // prints are imported from the page, in place of the VM's output callback
+                      const print = outputConfig.output ? outputCallback : () => { }
+                      const reader = inputReader(input)
+                      const { instance } = await WebAssembly.instantiate(sourceWasmGen(codeRef.current), {
+                        env: {
+                          print_int: (val: number) => print(String(val)),
+                          print_char: (val: number) => print(String.fromCharCode(val)),
+                          read_int: reader.readInt,
+                          read_line: reader.readIntLine,
+                          // exit() unwinds out of main, which is caught below
+                          exit: (code: number) => { throw { exitCode: code } },
+                          clock: () => Math.floor(performance.now() - start),
+                        },
+                      })
+                      setOutput('')
+                      stage = 'execution'
+                      const start = performance.now()
+                      const main = instance.exports.main as () => number
+                      try {
+                        main()
+                      } catch (e) {
+                        if (!(e instanceof Object && 'exitCode' in e)) throw e
+                      }
+                      setTimings(cur => ({ ...cur, wasm: performance.now() - start }))
+                      smoothScrollTo('#output')
+                    } catch (e) {
+                      alert('encountered error in ' + stage + ' stage:\n' + e)
+                    }
+                  }}>Compile to WebAssembly & Run</Button>
                   <Stack spacing={5} direction="row">
                     <Checkbox
                       isChecked={outputConfig.output}
@@ -243,6 +319,8 @@ export default function App() {
               <Tab>Info</Tab>
               <Tab>Syntax Tree</Tab>
               <Tab>Instructions (Hex)</Tab>
+              <Tab>WebAssembly (Text)</Tab>
+              <Tab>Trace</Tab>
             </TabList>
             <TabPanels>
               <TabPanel>
@@ -268,6 +346,9 @@ export default function App() {
                           <Textarea value={output} readOnly minH="16rem" />
                         </Grid>
                         <br />
+                        {timings.vm !== undefined && <>Koala Virtual Machine: {timings.vm.toFixed(2)}ms<br /></>}
+                        {timings.wasm !== undefined && <>Compiled to WebAssembly: {timings.wasm.toFixed(2)}ms<br /></>}
+                        <br />
                         This is the output of our program,
                         but you can also view the <b>Syntax Tree</b> generated by the Language parser,
                         and the corresponding 32bit <b>Instructions</b> (in hex) that are run by the Koala Virtual Machine.
@@ -311,6 +392,58 @@ export default function App() {
                   }
                 />
               </TabPanel>
+              <TabPanel>
+                <Textarea
+                  overflow="auto"
+                  overscrollBehavior="contain"
+                  height={toRem(windowHeight)}
+                  fontFamily="monospace"
+                  value={wat}
+                  readOnly
+                />
+              </TabPanel>
+              <TabPanel>
+                <Box
+                  textAlign="left"
+                  overflow="auto"
+                  overscrollBehavior="contain"
+                  height={toRem(windowHeight)}
+                >
+                  {traceSteps > 0
+                    ? <Stack spacing={4}>
+                      <HStack>
+                        <Button
+                          onClick={() => setTracePosition(pos => Math.max(pos - 1, 0))}
+                          isDisabled={tracePosition == 0}
+                        >
+                          Back
+                        </Button>
+                        <Button
+                          onClick={() => setTracePosition(pos => Math.min(pos + 1, traceSteps - 1))}
+                          isDisabled={tracePosition == traceSteps - 1}
+                        >
+                          Forward
+                        </Button>
+                        <Text whiteSpace="nowrap">step {tracePosition + 1} / {traceSteps}</Text>
+                      </HStack>
+                      <Slider min={0} max={traceSteps - 1} value={tracePosition} onChange={setTracePosition}>
+                        <SliderTrack>
+                          <SliderFilledTrack />
+                        </SliderTrack>
+                        <SliderThumb />
+                      </Slider>
+                      <pre style={{ fontFamily: 'monospace' }}>
+                        {traceState.step && 'next:    ' + traceState.step.pc.toString().padStart(4, '0') + ' ' + traceState.step.opcode + '\n'}
+                        {'stack:   ' + JSON.stringify(traceState.stack) + '\n'}
+                        {traceState.frames.map(frame => 'frame @' + frame.fn_addr + ': ' + JSON.stringify(frame.locals) + '\n')}
+                        {'globals: ' + JSON.stringify(traceState.globals) + '\n'}
+                        {'output:  ' + JSON.stringify(traceState.output)}
+                      </pre>
+                    </Stack>
+                    : <>Check <b>Debug</b> before running to record a trace that can be stepped through here.</>
+                  }
+                </Box>
+              </TabPanel>
             </TabPanels>
           </Tabs>
           <Grid>
@@ -328,6 +461,66 @@ export default function App() {
   )
 }
 
+/** State of the VM just before the step at `position` executes, rebuilt from the events before it */
+function replayTrace(trace: VmEvent[], position: number): TraceState {
+  const state: TraceState = { stack: [], frames: [], globals: [], output: '' }
+  let steps = 0
+  for (const event of trace) {
+    switch (event.kind) {
+      case 'step':
+        if (steps++ == position) {
+          return { ...state, step: event, stack: event.stack }
+        }
+        break
+      case 'call':
+        state.frames.push({ fn_addr: event.fn_addr, locals: [...event.args] })
+        break
+      case 'return':
+        state.frames.pop()
+        break
+      case 'store_local':
+      case 'store_global':
+        const slots = event.kind == 'store_global' ? state.globals : state.frames[state.frames.length - 1]?.locals
+        if (slots) {
+          while (slots.length <= event.offset) slots.push(0)
+          slots[event.offset] = event.new
+        }
+        break
+      case 'print':
+        state.output += event.message
+        break
+    }
+  }
+  return state
+}
+
+/** Callback handing the VM one line of the input at a time, then null once it runs out */
+function inputLines(input: string): () => string | null {
+  const lines = input.split('\n')
+  if (lines[lines.length - 1] == '') lines.pop()
+  return () => lines.shift() ?? null
+}
+
+/** Imports for compiled WebAssembly, reading integers the same way the VM does */
+function inputReader(input: string) {
+  const readInt = () => {
+    const match = /^\s*(\S+)/.exec(input)
+    if (!match) throw new Error('read past the end of the input')
+    const val = Number(match[1])
+    if (!Number.isInteger(val)) throw new Error(`expected an integer but read '${match[1]}'`)
+    input = input.slice(match[0].length)
+    return val
+  }
+  return {
+    readInt,
+    readIntLine: () => {
+      const val = readInt()
+      input = input.replace(/^[^\n]*\n?/, '')
+      return val
+    },
+  }
+}
+
 function smoothScrollTo(id: string) {
   document.querySelector(id)?.scrollIntoView({ behavior: 'smooth' })
 }
@@ -23,7 +23,7 @@ fn dfs_demo() {
 fn dfs(row) {
   print(row)
   visited[row] = 1
-  let goTo = 0
+  let mut goTo = 0
   while goTo < 5 {
     if (visited[goTo] == 0) && (graph[m2d(row, goTo, 5)] != 0) {
       dfs(goTo, row)